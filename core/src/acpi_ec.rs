@@ -0,0 +1,63 @@
+use alloc::string::String;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+use super::{Ec, EcParam, HostInterface};
+
+/// ACPI space of the first EC, exposed by the ec_sys module in debugfs
+pub const ACPI_EC_IO: &str = "/sys/kernel/debug/ec/ec0/io";
+
+/// The primary EC, reached through the kernel ACPI EC driver instead of port
+/// I/O, for kernels in lockdown mode that refuse iopl and /dev/port
+///
+/// Each access is a transaction of the kernel driver, so it cannot corrupt
+/// transactions of the driver, but only the ACPI parameter space is reachable.
+/// The project and version are read with commands that the driver does not
+/// expose, so they are empty, and flash mode is not available. Lockdown also
+/// restricts debugfs to files that are only readable, so ec_sys must be loaded
+/// without write_support.
+#[derive(Debug)]
+pub struct AcpiEc {
+    file: File,
+}
+
+impl AcpiEc {
+    pub fn open() -> io::Result<Self> {
+        Self::open_path(ACPI_EC_IO)
+    }
+
+    /// Open the ACPI space at another path, such as that of a second EC
+    pub fn open_path<P: AsRef<std::path::Path>>(path: P) -> io::Result<Self> {
+        Ok(Self { file: File::open(path)? })
+    }
+
+    pub fn get_param(&mut self, param: EcParam) -> io::Result<u8> {
+        let mut value = [0];
+        self.file.read_exact_at(&mut value, param.offset() as u64)?;
+        Ok(value[0])
+    }
+}
+
+impl Ec for AcpiEc {
+    /// Size of the flash, with the same rule as EcFlash
+    fn size(&mut self) -> usize {
+        if self.get_param(EcParam::FlashSizeFlag).ok() == Some(0x80) {
+            128 * 1024
+        } else {
+            64 * 1024
+        }
+    }
+
+    fn project(&mut self) -> String {
+        String::new()
+    }
+
+    fn version(&mut self) -> String {
+        String::new()
+    }
+
+    fn interface(&mut self) -> Option<(HostInterface, u16, u16)> {
+        Some((HostInterface::Kernel, 0x62, 0x66))
+    }
+}
@@ -0,0 +1,84 @@
+//! Choice between the ways of erasing and programming the flash of an EC
+//!
+//! The proprietary EC firmware has three command sets that reach the flash:
+//! follow mode through the mailbox, the fcommand parameters, and the scratch
+//! ROM. [`Flasher::probe`](crate::Flasher::probe) asks the EC which of them
+//! answer, and [`AlgorithmProbe::select`] picks the safest one that can
+//! program the flash.
+
+use core::fmt;
+
+use super::Handshake;
+
+/// Way of erasing and programming the flash, safest first
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum FlashAlgorithm {
+    /// Follow mode through the mailbox of the running EC firmware, which can
+    /// be interrupted, resumed, and left without powering off
+    FollowMode,
+    /// Follow mode through the scratch ROM, which stops the EC firmware and
+    /// powers off the system when left, even if programming failed
+    ScratchRom,
+}
+
+impl FlashAlgorithm {
+    /// Every algorithm, safest first
+    pub const ALL: [FlashAlgorithm; 2] = [FlashAlgorithm::FollowMode, FlashAlgorithm::ScratchRom];
+
+    /// Algorithm by the name that name returns
+    pub fn parse(s: &str) -> Option<Self> {
+        FlashAlgorithm::ALL.iter().copied().find(|algorithm| algorithm.name() == s)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FlashAlgorithm::FollowMode => "follow",
+            FlashAlgorithm::ScratchRom => "scratch",
+        }
+    }
+}
+
+impl fmt::Display for FlashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FlashAlgorithm::FollowMode => write!(f, "follow mode"),
+            FlashAlgorithm::ScratchRom => write!(f, "scratch ROM"),
+        }
+    }
+}
+
+/// What the EC answered to the probe of each command set
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AlgorithmProbe {
+    /// Answer to the handshake of flash mode, which follow mode needs
+    pub handshake: Handshake,
+    /// Whether the fcommand parameters answer as idle
+    ///
+    /// The EC firmware has no known fcommand for programming the flash, so
+    /// this is only reported.
+    pub fcommand: bool,
+    /// Whether I2EC reads back the Super I/O ID of the primary EC, which the
+    /// scratch ROM path needs
+    pub scratch_rom: bool,
+}
+
+impl AlgorithmProbe {
+    /// Whether the EC answered what algorithm needs
+    pub fn supports(&self, algorithm: FlashAlgorithm) -> bool {
+        match algorithm {
+            FlashAlgorithm::FollowMode => self.handshake == Handshake::Accepted,
+            FlashAlgorithm::ScratchRom => self.scratch_rom,
+        }
+    }
+
+    /// Safest algorithm that the EC supports
+    ///
+    /// A busy EC may accept the handshake when asked again, so nothing is
+    /// picked rather than falling back to the scratch ROM.
+    pub fn select(&self) -> Option<FlashAlgorithm> {
+        if self.handshake == Handshake::Busy {
+            return None;
+        }
+        FlashAlgorithm::ALL.iter().copied().find(|&algorithm| self.supports(algorithm))
+    }
+}
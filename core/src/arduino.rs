@@ -0,0 +1,684 @@
+//! Arduino programmer on the debug header of the EC, reached through a serial
+//! port or a TCP serial bridge, and the STK500v2 bootloader of the Arduino
+//! Mega 2560 that its sketch is uploaded with.
+//!
+//! The frames are those of [`Protocol`], the same as [`AsyncParallelArduino`]
+//! speaks.
+//!
+//! [`AsyncParallelArduino`]: crate::AsyncParallelArduino
+
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cmp;
+use core::time::Duration;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+
+use super::{Address, Debugger, Error, Mega2560, Protocol, Result, SmfiAccel, SpiRom, CONFIG_PATH, KNOWN_IDS, PICO_USB_VIDS};
+
+/// Times the programmer is reopened when it is reconnected after its
+/// transport fails
+const RECONNECT_ATTEMPTS: usize = 5;
+
+/// Convert a serial port error into a transport error
+fn transport<E: core::fmt::Display>(err: E) -> Error {
+    Error::Transport(err.to_string())
+}
+
+/// Read the chip ID and version through the debugger
+fn read_id<T: Debugger>(port: &mut T) -> Result<(u16, u8)> {
+    let mut id = [0; 3];
+    port.read_at(Address::CHIPID0, &mut id[0..1])?;
+    port.read_at(Address::CHIPID1, &mut id[1..2])?;
+    port.read_at(Address::CHIPVER, &mut id[2..3])?;
+    Ok((((id[0] as u16) << 8) | (id[1] as u16), id[2]))
+}
+
+/// Read the chip ID and version twice and check them before anything is
+/// erased, accepting known_ids besides KNOWN_IDS
+///
+/// A misconfigured programmer, such as a Mega 2560 with the wrong pins, reads
+/// floating or shorted lines as IDs like 0xFF7F, so reads must agree, neither
+/// byte may be 0x00 or 0xFF, and the ID must be known.
+pub fn check_id<T: Debugger>(port: &mut T, known_ids: &[u16]) -> Result<(u16, u8)> {
+    let (ecid, version) = read_id(port)?;
+
+    let again = read_id(port)?;
+    if again != (ecid, version) {
+        return Err(Error::InvalidData(format!(
+            "ID reads do not agree: {:04X} VER {} then {:04X} VER {}",
+            ecid, version, again.0, again.1
+        )));
+    }
+
+    if ecid.to_be_bytes().iter().any(|&byte| byte == 0x00 || byte == 0xFF) {
+        return Err(Error::InvalidData(format!(
+            "ID {:04X} looks like floating or shorted lines, check the programmer wiring",
+            ecid
+        )));
+    }
+
+    if ! KNOWN_IDS.contains(&ecid) && ! known_ids.contains(&ecid) {
+        return Err(Error::Incompatible(format!(
+            "unknown ID {:04X}, add it to known_ids in {} if it is supported",
+            ecid, CONFIG_PATH
+        )));
+    }
+
+    Ok((ecid, version))
+}
+
+/// Serial ports of the attached USB serial devices
+pub fn usb_programmers() -> Result<Vec<String>> {
+    Ok(serialport::available_ports()
+        .map_err(transport)?
+        .into_iter()
+        .filter(|port| matches!(port.port_type, serialport::SerialPortType::UsbPort(_)))
+        .map(|port| port.port_name)
+        .collect())
+}
+
+/// Parallel port Arduino programmer, or a dongle with the same frames
+///
+/// The frames are those of a version of the programmer protocol, which is
+/// documented with [`Protocol`]. Older sketches do not understand the
+/// combined frames, so they are only used with version 2 or later.
+pub struct ParallelArduino {
+    stream: Box<dyn Stream>,
+    buffer_size: usize,
+    protocol: Box<dyn Protocol>,
+    /// Programmer argument that open connected with, to reconnect
+    programmer: Option<String>,
+}
+
+/// Byte stream to the programmer
+pub trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+impl ParallelArduino {
+    /// Connect to parallel port arduino using provided port
+    pub fn new<S: AsRef<str>>(path: S, protocol: Box<dyn Protocol>) -> Result<Self> {
+        let tty = serialport::new(path.as_ref(), 1_000_000)
+            .data_bits(serialport::DataBits::Eight)
+            .flow_control(serialport::FlowControl::None)
+            .parity(serialport::Parity::None)
+            .stop_bits(serialport::StopBits::One)
+            .timeout(Duration::new(1, 0))
+            .open_native()
+            .map_err(transport)?;
+
+        Self::with_protocol(Box::new(tty), protocol)
+    }
+
+    /// Connect to parallel port arduino through a TCP serial bridge, such as
+    /// ser2net in raw mode, at host:port
+    pub fn connect<A: ToSocketAddrs>(addr: A, protocol: Box<dyn Protocol>) -> Result<Self> {
+        let tcp = TcpStream::connect(addr)?;
+        // Frames are small, so do not wait to coalesce them
+        tcp.set_nodelay(true)?;
+        tcp.set_read_timeout(Some(Duration::new(1, 0)))?;
+
+        Self::with_protocol(Box::new(tcp), protocol)
+    }
+
+    /// Connect to parallel port arduino using any byte stream
+    pub fn with_stream(stream: Box<dyn Stream>) -> Result<Self> {
+        Self::with_protocol(stream, Box::new(Mega2560 { combined: false }))
+    }
+
+    /// Connect to a programmer that speaks protocol using any byte stream
+    pub fn with_protocol(stream: Box<dyn Stream>, protocol: Box<dyn Protocol>) -> Result<Self> {
+        let mut port = Self { stream, buffer_size: 0, protocol, programmer: None };
+        // Wait until programmer is ready, opening the port resets it
+        thread::sleep(Duration::new(1, 0));
+        // Check that programmer is ready
+        port.echo()?;
+        // Read buffer size
+        port.update_buffer_size()?;
+
+        Ok(port)
+    }
+
+    /// Connect using a programmer argument, which is either `tcp:host:port`
+    /// or a serial port path, with a version of the protocol
+    ///
+    /// Without a version, RP2040 and STM32 dongles are told apart from the
+    /// Mega 2560 by their USB vendor ID, and a bridge is assumed to have a
+    /// Mega 2560 behind it.
+    pub fn open(programmer: &str, version: Option<u8>) -> Result<Self> {
+        let version = version.unwrap_or_else(|| {
+            let pico = serialport::available_ports().unwrap_or_default().into_iter().any(|port| {
+                port.port_name == programmer && matches!(
+                    port.port_type,
+                    serialport::SerialPortType::UsbPort(ref usb) if PICO_USB_VIDS.contains(&usb.vid)
+                )
+            });
+            if pico { 3 } else { 1 }
+        });
+        let protocol = super::protocol(version).ok_or_else(|| Error::InvalidInput(
+            format!("unknown programmer protocol version {}", version)
+        ))?;
+
+        let mut port = match programmer.strip_prefix("tcp:") {
+            Some(addr) => Self::connect(addr, protocol)?,
+            None => Self::new(programmer, protocol)?,
+        };
+        port.programmer = Some(programmer.to_string());
+        Ok(port)
+    }
+
+    fn echo(&mut self) -> Result<()> {
+        self.stream.write_all(&[
+            b'E',
+            0,
+            0x76,
+        ])?;
+
+        let mut b = [0];
+        self.stream.read_exact(&mut b)?;
+        if b[0] != 0x76 {
+            return Err(Error::InvalidData(
+                format!("received echo of {:02X} instead of {:02X}", b[0], 0x76)
+            ));
+        }
+        Ok(())
+    }
+
+    fn update_buffer_size(&mut self) -> Result<()> {
+        self.stream.write_all(&[
+            b'B',
+            0,
+        ])?;
+
+        let mut b = vec![0; self.protocol.length_bytes()];
+        self.stream.read_exact(&mut b)?;
+        // Size is recieved data + 1
+        self.buffer_size = self.protocol.decode_length(&b);
+        Ok(())
+    }
+
+    /// Bytes that the programmer transfers per frame
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Version of the protocol that the sketch speaks, 2 if it answers the
+    /// combined 'r' frame, and 1 if it only has the frames without an address
+    ///
+    /// An older sketch is left out of step with the host, so the port must be
+    /// opened again afterwards, which resets it. Firmware that was opened
+    /// with version 3 or later is not probed.
+    pub fn protocol(&mut self) -> Result<u8> {
+        if self.protocol.version() >= 3 {
+            return Ok(self.protocol.version());
+        }
+
+        self.stream.write_all(&[
+            b'r',
+            Address::CHIPID0 as u8,
+            0,
+        ])?;
+
+        let mut b = [0];
+        match self.stream.read_exact(&mut b) {
+            Ok(()) => Ok(2),
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => Ok(1),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Largest transfer that fits both the buffer and a frame
+    fn chunk_size(&self) -> usize {
+        cmp::min(self.buffer_size, self.protocol.max_len())
+    }
+
+    /// Send the frame that reads len bytes of the flash data register
+    fn request_data(&mut self, len: usize) -> Result<()> {
+        let header = if self.protocol.combined() {
+            self.protocol.header(b'r', Some(Address::INDDR as u8), len)
+        } else {
+            self.protocol.header(b'R', None, len)
+        };
+        self.stream.write_all(&header)?;
+        Ok(())
+    }
+
+    fn ack(&mut self, len: usize) -> Result<()> {
+        let expected = self.protocol.length(len);
+        let mut b = vec![0; expected.len()];
+        self.stream.read_exact(&mut b)?;
+        if b != expected {
+            return Err(Error::InvalidData(
+                format!("received ack of {:02X?} instead of {:02X?}", b, expected)
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Debugger for ParallelArduino {
+    fn address(&mut self, address: u8) -> Result<()> {
+        self.stream.write_all(&[
+            b'A',
+            address,
+        ])?;
+
+        Ok(())
+    }
+
+    fn read(&mut self, data: &mut [u8]) -> Result<usize> {
+        for chunk in data.chunks_mut(self.chunk_size()) {
+            let header = self.protocol.header(b'R', None, chunk.len());
+            self.stream.write_all(&header)?;
+            self.stream.read_exact(chunk)?;
+        }
+
+        Ok(data.len())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        for chunk in data.chunks(self.chunk_size()) {
+            let header = self.protocol.header(b'W', None, chunk.len());
+            self.stream.write_all(&header)?;
+            self.stream.write_all(chunk)?;
+            self.ack(chunk.len())?;
+        }
+
+        Ok(data.len())
+    }
+
+    fn read_at(&mut self, address: Address, data: &mut [u8]) -> Result<usize> {
+        if ! self.protocol.combined() {
+            self.address(address as u8)?;
+            return self.read(data);
+        }
+
+        for chunk in data.chunks_mut(self.chunk_size()) {
+            let header = self.protocol.header(b'r', Some(address as u8), chunk.len());
+            self.stream.write_all(&header)?;
+            self.stream.read_exact(chunk)?;
+        }
+
+        Ok(data.len())
+    }
+
+    fn write_at(&mut self, address: Address, data: &[u8]) -> Result<usize> {
+        if ! self.protocol.combined() {
+            self.address(address as u8)?;
+            return self.write(data);
+        }
+
+        for chunk in data.chunks(self.chunk_size()) {
+            let header = self.protocol.header(b'w', Some(address as u8), chunk.len());
+            self.stream.write_all(&header)?;
+            self.stream.write_all(chunk)?;
+            self.ack(chunk.len())?;
+        }
+
+        Ok(data.len())
+    }
+}
+
+impl SmfiAccel for ParallelArduino {
+    fn accelerated(&self) -> bool {
+        true
+    }
+
+    /// Open the programmer again, waiting for it to come back if its USB
+    /// connection dropped and is enumerating again, then sync with it
+    fn reconnect(&mut self) -> Result<bool> {
+        let programmer = match &self.programmer {
+            Some(programmer) => programmer.clone(),
+            None => return Ok(false),
+        };
+
+        let mut attempt = 0;
+        let port = loop {
+            match Self::open(&programmer, Some(self.protocol.version())) {
+                Ok(port) => break port,
+                Err(_) if attempt + 1 < RECONNECT_ATTEMPTS => {
+                    attempt += 1;
+                    thread::sleep(Duration::new(1, 0));
+                },
+                Err(err) => return Err(err),
+            }
+        };
+
+        self.stream = port.stream;
+        self.buffer_size = port.buffer_size;
+        Ok(true)
+    }
+
+    /// Program with the 'P' command, which runs the word program loop on the
+    /// Arduino for each buffer
+    ///
+    /// The sketch only sends an address for the first word if auto address
+    /// increment mode is not active yet, so that word is programmed here to
+    /// start at address.
+    fn program_aai_bulk(rom: &mut SpiRom<'_, '_, Self>, address: u32, data: &[u8]) -> Result<usize> {
+        if data.len() < 2 || ! data.len().is_multiple_of(2) {
+            return Err(Error::InvalidInput(
+                format!("length {} is not a multiple of 2", data.len())
+            ));
+        }
+
+        rom.write_enable()?;
+
+        rom.bus.reset()?;
+        rom.bus.write(&[
+            0xAD,
+            (address >> 16) as u8,
+            (address >> 8) as u8,
+            address as u8,
+            data[0],
+            data[1],
+        ])?;
+        rom.wait_status(rom.timeouts.write_busy, |status| status & 1 == 0)?;
+
+        {
+            let port = &mut *rom.bus.port;
+            for chunk in data[2..].chunks(port.chunk_size()) {
+                let header = port.protocol.header(b'P', None, chunk.len());
+                port.stream.write_all(&header)?;
+                port.stream.write_all(chunk)?;
+                port.ack(chunk.len())?;
+            }
+        }
+
+        rom.write_disable()?;
+
+        Ok(data.len())
+    }
+
+    /// Send the frame for the next buffer before comparing the one that
+    /// arrived, so the programmer reads the flash while this compares, and
+    /// the link does not sit idle between buffers
+    fn verify_bulk(rom: &mut SpiRom<'_, '_, Self>, address: u32, expected: &[u8]) -> Result<Option<(usize, u8)>> {
+        rom.read_command(address)?;
+
+        let port = &mut *rom.bus.port;
+        if ! port.protocol.combined() {
+            port.address(Address::INDDR as u8)?;
+        }
+
+        let chunk_size = port.chunk_size();
+        let chunks: Vec<&[u8]> = expected.chunks(chunk_size).collect();
+        let mut buf = vec![0; chunk_size];
+        if let Some(chunk) = chunks.first() {
+            port.request_data(chunk.len())?;
+        }
+        for (i, chunk) in chunks.iter().enumerate() {
+            let data = &mut buf[..chunk.len()];
+            port.stream.read_exact(data)?;
+            let next = chunks.get(i + 1);
+            if let Some(next) = next {
+                port.request_data(next.len())?;
+            }
+
+            if let Some(j) = data.iter().zip(chunk.iter()).position(|(a, b)| a != b) {
+                let mismatch = (address as usize + i * chunk_size + j, data[j]);
+                // Drain the buffer already requested to keep the frames in step
+                if let Some(next) = next {
+                    port.stream.read_exact(&mut buf[..next.len()])?;
+                }
+                return Ok(Some(mismatch));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Start of every STK500v2 message
+const STK_MESSAGE_START: u8 = 0x1B;
+/// Marks the end of the header of an STK500v2 message
+const STK_TOKEN: u8 = 0x0E;
+const STK_STATUS_OK: u8 = 0x00;
+/// Signature bytes of the ATmega2560 of the Arduino Mega 2560
+const ATMEGA2560_SIGNATURE: [u8; 3] = [0x1E, 0x98, 0x01];
+/// Flash page of the ATmega2560
+const SKETCH_PAGE_SIZE: usize = 256;
+/// Flash of the ATmega2560 below the 8 KiB bootloader
+const SKETCH_MAX_SIZE: usize = 248 * 1024;
+
+/// STK500v2 bootloader of the Arduino Mega 2560, which the sketch of the
+/// programmer is uploaded with
+pub struct Stk500 {
+    port: Box<dyn serialport::SerialPort>,
+    sequence: u8,
+    /// Name that the bootloader signed on with
+    pub name: String,
+}
+
+impl Stk500 {
+    /// Reset the board into its bootloader by pulsing DTR, then sign on
+    pub fn open(path: &str) -> Result<Self> {
+        let mut port = serialport::new(path, 115_200)
+            .timeout(Duration::from_millis(500))
+            .open()
+            .map_err(transport)?;
+        port.write_data_terminal_ready(false).map_err(transport)?;
+        thread::sleep(Duration::from_millis(100));
+        port.write_data_terminal_ready(true).map_err(transport)?;
+        thread::sleep(Duration::from_millis(100));
+        port.clear(serialport::ClearBuffer::Input).map_err(transport)?;
+
+        let mut stk = Self { port, sequence: 0, name: String::new() };
+        // The bootloader only listens for a moment after reset, and may miss
+        // the first message
+        let mut res = stk.command(&[0x01]);
+        for _ in 0..4 {
+            if res.is_ok() {
+                break;
+            }
+            res = stk.command(&[0x01]);
+        }
+        let name = res?;
+        stk.name = String::from_utf8_lossy(name.get(1..).unwrap_or(&[])).into_owned();
+        Ok(stk)
+    }
+
+    /// Send a command body, returning what the answer holds after its
+    /// command and status bytes
+    fn command(&mut self, body: &[u8]) -> Result<Vec<u8>> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let mut message = vec![
+            STK_MESSAGE_START,
+            self.sequence,
+            (body.len() >> 8) as u8,
+            body.len() as u8,
+            STK_TOKEN,
+        ];
+        message.extend_from_slice(body);
+        message.push(message.iter().fold(0, |sum, x| sum ^ x));
+        self.port.write_all(&message)?;
+
+        let mut header = [0; 5];
+        self.port.read_exact(&mut header)?;
+        if header[0] != STK_MESSAGE_START || header[1] != self.sequence || header[4] != STK_TOKEN {
+            return Err(Error::InvalidData(format!("received STK500v2 header {:02X?}", header)));
+        }
+        let mut answer = vec![0; (((header[2] as usize) << 8) | header[3] as usize) + 1];
+        self.port.read_exact(&mut answer)?;
+        if header.iter().chain(answer.iter()).fold(0, |sum, x| sum ^ x) != 0 {
+            return Err(Error::InvalidData("STK500v2 answer has a bad checksum".to_string()));
+        }
+        answer.pop();
+
+        if answer.len() < 2 || answer[0] != body[0] || answer[1] != STK_STATUS_OK {
+            return Err(Error::InvalidData(format!(
+                "bootloader answered {:02X?} to command {:02X}",
+                answer, body[0]
+            )));
+        }
+        Ok(answer.split_off(2))
+    }
+
+    /// Enter programming mode, with the parameters avrdude uses for the
+    /// ATmega2560
+    pub fn enter(&mut self) -> Result<()> {
+        self.command(&[0x10, 200, 100, 25, 32, 0, 0x53, 3, 0xAC, 0x53, 0, 0])?;
+        Ok(())
+    }
+
+    pub fn leave(&mut self) -> Result<()> {
+        self.command(&[0x11, 1, 1])?;
+        Ok(())
+    }
+
+    pub fn signature(&mut self) -> Result<[u8; 3]> {
+        let mut signature = [0; 3];
+        for (i, byte) in signature.iter_mut().enumerate() {
+            let answer = self.command(&[0x1B, 4, 0x30, 0, i as u8, 0])?;
+            *byte = *answer.first().ok_or_else(|| Error::InvalidData("empty signature answer".to_string()))?;
+        }
+        Ok(signature)
+    }
+
+    /// Set the byte address of the next page, as a word address with the
+    /// extended address bit set for flash above 64 KiB words
+    fn load_address(&mut self, address: usize) -> Result<()> {
+        let word = (address / 2) as u32 | 0x8000_0000;
+        self.command(&[0x06, (word >> 24) as u8, (word >> 16) as u8, (word >> 8) as u8, word as u8])?;
+        Ok(())
+    }
+
+    pub fn program_page(&mut self, address: usize, page: &[u8]) -> Result<()> {
+        self.load_address(address)?;
+        let mut body = vec![0x13, (page.len() >> 8) as u8, page.len() as u8, 0xC1, 10, 0x40, 0x4C, 0x20, 0, 0];
+        body.extend_from_slice(page);
+        self.command(&body)?;
+        Ok(())
+    }
+
+    pub fn read_page(&mut self, address: usize, len: usize) -> Result<Vec<u8>> {
+        self.load_address(address)?;
+        let mut data = self.command(&[0x14, (len >> 8) as u8, len as u8, 0x20])?;
+        // The data is followed by a second status byte
+        data.truncate(len);
+        Ok(data)
+    }
+}
+
+/// Parse an Intel HEX file into an image that starts at address 0, with the
+/// gaps between records filled with 0xFF
+pub fn parse_ihex(text: &str) -> Result<Vec<u8>> {
+    let mut image = Vec::new();
+    let mut base = 0;
+    for (number, line) in text.lines().enumerate().map(|(i, line)| (i + 1, line.trim())) {
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = || Error::InvalidData(format!("line {} is not an Intel HEX record", number));
+        let hex = line.strip_prefix(':').ok_or_else(invalid)?;
+        let bytes = (0..hex.len()).step_by(2)
+            .map(|i| hex.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(invalid)?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(invalid());
+        }
+        if bytes.iter().fold(0u8, |sum, &x| sum.wrapping_add(x)) != 0 {
+            return Err(Error::InvalidData(format!("line {} has a bad checksum", number)));
+        }
+
+        let address = ((bytes[1] as usize) << 8) | bytes[2] as usize;
+        let data = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            0x00 => {
+                let start = base + address;
+                if image.len() < start + data.len() {
+                    image.resize(start + data.len(), 0xFF);
+                }
+                image[start..start + data.len()].copy_from_slice(data);
+            },
+            0x01 => break,
+            0x02 if data.len() == 2 => base = (((data[0] as usize) << 8) | data[1] as usize) << 4,
+            0x04 if data.len() == 2 => base = (((data[0] as usize) << 8) | data[1] as usize) << 16,
+            // Start addresses do not matter to the bootloader
+            0x03 | 0x05 => (),
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(image)
+}
+
+/// Upload the programmer sketch, parsed with [`parse_ihex`], to the Arduino
+/// Mega 2560 on path through its bootloader, verify it, then open the
+/// programmer it runs as
+///
+/// progress is called with the phase, program or verify, bytes done, and
+/// bytes total.
+pub fn flash_sketch(path: &str, sketch: &[u8], progress: &mut dyn FnMut(&str, usize, usize)) -> Result<ParallelArduino> {
+    if sketch.is_empty() || sketch.len() > SKETCH_MAX_SIZE {
+        return Err(Error::InvalidInput(format!(
+            "sketch size {} is not between 1 and {} bytes",
+            sketch.len(), SKETCH_MAX_SIZE
+        )));
+    }
+
+    let mut stk = Stk500::open(path)?;
+    stk.enter()?;
+    let res = (|| {
+        let signature = stk.signature()?;
+        if signature != ATMEGA2560_SIGNATURE {
+            return Err(Error::InvalidData(format!(
+                "signature {:02X?} is not an ATmega2560",
+                signature
+            )));
+        }
+
+        let pages: Vec<(usize, Vec<u8>)> = sketch.chunks(SKETCH_PAGE_SIZE)
+            .enumerate()
+            .map(|(i, page)| {
+                let mut page = page.to_owned();
+                page.resize(SKETCH_PAGE_SIZE, 0xFF);
+                (i * SKETCH_PAGE_SIZE, page)
+            })
+            .collect();
+
+        for (address, page) in pages.iter() {
+            progress("program", *address, sketch.len());
+            stk.program_page(*address, page)?;
+        }
+        progress("program", sketch.len(), sketch.len());
+
+        for (address, page) in pages.iter() {
+            progress("verify", *address, sketch.len());
+            if stk.read_page(*address, page.len())? != *page {
+                return Err(Error::InvalidData(format!("sketch page {:05X} failed to verify", address)));
+            }
+        }
+        progress("verify", sketch.len(), sketch.len());
+        Ok(())
+    })();
+    // Leaving programming mode starts the sketch
+    let left = stk.leave();
+    res?;
+    left?;
+    drop(stk);
+
+    ParallelArduino::open(path, Some(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ihex_parses_extended_addresses() {
+        let image = parse_ihex(
+            ":0400000001020304F2\n:020000040001F9\n:02000200AABB97\n:00000001FF\n"
+        ).unwrap();
+        assert_eq!(&image[..4], &[1, 2, 3, 4]);
+        assert_eq!(image.len(), 0x10004);
+        assert_eq!(&image[0x10002..], &[0xAA, 0xBB]);
+        assert!(image[4..0x10002].iter().all(|&x| x == 0xFF));
+
+        assert!(parse_ihex(":0400000001020304F3\n").is_err());
+    }
+}
@@ -0,0 +1,264 @@
+//! Asynchronous variants of the debugger transports, so that many remote
+//! programmers can be driven from a single tokio runtime.
+
+use alloc::boxed::Box;
+use core::future::Future;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::{Address, Error, Mega2560, Protocol, Result};
+
+pub trait AsyncDebugger: Send {
+    /// Set the debugger address
+    fn address(&mut self, address: u8) -> impl Future<Output = Result<()>> + Send;
+    /// Read data from the debugger port
+    fn read(&mut self, data: &mut [u8]) -> impl Future<Output = Result<usize>> + Send;
+    /// Write data to the debugger port
+    fn write(&mut self, data: &[u8]) -> impl Future<Output = Result<usize>> + Send;
+
+    /// Read data at a debugger address
+    fn read_at(&mut self, address: Address, data: &mut [u8]) -> impl Future<Output = Result<usize>> + Send {
+        async move {
+            self.address(address as u8).await?;
+            self.read(data).await
+        }
+    }
+
+    /// Write data at a debugger address
+    fn write_at(&mut self, address: Address, data: &[u8]) -> impl Future<Output = Result<usize>> + Send {
+        async move {
+            self.address(address as u8).await?;
+            self.write(data).await
+        }
+    }
+
+    /// Set EC memory snoop address
+    fn ecms_address(&mut self, address: u16) -> impl Future<Output = Result<()>> + Send {
+        async move {
+            self.write_at(Address::ECMSADDR1, &[(address >> 8) as u8]).await?;
+            self.write_at(Address::ECMSADDR0, &[(address) as u8]).await?;
+            Ok(())
+        }
+    }
+
+    /// Read data from memory at address using EC memory snoop
+    fn ecms_read_at(&mut self, address: u16, data: &mut [u8]) -> impl Future<Output = Result<usize>> + Send {
+        async move {
+            self.ecms_address(address).await?;
+            self.read_at(Address::ECMSDATA, data).await
+        }
+    }
+
+    /// Write data to memory at address using EC memory snoop
+    fn ecms_write_at(&mut self, address: u16, data: &[u8]) -> impl Future<Output = Result<usize>> + Send {
+        async move {
+            self.ecms_address(address).await?;
+            self.write_at(Address::ECMSDATA, data).await
+        }
+    }
+}
+
+pub trait AsyncSmfi: Send {
+    /// Set indar1 register (special case for follow mode)
+    fn flash_indar1(&mut self, data: u8) -> impl Future<Output = Result<()>> + Send;
+
+    /// Set EC-indirect flash address
+    fn flash_address(&mut self, address: u32) -> impl Future<Output = Result<()>> + Send;
+
+    /// Read data from flash using EC-indirect mode
+    fn flash_read(&mut self, data: &mut [u8]) -> impl Future<Output = Result<usize>> + Send;
+
+    /// Write data to flash using EC-indirect mode
+    fn flash_write(&mut self, data: &[u8]) -> impl Future<Output = Result<usize>> + Send;
+
+    /// Read data from flash at address using EC-indirect mode
+    fn flash_read_at(&mut self, address: u32, data: &mut [u8]) -> impl Future<Output = Result<usize>> + Send {
+        async move {
+            self.flash_address(address).await?;
+            self.flash_read(data).await
+        }
+    }
+
+    /// Write data to flash at address using EC-indirect mode
+    fn flash_write_at(&mut self, address: u32, data: &[u8]) -> impl Future<Output = Result<usize>> + Send {
+        async move {
+            self.flash_address(address).await?;
+            self.flash_write(data).await
+        }
+    }
+}
+
+impl<T> AsyncSmfi for T where T: AsyncDebugger {
+    /// Set indar1 register (special case for follow mode)
+    async fn flash_indar1(&mut self, data: u8) -> Result<()> {
+        self.write_at(Address::INDAR1, &[data]).await?;
+        Ok(())
+    }
+
+    /// Set EC-indirect flash address
+    async fn flash_address(&mut self, address: u32) -> Result<()> {
+        self.write_at(Address::INDAR3, &[(address >> 24) as u8]).await?;
+        self.write_at(Address::INDAR2, &[(address >> 16) as u8]).await?;
+        self.write_at(Address::INDAR1, &[(address >> 8) as u8]).await?;
+        self.write_at(Address::INDAR0, &[(address) as u8]).await?;
+        Ok(())
+    }
+
+    /// Read data from flash using EC-indirect mode
+    async fn flash_read(&mut self, data: &mut [u8]) -> Result<usize> {
+        self.read_at(Address::INDDR, data).await
+    }
+
+    /// Write data to flash using EC-indirect mode
+    async fn flash_write(&mut self, data: &[u8]) -> Result<usize> {
+        self.write_at(Address::INDDR, data).await
+    }
+}
+
+/// Parallel port Arduino programmer protocol over any asynchronous stream,
+/// such as a tokio serial port or a TCP connection to a serial bridge
+///
+/// The frames are the same as `ParallelArduino` sends, in a version of
+/// [`Protocol`].
+pub struct AsyncParallelArduino<S> {
+    stream: S,
+    buffer_size: usize,
+    protocol: Box<dyn Protocol>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncParallelArduino<S> {
+    /// Connect to parallel port arduino using provided stream
+    pub async fn new(stream: S) -> Result<Self> {
+        Self::with_protocol(stream, Box::new(Mega2560 { combined: false })).await
+    }
+
+    /// Connect to a programmer that speaks protocol using provided stream
+    pub async fn with_protocol(stream: S, protocol: Box<dyn Protocol>) -> Result<Self> {
+        let mut port = Self { stream, buffer_size: 0, protocol };
+        // Check that programmer is ready
+        port.echo().await?;
+        // Read buffer size
+        port.update_buffer_size().await?;
+        Ok(port)
+    }
+
+    /// Size of the programmer's transfer buffer
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Send the address with each transfer using the 'r' and 'w' frames,
+    /// which the sketch must support
+    ///
+    /// Firmware that speaks version 3 or later always does.
+    pub fn set_combined(&mut self, combined: bool) {
+        if self.protocol.version() < 3 {
+            self.protocol = Box::new(Mega2560 { combined });
+        }
+    }
+
+    /// Largest transfer that fits both the buffer and a frame
+    fn chunk_size(&self) -> usize {
+        core::cmp::min(self.buffer_size, self.protocol.max_len())
+    }
+
+    async fn ack(&mut self, len: usize) -> Result<()> {
+        let expected = self.protocol.length(len);
+        let mut b = vec![0; expected.len()];
+        self.stream.read_exact(&mut b).await?;
+        if b != expected {
+            return Err(Error::InvalidData(format!("received ack of {:02X?} instead of {:02X?}", b, expected)));
+        }
+        Ok(())
+    }
+
+    async fn echo(&mut self) -> Result<()> {
+        self.stream.write_all(&[
+            b'E',
+            0,
+            0x76,
+        ]).await?;
+
+        let mut b = [0];
+        self.stream.read_exact(&mut b).await?;
+        if b[0] != 0x76 {
+            return Err(Error::InvalidData(format!("received echo of {:02X} instead of {:02X}", b[0], 0x76)));
+        }
+        Ok(())
+    }
+
+    async fn update_buffer_size(&mut self) -> Result<()> {
+        self.stream.write_all(&[
+            b'B',
+            0,
+        ]).await?;
+
+        let mut b = vec![0; self.protocol.length_bytes()];
+        self.stream.read_exact(&mut b).await?;
+        // Size is recieved data + 1
+        self.buffer_size = self.protocol.decode_length(&b);
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncDebugger for AsyncParallelArduino<S> {
+    async fn address(&mut self, address: u8) -> Result<()> {
+        self.stream.write_all(&[
+            b'A',
+            address,
+        ]).await?;
+
+        Ok(())
+    }
+
+    async fn read(&mut self, data: &mut [u8]) -> Result<usize> {
+        for chunk in data.chunks_mut(self.chunk_size()) {
+            let header = self.protocol.header(b'R', None, chunk.len());
+            self.stream.write_all(&header).await?;
+            self.stream.read_exact(chunk).await?;
+        }
+
+        Ok(data.len())
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<usize> {
+        for chunk in data.chunks(self.chunk_size()) {
+            let header = self.protocol.header(b'W', None, chunk.len());
+            self.stream.write_all(&header).await?;
+            self.stream.write_all(chunk).await?;
+            self.ack(chunk.len()).await?;
+        }
+
+        Ok(data.len())
+    }
+
+    async fn read_at(&mut self, address: Address, data: &mut [u8]) -> Result<usize> {
+        if ! self.protocol.combined() {
+            self.address(address as u8).await?;
+            return self.read(data).await;
+        }
+
+        for chunk in data.chunks_mut(self.chunk_size()) {
+            let header = self.protocol.header(b'r', Some(address as u8), chunk.len());
+            self.stream.write_all(&header).await?;
+            self.stream.read_exact(chunk).await?;
+        }
+
+        Ok(data.len())
+    }
+
+    async fn write_at(&mut self, address: Address, data: &[u8]) -> Result<usize> {
+        if ! self.protocol.combined() {
+            self.address(address as u8).await?;
+            return self.write(data).await;
+        }
+
+        for chunk in data.chunks(self.chunk_size()) {
+            let header = self.protocol.header(b'w', Some(address as u8), chunk.len());
+            self.stream.write_all(&header).await?;
+            self.stream.write_all(chunk).await?;
+            self.ack(chunk.len()).await?;
+        }
+
+        Ok(data.len())
+    }
+}
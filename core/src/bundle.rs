@@ -0,0 +1,224 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+use super::sha256::Sha256;
+use super::{Error, Result};
+
+/// Name of the firmware image inside a bundle
+pub const BUNDLE_FIRMWARE: &str = "firmware.rom";
+/// Name of the detached firmware signature inside a bundle, if it is signed
+pub const BUNDLE_SIGNATURE: &str = "firmware.rom.sig";
+/// Name of the manifest inside a bundle
+pub const BUNDLE_MANIFEST: &str = "manifest.toml";
+
+/// Description of the firmware in an update bundle
+///
+/// The manifest is a small subset of TOML, with one `key = "value"` pair per
+/// line:
+///
+/// ```toml
+/// project = "N130ZU"
+/// min_version = "1.07.02"
+/// max_version = "1.07.08"
+/// sha256 = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+/// ```
+///
+/// The version bounds are optional and inclusive, and apply to the version
+/// currently running on the EC.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Manifest {
+    pub project: String,
+    pub min_version: Option<String>,
+    pub max_version: Option<String>,
+    pub sha256: [u8; 32],
+}
+
+impl Manifest {
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut project = None;
+        let mut min_version = None;
+        let mut max_version = None;
+        let mut sha256 = None;
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |message: &str| Error::InvalidData(format!("manifest line {}: {}", i + 1, message));
+
+            let (key, value) = line.split_once('=').ok_or_else(|| invalid("expected key = value"))?;
+            let value = value.trim();
+            let value = value.strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .ok_or_else(|| invalid("expected a quoted string"))?
+                .to_string();
+
+            match key.trim() {
+                "project" => project = Some(value),
+                "min_version" => min_version = Some(value),
+                "max_version" => max_version = Some(value),
+                "sha256" => sha256 = Some(parse_digest(&value).ok_or_else(|| invalid("invalid sha256"))?),
+                other => return Err(invalid(&format!("unknown key '{}'", other))),
+            }
+        }
+
+        Ok(Self {
+            project: project.ok_or_else(|| Error::InvalidData("manifest has no project".to_string()))?,
+            min_version,
+            max_version,
+            sha256: sha256.ok_or_else(|| Error::InvalidData("manifest has no sha256".to_string()))?,
+        })
+    }
+
+    /// Check that the bundle applies to an EC running the given project and version
+    pub fn check(&self, project: &str, version: &str) -> Result<()> {
+        if project.trim() != self.project {
+            return Err(Error::Incompatible(format!(
+                "bundle is for project '{}', EC is running '{}'",
+                self.project, project.trim()
+            )));
+        }
+
+        if let Some(min_version) = &self.min_version {
+            if compare_versions(version, min_version) == Ordering::Less {
+                return Err(Error::Incompatible(format!(
+                    "EC version '{}' is older than the minimum '{}'",
+                    version.trim(), min_version
+                )));
+            }
+        }
+
+        if let Some(max_version) = &self.max_version {
+            if compare_versions(version, max_version) == Ordering::Greater {
+                return Err(Error::Incompatible(format!(
+                    "EC version '{}' is newer than the maximum '{}'",
+                    version.trim(), max_version
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// An update bundle, which is a tar archive holding the firmware image and its
+/// manifest
+#[derive(Clone, Debug)]
+pub struct Bundle {
+    pub manifest: Manifest,
+    pub firmware: Vec<u8>,
+    /// Detached signature of the firmware
+    pub signature: Option<Vec<u8>>,
+}
+
+impl Bundle {
+    /// Parse a bundle, checking the firmware against the manifest digest
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut manifest = None;
+        let mut firmware = None;
+        let mut signature = None;
+
+        for (name, contents) in tar_entries(data)? {
+            match name.trim_start_matches("./") {
+                BUNDLE_MANIFEST => {
+                    let s = core::str::from_utf8(contents)
+                        .map_err(|_| Error::InvalidData("manifest is not UTF-8".to_string()))?;
+                    manifest = Some(Manifest::parse(s)?);
+                },
+                BUNDLE_FIRMWARE => firmware = Some(contents.to_vec()),
+                BUNDLE_SIGNATURE => signature = Some(contents.to_vec()),
+                _ => (),
+            }
+        }
+
+        let manifest = manifest.ok_or_else(|| Error::InvalidData(format!("bundle has no {}", BUNDLE_MANIFEST)))?;
+        let firmware = firmware.ok_or_else(|| Error::InvalidData(format!("bundle has no {}", BUNDLE_FIRMWARE)))?;
+
+        let mut sha256 = Sha256::new();
+        sha256.update(&firmware);
+        if sha256.finish() != manifest.sha256 {
+            return Err(Error::InvalidData(format!("{} does not match the manifest sha256", BUNDLE_FIRMWARE)));
+        }
+
+        Ok(Self { manifest, firmware, signature })
+    }
+}
+
+fn parse_digest(s: &str) -> Option<[u8; 32]> {
+    if s.len() != 64 {
+        return None;
+    }
+
+    let mut digest = [0; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// Compare versions by their numeric and alphabetic parts, so that "1.7.10"
+/// is newer than "1.7.9"
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let mut a = a.trim().split(|c: char| ! c.is_ascii_alphanumeric()).filter(|s| ! s.is_empty());
+    let mut b = b.trim().split(|c: char| ! c.is_ascii_alphanumeric()).filter(|s| ! s.is_empty());
+    loop {
+        match (a.next(), b.next()) {
+            (Some(a), Some(b)) => {
+                let ordering = match (a.parse::<u64>(), b.parse::<u64>()) {
+                    (Ok(a), Ok(b)) => a.cmp(&b),
+                    _ => a.cmp(b),
+                };
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            },
+            (Some(_), None) => return Ordering::Greater,
+            (None, Some(_)) => return Ordering::Less,
+            (None, None) => return Ordering::Equal,
+        }
+    }
+}
+
+/// Regular files in a ustar archive, as name and contents
+fn tar_entries(data: &[u8]) -> Result<Vec<(String, &[u8])>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset + 512 <= data.len() {
+        let header = &data[offset..offset + 512];
+        // The archive ends with zero blocks
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+
+        let field = |range: core::ops::Range<usize>| {
+            let field = &header[range];
+            let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+            core::str::from_utf8(&field[..len])
+                .map_err(|_| Error::InvalidData("invalid tar header".to_string()))
+        };
+
+        let mut name = field(0..100)?.to_string();
+        let prefix = field(345..500)?;
+        if header[257..262] == *b"ustar" && ! prefix.is_empty() {
+            name = format!("{}/{}", prefix, name);
+        }
+        let size = usize::from_str_radix(field(124..136)?.trim_matches(|c| c == ' ' || c == '\0'), 8)
+            .map_err(|_| Error::InvalidData(format!("invalid size for '{}'", name)))?;
+        let kind = header[156];
+
+        let start = offset + 512;
+        let end = start.checked_add(size)
+            .filter(|&end| end <= data.len())
+            .ok_or_else(|| Error::InvalidData(format!("'{}' is truncated", name)))?;
+
+        if kind == b'0' || kind == 0 {
+            entries.push((name, &data[start..end]));
+        }
+
+        offset = start + size.div_ceil(512) * 512;
+    }
+    Ok(entries)
+}
@@ -0,0 +1,227 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::ops::Range;
+
+use super::{EcParam, Error, Layout, Result, Watchdog};
+
+/// Default location of the configuration file
+pub const CONFIG_PATH: &str = "/etc/ecflash.toml";
+
+/// Defaults for lab setups that would otherwise pass the same flags every time
+///
+/// The file is a small subset of TOML, with one `key = value` pair per line,
+/// where values are quoted strings, integers, booleans, or arrays of those on
+/// one line:
+///
+/// ```toml
+/// known_ids = [0x8587, 0x5570]
+/// backend = "mmio"
+/// mmio = 0xFE0B0000
+/// serial_port = "tcp:rig-3:7000"
+/// battery_threshold = 30
+/// protected = ["boot", "0x1E000-0x1EFFF"]
+/// backup_dir = "/var/lib/ecflash/backups"
+/// watchdog = ["N130ZU:0xB4=0x00"]
+/// tcpc_addresses = [0x2C, 0x2E]
+/// migrate = ["N130ZU:system76/galp3-c"]
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Config {
+    /// Super I/O or debugger chip IDs to accept besides the built-in ones
+    pub known_ids: Vec<u16>,
+    /// How to reach the EC: ports, devport, or mmio
+    pub backend: Option<String>,
+    /// Physical address of the memory-mapped host interface
+    pub mmio: Option<u64>,
+    /// Serial port of the ISP programmer, or tcp:host:port
+    pub serial_port: Option<String>,
+    /// Lowest battery charge in percent to flash at without AC power
+    pub battery_threshold: Option<u8>,
+    /// Regions by name, or inclusive START-END ranges, that erase and write
+    /// leave untouched
+    pub protected: Vec<String>,
+    /// Directory to save the flash contents to before erasing it
+    pub backup_dir: Option<String>,
+    /// Watchdog to suppress in flash mode by project, from entries of the
+    /// form PROJECT:OFFSET=VALUE
+    pub watchdog: Vec<(String, Watchdog)>,
+    /// I2C addresses of the USB-C port controllers behind the EC, by port
+    pub tcpc_addresses: Vec<u8>,
+    /// Boards of the open-source System76 EC firmware that projects of the
+    /// proprietary firmware can migrate to, from entries of the form
+    /// PROJECT:BOARD
+    pub migrate: Vec<(String, String)>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Value {
+    Str(String),
+    Int(u64),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+fn parse_int(s: &str) -> Option<u64> {
+    let s = s.replace('_', "");
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Parse a watchdog entry of the form PROJECT:OFFSET=VALUE
+fn parse_watchdog(s: &str) -> Option<(String, Watchdog)> {
+    let (project, rest) = s.split_once(':')?;
+    let (offset, value) = rest.split_once('=')?;
+    let offset = u8::try_from(parse_int(offset.trim())?).ok()?;
+    let suppress = u8::try_from(parse_int(value.trim())?).ok()?;
+    Some((project.trim().to_string(), Watchdog {
+        param: EcParam::Raw(offset),
+        suppress,
+    }))
+}
+
+/// Parse one value, returning it and the rest of the input
+fn parse_value(s: &str) -> Option<(Value, &str)> {
+    let s = s.trim_start();
+    if let Some(rest) = s.strip_prefix('"') {
+        let end = rest.find('"')?;
+        return Some((Value::Str(rest[..end].to_string()), &rest[end + 1..]));
+    }
+
+    if let Some(mut rest) = s.strip_prefix('[') {
+        let mut values = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(rest) = rest.strip_prefix(']') {
+                return Some((Value::Array(values), rest));
+            }
+            let (value, next) = parse_value(rest)?;
+            values.push(value);
+            rest = next.trim_start();
+            rest = rest.strip_prefix(',').unwrap_or(rest);
+        }
+    }
+
+    let end = s.find([',', ']', ' ', '\t', '#']).unwrap_or(s.len());
+    let value = match &s[..end] {
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        word => Value::Int(parse_int(word)?),
+    };
+    Some((value, &s[end..]))
+}
+
+impl Config {
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |message: &str| Error::InvalidData(format!("config line {}: {}", i + 1, message));
+
+            let (key, value) = line.split_once('=').ok_or_else(|| invalid("expected key = value"))?;
+            let (value, rest) = parse_value(value).ok_or_else(|| invalid("invalid value"))?;
+            let rest = rest.trim();
+            if ! rest.is_empty() && ! rest.starts_with('#') {
+                return Err(invalid("unexpected text after value"));
+            }
+
+            let string = |value: Value| match value {
+                Value::Str(s) => Ok(s),
+                _ => Err(invalid("expected a quoted string")),
+            };
+            let int = |value: &Value, max: u64| match value {
+                Value::Int(x) if *x <= max => Ok(*x),
+                _ => Err(invalid(&format!("expected an integer up to {}", max))),
+            };
+            let array = |value: Value| match value {
+                Value::Array(values) => Ok(values),
+                _ => Err(invalid("expected an array")),
+            };
+
+            match key.trim() {
+                "known_ids" => for id in array(value)? {
+                    config.known_ids.push(int(&id, 0xFFFF)? as u16);
+                },
+                "backend" => config.backend = Some(string(value)?),
+                "mmio" => config.mmio = Some(int(&value, u64::MAX)?),
+                "serial_port" => config.serial_port = Some(string(value)?),
+                "battery_threshold" => config.battery_threshold = Some(int(&value, 100)? as u8),
+                "protected" => for region in array(value)? {
+                    config.protected.push(string(region)?);
+                },
+                "backup_dir" => config.backup_dir = Some(string(value)?),
+                "watchdog" => for entry in array(value)? {
+                    let entry = string(entry)?;
+                    let watchdog = parse_watchdog(&entry)
+                        .ok_or_else(|| invalid(&format!("watchdog '{}' is not PROJECT:OFFSET=VALUE", entry)))?;
+                    config.watchdog.push(watchdog);
+                },
+                "tcpc_addresses" => for address in array(value)? {
+                    config.tcpc_addresses.push(int(&address, 0xFF)? as u8);
+                },
+                "migrate" => for entry in array(value)? {
+                    let entry = string(entry)?;
+                    let (project, board) = entry.split_once(':')
+                        .filter(|(project, board)| ! project.trim().is_empty() && board.contains('/'))
+                        .ok_or_else(|| invalid(&format!("migrate '{}' is not PROJECT:BOARD", entry)))?;
+                    config.migrate.push((project.trim().to_string(), board.trim().to_string()));
+                },
+                other => return Err(invalid(&format!("unknown key '{}'", other))),
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Read the configuration at path, which is empty if the file does not
+    /// exist
+    #[cfg(feature = "std")]
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(s) => Self::parse(&s),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The watchdog to suppress in flash mode on project, if any
+    pub fn watchdog(&self, project: &str) -> Option<Watchdog> {
+        self.watchdog.iter()
+            .find(|(name, _)| name == project.trim())
+            .map(|&(_, watchdog)| watchdog)
+    }
+
+    /// Whether the proprietary firmware of project can be replaced by the
+    /// open-source firmware of board, which is the case for the boards in
+    /// migrate, and for boards named after the project, such as
+    /// system76/lemp9 for LEMP9
+    pub fn migrates_to(&self, project: &str, board: &str) -> bool {
+        let project = project.trim();
+        self.migrate.iter().any(|(name, to)| name == project && to == board)
+            || board.rsplit('/').next().is_some_and(|model| model.eq_ignore_ascii_case(project))
+    }
+
+    /// The protected regions as ranges of a flash of the given size
+    pub fn protected_ranges(&self, size: usize) -> Result<Vec<Range<usize>>> {
+        let layout = Layout::new(size);
+        self.protected.iter().map(|protected| {
+            if let Some(region) = layout.region(protected) {
+                return Ok(region.range.clone());
+            }
+
+            let range = protected.split_once('-')
+                .and_then(|(start, end)| Some((parse_int(start.trim())?, parse_int(end.trim())?)));
+            match range {
+                Some((start, end)) if start <= end => Ok(start as usize..end as usize + 1),
+                _ => Err(Error::InvalidInput(format!("protected region '{}' is not a region or range", protected))),
+            }
+        }).collect()
+    }
+}
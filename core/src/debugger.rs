@@ -0,0 +1,217 @@
+use alloc::string::ToString;
+
+use super::{Error, Result};
+
+/// Registers of the ITE debugger interface
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Address {
+    CHIPID0 = 0,
+    CHIPID1 = 1,
+    CHIPVER = 2,
+    INDAR0 = 4,
+    INDAR1 = 5,
+    INDAR2 = 6,
+    INDAR3 = 7,
+    INDDR = 8,
+    /// Control of the 8051 core: halt, step, or run
+    DBGCTRL = 0x20,
+    /// Status of the 8051 core
+    DBGSTS = 0x21,
+    /// Program counter of the halted core, low byte
+    DBGPC0 = 0x22,
+    /// Program counter of the halted core, high byte
+    DBGPC1 = 0x23,
+    ECMSADDR0 = 0x2E,
+    ECMSADDR1 = 0x2F,
+    ECMSDATA = 0x30,
+}
+
+/// DBGCTRL bit that holds the core halted
+pub const DBGCTRL_HALT: u8 = 1 << 0;
+/// DBGCTRL bit that runs one instruction of the halted core
+pub const DBGCTRL_STEP: u8 = 1 << 1;
+/// DBGSTS bit that is set once the core is halted
+pub const DBGSTS_HALTED: u8 = 1 << 0;
+
+/// Reads of DBGSTS to wait for the core to halt, each of which is a round
+/// trip to the programmer
+const HALT_POLLS: usize = 100;
+
+/// Wait until the core reports that it is halted
+fn wait_halted<D: Debugger + ?Sized>(debugger: &mut D) -> Result<()> {
+    for _poll in 0..HALT_POLLS {
+        if debugger.halted()? {
+            return Ok(());
+        }
+    }
+    Err(Error::InvalidData("the 8051 core did not halt".to_string()))
+}
+
+/// Low level access to the ITE debugger interface, implemented by each
+/// programmer backend
+///
+/// Implementors only provide `address`, `read`, and `write`. The trait is
+/// object safe, so backends can be selected at runtime as `&mut dyn Debugger`.
+pub trait Debugger {
+    /// Set the debugger address
+    fn address(&mut self, address: u8) -> Result<()>;
+    /// Read data from the debugger port
+    fn read(&mut self, data: &mut [u8]) -> Result<usize>;
+    /// Write data to the debugger port
+    fn write(&mut self, data: &[u8]) -> Result<usize>;
+
+    /// Read data at a debugger address
+    fn read_at(&mut self, address: Address, data: &mut [u8]) -> Result<usize> {
+        self.address(address as u8)?;
+        self.read(data)
+    }
+
+    /// Write data at a debugger address
+    fn write_at(&mut self, address: Address, data: &[u8]) -> Result<usize> {
+        self.address(address as u8)?;
+        self.write(data)
+    }
+
+    /// Set EC memory snoop address
+    fn ecms_address(&mut self, address: u16) -> Result<()> {
+        self.write_at(Address::ECMSADDR1, &[(address >> 8) as u8])?;
+        self.write_at(Address::ECMSADDR0, &[(address) as u8])?;
+
+        Ok(())
+    }
+
+    /// Read data from memory using EC-indirect mode
+    fn ecms_read(&mut self, data: &mut [u8]) -> Result<usize> {
+        self.read_at(Address::ECMSDATA, data)
+    }
+
+    /// Write data to memory using EC memory snoop
+    fn ecms_write(&mut self, data: &[u8]) -> Result<usize> {
+        self.write_at(Address::ECMSDATA, data)
+    }
+
+    /// Read data from memory at address using EC memory snoop
+    fn ecms_read_at(&mut self, address: u16, data: &mut [u8]) -> Result<usize> {
+        self.ecms_address(address)?;
+        self.ecms_read(data)
+    }
+
+    /// Write data to memory at address using EC memory snoop
+    fn ecms_write_at(&mut self, address: u16, data: &[u8]) -> Result<usize> {
+        self.ecms_address(address)?;
+        self.ecms_write(data)
+    }
+
+    /// Whether the 8051 core is halted
+    fn halted(&mut self) -> Result<bool> {
+        let mut sts = [0];
+        self.read_at(Address::DBGSTS, &mut sts)?;
+        Ok(sts[0] & DBGSTS_HALTED != 0)
+    }
+
+    /// Halt the 8051 core before its next instruction, returning the program
+    /// counter it halted at
+    ///
+    /// The EC firmware stops servicing the host, the keyboard, and the fans
+    /// until the core is resumed.
+    fn halt(&mut self) -> Result<u16> {
+        self.write_at(Address::DBGCTRL, &[DBGCTRL_HALT])?;
+        wait_halted(self)?;
+        self.read_pc()
+    }
+
+    /// Let the halted 8051 core run again
+    fn resume(&mut self) -> Result<()> {
+        self.write_at(Address::DBGCTRL, &[0])?;
+        Ok(())
+    }
+
+    /// Run one instruction of the halted 8051 core, returning the program
+    /// counter it halted at afterwards
+    fn step(&mut self) -> Result<u16> {
+        if ! self.halted()? {
+            return Err(Error::InvalidInput("the 8051 core must be halted to step".to_string()));
+        }
+        self.write_at(Address::DBGCTRL, &[DBGCTRL_HALT | DBGCTRL_STEP])?;
+        wait_halted(self)?;
+        self.read_pc()
+    }
+
+    /// Sample the program counter of the running 8051 core, which is only
+    /// halted for as long as the read takes
+    fn sample_pc(&mut self) -> Result<u16> {
+        let pc = self.halt()?;
+        self.resume()?;
+        Ok(pc)
+    }
+
+    /// Program counter of the halted 8051 core
+    fn read_pc(&mut self) -> Result<u16> {
+        if ! self.halted()? {
+            return Err(Error::InvalidInput("the 8051 core must be halted to read its program counter".to_string()));
+        }
+        let mut pc = [0; 2];
+        self.read_at(Address::DBGPC0, &mut pc[0..1])?;
+        self.read_at(Address::DBGPC1, &mut pc[1..2])?;
+        Ok(u16::from_le_bytes(pc))
+    }
+}
+
+/// Access to flash using the EC-indirect (SMFI) registers
+///
+/// This is implemented for every [`Debugger`], and can also be implemented
+/// directly by backends that reach the SMFI registers another way, such as
+/// the PMC scratch ROM interface.
+pub trait Smfi {
+    /// Set indar1 register (special case for follow mode)
+    fn flash_indar1(&mut self, data: u8) -> Result<()>;
+
+    /// Set EC-indirect flash address
+    fn flash_address(&mut self, address: u32) -> Result<()>;
+
+    /// Read data from flash using EC-indirect mode
+    fn flash_read(&mut self, data: &mut [u8]) -> Result<usize>;
+
+    /// Write data to flash using EC-indirect mode
+    fn flash_write(&mut self, data: &[u8]) -> Result<usize>;
+
+    /// Read data from flash at address using EC-indirect mode
+    fn flash_read_at(&mut self, address: u32, data: &mut [u8]) -> Result<usize> {
+        self.flash_address(address)?;
+        self.flash_read(data)
+    }
+
+    /// Write data to flash at address using EC-indirect mode
+    fn flash_write_at(&mut self, address: u32, data: &[u8]) -> Result<usize> {
+        self.flash_address(address)?;
+        self.flash_write(data)
+    }
+}
+
+impl<T> Smfi for T where T: Debugger + ?Sized {
+    /// Set indar1 register (special case for follow mode)
+    fn flash_indar1(&mut self, data: u8) -> Result<()> {
+        self.write_at(Address::INDAR1, &[data])?;
+        Ok(())
+    }
+
+    /// Set EC-indirect flash address
+    fn flash_address(&mut self, address: u32) -> Result<()> {
+        self.write_at(Address::INDAR3, &[(address >> 24) as u8])?;
+        self.write_at(Address::INDAR2, &[(address >> 16) as u8])?;
+        self.write_at(Address::INDAR1, &[(address >> 8) as u8])?;
+        self.write_at(Address::INDAR0, &[(address) as u8])?;
+        Ok(())
+    }
+
+    /// Read data from flash using EC-indirect mode
+    fn flash_read(&mut self, data: &mut [u8]) -> Result<usize> {
+        self.read_at(Address::INDDR, data)
+    }
+
+    /// Write data to flash using EC-indirect mode
+    fn flash_write(&mut self, data: &[u8]) -> Result<usize> {
+        self.write_at(Address::INDDR, data)
+    }
+}
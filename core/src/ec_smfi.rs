@@ -0,0 +1,151 @@
+#![allow(clippy::missing_safety_doc)]
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{Ec, HostInterface, PortIo, RawPortIo, Timer, TIMEOUT_US};
+use super::regs;
+
+/// Base port of the SMFI command region of the open-source System76 EC
+/// firmware
+pub const SMFI_CMD_BASE: u16 = 0xE00;
+/// Number of ports of the SMFI command region
+pub const SMFI_CMD_SIZE: usize = 0x100;
+
+/// Offset of the command, which the firmware clears when it is done
+const SMFI_CMD_CMD: u16 = 0x00;
+/// Offset of the result, 0 on success
+const SMFI_CMD_RES: u16 = 0x01;
+/// Offset of the data the command takes and answers
+const SMFI_CMD_DATA: u16 = 0x02;
+
+/// Answer the signature and the protocol version
+const CMD_PROBE: u8 = 1;
+/// Answer the board name, such as system76/lemp9
+const CMD_BOARD: u8 = 2;
+/// Answer the firmware version
+const CMD_VERSION: u8 = 3;
+
+/// Signature answered by CMD_PROBE
+const SIGNATURE: [u8; 2] = [0x76, 0xEC];
+
+/// Open-source System76 EC firmware, reached through the SMFI command region
+/// it maps to the host
+///
+/// That firmware does not answer the mailbox string commands 0x92 and 0x93
+/// that EcFlash uses, so the project and version come from its own board and
+/// version commands.
+pub struct EcSmfi<P: PortIo = RawPortIo> {
+    io: P,
+    protocol: u8,
+    timer: Box<dyn Timer + Send>,
+    timeout_us: u64,
+}
+
+impl<P: PortIo> EcSmfi<P> {
+    /// Probe for the open-source firmware using the given port I/O, giving
+    /// the port I/O back if it does not answer
+    pub fn with_io(io: P) -> Result<Self, P> {
+        let mut ec = Self {
+            io,
+            protocol: 0,
+            #[cfg(feature = "std")]
+            timer: Box::new(super::StdTimer::new()),
+            #[cfg(not(feature = "std"))]
+            timer: Box::new(super::CounterTimer::new()),
+            timeout_us: TIMEOUT_US,
+        };
+
+        unsafe {
+            if ec.command(CMD_PROBE).is_ok() && [ec.data(0), ec.data(1)] == SIGNATURE {
+                ec.protocol = ec.data(2);
+                return Ok(ec);
+            }
+        }
+        Err(ec.io)
+    }
+
+    /// Version of the SMFI command protocol the firmware speaks
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+
+    unsafe fn data(&mut self, index: u16) -> u8 {
+        self.io.inb(SMFI_CMD_BASE + SMFI_CMD_DATA + index)
+    }
+
+    /// Run a command and check its result
+    ///
+    /// The command byte reads 0 when the firmware is idle, and is written last
+    /// since the firmware starts as soon as it changes. Ports that nothing
+    /// answers read 0xFF, which the check for idle rejects at once.
+    unsafe fn command(&mut self, cmd: u8) -> Result<(), ()> {
+        if self.io.inb(SMFI_CMD_BASE + SMFI_CMD_CMD) != 0 {
+            return Err(());
+        }
+
+        self.io.outb(SMFI_CMD_BASE + SMFI_CMD_CMD, cmd);
+        let start = self.timer.now_us();
+        while self.io.inb(SMFI_CMD_BASE + SMFI_CMD_CMD) != 0 {
+            if self.timer.now_us().wrapping_sub(start) > self.timeout_us {
+                return Err(());
+            }
+        }
+
+        if self.io.inb(SMFI_CMD_BASE + SMFI_CMD_RES) != 0 {
+            return Err(());
+        }
+        Ok(())
+    }
+
+    /// Run a command that answers a string ending at the first NUL
+    unsafe fn get_str(&mut self, cmd: u8) -> Result<String, ()> {
+        self.command(cmd)?;
+
+        let mut bytes = Vec::new();
+        for index in 0..(SMFI_CMD_SIZE as u16 - SMFI_CMD_DATA) {
+            match self.data(index) {
+                0 => break,
+                byte => bytes.push(byte),
+            }
+        }
+        Ok(bytes.into_iter().map(char::from).collect())
+    }
+}
+
+impl<P: PortIo> Ec for EcSmfi<P> {
+    /// Size of the flash, which the firmware does not report, so the 128 KiB
+    /// that every board it supports is built for
+    fn size(&mut self) -> usize {
+        128 * 1024
+    }
+
+    fn project(&mut self) -> String {
+        unsafe { self.get_str(CMD_BOARD) }.unwrap_or_default()
+    }
+
+    fn version(&mut self) -> String {
+        unsafe { self.get_str(CMD_VERSION) }.unwrap_or_default()
+    }
+
+    fn chip_id(&mut self) -> Option<u16> {
+        unsafe {
+            let a = regs::super_io_read(&mut self.io, regs::SIO_CHIPID1);
+            let b = regs::super_io_read(&mut self.io, regs::SIO_CHIPID2);
+            Some(((a as u16) << 8) | (b as u16))
+        }
+    }
+
+    fn chip_version(&mut self) -> Option<u8> {
+        Some(unsafe { regs::super_io_read(&mut self.io, regs::SIO_CHIPVER) })
+    }
+
+    fn interface(&mut self) -> Option<(HostInterface, u16, u16)> {
+        Some((self.io.interface(), SMFI_CMD_BASE + SMFI_CMD_DATA, SMFI_CMD_BASE + SMFI_CMD_CMD))
+    }
+
+    fn firmware(&mut self) -> Option<&'static str> {
+        Some("System76 EC")
+    }
+}
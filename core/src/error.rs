@@ -0,0 +1,40 @@
+use alloc::string::String;
+#[cfg(feature = "std")]
+use alloc::string::ToString;
+use core::fmt;
+
+/// Errors returned by debugger transports, the SPI flash helpers, and bundles
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// An argument was out of range
+    InvalidInput(String),
+    /// The device responded with unexpected data
+    InvalidData(String),
+    /// The underlying transport failed
+    Transport(String),
+    /// An image or bundle does not apply to the EC
+    Incompatible(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidInput(message) => write!(f, "invalid input: {}", message),
+            Error::InvalidData(message) => write!(f, "invalid data: {}", message),
+            Error::Transport(message) => write!(f, "transport error: {}", message),
+            Error::Incompatible(message) => write!(f, "incompatible: {}", message),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Transport(err.to_string())
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
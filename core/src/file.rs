@@ -0,0 +1,198 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{Ec, Layout, Region};
+
+/// Longest string that get_str returns
+const MAX_STR_LEN: usize = 32;
+
+/// Key of the board name in images of the open-source System76 EC firmware,
+/// which ends with a NUL instead of '$'
+const OPEN_BOARD_KEY: &[u8] = b"76EC_BOARD=";
+/// Key of the version in images of the open-source System76 EC firmware
+const OPEN_VERSION_KEY: &[u8] = b"76EC_VERSION=";
+
+pub struct EcFile(Vec<u8>);
+
+impl EcFile {
+    /// Find the string that follows key and ends with '$', such as the
+    /// project after "PRJ:"
+    ///
+    /// Keys also appear in code and tables, so occurrences that are not
+    /// followed by printable ASCII and a '$' within 32 bytes are skipped.
+    pub fn get_str(&self, key: &[u8]) -> Option<String> {
+        self.find_str(key, b'$')
+    }
+
+    fn find_str(&self, key: &[u8], terminator: u8) -> Option<String> {
+        if key.is_empty() {
+            return None;
+        }
+
+        self.0.windows(key.len())
+            .enumerate()
+            .filter(|(_, window)| *window == key)
+            .find_map(|(i, _)| {
+                let value = &self.0[i + key.len()..];
+                let end = value.iter().take(MAX_STR_LEN + 1).position(|&b| b == terminator)?;
+                let value = &value[..end];
+                if value.iter().all(|&b| (0x20..0x7F).contains(&b)) {
+                    Some(value.iter().map(|&b| b as char).collect())
+                } else {
+                    None
+                }
+            })
+    }
+
+    pub fn new(data: Vec<u8>) -> Self {
+        EcFile(data)
+    }
+
+    /// Read an image from a file
+    #[cfg(feature = "std")]
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<Self> {
+        std::fs::read(path).map(EcFile)
+    }
+
+    /// Whether the image is of the open-source System76 EC firmware, which
+    /// has its board and version after 76EC_BOARD= and 76EC_VERSION=
+    pub fn is_open(&self) -> bool {
+        self.find_str(OPEN_BOARD_KEY, 0).is_some()
+    }
+
+    /// Image data
+    pub fn data(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Flash layout matching the size of the image
+    pub fn layout(&self) -> Layout {
+        Layout::new(self.0.len())
+    }
+
+    /// Check if a region of the image is missing or only contains 0xFF
+    pub fn is_blank(&self, region: &Region) -> bool {
+        self.0.iter()
+            .skip(region.range.start)
+            .take(region.range.len())
+            .all(|&b| b == 0xFF)
+    }
+}
+
+impl Ec for EcFile {
+    fn size(&mut self) -> usize {
+        self.0.len()
+    }
+
+    /// Project of the proprietary firmware, or board of the open-source one
+    fn project(&mut self) -> String {
+        self.get_str(b"PRJ:")
+            .or_else(|| self.find_str(OPEN_BOARD_KEY, 0))
+            .unwrap_or_default()
+    }
+
+    fn version(&mut self) -> String {
+        if let Some(version) = self.find_str(OPEN_VERSION_KEY, 0) {
+            return version;
+        }
+
+        let mut version = self.get_str(b"VER:").unwrap_or_default();
+        while version.starts_with(' ') {
+            version.remove(0);
+        }
+        version
+    }
+
+    fn firmware(&mut self) -> Option<&'static str> {
+        if self.is_open() {
+            Some("System76 EC")
+        } else if self.get_str(b"PRJ:").is_some() {
+            Some("proprietary")
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    /// Image laid out like a firmware file, with code before the strings and
+    /// the rest of the flash erased
+    fn rom(strings: &[u8]) -> EcFile {
+        let mut data = vec![0xFF; 128 * 1024];
+        for (i, b) in data[..0x2000].iter_mut().enumerate() {
+            *b = (i * 37 + i / 7) as u8;
+        }
+        data[0x2000..0x2000 + strings.len()].copy_from_slice(strings);
+        EcFile::new(data)
+    }
+
+    #[test]
+    fn project_and_version() {
+        let mut file = rom(b"PRJ:N130WU$ VER: 1.07.02$");
+        assert_eq!(file.get_str(b"PRJ:"), Some("N130WU".to_string()));
+        assert_eq!(file.project(), "N130WU");
+        assert_eq!(file.version(), "1.07.02");
+    }
+
+    #[test]
+    fn open_firmware() {
+        let mut file = rom(b"76EC_BOARD=system76/lemp9\x0076EC_VERSION=2024-03-01_5e8f1ab\x00");
+        assert!(file.is_open());
+        assert_eq!(file.project(), "system76/lemp9");
+        assert_eq!(file.version(), "2024-03-01_5e8f1ab");
+        assert_eq!(file.firmware(), Some("System76 EC"));
+
+        let mut file = rom(b"PRJ:N130WU$ VER: 1.07.02$");
+        assert!(! file.is_open());
+        assert_eq!(file.firmware(), Some("proprietary"));
+        assert_eq!(rom(b"").firmware(), None);
+    }
+
+    #[test]
+    fn missing_key() {
+        let mut file = rom(b"PRJ:N130WU$");
+        assert_eq!(file.get_str(b"VER:"), None);
+        assert_eq!(file.get_str(b""), None);
+        assert_eq!(file.version(), "");
+    }
+
+    #[test]
+    fn partially_repeated_key() {
+        let file = rom(b"PRPRJ:GAZE15$ VVER:1.0$ AAB:x$");
+        assert_eq!(file.get_str(b"PRJ:"), Some("GAZE15".to_string()));
+        assert_eq!(file.get_str(b"VER:"), Some("1.0".to_string()));
+
+        let file = rom(b"AAAB:x$");
+        assert_eq!(file.get_str(b"AAB:"), Some("x".to_string()));
+    }
+
+    #[test]
+    fn skips_invalid_occurrences() {
+        // A key in code, a value without an end, and a value that is not
+        // ASCII all come before the real string
+        let file = rom(b"PRJ:\x00\x01$ PRJ:0123456789012345678901234567890123456789$ PRJ:ab\xFEcd$ PRJ:ORYP5$");
+        assert_eq!(file.get_str(b"PRJ:"), Some("ORYP5".to_string()));
+    }
+
+    #[test]
+    fn length_is_capped() {
+        let mut strings = b"PRJ:".to_vec();
+        strings.extend_from_slice(&[b'A'; MAX_STR_LEN]);
+        strings.push(b'$');
+        assert_eq!(rom(&strings).get_str(b"PRJ:").map(|s| s.len()), Some(MAX_STR_LEN));
+
+        strings.insert(4, b'A');
+        assert_eq!(rom(&strings).get_str(b"PRJ:"), None);
+    }
+
+    #[test]
+    fn key_at_end() {
+        let mut data = vec![0xFF; 16];
+        data.extend_from_slice(b"PRJ:");
+        assert_eq!(EcFile::new(data).get_str(b"PRJ:"), None);
+    }
+}
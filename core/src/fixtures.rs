@@ -0,0 +1,94 @@
+//! Parity of EcFile and EcFlash on the images in the fixtures directory
+//!
+//! Each image is parsed as a file, and flashed into a MailboxModel that
+//! answers the project and version commands with the strings of the image,
+//! and both must report what expected.txt lists for it.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+use crate::{Ec, EcFile, EcFlash, MailboxModel, SpiFlashModel};
+
+/// An image and what the running EC reported for it
+struct Fixture {
+    file: String,
+    size: usize,
+    project: String,
+    version: String,
+}
+
+fn dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures")
+}
+
+fn fixtures() -> Vec<Fixture> {
+    let expected = fs::read_to_string(dir().join("expected.txt")).unwrap();
+    expected.lines()
+        .filter(|line| ! line.trim().is_empty() && ! line.starts_with('#'))
+        .map(|line| match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [file, size, project, version] => Fixture {
+                file: file.to_string(),
+                size: size.parse().unwrap(),
+                project: project.to_string(),
+                version: version.to_string(),
+            },
+            _ => panic!("invalid line in expected.txt: {}", line),
+        })
+        .collect()
+}
+
+/// String after key in an image, as its EC firmware sends it: the first one
+/// made of printable characters up to a '$', without padding
+fn image_string(data: &[u8], key: &[u8]) -> String {
+    data.windows(key.len())
+        .enumerate()
+        .filter(|&(_, window)| window == key)
+        .find_map(|(i, _)| {
+            let rest = &data[i + key.len()..];
+            let string = &rest[..rest.iter().position(|&b| b == b'$')?];
+            if string.iter().all(|&b| b == b' ' || b.is_ascii_graphic()) {
+                Some(String::from_utf8_lossy(string).trim().to_string())
+            } else {
+                None
+            }
+        })
+        .unwrap_or_default()
+}
+
+/// Answers of the EC firmware of an image, taken from the image itself
+fn live(data: Vec<u8>) -> EcFlash<MailboxModel> {
+    let project = image_string(&data, b"PRJ:");
+    let version = image_string(&data, b"VER:");
+    let mut model = MailboxModel::new(SpiFlashModel::new(data));
+    model.project = project;
+    model.version = version.strip_prefix("1.").unwrap_or(&version).to_string();
+    EcFlash::with_io(model, true).unwrap()
+}
+
+#[test]
+fn every_image_is_expected() {
+    let fixtures = fixtures();
+    for entry in fs::read_dir(dir()).unwrap() {
+        let name = entry.unwrap().file_name().to_string_lossy().into_owned();
+        if name.ends_with(".rom") {
+            assert!(fixtures.iter().any(|fixture| fixture.file == name), "{} is not in expected.txt", name);
+        }
+    }
+}
+
+#[test]
+fn file_and_flash_agree() {
+    let fixtures = fixtures();
+    assert!(! fixtures.is_empty());
+    for fixture in &fixtures {
+        let data = fs::read(dir().join(&fixture.file)).unwrap();
+        let mut file = EcFile::new(data.clone());
+        let mut flash = live(data);
+
+        let expected = (fixture.size, fixture.project.clone(), fixture.version.clone());
+        assert_eq!((file.size(), file.project(), file.version()), expected, "EcFile of {}", fixture.file);
+        assert_eq!((flash.size(), flash.project(), flash.version()), expected, "EcFlash of {}", fixture.file);
+    }
+}
@@ -0,0 +1,523 @@
+#![allow(clippy::missing_safety_doc)]
+// TODO: Use real errors
+#![allow(clippy::result_unit_err)]
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::time::Duration;
+
+use super::{Backoff, Ec, EcParam, HostInterface, PROTECT_REGIONS, PROTECT_REGISTERS, PortIo, ProtectRegion, RawPortIo, Timer, Trace, TraceEvent};
+use super::regs::{self, ETWCFG, EWDKEYEN, EWDKEYR, i2ec_read, i2ec_write};
+
+/// Default timeout for each transfer to or from the EC, in microseconds
+pub const TIMEOUT_US: u64 = 100_000;
+/// How long the mailbox is given to answer after a reset, which is shorter
+/// than the EC firmware takes to start again
+const RESET_PROBE_US: u64 = 10_000;
+
+/// Super I/O IDs of the ECs that can be flashed
+pub const KNOWN_IDS: &[u16] = &[0x8587, 0x5570];
+
+/// Status port of the keyboard controller, with the output and input buffer
+/// full flags in the low bits
+const KBC_STATUS: u16 = 0x64;
+/// Data port of the keyboard controller
+const KBC_DATA: u16 = 0x60;
+
+/// Interrupts of the keyboard controller since boot, as counted by the kernel
+#[cfg(all(feature = "std", target_os = "linux"))]
+fn keyboard_interrupts() -> Option<u64> {
+    let interrupts = std::fs::read_to_string("/proc/interrupts").ok()?;
+    let line = interrupts.lines().find(|line| line.trim_start().starts_with("1:"))?;
+    Some(line.split_whitespace().skip(1).map_while(|count| count.parse::<u64>().ok()).sum())
+}
+
+#[cfg(not(all(feature = "std", target_os = "linux")))]
+fn keyboard_interrupts() -> Option<u64> {
+    None
+}
+
+/// Base address of the SMFI flash configuration registers in EC memory
+pub const FLASH_OPTION_BASE: u16 = regs::SMFI_BASE;
+/// Number of SMFI flash configuration registers
+pub const FLASH_OPTION_SIZE: usize = regs::SMFI_SIZE;
+
+pub struct EcFlash<P: PortIo = RawPortIo> {
+    io: P,
+    primary: bool,
+    data_port: u16,
+    cmd_port: u16,
+    id: u16,
+    chip_version: u8,
+    supported: bool,
+    timer: Box<dyn Timer + Send>,
+    timeout_us: u64,
+    read_timeout_us: u64,
+    backoff: Option<Backoff>,
+    trace: Option<Box<dyn Trace + Send>>,
+}
+
+impl<P: PortIo> EcFlash<P> {
+    /// Use a different time source for timeouts
+    ///
+    /// With the std feature, the monotonic clock is used by default. Otherwise,
+    /// polls are counted.
+    pub fn set_timer<T: Timer + Send + 'static>(&mut self, timer: T) {
+        self.timer = Box::new(timer);
+    }
+
+    /// Change the timeout for each transfer, which is TIMEOUT_US by default
+    ///
+    /// This also changes the read timeout, so call set_read_timeout after it.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout_us = timeout.as_micros() as u64;
+        self.read_timeout_us = self.timeout_us;
+    }
+
+    /// Change the timeout for the EC to return data, which is TIMEOUT_US by
+    /// default
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        self.read_timeout_us = timeout.as_micros() as u64;
+    }
+
+    /// Change how waits sleep between polls, or spin without sleeping with None
+    ///
+    /// With the std feature, the default Backoff is used. Otherwise, waits
+    /// spin by default, as the timer may not be able to sleep.
+    pub fn set_backoff(&mut self, backoff: Option<Backoff>) {
+        self.backoff = backoff;
+    }
+
+    /// Record every command, data byte, and timeout from now on
+    pub fn set_trace<T: Trace + Send + 'static>(&mut self, trace: T) {
+        self.trace = Some(Box::new(trace));
+    }
+
+    /// Record an event on the trace, if there is one
+    pub(crate) fn trace(&mut self, event: TraceEvent) {
+        if let Some(trace) = &mut self.trace {
+            trace.event(self.timer.now_us(), event);
+        }
+    }
+
+    /// Current time of the timer, for measuring how long operations take
+    pub(crate) fn now_us(&mut self) -> u64 {
+        self.timer.now_us()
+    }
+
+    /// Sleep before the next poll of a wait that started at start, once it
+    /// has spun for longer than the backoff allows
+    ///
+    /// sleep_us holds the last sleep, and starts at 0 for each wait.
+    pub(crate) fn backoff(&mut self, start: u64, sleep_us: &mut u64) {
+        let backoff = match self.backoff {
+            Some(backoff) => backoff,
+            None => return,
+        };
+        if self.timer.now_us().wrapping_sub(start) < backoff.spin.as_micros() as u64 {
+            return;
+        }
+
+        let min_us = backoff.min_sleep.as_micros() as u64;
+        let max_us = backoff.max_sleep.as_micros() as u64;
+        *sleep_us = sleep_us.saturating_mul(2).max(min_us).min(max_us);
+        self.timer.delay_us(*sleep_us);
+    }
+
+    /// Poll until ready returns true, for at most timeout_us microseconds
+    unsafe fn wait<F: FnMut(&mut Self) -> bool>(&mut self, timeout_us: u64, mut ready: F) -> Result<(), ()> {
+        let start = self.timer.now_us();
+        let mut sleep_us = 0;
+        loop {
+            if ready(self) {
+                return Ok(());
+            }
+            if self.timer.now_us().wrapping_sub(start) >= timeout_us {
+                self.trace(TraceEvent::Timeout);
+                return Err(());
+            }
+            self.backoff(start, &mut sleep_us);
+        }
+    }
+
+    pub unsafe fn sts(&mut self) -> u8 {
+        self.io.inb(self.cmd_port)
+    }
+
+    pub unsafe fn can_read(&mut self) -> bool {
+        self.sts() & 1 == 1
+    }
+
+    pub unsafe fn wait_read(&mut self, timeout_us: u64) -> Result<(), ()> {
+        self.wait(timeout_us, |ec| ec.can_read())
+    }
+
+    pub unsafe fn can_write(&mut self) -> bool {
+        self.sts() & 2 == 0
+    }
+
+    pub unsafe fn wait_write(&mut self, timeout_us: u64) -> Result<(), ()> {
+        self.wait(timeout_us, |ec| ec.can_write())
+    }
+
+    pub unsafe fn flush(&mut self) -> Result<(), ()> {
+        self.wait(self.timeout_us, |ec| if ec.can_read() {
+            ec.io.inb(ec.data_port);
+            false
+        } else {
+            true
+        })
+    }
+
+    pub unsafe fn cmd(&mut self, data: u8) -> Result<(), ()> {
+        self.wait_write(self.timeout_us)?;
+        self.io.outb(self.cmd_port, data);
+        self.trace(TraceEvent::Command(data));
+        self.wait_write(self.timeout_us)
+    }
+
+    pub unsafe fn read(&mut self) -> Result<u8, ()> {
+        self.wait_read(self.read_timeout_us)?;
+        let data = self.io.inb(self.data_port);
+        self.trace(TraceEvent::Read(data));
+        Ok(data)
+    }
+
+    pub unsafe fn write(&mut self, data: u8) -> Result<(), ()> {
+        self.wait_write(self.timeout_us)?;
+        self.io.outb(self.data_port, data);
+        self.trace(TraceEvent::Write(data));
+        self.wait_write(self.timeout_us)
+    }
+
+    pub unsafe fn get_param(&mut self, param: EcParam) -> Result<u8, ()> {
+        self.get_param_raw(param.offset())
+    }
+
+    pub unsafe fn set_param(&mut self, param: EcParam, data: u8) -> Result<(), ()> {
+        self.set_param_raw(param.offset(), data)
+    }
+
+    /// Read a parameter by offset, for parameters without a name
+    pub unsafe fn get_param_raw(&mut self, offset: u8) -> Result<u8, ()> {
+        self.cmd(0x80)?;
+        self.write(offset)?;
+        self.read()
+    }
+
+    /// Write a parameter by offset, for parameters without a name
+    pub unsafe fn set_param_raw(&mut self, offset: u8, data: u8) -> Result<(), ()> {
+        self.cmd(0x81)?;
+        self.write(offset)?;
+        self.write(data)
+    }
+
+    /// Run an EC function with 4 bytes of data passed through the fcommand
+    /// parameters, which are replaced with the result
+    pub unsafe fn fcommand(&mut self, cmd: u8, dat: u8, buf: &mut [u8; 4]) -> Result<(), ()> {
+        self.set_param(EcParam::FcmdArgument, dat)?;
+        for (i, &x) in buf.iter().enumerate() {
+            self.set_param(EcParam::FcmdData(i as u8), x)?;
+        }
+
+        self.set_param(EcParam::FcmdCommand, cmd)?;
+
+        for (i, x) in buf.iter_mut().enumerate() {
+            *x = self.get_param(EcParam::FcmdData(i as u8))?;
+        }
+
+        self.set_param(EcParam::FcmdCommand, 0x00)
+    }
+
+    pub unsafe fn get_str(&mut self, index: u8) -> Result<String, ()> {
+        let mut string = String::new();
+
+        self.cmd(index)?;
+        for _i in 0..16 {
+            let byte = self.read()?;
+            if byte == b'$' {
+                break;
+            } else {
+                string.push(byte as char);
+            }
+        }
+
+        Ok(string)
+    }
+
+    /// Poll until no key has been pressed or released for quiet, failing if
+    /// keys are still in use after timeout
+    ///
+    /// Each key event leaves a scancode in the keyboard controller until the
+    /// host reads it. The kernel driver reads it right away, so on Linux its
+    /// interrupts are counted too.
+    pub unsafe fn wait_keys_released(&mut self, quiet: Duration, timeout: Duration) -> Result<(), ()> {
+        let quiet_us = quiet.as_micros() as u64;
+        let timeout_us = timeout.as_micros() as u64;
+        let start = self.timer.now_us();
+        let mut last_event = start;
+        let mut interrupts = keyboard_interrupts();
+        loop {
+            let now = self.timer.now_us();
+            let count = keyboard_interrupts();
+            if self.io.inb(KBC_STATUS) & 3 != 0 || count != interrupts {
+                interrupts = count;
+                last_event = now;
+            }
+            if now.wrapping_sub(last_event) >= quiet_us {
+                return Ok(());
+            }
+            if now.wrapping_sub(start) >= timeout_us {
+                return Err(());
+            }
+            self.timer.delay_us(1000);
+        }
+    }
+
+    /// Reset the EC by triggering its watchdog through the Super I/O
+    ///
+    /// Only the primary EC is reachable through the Super I/O. The EC will
+    /// restart from its boot block, which may cut power to the system. Fails
+    /// if the EC still answers its mailbox afterwards.
+    pub unsafe fn reset(&mut self) -> Result<(), ()> {
+        if ! self.primary || ! self.supported {
+            return Err(());
+        }
+
+        let _ = self.flush();
+        let etwcfg = i2ec_read(&mut self.io, ETWCFG);
+        i2ec_write(&mut self.io, ETWCFG, etwcfg | EWDKEYEN);
+        i2ec_write(&mut self.io, EWDKEYR, 0);
+
+        let (timeout_us, read_timeout_us) = (self.timeout_us, self.read_timeout_us);
+        self.timeout_us = RESET_PROBE_US;
+        self.read_timeout_us = RESET_PROBE_US;
+        let answer = self.get_str(0x92);
+        self.timeout_us = timeout_us;
+        self.read_timeout_us = read_timeout_us;
+
+        match answer {
+            Ok(project) if ! project.is_empty() => Err(()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read a byte of EC memory through the I2EC interface of the Super I/O,
+    /// which works even if the EC firmware does not run
+    ///
+    /// Only the primary EC is reachable through the Super I/O.
+    pub unsafe fn memory_read(&mut self, address: u16) -> Result<u8, ()> {
+        if ! self.primary {
+            return Err(());
+        }
+
+        Ok(i2ec_read(&mut self.io, address))
+    }
+
+    /// Write a byte of EC memory through the I2EC interface of the Super I/O
+    ///
+    /// This changes the state of the running EC firmware under it, such as a
+    /// variable it is in the middle of updating, so it is refused unless the
+    /// ID is supported.
+    pub unsafe fn memory_write(&mut self, address: u16, value: u8) -> Result<(), ()> {
+        if ! self.primary || ! self.supported {
+            return Err(());
+        }
+
+        i2ec_write(&mut self.io, address, value);
+        Ok(())
+    }
+
+    /// Read one of the SMFI flash configuration registers
+    ///
+    /// These control flash protection and host access, and are only reachable
+    /// on the primary EC.
+    pub unsafe fn flash_option(&mut self, offset: u8) -> Result<u8, ()> {
+        if ! self.primary {
+            return Err(());
+        }
+
+        Ok(i2ec_read(&mut self.io, FLASH_OPTION_BASE + offset as u16))
+    }
+
+    /// Write one of the SMFI flash configuration registers
+    ///
+    /// A wrong value can lock the host out of the flash until the EC is reset.
+    pub unsafe fn set_flash_option(&mut self, offset: u8, value: u8) -> Result<(), ()> {
+        if ! self.primary || ! self.supported {
+            return Err(());
+        }
+
+        i2ec_write(&mut self.io, FLASH_OPTION_BASE + offset as u16, value);
+        Ok(())
+    }
+
+    /// Read protect region index of the internal flash
+    pub unsafe fn protect_region(&mut self, index: u8) -> Result<ProtectRegion, ()> {
+        if index >= PROTECT_REGIONS {
+            return Err(());
+        }
+
+        let mut registers = [0; 3];
+        for (value, &offset) in registers.iter_mut().zip(PROTECT_REGISTERS[index as usize].iter()) {
+            *value = self.flash_option(offset)?;
+        }
+        Ok(ProtectRegion::from_registers(registers))
+    }
+
+    /// Program protect region index of the internal flash, size last so that
+    /// a lock only applies once the base is in place, then check that it
+    /// reads back
+    pub unsafe fn set_protect_region(&mut self, index: u8, region: &ProtectRegion) -> Result<(), ()> {
+        if index >= PROTECT_REGIONS {
+            return Err(());
+        }
+
+        for (&value, &offset) in region.registers().iter().zip(PROTECT_REGISTERS[index as usize].iter()) {
+            self.set_flash_option(offset, value)?;
+        }
+        if self.protect_region(index)? != *region {
+            return Err(());
+        }
+        Ok(())
+    }
+
+    /// Probe for the EC using the given port I/O
+    pub fn with_io(io: P, primary: bool) -> Result<Self, String> {
+        Self::with_io_known(io, primary, &[])
+    }
+
+    /// Probe for the EC using the given port I/O, also accepting the Super I/O
+    /// IDs in known
+    pub fn with_io_known(io: P, primary: bool, known: &[u16]) -> Result<Self, String> {
+        let mut ec = Self::with_io_unchecked(io, primary);
+        ec.supported |= known.contains(&ec.id);
+        if ! ec.supported {
+            return Err(format!("Unknown EC ID: 0x{:>04X}", ec.id));
+        }
+        Ok(ec)
+    }
+
+    /// Open the EC using the given port I/O even if its Super I/O ID is not
+    /// known, to gather information for bring-up of new boards
+    ///
+    /// The mailbox can be used, but flash mode, reset, writing EC memory, and
+    /// changing flash options are refused unless the ID is supported.
+    pub fn with_io_unchecked(mut io: P, primary: bool) -> Self {
+        // Probe for Super I/O chip
+        let (id, chip_version) = unsafe {
+            let a = regs::super_io_read(&mut io, regs::SIO_CHIPID1);
+            let b = regs::super_io_read(&mut io, regs::SIO_CHIPID2);
+            let c = regs::super_io_read(&mut io, regs::SIO_CHIPVER);
+            (((a as u16) << 8) | (b as u16), c)
+        };
+
+        let supported = KNOWN_IDS.contains(&id);
+
+        let (data_port, cmd_port) = if primary {
+            (0x62, 0x66)
+        } else {
+            (0x68, 0x6c)
+        };
+
+        Self {
+            io,
+            primary,
+            data_port,
+            cmd_port,
+            id,
+            chip_version,
+            supported,
+            #[cfg(feature = "std")]
+            timer: Box::new(super::StdTimer::new()),
+            #[cfg(not(feature = "std"))]
+            timer: Box::new(super::CounterTimer::new()),
+            timeout_us: TIMEOUT_US,
+            read_timeout_us: TIMEOUT_US,
+            #[cfg(feature = "std")]
+            backoff: Some(Backoff::default()),
+            #[cfg(not(feature = "std"))]
+            backoff: None,
+            trace: None,
+        }
+    }
+
+    /// Send mailbox transactions to the keyboard controller ports 0x60/0x64,
+    /// for older boards whose flash protocol only answers there
+    ///
+    /// Scancodes of the keyboard and touchpad come out of the same data port,
+    /// so the kernel i8042 driver must not be bound, and no key pressed, while
+    /// the EC is used this way. Only the primary EC has these ports.
+    pub fn use_kbc_ports(&mut self) -> Result<(), ()> {
+        if ! self.primary {
+            return Err(());
+        }
+        self.data_port = KBC_DATA;
+        self.cmd_port = KBC_STATUS;
+        Ok(())
+    }
+
+    /// Whether the mailbox is on the keyboard controller ports
+    pub fn kbc_ports(&self) -> bool {
+        self.cmd_port == KBC_STATUS
+    }
+
+    /// Whether the Super I/O ID is one that can be flashed
+    pub fn supported(&self) -> bool {
+        self.supported
+    }
+}
+
+impl EcFlash {
+    /// Probe for the EC using the in and out instructions, which requires I/O
+    /// privileges
+    pub fn new(primary: bool) -> Result<Self, String> {
+        Self::with_io(RawPortIo, primary)
+    }
+}
+
+impl<P: PortIo> Ec for EcFlash<P> {
+    /// Size of the flash, which each EC reports through its own mailbox
+    ///
+    /// ECs that do not answer the query, as older secondary ECs may not, are
+    /// assumed to have 64 KiB.
+    fn size(&mut self) -> usize {
+        let _ = unsafe { self.flush() };
+
+        if unsafe { self.get_param(EcParam::FlashSizeFlag) } == Ok(0x80) {
+            128 * 1024
+        } else {
+            64 * 1024
+        }
+    }
+
+    fn project(&mut self) -> String {
+        let _ = unsafe { self.flush() };
+
+        unsafe { self.get_str(0x92) }.unwrap_or_default()
+    }
+
+    fn version(&mut self) -> String {
+        let _ = unsafe { self.flush() };
+
+        let mut version = unsafe { self.get_str(0x93) }.unwrap_or_default();
+        version.insert_str(0, "1.");
+        version
+    }
+
+    fn chip_id(&mut self) -> Option<u16> {
+        Some(self.id)
+    }
+
+    fn chip_version(&mut self) -> Option<u8> {
+        Some(self.chip_version)
+    }
+
+    fn interface(&mut self) -> Option<(HostInterface, u16, u16)> {
+        Some((self.io.interface(), self.data_port, self.cmd_port))
+    }
+
+    fn firmware(&mut self) -> Option<&'static str> {
+        Some("proprietary")
+    }
+}
@@ -0,0 +1,882 @@
+#![allow(clippy::missing_safety_doc)]
+// TODO: Use real errors
+#![allow(clippy::result_unit_err)]
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use super::regs::{ECHIPID1, ECHIPID2};
+use super::{AlgorithmProbe, Ec, EcFlash, EcParam, FlashReport, HostInterface, PortIo, ProtectRegion, RawPortIo, SFDP_OPCODE, TIMEOUT_US, TraceEvent};
+
+/// Response of the EC to a request to enter flash mode
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Handshake {
+    /// The EC entered flash mode
+    Accepted,
+    /// The EC took the request but did not answer in time
+    Busy,
+    /// The EC answered with an unknown value, so it may use another protocol
+    UnsupportedProtocol(u8),
+    /// The Super I/O ID is not known, so flash mode was not requested
+    UnsupportedChip(u16),
+}
+
+impl Handshake {
+    /// Value the EC answers with when it enters flash mode
+    pub const ACCEPTED: u8 = 51;
+}
+
+impl fmt::Display for Handshake {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Handshake::Accepted => write!(f, "accepted"),
+            Handshake::Busy => write!(f, "EC is busy"),
+            Handshake::UnsupportedProtocol(value) => write!(
+                f,
+                "EC answered {} instead of {}, its flash protocol is not supported",
+                value,
+                Handshake::ACCEPTED
+            ),
+            Handshake::UnsupportedChip(id) => write!(
+                f,
+                "EC ID 0x{:04X} is not supported, refusing to enter flash mode",
+                id
+            ),
+        }
+    }
+}
+
+/// Why Flasher::program stopped
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProgramError {
+    /// The named step failed, or was interrupted
+    Failed(&'static str),
+    /// A byte, the first of count, did not verify after VERIFY_RETRIES retries
+    Verify { address: usize, value: u8, expected: u8, count: usize },
+}
+
+impl fmt::Display for ProgramError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProgramError::Failed(step) => write!(f, "failed to {}", step),
+            ProgramError::Verify { address, value, expected, count } => write!(
+                f,
+                "written data does not match, 0x{:X}: 0x{:02X} != 0x{:02X}, {} bytes in all",
+                address, value, expected, count
+            ),
+        }
+    }
+}
+
+/// Times to erase or write a block again if it does not verify
+pub const VERIFY_RETRIES: usize = 2;
+
+/// The EC boot block, which holds the reset vector and must stay intact for the
+/// EC to come back up after an interrupted flash
+pub const BOOT_BLOCK: Range<usize> = 0..0x1000;
+
+/// Block protect bits of the flash status register
+pub const BLOCK_PROTECT_MASK: u8 = 0x3C;
+/// Block protect lock bit of the flash status register, which makes the block
+/// protect bits read-only
+pub const BLOCK_PROTECT_LOCK: u8 = 0x80;
+/// Block protect bits covering the lowest 4 KiB of the flash of supported ECs,
+/// which holds the boot block
+pub const BOOT_BLOCK_PROTECT: u8 = 0x04;
+
+/// Limits for each kind of wait, so that slow chips and slow transports can be
+/// given more time than the LPC path needs
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Timeouts {
+    /// Waiting for the EC to take a command or data byte
+    pub command: Duration,
+    /// Waiting for the EC to return data
+    pub read: Duration,
+    /// Waiting for the flash to finish erasing a block
+    pub erase_busy: Duration,
+    /// Waiting for the flash to finish programming a word, or to change its
+    /// write enable state
+    pub write_busy: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            command: Duration::from_micros(TIMEOUT_US),
+            read: Duration::from_micros(TIMEOUT_US),
+            erase_busy: Duration::from_secs(1),
+            write_busy: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Parameter write that keeps the watchdog of some EC firmwares from resetting
+/// the EC when the host stops servicing it during a long flash mode session
+///
+/// Which parameter and value this takes depends on the project.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Watchdog {
+    /// Parameter that controls the watchdog
+    pub param: EcParam,
+    /// Value that suppresses the watchdog
+    pub suppress: u8,
+}
+
+/// What a Flasher is doing, so that it can be left cleanly when dropped
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FlasherState {
+    /// Not in flash mode
+    Idle,
+    /// In flash mode, outside of follow mode
+    Started,
+    /// In follow mode, outside of erase and write
+    FollowMode,
+    /// Erasing, possibly in follow mode
+    Erasing,
+    /// Writing, possibly in follow mode or auto address increment mode
+    Writing,
+}
+
+/// Flash mode access to the SPI flash of an EC, through the mailbox of either
+/// the primary EC at 0x62/0x66 or the secondary EC at 0x68/0x6C
+pub struct Flasher<P: PortIo = RawPortIo> {
+    ec: EcFlash<P>,
+    pub size: usize,
+    /// Range that erase and write operate on, the entire flash by default
+    pub range: Range<usize>,
+    /// Ranges that erase and write leave untouched, unless allow_bootblock is set
+    pub protected: Vec<Range<usize>>,
+    /// Erase and write protected ranges too
+    pub allow_bootblock: bool,
+    /// What read, erase, and write have done so far
+    pub report: FlashReport,
+    /// Limits for each kind of wait, which start applies to the EC
+    pub timeouts: Timeouts,
+    /// Watchdog that start suppresses, and stop restores
+    pub watchdog: Option<Watchdog>,
+    /// Flag that makes erase and write stop before the next block, out of
+    /// follow mode, such as one set by a signal handler
+    pub interrupt: Option<&'static AtomicBool>,
+    /// Address of the block that erase or write stopped before
+    interrupted: Option<usize>,
+    /// Value of the watchdog parameter before start suppressed it
+    watchdog_restore: Option<u8>,
+    /// Contents of the flash as last read, kept across sessions since only
+    /// flash mode changes it
+    cache: Vec<u8>,
+    /// Blocks of 1 KiB in cache that were read, and not erased or written since
+    cached: Vec<bool>,
+    state: FlasherState,
+}
+
+impl<P: PortIo> Flasher<P> {
+    pub fn new(mut ec: EcFlash<P>) -> Self {
+        let size = ec.size();
+        Self {
+            ec,
+            size,
+            range: 0..size,
+            protected: vec![BOOT_BLOCK],
+            allow_bootblock: false,
+            report: FlashReport::new(),
+            timeouts: Timeouts::default(),
+            watchdog: None,
+            interrupt: None,
+            interrupted: None,
+            watchdog_restore: None,
+            cache: vec![0xFF; size],
+            cached: vec![false; size / 1024],
+            state: FlasherState::Idle,
+        }
+    }
+
+    /// What the flasher is doing, which stays at Erasing or Writing if either
+    /// failed part way
+    pub fn state(&self) -> FlasherState {
+        self.state
+    }
+
+    /// Address of the block that erase or write stopped before when interrupt
+    /// was set, with the EC still in flash mode
+    pub fn interrupted(&self) -> Option<usize> {
+        self.interrupted
+    }
+
+    /// Check interrupt before the block at index, recording where it stopped
+    fn check_interrupt(&mut self, index: usize) -> Result<(), ()> {
+        if self.interrupt.is_some_and(|interrupt| interrupt.load(Ordering::SeqCst)) {
+            self.interrupted = Some(index);
+            return Err(());
+        }
+        Ok(())
+    }
+
+    /// Enter an operation from Started, returning the state to go back to
+    fn begin(&mut self, state: FlasherState) -> FlasherState {
+        self.interrupted = None;
+        core::mem::replace(&mut self.state, state)
+    }
+
+    /// Super I/O chip ID of the EC
+    pub fn chip_id(&mut self) -> Option<u16> {
+        self.ec.chip_id()
+    }
+
+    /// Read protect region index of the internal flash, which only the
+    /// primary EC has
+    pub unsafe fn protect_region(&mut self, index: u8) -> Result<ProtectRegion, ()> {
+        self.ec.protect_region(index)
+    }
+
+    /// How the EC is reached, and its data and command ports
+    pub fn interface(&mut self) -> Option<(HostInterface, u16, u16)> {
+        self.ec.interface()
+    }
+
+    /// Add the time since start, from the EC timer, to a phase of the report
+    fn end_phase(&mut self, name: &str, start: u64) {
+        let elapsed = self.ec.now_us().wrapping_sub(start);
+        self.report.add_phase(name, Duration::from_micros(elapsed));
+    }
+
+    /// Check if any byte in the range will be left untouched by erase and write
+    pub fn is_protected(&self, range: Range<usize>) -> bool {
+        range.start < self.range.start || range.end > self.range.end
+            || ! self.allow_bootblock && self.protected.iter().any(|protected| {
+                range.start < protected.end && protected.start < range.end
+            })
+    }
+
+    /// Parts of range to erase and write one after the other, with a boot
+    /// block that will be flashed last, so that a power loss before the rest
+    /// of the flash verifies leaves the old boot block to recover from
+    pub fn program_order(&self) -> Vec<Range<usize>> {
+        let boot_end = BOOT_BLOCK.end.clamp(self.range.start, self.range.end);
+        if boot_end == self.range.start || boot_end == self.range.end || self.is_protected(self.range.start..boot_end) {
+            vec![self.range.clone()]
+        } else {
+            vec![boot_end..self.range.end, self.range.start..boot_end]
+        }
+    }
+
+    /// Find the first address outside of protected ranges where the data differs
+    pub fn mismatch(&self, a: &[u8], b: &[u8]) -> Option<usize> {
+        (0..a.len().max(b.len())).find(|&i| {
+            a.get(i) != b.get(i) && ! self.is_protected(i..i + 1)
+        })
+    }
+
+    /// Find the first address of the boot block where the data differs, if
+    /// erase and write leave it untouched
+    ///
+    /// mismatch skips these addresses, so flashing an image with a new boot
+    /// block would verify while leaving the old one in place.
+    pub fn bootblock_mismatch(&self, a: &[u8], b: &[u8]) -> Option<usize> {
+        (self.range.start..BOOT_BLOCK.end.min(self.range.end)).find(|&i| a.get(i) != b.get(i) && self.is_protected(i..i + 1))
+    }
+
+    /// Every flash access starts here, so this also refuses unsupported chips
+    unsafe fn enter_follow_mode(&mut self) -> Result<(), ()> {
+        if ! self.ec.supported() {
+            return Err(());
+        }
+        self.ec.cmd(1)?;
+        if self.state == FlasherState::Started {
+            self.state = FlasherState::FollowMode;
+        }
+        Ok(())
+    }
+
+    unsafe fn spi_cmd(&mut self, cmd: u8) -> Result<(), ()> {
+        self.ec.trace(TraceEvent::SpiOpcode(cmd));
+        self.ec.cmd(2)?;
+        self.ec.cmd(cmd)
+    }
+
+    unsafe fn spi_write(&mut self, value: u8) -> Result<(), ()> {
+        self.ec.cmd(3)?;
+        self.ec.cmd(value)
+    }
+
+    unsafe fn spi_read(&mut self) -> Result<u8, ()> {
+        self.ec.cmd(4)?;
+        self.ec.read()
+    }
+
+    unsafe fn exit_follow_mode(&mut self) -> Result<(), ()> {
+        self.ec.cmd(5)?;
+        if self.state == FlasherState::FollowMode {
+            self.state = FlasherState::Started;
+        }
+        Ok(())
+    }
+
+    /// Poll the flash status register until done returns true, or fail after
+    /// timeout
+    unsafe fn spi_poll<F: Fn(u8) -> bool>(&mut self, timeout: Duration, done: F) -> Result<(), ()> {
+        self.enter_follow_mode()?;
+        self.spi_cmd(5)?;
+        let start = self.ec.now_us();
+        let timeout_us = timeout.as_micros() as u64;
+        let mut sleep_us = 0;
+        while ! done(self.spi_read()?) {
+            if self.ec.now_us().wrapping_sub(start) >= timeout_us {
+                let _ = self.exit_follow_mode();
+                return Err(());
+            }
+            self.ec.backoff(start, &mut sleep_us);
+        }
+        self.exit_follow_mode()
+    }
+
+    /// Wait until the flash is not busy
+    unsafe fn spi_wait(&mut self, timeout: Duration) -> Result<(), ()> {
+        self.spi_poll(timeout, |status| status & 1 == 0)
+    }
+
+    unsafe fn spi_write_enable(&mut self) -> Result<(), ()> {
+        self.spi_wait(self.timeouts.write_busy)?;
+        self.enter_follow_mode()?;
+        self.spi_cmd(6)?;
+        //TODO: extra spi command 80 based on device id 0xbf
+        self.spi_poll(self.timeouts.write_busy, |status| status & 3 == 2)
+    }
+
+    unsafe fn spi_write_disable(&mut self) -> Result<(), ()> {
+        self.spi_wait(self.timeouts.write_busy)?;
+        self.enter_follow_mode()?;
+        self.spi_cmd(4)?;
+        self.spi_poll(self.timeouts.write_busy, |status| status & 2 == 0)
+    }
+
+    /// Suppress the watchdog, saving the value to restore
+    unsafe fn suppress_watchdog(&mut self) -> Result<(), ()> {
+        if let Some(watchdog) = self.watchdog {
+            // Starting again must not save the suppressed value
+            if self.watchdog_restore.is_none() {
+                self.watchdog_restore = Some(self.ec.get_param(watchdog.param)?);
+            }
+            self.ec.set_param(watchdog.param, watchdog.suppress)?;
+        }
+        Ok(())
+    }
+
+    /// Restore the watchdog suppressed by suppress_watchdog
+    unsafe fn restore_watchdog(&mut self) -> Result<(), ()> {
+        match (self.watchdog, self.watchdog_restore.take()) {
+            (Some(watchdog), Some(value)) => self.ec.set_param(watchdog.param, value),
+            _ => Ok(()),
+        }
+    }
+
+    /// Poll until no key has been pressed or released for quiet, see
+    /// [`EcFlash::wait_keys_released`]
+    pub unsafe fn wait_keys_released(&mut self, quiet: Duration, timeout: Duration) -> Result<(), ()> {
+        self.ec.wait_keys_released(quiet, timeout)
+    }
+
+    /// Take over an EC that an interrupted erase or write left in flash mode,
+    /// which does not answer the handshake of start again
+    ///
+    /// The watchdog value saved by that start is lost, so stop does not
+    /// restore it.
+    pub unsafe fn resume(&mut self) -> Result<(), ()> {
+        if ! self.ec.supported() {
+            return Err(());
+        }
+        self.ec.set_timeout(self.timeouts.command);
+        self.ec.set_read_timeout(self.timeouts.read);
+        self.state = FlasherState::Started;
+        Ok(())
+    }
+
+    /// Exit follow mode and leave flash mode of an EC that a crashed session
+    /// left there, without knowing how far it got
+    pub unsafe fn recover(&mut self) -> Result<(), ()> {
+        self.resume()?;
+        self.state = FlasherState::FollowMode;
+        self.stop()
+    }
+
+    /// Ask the EC to enter flash mode, after suppressing the watchdog
+    ///
+    /// Returns an error if the EC did not take the request at all. The
+    /// watchdog is restored unless the EC entered flash mode.
+    pub unsafe fn start(&mut self) -> Result<Handshake, ()> {
+        if ! self.ec.supported() {
+            return Ok(Handshake::UnsupportedChip(self.ec.chip_id().unwrap_or(0)));
+        }
+
+        self.ec.set_timeout(self.timeouts.command);
+        self.ec.set_read_timeout(self.timeouts.read);
+
+        if self.suppress_watchdog().is_err() {
+            let _ = self.restore_watchdog();
+            return Err(());
+        }
+
+        let handshake = match self.ec.cmd(0xDC) {
+            Ok(()) => match self.ec.read() {
+                Ok(Handshake::ACCEPTED) => {
+                    self.state = FlasherState::Started;
+                    return Ok(Handshake::Accepted);
+                },
+                Ok(value) => Ok(Handshake::UnsupportedProtocol(value)),
+                Err(()) => Ok(Handshake::Busy),
+            },
+            Err(()) => Err(()),
+        };
+        let _ = self.restore_watchdog();
+        handshake
+    }
+
+    /// Ask the EC which of the command sets that reach the flash answer
+    ///
+    /// The fcommand parameters and I2EC are read first, then flash mode is
+    /// requested, and left again if the EC entered it.
+    pub unsafe fn probe(&mut self) -> Result<AlgorithmProbe, ()> {
+        let fcommand = self.ec.get_param(EcParam::FcmdCommand) == Ok(0);
+        let scratch_rom = self.ec.supported() && match (self.ec.memory_read(ECHIPID1), self.ec.memory_read(ECHIPID2)) {
+            (Ok(a), Ok(b)) => self.ec.chip_id() == Some(((a as u16) << 8) | b as u16),
+            _ => false,
+        };
+
+        let handshake = self.start()?;
+        if handshake == Handshake::Accepted {
+            self.stop()?;
+        }
+        Ok(AlgorithmProbe { handshake, fcommand, scratch_rom })
+    }
+
+    /// Read the status register of the flash, which holds the busy, write
+    /// enable, and block protect bits
+    pub unsafe fn status(&mut self) -> Result<u8, ()> {
+        self.enter_follow_mode()?;
+        self.spi_cmd(5)?;
+        let status = self.spi_read();
+        self.exit_follow_mode()?;
+        status
+    }
+
+    /// Write the status register of the flash, then check that the block
+    /// protect bits read back as written
+    ///
+    /// A locked status register cannot be changed until the flash is reset.
+    pub unsafe fn set_status(&mut self, status: u8) -> Result<(), ()> {
+        self.spi_write_enable()?;
+        self.enter_follow_mode()?;
+        self.spi_cmd(1)?;
+        self.spi_write(status)?;
+        self.spi_wait(self.timeouts.write_busy)?;
+
+        let mask = BLOCK_PROTECT_MASK | BLOCK_PROTECT_LOCK;
+        if self.status()? & mask != status & mask {
+            return Err(());
+        }
+        Ok(())
+    }
+
+    /// Send tx to the flash in follow mode, its first byte as the opcode, then
+    /// read rx bytes, for opcodes that this crate does not use
+    pub unsafe fn spi_transfer(&mut self, tx: &[u8], rx: usize) -> Result<Vec<u8>, ()> {
+        let (&opcode, rest) = tx.split_first().ok_or(())?;
+        // The opcode may erase or program anything
+        self.cached.iter_mut().for_each(|cached| *cached = false);
+        self.enter_follow_mode()?;
+        let res = (|| {
+            self.spi_cmd(opcode)?;
+            for &value in rest {
+                self.spi_write(value)?;
+            }
+            (0..rx).map(|_| self.spi_read()).collect()
+        })();
+        self.exit_follow_mode()?;
+        res
+    }
+
+    /// Read len bytes of the SFDP space of the flash from address in follow
+    /// mode, which unlike spi_transfer leaves the read cache alone
+    pub unsafe fn read_sfdp(&mut self, address: u32, len: usize) -> Result<Vec<u8>, ()> {
+        self.enter_follow_mode()?;
+        let res = (|| {
+            self.spi_cmd(SFDP_OPCODE)?;
+            for value in [(address >> 16) as u8, (address >> 8) as u8, address as u8, 0] {
+                self.spi_write(value)?;
+            }
+            (0..len).map(|_| self.spi_read()).collect()
+        })();
+        self.exit_follow_mode()?;
+        res
+    }
+
+    /// Set the block protect bits that cover the boot block, so that erase and
+    /// program leave it alone until unlock_bootblock
+    pub unsafe fn lock_bootblock(&mut self) -> Result<(), ()> {
+        let status = self.status()?;
+        self.set_status((status & ! BLOCK_PROTECT_MASK) | BOOT_BLOCK_PROTECT)
+    }
+
+    /// Clear all block protect bits
+    pub unsafe fn unlock_bootblock(&mut self) -> Result<(), ()> {
+        let status = self.status()?;
+        self.set_status(status & ! (BLOCK_PROTECT_MASK | BLOCK_PROTECT_LOCK))
+    }
+
+    pub unsafe fn read<F: Fn(usize)>(&mut self, callback: F) -> Result<Vec<u8>, ()> {
+        self.read_range(0, self.size, callback)
+    }
+
+    /// Read len bytes starting at offset, calling callback with the number of
+    /// bytes read so far
+    ///
+    /// Each byte takes two mailbox transactions, a follow mode read command
+    /// and the data. The proprietary EC firmware has no known command to read
+    /// a block into the mailbox, so there is nothing faster to fall back from.
+    pub unsafe fn read_range<F: Fn(usize)>(&mut self, offset: usize, len: usize, callback: F) -> Result<Vec<u8>, ()> {
+        let end = match offset.checked_add(len) {
+            Some(end) if end <= self.size => end,
+            _ => return Err(()),
+        };
+
+        let start = self.ec.now_us();
+        let res = self.read_range_inner(offset, end, callback);
+        self.end_phase("read", start);
+        res
+    }
+
+    /// Read the whole flash like read, but take the blocks that were read
+    /// before and not erased or written since from the cache
+    ///
+    /// Verifying an erase then only reads the blocks that were erased, and
+    /// verifying a write only the blocks that were programmed.
+    pub unsafe fn read_cached<F: Fn(usize)>(&mut self, callback: F) -> Result<Vec<u8>, ()> {
+        let start = self.ec.now_us();
+        let res = self.read_cached_inner(callback);
+        self.end_phase("read", start);
+        res
+    }
+
+    unsafe fn read_cached_inner<F: Fn(usize)>(&mut self, callback: F) -> Result<Vec<u8>, ()> {
+        let mut block = 0;
+        while block < self.cached.len() {
+            let offset = block * 1024;
+            if self.cached[block] {
+                block += 1;
+                callback(offset + 1024);
+                continue;
+            }
+
+            // Read each run of blocks that are not cached at once
+            let run = self.cached[block..].iter().take_while(|&&cached| ! cached).count();
+            self.read_range_inner(offset, (block + run) * 1024, |x| callback(offset + x))?;
+            block += run;
+        }
+        Ok(self.cache.clone())
+    }
+
+    unsafe fn read_range_inner<F: Fn(usize)>(&mut self, offset: usize, end: usize, callback: F) -> Result<Vec<u8>, ()> {
+        let mut buf = Vec::with_capacity(end - offset);
+        let mut address = offset;
+        while address < end {
+            // Read up to the end of the sector
+            let sector_end = (address / 65536 + 1) * 65536;
+            let chunk_end = sector_end.min(end);
+
+            self.spi_write_disable()?;
+            self.spi_wait(self.timeouts.write_busy)?;
+
+            self.enter_follow_mode()?;
+
+            self.ec.trace(TraceEvent::SpiAddress(address as u32));
+            self.spi_cmd(0x0B)?;
+            self.spi_write((address >> 16) as u8)?;
+            self.spi_write((address >> 8) as u8)?;
+            self.spi_write(address as u8)?;
+            self.spi_write(0)?;
+
+            while address < chunk_end {
+                buf.push(self.spi_read()?);
+                self.report.bytes_read += 1;
+                address += 1;
+                if buf.len() % 1024 == 0 || address == chunk_end {
+                    callback(buf.len());
+                }
+            }
+
+            self.spi_wait(self.timeouts.write_busy)?;
+        }
+
+        // Only whole blocks are cached
+        self.cache[offset..end].copy_from_slice(&buf);
+        for block in offset.div_ceil(1024)..end / 1024 {
+            self.cached[block] = true;
+        }
+
+        Ok(buf)
+    }
+
+    pub unsafe fn erase<F: Fn(usize)>(&mut self, callback: F) -> Result<(), ()> {
+        self.erase_inner(None, callback)
+    }
+
+    /// Erase the flash, skipping blocks that are already blank in current,
+    /// which should be a read of the flash taken just before
+    pub unsafe fn erase_changed<F: Fn(usize)>(&mut self, current: &[u8], callback: F) -> Result<(), ()> {
+        self.erase_inner(Some(current), callback)
+    }
+
+    unsafe fn erase_inner<F: Fn(usize)>(&mut self, current: Option<&[u8]>, callback: F) -> Result<(), ()> {
+        let start = self.ec.now_us();
+        let previous = self.begin(FlasherState::Erasing);
+        let res = self.erase_blocks(current, callback);
+        if res.is_ok() {
+            self.state = previous;
+        }
+        self.end_phase("erase", start);
+        res
+    }
+
+    unsafe fn erase_blocks<F: Fn(usize)>(&mut self, current: Option<&[u8]>, callback: F) -> Result<(), ()> {
+        for sector in 0..self.size/65536 {
+            for block in 0..64 {
+                let index = sector * 65536 + block * 1024;
+
+                let blank = current.and_then(|current| current.get(index..index + 1024))
+                    .is_some_and(|data| data.iter().all(|&x| x == 0xFF));
+                if blank || self.is_protected(index..index + 1024) {
+                    self.report.blocks_skipped += 1;
+                    callback(index + 1024);
+                    continue;
+                }
+                self.check_interrupt(index)?;
+
+                self.cached[index / 1024] = false;
+                self.spi_write_enable()?;
+                self.enter_follow_mode()?;
+                self.ec.trace(TraceEvent::SpiAddress(index as u32));
+                self.spi_cmd(0xD7)?;
+                self.spi_write((index >> 16) as u8)?;
+                self.spi_write((index >> 8) as u8)?;
+                self.spi_write(index as u8)?;
+                self.exit_follow_mode()?;
+                self.spi_wait(self.timeouts.erase_busy)?;
+                self.report.bytes_erased += 1024;
+
+                callback(index + 1024);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Program the flash with buf, using the auto address increment program
+    /// command of the SPI flash
+    ///
+    /// Each word takes about 13 mailbox transactions in follow mode. The
+    /// fcommand interface is not faster: it moves 4 bytes per call through
+    /// parameters 0xF9-0xFD, which takes 33 transactions, and the EC firmware
+    /// has no known fcommand for programming the flash.
+    pub unsafe fn write<F: Fn(usize)>(&mut self, buf: &[u8], callback: F) -> Result<(), ()> {
+        let start = self.ec.now_us();
+        let previous = self.begin(FlasherState::Writing);
+        let res = self.write_blocks(buf, callback);
+        if res.is_ok() {
+            self.state = previous;
+        }
+        self.end_phase("write", start);
+        res
+    }
+
+    unsafe fn write_blocks<F: Fn(usize)>(&mut self, buf: &[u8], callback: F) -> Result<(), ()> {
+        for sector in 0..self.size/65536 {
+            // Auto address increment program is active, so no address is needed
+            let mut aai = false;
+
+            for block in 0..64 {
+                let index = sector * 65536 + block * 1024;
+
+                if self.is_protected(index..index + 1024) {
+                    // Leave auto address increment mode to skip this block
+                    if aai {
+                        self.spi_write_disable()?;
+                        aai = false;
+                    }
+
+                    callback(index + 1024);
+                    continue;
+                }
+
+                if self.check_interrupt(index).is_err() {
+                    if aai {
+                        self.spi_write_disable()?;
+                    }
+                    return Err(());
+                }
+
+                self.cached[index / 1024] = false;
+                if ! aai {
+                    self.spi_write_enable()?;
+                }
+
+                for word in 0..512 {
+                    self.enter_follow_mode()?;
+                    // Traced for every word, although only the first sends it
+                    self.ec.trace(TraceEvent::SpiAddress((index + word * 2) as u32));
+                    self.spi_cmd(0xAD)?;
+                    if ! aai {
+                        self.spi_write((index >> 16) as u8)?;
+                        self.spi_write((index >> 8) as u8)?;
+                        self.spi_write(index as u8)?;
+                        aai = true;
+                    }
+                    self.spi_write(buf.get(index + word * 2).map_or(0xFF, |x| *x))?;
+                    self.spi_write(buf.get(index + word * 2 + 1).map_or(0xFF, |x| *x))?;
+                    self.spi_wait(self.timeouts.write_busy)?;
+                }
+                self.report.bytes_written += 1024;
+
+                callback(index + 1024);
+            }
+
+            if aai {
+                self.spi_write_disable()?;
+            }
+            self.spi_wait(self.timeouts.write_busy)?;
+        }
+
+        Ok(())
+    }
+
+    /// Erase, and write if asked, each 1 KB block of range where actual does
+    /// not match expected again, up to VERIFY_RETRIES times, keeping actual up
+    /// to date
+    pub unsafe fn retry_blocks(&mut self, actual: &mut [u8], expected: &[u8], write: bool) -> Result<(), ()> {
+        let range = self.range.clone();
+        for _ in 0..VERIFY_RETRIES {
+            let bad: Vec<(usize, usize)> = range.clone().step_by(1024)
+                .map(|block| {
+                    let end = (block + 1024).min(range.end);
+                    let count = (block..end)
+                        .filter(|&i| actual[i] != expected[i] && ! self.is_protected(i..i + 1))
+                        .count();
+                    (block, count)
+                })
+                .filter(|&(_, count)| count > 0)
+                .collect();
+            if bad.is_empty() {
+                break;
+            }
+
+            for (block, count) in bad {
+                self.report.retries += 1;
+                self.range = block..block + 1024;
+                let res = (|| {
+                    self.erase(|_| ())?;
+                    if write {
+                        self.write(expected, |_| ())?;
+                    }
+                    self.read_range(block, 1024, |_| ())
+                })();
+                self.range = range.clone();
+
+                actual[block..block + 1024].copy_from_slice(&res?);
+                if actual[block..block + 1024] == expected[block..block + 1024] {
+                    self.report.mismatches_fixed += count;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Erase and program range with data in the parts of program_order, so
+    /// that the boot block is flashed last, verifying each part and erasing
+    /// or writing the blocks that do not verify again
+    ///
+    /// original is a read of the flash taken just before, whose blank blocks
+    /// are not erased. progress is called with the phase, bytes done, and
+    /// bytes total. A byte that is not erased is left for the verify of the
+    /// write to catch, since writing the rest is the better chance to boot.
+    pub unsafe fn program(&mut self, original: &[u8], data: &[u8], progress: &dyn Fn(&str, usize, usize)) -> Result<(), ProgramError> {
+        let range = self.range.clone();
+        let mut res = Ok(());
+        for part in self.program_order() {
+            self.range = part;
+            res = self.program_part(original, data, progress);
+            if res.is_err() {
+                break;
+            }
+        }
+        self.range = range;
+        res
+    }
+
+    unsafe fn program_part(&mut self, original: &[u8], data: &[u8], progress: &dyn Fn(&str, usize, usize)) -> Result<(), ProgramError> {
+        let size = self.size;
+        self.erase_changed(original, |x| progress("erase", x, size))
+            .map_err(|()| ProgramError::Failed("erase data"))?;
+
+        // Blocks that were skipped are known from the read of the original
+        let mut erased = self.read_cached(|x| progress("verify erase", x, size))
+            .map_err(|()| ProgramError::Failed("read erased data"))?;
+        self.retry_blocks(&mut erased, &vec![0xFF; size], false)
+            .map_err(|()| ProgramError::Failed("erase blocks again"))?;
+
+        self.write(data, |x| progress("write", x, size))
+            .map_err(|()| ProgramError::Failed("write data"))?;
+
+        let mut written = self.read_cached(|x| progress("verify", x, size))
+            .map_err(|()| ProgramError::Failed("read written data"))?;
+        self.retry_blocks(&mut written, data, true)
+            .map_err(|()| ProgramError::Failed("write blocks again"))?;
+        match self.mismatch(&written, data) {
+            Some(address) => Err(ProgramError::Verify {
+                address,
+                value: written[address],
+                expected: data[address],
+                count: (address..size).filter(|&i| written[i] != data[i] && ! self.is_protected(i..i + 1)).count(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Leave flash mode, then restore the watchdog
+    ///
+    /// Follow mode is left first if an operation was interrupted in it, and
+    /// auto address increment mode with a write disable.
+    pub unsafe fn stop(&mut self) -> Result<(), ()> {
+        if ! self.ec.supported() {
+            return Err(());
+        }
+        match self.state {
+            FlasherState::Idle | FlasherState::Started => (),
+            FlasherState::FollowMode | FlasherState::Erasing => {
+                let _ = self.ec.cmd(5);
+            },
+            FlasherState::Writing => {
+                let _ = self.ec.cmd(5);
+                self.state = FlasherState::Started;
+                let _ = self.spi_write_disable();
+            },
+        }
+        self.state = FlasherState::Idle;
+        let res = self.ec.cmd(0x95).and_then(|()| self.ec.cmd(0xFC));
+        let restored = self.restore_watchdog();
+        res.and(restored)
+    }
+}
+
+impl<P: PortIo> Drop for Flasher<P> {
+    /// Stop a session that was not stopped, such as after an early return or
+    /// a panic, so that the EC does not wait in flash mode until its battery
+    /// is removed
+    fn drop(&mut self) {
+        if self.state != FlasherState::Idle {
+            // The flasher was started, so the port access was already vouched
+            // for by whoever created its EcFlash
+            let _ = unsafe { self.stop() };
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::sha1::Sha1;
+
+/// The DNS namespace, which fwupd uses when hashing instance IDs into GUIDs
+const NAMESPACE: [u8; 16] = [
+    0x6b, 0xa7, 0xb8, 0x10, 0x9d, 0xad, 0x11, 0xd1,
+    0x80, 0xb4, 0x00, 0xc0, 0x4f, 0xd4, 0x30, 0xc8,
+];
+
+/// System identification from DMI, as found in /sys/class/dmi/id
+#[derive(Clone, Debug, Default)]
+pub struct Dmi {
+    /// sys_vendor, for example "System76"
+    pub vendor: String,
+    /// product_version, which holds the model on System76 machines, for example "galp5"
+    pub model: String,
+}
+
+/// Device metadata in the form fwupd expects
+#[derive(Clone, Debug)]
+pub struct FwupdDevice {
+    /// Instance IDs, from least to most specific
+    pub instance_ids: Vec<String>,
+    /// Current firmware version
+    pub version: String,
+}
+
+impl FwupdDevice {
+    pub fn new(project: &str, version: &str, dmi: &Dmi) -> Self {
+        let project = strsafe(project);
+        let vendor = strsafe(&dmi.vendor);
+        let model = strsafe(&dmi.model);
+
+        let mut instance_ids = vec![format!("EC\\PRJ_{}", project)];
+        if ! vendor.is_empty() {
+            instance_ids.push(format!("EC\\VEN_{}&PRJ_{}", vendor, project));
+            if ! model.is_empty() {
+                instance_ids.push(format!("EC\\VEN_{}&MOD_{}&PRJ_{}", vendor, model, project));
+            }
+        }
+
+        Self {
+            instance_ids,
+            version: String::from(version.trim()),
+        }
+    }
+
+    /// GUIDs for each instance ID, in the same order
+    pub fn guids(&self) -> Vec<String> {
+        self.instance_ids.iter().map(|id| guid(id)).collect()
+    }
+}
+
+/// Replace characters that are not allowed in instance IDs
+fn strsafe(s: &str) -> String {
+    s.trim().chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+        c
+    } else {
+        '_'
+    }).collect()
+}
+
+/// Name-based (version 5) UUID of an instance ID, as generated by fwupd
+fn guid(instance_id: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(&NAMESPACE);
+    sha1.update(instance_id.as_bytes());
+    let hash = sha1.finish();
+
+    let mut b = [0; 16];
+    b.copy_from_slice(&hash[..16]);
+    b[6] = (b[6] & 0x0F) | 0x50;
+    b[8] = (b[8] & 0x3F) | 0x80;
+
+    format!(
+        "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7],
+        b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instance_id_guid() {
+        assert_eq!(guid("EC\\PRJ_N130WU"), "18b8b59d-f024-5efd-bf89-656c2468980c");
+    }
+
+    #[test]
+    fn instance_ids() {
+        let dmi = Dmi {
+            vendor: String::from("System76"),
+            model: String::from("galp5"),
+        };
+        let device = FwupdDevice::new("NS50MU ", "1.07.02", &dmi);
+        assert_eq!(device.instance_ids, [
+            "EC\\PRJ_NS50MU",
+            "EC\\VEN_System76&PRJ_NS50MU",
+            "EC\\VEN_System76&MOD_galp5&PRJ_NS50MU",
+        ]);
+        assert_eq!(device.guids()[2], "05695880-03cb-577a-9dc6-24b593da7f41");
+    }
+}
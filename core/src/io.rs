@@ -0,0 +1,272 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::fmt;
+
+#[inline(always)]
+pub unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx", out("al") value, in("dx") port, options(nostack));
+    value
+}
+
+#[inline(always)]
+pub unsafe fn outb(port: u16, value: u8) {
+    asm!("out dx, al", in("al") value, in("dx") port, options(nostack));
+}
+
+/// How the host reaches the EC
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HostInterface {
+    /// Legacy I/O ports, which the chipset forwards over LPC or eSPI
+    Ports,
+    /// Legacy I/O ports through /dev/port
+    DevPort,
+    /// Memory-mapped window of the host interface at a physical address
+    Mmio(u64),
+    /// Transactions of the kernel ACPI EC driver
+    Kernel,
+    /// Test double, not real hardware
+    Mock,
+}
+
+impl fmt::Display for HostInterface {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HostInterface::Ports => write!(f, "I/O ports"),
+            HostInterface::DevPort => write!(f, "I/O ports through /dev/port"),
+            HostInterface::Mmio(base) => write!(f, "memory-mapped window at 0x{:X}", base),
+            HostInterface::Kernel => write!(f, "kernel ACPI EC driver"),
+            HostInterface::Mock => write!(f, "mock"),
+        }
+    }
+}
+
+/// Access to x86 I/O ports
+pub trait PortIo {
+    /// How the ports are reached
+    fn interface(&self) -> HostInterface;
+
+    /// Read a byte from a port
+    ///
+    /// # Safety
+    ///
+    /// Reading some ports has side effects on the hardware behind them.
+    unsafe fn inb(&mut self, port: u16) -> u8;
+    /// Write a byte to a port
+    ///
+    /// # Safety
+    ///
+    /// The write goes straight to hardware, which may misbehave or corrupt
+    /// memory if the port is not what the caller expects.
+    unsafe fn outb(&mut self, port: u16, value: u8);
+}
+
+impl<T: PortIo + ?Sized> PortIo for alloc::boxed::Box<T> {
+    fn interface(&self) -> HostInterface {
+        (**self).interface()
+    }
+
+    unsafe fn inb(&mut self, port: u16) -> u8 {
+        (**self).inb(port)
+    }
+
+    unsafe fn outb(&mut self, port: u16, value: u8) {
+        (**self).outb(port, value)
+    }
+}
+
+impl<T: PortIo + ?Sized> PortIo for &mut T {
+    fn interface(&self) -> HostInterface {
+        (**self).interface()
+    }
+
+    unsafe fn inb(&mut self, port: u16) -> u8 {
+        (**self).inb(port)
+    }
+
+    unsafe fn outb(&mut self, port: u16, value: u8) {
+        (**self).outb(port, value)
+    }
+}
+
+/// Port I/O with the in and out instructions, for kernels, UEFI, and
+/// userspace with iopl
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RawPortIo;
+
+impl PortIo for RawPortIo {
+    fn interface(&self) -> HostInterface {
+        HostInterface::Ports
+    }
+
+    #[inline(always)]
+    unsafe fn inb(&mut self, port: u16) -> u8 {
+        inb(port)
+    }
+
+    #[inline(always)]
+    unsafe fn outb(&mut self, port: u16, value: u8) {
+        outb(port, value)
+    }
+}
+
+/// Port I/O through /dev/port, which needs CAP_SYS_RAWIO but not iopl
+///
+/// Each access is a system call, so this is much slower than RawPortIo.
+#[cfg(all(feature = "std", unix))]
+#[derive(Debug)]
+pub struct DevPort {
+    file: std::fs::File,
+}
+
+#[cfg(all(feature = "std", unix))]
+impl DevPort {
+    pub fn open() -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/port")?;
+        Ok(Self { file })
+    }
+}
+
+#[cfg(all(feature = "std", unix))]
+impl PortIo for DevPort {
+    fn interface(&self) -> HostInterface {
+        HostInterface::DevPort
+    }
+
+    unsafe fn inb(&mut self, port: u16) -> u8 {
+        use std::os::unix::fs::FileExt;
+
+        let mut value = [0xFF];
+        let _ = self.file.read_exact_at(&mut value, port as u64);
+        value[0]
+    }
+
+    unsafe fn outb(&mut self, port: u16, value: u8) {
+        use std::os::unix::fs::FileExt;
+
+        let _ = self.file.write_all_at(&[value], port as u64);
+    }
+}
+
+/// Port I/O through a memory-mapped window of the host interface, where each
+/// port is a byte at the same offset from the base
+///
+/// Some eSPI platforms route the EC host interface to memory instead of
+/// decoding the legacy ports.
+#[derive(Debug)]
+pub struct MmioPortIo {
+    base: *mut u8,
+}
+
+// The window is only accessed through &mut self
+unsafe impl Send for MmioPortIo {}
+
+impl MmioPortIo {
+    /// Use a window mapped at base
+    ///
+    /// # Safety
+    ///
+    /// base must point to an uncached mapping of the host interface that is
+    /// valid for 64 KiB and not used by anything else.
+    pub unsafe fn new(base: *mut u8) -> Self {
+        Self { base }
+    }
+}
+
+impl PortIo for MmioPortIo {
+    fn interface(&self) -> HostInterface {
+        HostInterface::Mmio(self.base as u64)
+    }
+
+    unsafe fn inb(&mut self, port: u16) -> u8 {
+        core::ptr::read_volatile(self.base.add(port as usize))
+    }
+
+    unsafe fn outb(&mut self, port: u16, value: u8) {
+        core::ptr::write_volatile(self.base.add(port as usize), value)
+    }
+}
+
+/// Port I/O through a memory-mapped window of the host interface at a
+/// physical address, accessed with /dev/mem
+#[cfg(all(feature = "std", unix))]
+#[derive(Debug)]
+pub struct DevMemPortIo {
+    file: std::fs::File,
+    base: u64,
+}
+
+#[cfg(all(feature = "std", unix))]
+impl DevMemPortIo {
+    pub fn open(base: u64) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/mem")?;
+        Ok(Self { file, base })
+    }
+}
+
+#[cfg(all(feature = "std", unix))]
+impl PortIo for DevMemPortIo {
+    fn interface(&self) -> HostInterface {
+        HostInterface::Mmio(self.base)
+    }
+
+    unsafe fn inb(&mut self, port: u16) -> u8 {
+        use std::os::unix::fs::FileExt;
+
+        let mut value = [0xFF];
+        let _ = self.file.read_exact_at(&mut value, self.base + port as u64);
+        value[0]
+    }
+
+    unsafe fn outb(&mut self, port: u16, value: u8) {
+        use std::os::unix::fs::FileExt;
+
+        let _ = self.file.write_all_at(&[value], self.base + port as u64);
+    }
+}
+
+/// Port I/O double for tests, which answers reads from queued values and
+/// records writes
+///
+/// Ports without queued values read as 0xFF, like a floating bus.
+#[derive(Clone, Debug, Default)]
+pub struct MockPortIo {
+    /// Values to return from reads of each port, in order
+    pub reads: BTreeMap<u16, VecDeque<u8>>,
+    /// Every write, as port and value
+    pub writes: Vec<(u16, u8)>,
+}
+
+impl MockPortIo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue values to be returned by reads of a port
+    pub fn queue(&mut self, port: u16, values: &[u8]) {
+        self.reads.entry(port).or_default().extend(values.iter().copied());
+    }
+}
+
+impl PortIo for MockPortIo {
+    fn interface(&self) -> HostInterface {
+        HostInterface::Mock
+    }
+
+    unsafe fn inb(&mut self, port: u16) -> u8 {
+        self.reads.get_mut(&port)
+            .and_then(|values| values.pop_front())
+            .unwrap_or(0xFF)
+    }
+
+    unsafe fn outb(&mut self, port: u16, value: u8) {
+        self.writes.push((port, value));
+    }
+}
@@ -0,0 +1,667 @@
+//! In-system programming of the EC from the machine it runs, through the
+//! scratch ROM
+//!
+//! The EC copies a small program to its RAM and runs it when told to over
+//! PMC1, after which its flash can be erased and programmed through follow
+//! mode on PMC3 without the EC firmware running. Leaving the scratch ROM
+//! powers off the system, so anything that must reach disk has to be written
+//! before then, and a failed run cannot be retried without booting again.
+//!
+//! The same loop programs the flash from another machine, through a debugger
+//! such as the Arduino programmer on the debug header, with [`isp_external`].
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::fmt;
+use core::time::Duration;
+
+use super::{
+    Address, Ec, EcFlash, Error, FlashChip, FlashReport, PortIo, Result, Smfi, SmfiAccel, SpiBus, SpiChip, SpiRom,
+    Timer, TIMEOUT_US,
+};
+
+/// Command on PMC1 that runs the scratch ROM, and on PMC3 that leaves it
+const SCRATCH_COMMAND: u8 = 0xEC;
+/// Answer of the EC on PMC1 once it runs the scratch ROM
+const SCRATCH_ANSWER: u8 = 0x76;
+const PMC1: u16 = 0x62;
+const PMC3: u16 = 0x6A;
+
+/// 1 KiB sectors of the flash that are read again to check a backup, spread
+/// evenly over it
+pub const BACKUP_CHECKS: usize = 8;
+
+/// Times a sector that fails to verify after programming is programmed again
+const PROGRAM_RETRIES: usize = 3;
+
+/// Times the transport is reconnected while programming, after it fails
+const RECONNECT_ATTEMPTS: usize = 5;
+
+/// Why programming through the scratch ROM failed, by the point it got to
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum IspError {
+    /// The firmware is larger than the largest supported ROM
+    FirmwareSize { size: usize, rom_size: usize },
+    /// No supported EC was found
+    NoEc(String),
+    /// Keys were still pressed when the timeout ran out, which the EC would
+    /// miss while it runs the scratch ROM
+    KeysPressed,
+    /// The EC answered the scratch ROM command with this instead of 0x76
+    ScratchRom(u8),
+    /// The PMC or the flash failed
+    Spi(Error),
+    /// The backup callback failed, before anything was erased
+    Backup(String),
+    /// A sector read differently the second time, so the backup cannot be
+    /// trusted
+    UnstableRead { address: usize },
+    /// A byte was not 0xFF after erasing
+    Erase { address: usize, value: u8 },
+    /// A byte did not verify after PROGRAM_RETRIES retries
+    Program { address: usize, value: u8, expected: u8 },
+    /// The interrupted callback asked to stop before a sector, with the
+    /// sectors before it erased or programmed
+    Interrupted { phase: &'static str, address: usize },
+}
+
+impl fmt::Display for IspError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IspError::FirmwareSize { size, rom_size } => write!(f, "firmware size {} exceeds rom size {}", size, rom_size),
+            IspError::NoEc(message) => write!(f, "failed to find EC: {}", message),
+            IspError::KeysPressed => write!(f, "keys were still pressed, release them and try again"),
+            IspError::ScratchRom(answer) => write!(f, "failed to enter scratch ROM, EC answered {:02X}", answer),
+            IspError::Spi(err) => write!(f, "{}", err),
+            IspError::Backup(message) => write!(f, "failed to save backup: {}", message),
+            IspError::UnstableRead { address } => write!(f, "backup is not trustworthy, {:06X} reads differently the second time", address),
+            IspError::Erase { address, value } => write!(f, "failed to erase: {:X} is {:X} instead of FF", address, value),
+            IspError::Program { address, value, expected } => write!(
+                f, "failed to program after {} retries: {:X} is {:X} instead of {:X}",
+                PROGRAM_RETRIES, address, value, expected
+            ),
+            IspError::Interrupted { phase, address } => write!(f, "interrupted before {} of {:06X}", phase, address),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for IspError {}
+
+impl From<Error> for IspError {
+    fn from(err: Error) -> Self {
+        IspError::Spi(err)
+    }
+}
+
+type Backup<'a> = &'a mut dyn FnMut(&[u8]) -> core::result::Result<(), String>;
+type Progress<'a> = &'a mut dyn FnMut(&str, usize, usize);
+
+/// Settings and callbacks of [`isp_internal`]
+pub struct IspOptions<'a> {
+    /// Flash that is programmed
+    pub flash: FlashChip,
+    /// Super I/O IDs accepted besides KNOWN_IDS
+    pub known_ids: &'a [u16],
+    /// How long no key may be pressed before entering the scratch ROM
+    pub key_quiet: Duration,
+    /// How long to wait for keys to be released
+    pub key_timeout: Duration,
+    /// Called with the flash contents before anything is erased, to save a
+    /// backup, which is skipped if the flash already holds the firmware
+    pub backup: Option<Backup<'a>>,
+    /// Called with the phase, bytes done, and bytes total as sectors are
+    /// erased and programmed, and with "reconnect" when the transport failed
+    /// in a sector and was reconnected
+    pub progress: Option<Progress<'a>>,
+    /// Called before leaving the scratch ROM, which powers off the system,
+    /// such as to sync filesystems
+    pub power_off: Option<&'a mut dyn FnMut()>,
+    /// Called before each sector is erased or programmed, to stop there if it
+    /// returns true
+    pub interrupted: Option<&'a dyn Fn() -> bool>,
+}
+
+impl<'a> IspOptions<'a> {
+    pub fn new(flash: FlashChip) -> Self {
+        Self {
+            flash,
+            known_ids: &[],
+            key_quiet: Duration::from_secs(2),
+            key_timeout: Duration::from_secs(30),
+            backup: None,
+            progress: None,
+            power_off: None,
+            interrupted: None,
+        }
+    }
+
+    fn progress(&mut self, phase: &str, done: usize, total: usize) {
+        if let Some(progress) = self.progress.as_mut() {
+            progress(phase, done, total);
+        }
+    }
+
+    fn check_interrupted(&self, phase: &'static str, address: usize) -> core::result::Result<(), IspError> {
+        match self.interrupted {
+            Some(interrupted) if interrupted() => Err(IspError::Interrupted { phase, address }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What [`isp_internal`] did
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct IspReport {
+    /// Super I/O chip ID of the EC, or 0 if it was not read here
+    pub chip_id: u16,
+    /// Flash that was detected
+    pub spi: SpiChip,
+    /// Whether the flash already held the firmware, so nothing was erased
+    pub unchanged: bool,
+    pub report: FlashReport,
+}
+
+/// The EC running the scratch ROM, whose SMFI registers are reached through
+/// the ACPI commands of PMC3
+pub struct ScratchRom<P: PortIo> {
+    io: P,
+    timer: Box<dyn Timer + Send>,
+}
+
+impl<P: PortIo> ScratchRom<P> {
+    /// Tell the EC to run the scratch ROM
+    ///
+    /// # Safety
+    ///
+    /// The EC firmware stops, so the keyboard, fans, and battery are not
+    /// serviced until leave.
+    pub unsafe fn enter(io: P) -> core::result::Result<Self, IspError> {
+        let mut rom = Self {
+            io,
+            #[cfg(feature = "std")]
+            timer: Box::new(super::StdTimer::new()),
+            #[cfg(not(feature = "std"))]
+            timer: Box::new(super::CounterTimer::new()),
+        };
+        rom.command(PMC1, SCRATCH_COMMAND)?;
+        let answer = rom.read(PMC1)?;
+        if answer != SCRATCH_ANSWER {
+            return Err(IspError::ScratchRom(answer));
+        }
+        Ok(rom)
+    }
+
+    /// Leave the scratch ROM, which currently powers off the system
+    ///
+    /// # Safety
+    ///
+    /// The system may lose power before this returns.
+    pub unsafe fn leave(mut self) -> Result<()> {
+        self.command(PMC3, SCRATCH_COMMAND)
+    }
+
+    unsafe fn wait(&mut self, base: u16, mask: u8, value: u8) -> Result<()> {
+        let start = self.timer.now_us();
+        loop {
+            if self.io.inb(base + 4) & mask == value {
+                return Ok(());
+            }
+            if self.timer.now_us().wrapping_sub(start) >= TIMEOUT_US {
+                return Err(Error::Transport(format!("timed out waiting for PMC at {:02X}", base)));
+            }
+        }
+    }
+
+    unsafe fn command(&mut self, base: u16, data: u8) -> Result<()> {
+        self.wait(base, 2, 0)?;
+        self.io.outb(base + 4, data);
+        Ok(())
+    }
+
+    unsafe fn read(&mut self, base: u16) -> Result<u8> {
+        self.wait(base, 1, 1)?;
+        Ok(self.io.inb(base))
+    }
+
+    unsafe fn write(&mut self, base: u16, data: u8) -> Result<()> {
+        self.wait(base, 2, 0)?;
+        self.io.outb(base, data);
+        Ok(())
+    }
+
+    unsafe fn acpi_read(&mut self, address: u8) -> Result<u8> {
+        self.command(PMC3, 0x80)?;
+        self.write(PMC3, address)?;
+        self.read(PMC3)
+    }
+
+    unsafe fn acpi_write(&mut self, address: u8, data: u8) -> Result<()> {
+        self.command(PMC3, 0x81)?;
+        self.write(PMC3, address)?;
+        self.write(PMC3, data)
+    }
+}
+
+impl<P: PortIo> Smfi for ScratchRom<P> {
+    /// Set indar1 register (special case for follow mode)
+    fn flash_indar1(&mut self, data: u8) -> Result<()> {
+        unsafe { self.acpi_write(Address::INDAR1 as u8, data) }
+    }
+
+    /// Set EC-indirect flash address
+    fn flash_address(&mut self, address: u32) -> Result<()> {
+        unsafe {
+            self.acpi_write(Address::INDAR3 as u8, (address >> 24) as u8)?;
+            self.acpi_write(Address::INDAR2 as u8, (address >> 16) as u8)?;
+            self.acpi_write(Address::INDAR1 as u8, (address >> 8) as u8)?;
+            self.acpi_write(Address::INDAR0 as u8, (address) as u8)?;
+        }
+        Ok(())
+    }
+
+    /// Read data from flash using EC-indirect mode
+    fn flash_read(&mut self, data: &mut [u8]) -> Result<usize> {
+        for b in data.iter_mut() {
+            *b = unsafe { self.acpi_read(Address::INDDR as u8)? };
+        }
+        Ok(data.len())
+    }
+
+    /// Write data to flash using EC-indirect mode
+    fn flash_write(&mut self, data: &[u8]) -> Result<usize> {
+        for &b in data.iter() {
+            unsafe { self.acpi_write(Address::INDDR as u8, b)? };
+        }
+        Ok(data.len())
+    }
+}
+
+impl<P: PortIo> SmfiAccel for ScratchRom<P> {}
+
+/// Find the primary EC through io, wait for the keys to be released, and run
+/// f with the EC in the scratch ROM, leaving it after f returns
+///
+/// # Safety
+///
+/// See [`ScratchRom::enter`] and [`ScratchRom::leave`]. The system powers off
+/// whether f succeeds or not.
+pub unsafe fn with_scratch_rom<P, F, T>(mut io: P, opts: &mut IspOptions, f: F) -> core::result::Result<(u16, T), IspError>
+where
+    P: PortIo,
+    F: FnOnce(&mut ScratchRom<&mut P>, &mut IspOptions) -> core::result::Result<T, IspError>,
+{
+    let chip_id = {
+        let mut ec = EcFlash::with_io_known(&mut io, true, opts.known_ids).map_err(IspError::NoEc)?;
+        if ec.wait_keys_released(opts.key_quiet, opts.key_timeout).is_err() {
+            return Err(IspError::KeysPressed);
+        }
+        ec.chip_id().unwrap_or(0)
+    };
+
+    let mut rom = ScratchRom::enter(&mut io)?;
+    let res = f(&mut rom, opts);
+    if let Some(power_off) = opts.power_off.as_mut() {
+        power_off();
+    }
+    let left = rom.leave();
+    let value = res?;
+    left?;
+    Ok((chip_id, value))
+}
+
+/// Program firmware to the flash of the primary EC through io, from the
+/// machine the EC runs, and verify it
+///
+/// Each sector is programmed and read back before the next, and programmed
+/// again up to PROGRAM_RETRIES times if it does not match. The system powers
+/// off at the end, even on failure.
+///
+/// # Safety
+///
+/// See [`with_scratch_rom`]. The EC does not boot again until the flash holds
+/// a working firmware.
+pub unsafe fn isp_internal<P: PortIo>(io: P, firmware: &[u8], mut opts: IspOptions) -> core::result::Result<IspReport, IspError> {
+    let rom_size = rom_size(firmware)?;
+    let (chip_id, (spi, unchanged, report)) = with_scratch_rom(io, &mut opts, |rom, opts| {
+        program(rom, firmware, rom_size, opts)
+    })?;
+    Ok(IspReport { chip_id, spi, unchanged, report })
+}
+
+/// Program firmware to the flash behind port, such as the Arduino programmer
+/// on the debug header of a machine that does not boot, and verify it
+///
+/// This is the loop of [`isp_internal`], and the caller checks the chip ID
+/// first. If the transport fails, port is reconnected up to
+/// RECONNECT_ATTEMPTS times and the sector it failed in is programmed again.
+pub fn isp_external<T: SmfiAccel>(port: &mut T, firmware: &[u8], mut opts: IspOptions) -> core::result::Result<IspReport, IspError> {
+    let rom_size = rom_size(firmware)?;
+    let (spi, unchanged, report) = program(port, firmware, rom_size, &mut opts)?;
+    Ok(IspReport { chip_id: 0, spi, unchanged, report })
+}
+
+/// Read BACKUP_CHECKS sectors of rom again with read, returning the address
+/// of the first that reads differently, so that a flaky read is found before
+/// anything is erased instead of when the backup is restored
+pub fn verify_backup<E, F>(rom: &[u8], mut read: F) -> core::result::Result<Option<usize>, E>
+where
+    F: FnMut(usize, &mut [u8]) -> core::result::Result<(), E>,
+{
+    let sectors = rom.len() / 1024;
+    let checks = BACKUP_CHECKS.min(sectors);
+    let mut again = vec![0; 1024];
+    for check in 0..checks {
+        let address = check * sectors / checks * 1024;
+        read(address, &mut again)?;
+        if again[..] != rom[address..address + 1024] {
+            return Ok(Some(address));
+        }
+    }
+    Ok(None)
+}
+
+/// Size of the ROM that firmware is programmed to, of the two supported ROM
+/// sizes, 128KiB and 256KiB
+fn rom_size(firmware: &[u8]) -> core::result::Result<usize, IspError> {
+    let rom_size = if firmware.len() > 128 * 1024 {
+        256 * 1024
+    } else {
+        128 * 1024
+    };
+    if firmware.len() > rom_size {
+        return Err(IspError::FirmwareSize { size: firmware.len(), rom_size });
+    }
+    Ok(rom_size)
+}
+
+fn elapsed(timer: &mut dyn Timer, start: u64) -> Duration {
+    Duration::from_micros(timer.now_us().wrapping_sub(start))
+}
+
+/// Back up, erase, program, and verify the ROM, returning the flash, whether
+/// it already held the firmware, and what was done
+fn program<T: SmfiAccel>(port: &mut T, firmware: &[u8], rom_size: usize, opts: &mut IspOptions) -> core::result::Result<(SpiChip, bool, FlashReport), IspError> {
+    #[cfg(feature = "std")]
+    let mut timer = super::StdTimer::new();
+    #[cfg(not(feature = "std"))]
+    let mut timer = super::CounterTimer::new();
+
+    let mut spi_bus = SpiBus::new(port, opts.flash)?;
+    let mut spi = SpiRom::new(&mut spi_bus);
+    let chip = spi.detect()?;
+
+    let mut report = FlashReport::new();
+    let mut rom = vec![0; rom_size];
+    let start = timer.now_us();
+    spi.read_at(0, &mut rom)?;
+    report.bytes_read += rom.len();
+    report.add_phase("read", elapsed(&mut timer, start));
+
+    if (0..rom_size).all(|i| rom[i] == *firmware.get(i).unwrap_or(&0xFF)) {
+        return Ok((chip, true, report));
+    }
+
+    // The backup must be saved and trusted before anything is erased
+    if let Some(backup) = opts.backup.as_mut() {
+        backup(&rom).map_err(IspError::Backup)?;
+    }
+    let unstable = verify_backup(&rom, |address, again| {
+        report.bytes_read += again.len();
+        spi.read_at(address as u32, again).map(|_| ())
+    })?;
+    if let Some(address) = unstable {
+        return Err(IspError::UnstableRead { address });
+    }
+
+    let start = timer.now_us();
+    let sector_size = chip.sector_size;
+    let mut address = 0;
+    while address < rom_size {
+        opts.progress("erase", address, rom_size);
+        if rom[address..(address + sector_size).min(rom_size)].iter().all(|&b| b == 0xFF) {
+            report.blocks_skipped += 1;
+            address += sector_size;
+        } else {
+            opts.check_interrupted("erase", address)?;
+            let size = spi.erase_sector(address as u32)?;
+            report.bytes_erased += size;
+            address += size;
+        }
+    }
+    opts.progress("erase", rom_size, rom_size);
+    report.add_phase("erase", elapsed(&mut timer, start));
+
+    let start = timer.now_us();
+    let mismatch = T::verify_bulk(&mut spi, 0, &vec![0xFF; rom_size])?;
+    report.bytes_read += rom_size;
+    report.add_phase("read", elapsed(&mut timer, start));
+    if let Some((address, value)) = mismatch {
+        return Err(IspError::Erase { address, value });
+    }
+
+    // Words are programmed, so pad an odd length
+    let mut expected = firmware.to_vec();
+    expected.resize(firmware.len().div_ceil(2) * 2, 0xFF);
+
+    let mut address = 0;
+    let mut reconnects = 0;
+    while address < expected.len() {
+        opts.progress("write", address, expected.len());
+        let end = (address + sector_size).min(expected.len());
+        let chunk = &expected[address..end];
+        let mut retries = 0;
+        loop {
+            opts.check_interrupted("write", address)?;
+
+            let mismatch = match program_sector(&mut spi, &mut timer, &mut report, address, chunk) {
+                Err(Error::Transport(err)) if reconnects < RECONNECT_ATTEMPTS => {
+                    // Every sector before this one was verified, so only this
+                    // one is erased and programmed again
+                    opts.progress("reconnect", address, expected.len());
+                    if ! spi.bus.reconnect()? {
+                        return Err(IspError::Spi(Error::Transport(err)));
+                    }
+                    reconnects += 1;
+                    spi.write_disable()?;
+                    report.bytes_erased += spi.erase_sector(address as u32)?;
+                    continue;
+                },
+                res => res?,
+            };
+
+            let (i, value) = match mismatch {
+                Some(mismatch) => mismatch,
+                None => break,
+            };
+            if retries == PROGRAM_RETRIES {
+                return Err(IspError::Program { address: i, value, expected: expected[i] });
+            }
+
+            // Programming can only clear bits, so the sector is erased
+            // before it is programmed again
+            retries += 1;
+            report.retries += 1;
+            report.bytes_erased += spi.erase_sector(address as u32)?;
+        }
+        address = end;
+    }
+    opts.progress("write", expected.len(), expected.len());
+
+    Ok((chip, false, report))
+}
+
+/// Program a sector of data at address, then read it back, returning the first
+/// address that differs and the byte read there
+fn program_sector<T: SmfiAccel>(spi: &mut SpiRom<'_, '_, T>, timer: &mut dyn Timer, report: &mut FlashReport, address: usize, data: &[u8]) -> Result<Option<(usize, u8)>> {
+    let start = timer.now_us();
+    report.bytes_written += T::program_aai_bulk(spi, address as u32, data)?;
+    report.add_phase("write", elapsed(timer, start));
+
+    let start = timer.now_us();
+    let mismatch = T::verify_bulk(spi, address as u32, data)?;
+    report.bytes_read += data.len();
+    report.add_phase("read", elapsed(timer, start));
+    Ok(mismatch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+    use alloc::vec::Vec;
+    use crate::{Smfi, SpiFlashModel};
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    #[test]
+    fn isp_programs_firmware() {
+        let mut flash = SpiFlashModel::new(vec![0; 128 * 1024]);
+        let firmware = pattern(100 * 1024);
+
+        let mut backup = Vec::new();
+        let mut save = |rom: &[u8]| {
+            backup = rom.to_vec();
+            Ok(())
+        };
+        let mut opts = IspOptions::new(FlashChip::Internal);
+        opts.backup = Some(&mut save);
+        let isp = isp_external(&mut flash, &firmware, opts).unwrap();
+        assert_eq!(backup, vec![0; 128 * 1024]);
+
+        assert_eq!(&flash.data[..firmware.len()], &firmware[..]);
+        assert!(flash.data[firmware.len()..].iter().all(|&x| x == 0xFF));
+        assert!(! isp.unchanged);
+        assert_eq!(isp.report.bytes_written, firmware.len());
+        assert_eq!(flash.rejected, 0);
+
+        let isp = isp_external(&mut flash, &firmware, IspOptions::new(FlashChip::Internal)).unwrap();
+        assert!(isp.unchanged);
+    }
+
+    /// Flash that programs one byte wrong, or drops its transport part way
+    /// through a sector, once
+    struct FlakyFlash {
+        flash: SpiFlashModel,
+        corrupt: Option<usize>,
+        disconnect: Option<usize>,
+        reconnects: usize,
+    }
+
+    impl Smfi for FlakyFlash {
+        fn flash_indar1(&mut self, data: u8) -> Result<()> {
+            self.flash.flash_indar1(data)
+        }
+
+        fn flash_address(&mut self, address: u32) -> Result<()> {
+            self.flash.flash_address(address)
+        }
+
+        fn flash_read(&mut self, data: &mut [u8]) -> Result<usize> {
+            self.flash.flash_read(data)
+        }
+
+        fn flash_write(&mut self, data: &[u8]) -> Result<usize> {
+            self.flash.flash_write(data)
+        }
+    }
+
+    impl SmfiAccel for FlakyFlash {
+        fn reconnect(&mut self) -> Result<bool> {
+            self.reconnects += 1;
+            Ok(true)
+        }
+
+        fn program_aai_bulk(rom: &mut SpiRom<'_, '_, Self>, address: u32, data: &[u8]) -> Result<usize> {
+            let mut data = data.to_vec();
+            let range = address as usize..address as usize + data.len();
+            if let Some(corrupt) = rom.bus.port.corrupt.filter(|corrupt| range.contains(corrupt)) {
+                data[corrupt - range.start] ^= 0x10;
+                rom.bus.port.corrupt = None;
+            }
+            if let Some(disconnect) = rom.bus.port.disconnect.filter(|disconnect| range.contains(disconnect)) {
+                rom.bus.port.disconnect = None;
+                rom.write_at(address, &data[..disconnect - range.start])?;
+                return Err(Error::Transport("device disconnected".to_string()));
+            }
+            rom.write_at(address, &data)
+        }
+    }
+
+    fn flaky(corrupt: Option<usize>, disconnect: Option<usize>) -> FlakyFlash {
+        FlakyFlash {
+            flash: SpiFlashModel::new(vec![0; 128 * 1024]),
+            corrupt,
+            disconnect,
+            reconnects: 0,
+        }
+    }
+
+    #[test]
+    fn isp_retries_corrupted_sector() {
+        let mut flash = flaky(Some(0x4321), None);
+        let firmware = pattern(100 * 1024);
+
+        let report = isp_external(&mut flash, &firmware, IspOptions::new(FlashChip::Internal)).unwrap().report;
+        assert_eq!(&flash.flash.data[..firmware.len()], &firmware[..]);
+        assert_eq!(report.retries, 1);
+        assert_eq!(report.bytes_written, firmware.len() + 1024);
+        assert_eq!(flash.flash.rejected, 0);
+    }
+
+    #[test]
+    fn isp_reconnects_after_disconnect() {
+        let mut flash = flaky(None, Some(0x8200));
+        let firmware = pattern(100 * 1024);
+
+        let report = isp_external(&mut flash, &firmware, IspOptions::new(FlashChip::Internal)).unwrap().report;
+        assert_eq!(&flash.flash.data[..firmware.len()], &firmware[..]);
+        assert_eq!(flash.reconnects, 1);
+        assert_eq!(report.retries, 0);
+        assert_eq!(flash.flash.rejected, 0);
+    }
+
+    #[test]
+    fn isp_stops_when_interrupted() {
+        let mut flash = SpiFlashModel::new(vec![0; 128 * 1024]);
+        let firmware = pattern(100 * 1024);
+
+        let interrupted = || true;
+        let mut opts = IspOptions::new(FlashChip::Internal);
+        opts.interrupted = Some(&interrupted);
+        let err = isp_external(&mut flash, &firmware, opts).unwrap_err();
+        assert_eq!(err, IspError::Interrupted { phase: "erase", address: 0 });
+        assert!(flash.data.iter().all(|&x| x == 0));
+    }
+
+    #[test]
+    fn isp_erases_detected_sector_size() {
+        let mut flash = SpiFlashModel::new(vec![0; 128 * 1024]);
+        flash.jedec_id = Some([0xEF, 0x40, 0x14]);
+        let firmware = pattern(100 * 1024);
+
+        let isp = isp_external(&mut flash, &firmware, IspOptions::new(FlashChip::Internal)).unwrap();
+        assert_eq!(isp.spi.sector_size, 4096);
+        assert_eq!(&flash.data[..firmware.len()], &firmware[..]);
+        assert_eq!(isp.report.bytes_erased, 128 * 1024);
+        assert_eq!(flash.rejected, 0);
+    }
+
+    #[test]
+    fn backup_finds_unstable_sector() {
+        let rom = pattern(128 * 1024);
+        let mut reads = Vec::new();
+        let res: core::result::Result<_, ()> = verify_backup(&rom, |address, data| {
+            reads.push(address);
+            data.copy_from_slice(&rom[address..address + data.len()]);
+            if address == 0x8000 {
+                data[3] ^= 1;
+            }
+            Ok(())
+        });
+        assert_eq!(res, Ok(Some(0x8000)));
+        assert_eq!(reads, vec![0, 0x4000, 0x8000]);
+    }
+}
@@ -0,0 +1,49 @@
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use super::BOOT_BLOCK;
+
+/// A named region of the EC flash
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Region {
+    pub name: &'static str,
+    pub range: Range<usize>,
+}
+
+/// Regions of the EC flash, for --region and map
+///
+/// This is a fixed split of the flash, not read from an image: the boot block
+/// that the flasher leaves alone, and main after it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Layout {
+    pub regions: Vec<Region>,
+}
+
+impl Layout {
+    /// Layout of a flash of the given size, which is rounded up to a 64 KiB sector
+    pub fn new(size: usize) -> Self {
+        let size = size.div_ceil(0x10000).max(1) * 0x10000;
+        Self {
+            regions: vec![
+                Region {
+                    name: "boot",
+                    range: BOOT_BLOCK,
+                },
+                Region {
+                    name: "main",
+                    range: BOOT_BLOCK.end..size,
+                },
+            ],
+        }
+    }
+
+    /// Find a region by name
+    pub fn region(&self, name: &str) -> Option<&Region> {
+        self.regions.iter().find(|region| region.name == name)
+    }
+
+    /// Find the region containing an address
+    pub fn region_at(&self, address: usize) -> Option<&Region> {
+        self.regions.iter().find(|region| region.range.contains(&address))
+    }
+}
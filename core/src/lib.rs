@@ -0,0 +1,139 @@
+#![no_std]
+
+#[macro_use]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+use alloc::string::String;
+
+#[cfg(all(feature = "std", unix))]
+pub use self::acpi_ec::{ACPI_EC_IO, AcpiEc};
+pub use self::algorithm::{AlgorithmProbe, FlashAlgorithm};
+#[cfg(feature = "serial")]
+pub use self::arduino::{ParallelArduino, Stk500, Stream, check_id, flash_sketch, parse_ihex, usb_programmers};
+#[cfg(feature = "tokio")]
+pub use self::async_debugger::{AsyncDebugger, AsyncParallelArduino, AsyncSmfi};
+pub use self::bundle::{BUNDLE_FIRMWARE, BUNDLE_MANIFEST, BUNDLE_SIGNATURE, Bundle, Manifest, compare_versions};
+pub use self::config::{CONFIG_PATH, Config};
+pub use self::debugger::{Address, DBGCTRL_HALT, DBGCTRL_STEP, DBGSTS_HALTED, Debugger, Smfi};
+pub use self::ec_smfi::{EcSmfi, SMFI_CMD_BASE, SMFI_CMD_SIZE};
+pub use self::error::{Error, Result};
+pub use self::file::EcFile;
+pub use self::flash::{EcFlash, FLASH_OPTION_BASE, FLASH_OPTION_SIZE, KNOWN_IDS, TIMEOUT_US};
+pub use self::flasher::{BLOCK_PROTECT_LOCK, BLOCK_PROTECT_MASK, BOOT_BLOCK, BOOT_BLOCK_PROTECT, Flasher, FlasherState, Handshake, ProgramError, Timeouts, VERIFY_RETRIES, Watchdog};
+pub use self::fwupd::{Dmi, FwupdDevice};
+#[cfg(all(feature = "std", unix))]
+pub use self::io::{DevMemPortIo, DevPort};
+pub use self::io::{HostInterface, MmioPortIo, MockPortIo, PortIo, RawPortIo};
+pub use self::isp::{BACKUP_CHECKS, IspError, IspOptions, IspReport, ScratchRom, isp_external, isp_internal, verify_backup, with_scratch_rom};
+pub use self::layout::{Layout, Region};
+#[cfg(any(test, feature = "model"))]
+pub use self::model::{MailboxModel, SpiFlashModel};
+pub use self::param::EcParam;
+pub use self::profile::Profile;
+pub use self::protect::{PROTECT_BLOCK, PROTECT_REGIONS, PROTECT_REGISTERS, ProtectRegion};
+pub use self::protocol::{Mega2560, PICO_USB_VIDS, Pico, Protocol, protocol};
+pub use self::report::FlashReport;
+pub use self::sfdp::{BASIC_TABLE_ID, BasicFlash, EraseType, FastRead, SFDP_OPCODE, Sfdp, SfdpTable};
+pub use self::sha256::{hmac_sha256, sha256};
+pub use self::symbols::{Symbol, SymbolMap};
+pub use self::spi::{FlashChip, SmfiAccel, SpiBus, SpiChip, SpiRom};
+#[cfg(feature = "signature")]
+pub use self::signature::{SIGNATURE_SIZE, trusted_keys, verify_image, verify_signature};
+#[cfg(feature = "std")]
+pub use self::timer::StdTimer;
+pub use self::timer::{Backoff, CounterTimer, Timer};
+pub use self::trace::{Trace, TraceEvent};
+#[cfg(feature = "std")]
+pub use self::trace::TraceWriter;
+
+#[cfg(all(feature = "std", unix))]
+mod acpi_ec;
+mod algorithm;
+#[cfg(feature = "serial")]
+mod arduino;
+#[cfg(feature = "tokio")]
+mod async_debugger;
+mod bundle;
+mod config;
+mod debugger;
+mod ec_smfi;
+mod error;
+mod file;
+#[cfg(all(test, feature = "std"))]
+mod fixtures;
+mod flash;
+mod flasher;
+mod fwupd;
+mod io;
+mod isp;
+mod layout;
+#[cfg(any(test, feature = "model"))]
+mod model;
+mod param;
+mod profile;
+mod protect;
+mod protocol;
+pub mod regs;
+mod report;
+mod sfdp;
+mod sha1;
+mod sha256;
+#[cfg(feature = "signature")]
+mod signature;
+mod spi;
+mod symbols;
+mod timer;
+mod trace;
+
+pub trait Ec {
+    fn size(&mut self) -> usize;
+    fn project(&mut self) -> String;
+    fn version(&mut self) -> String;
+
+    /// Super I/O chip ID, such as 0x8587, if known
+    fn chip_id(&mut self) -> Option<u16> {
+        None
+    }
+
+    /// Super I/O chip version, if known
+    fn chip_version(&mut self) -> Option<u8> {
+        None
+    }
+
+    /// How the EC is reached and the data and command ports, if it is live
+    fn interface(&mut self) -> Option<(HostInterface, u16, u16)> {
+        None
+    }
+
+    /// Family of the firmware, proprietary or System76 EC, if known
+    fn firmware(&mut self) -> Option<&'static str> {
+        None
+    }
+}
+
+/// Call `f` until two consecutive calls return the same value, for at most
+/// `attempts` pairs of calls
+///
+/// Mailbox reads are occasionally corrupted by other EC traffic, so values
+/// like the project and version should be read this way. On failure, the
+/// last mismatching pair is returned.
+pub fn read_stable<T: PartialEq, F: FnMut() -> T>(mut f: F, attempts: usize) -> core::result::Result<T, Option<(T, T)>> {
+    let mut last = None;
+    for _attempt_i in 0..attempts {
+        let a = f();
+        let b = f();
+        if a == b {
+            return Ok(a);
+        }
+        last = Some((a, b));
+    }
+    Err(last)
+}
+
+/// Without the signature feature, images are not signed, and any image passes
+#[cfg(not(feature = "signature"))]
+pub fn verify_image(_data: &[u8], _signature: Option<&[u8]>) -> Result<()> {
+    Ok(())
+}
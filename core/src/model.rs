@@ -0,0 +1,812 @@
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{EcParam, HostInterface, PortIo, Result, SFDP_OPCODE, SMFI_CMD_BASE, SMFI_CMD_SIZE, Smfi};
+
+/// Value of INDAR1 that deasserts chip select in follow mode
+const FOLLOW_CS_HIGH: u8 = 0xFE;
+/// Value of INDAR1 that asserts chip select in follow mode
+const FOLLOW_DATA: u8 = 0xFD;
+
+/// SPI flash double for tests, with the semantics of a real part
+///
+/// Erase sets bytes to 0xFF and programming can only clear bits. Erase and
+/// program are ignored without the write enable latch, which they clear, and
+/// every command but read status is ignored while the part is busy, so code
+/// that skips a write enable or a status poll reads back the wrong data. Busy
+/// time is counted in status reads.
+///
+/// It implements [`Smfi`] through follow mode, like the EC does for a
+/// debugger, and can be put behind the EC mailbox with [`MailboxModel`].
+#[derive(Clone, Debug)]
+pub struct SpiFlashModel {
+    /// Contents of the flash
+    pub data: Vec<u8>,
+    /// JEDEC ID answered to 0x9F, or nothing like the internal flash of ITE
+    /// ECs
+    pub jedec_id: Option<[u8; 3]>,
+    /// SFDP space answered to 0x5A, or nothing like parts older than JESD216
+    pub sfdp: Option<Vec<u8>>,
+    /// Status reads that a 1 KiB sector erase stays busy for, 4 KiB sectors
+    /// stay busy for four times as long
+    pub erase_polls: usize,
+    /// Status reads that a word program stays busy for
+    pub program_polls: usize,
+    /// Commands that were ignored because the part was busy or not write
+    /// enabled
+    pub rejected: usize,
+    wel: bool,
+    busy: usize,
+    /// Next address of an auto address increment program
+    aai: Option<usize>,
+    /// Bytes clocked in since chip select was asserted
+    transaction: Vec<u8>,
+    /// Address of the next byte of a fast read
+    read_address: usize,
+    indar: u32,
+}
+
+impl SpiFlashModel {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            jedec_id: None,
+            sfdp: None,
+            erase_polls: 4,
+            program_polls: 1,
+            rejected: 0,
+            wel: false,
+            busy: 0,
+            aai: None,
+            transaction: Vec::new(),
+            read_address: 0,
+            indar: 0,
+        }
+    }
+
+    /// Status register: busy, write enable latch, and auto address increment
+    pub fn status(&self) -> u8 {
+        let mut status = 0;
+        if self.busy > 0 {
+            status |= 1;
+        }
+        if self.wel {
+            status |= 2;
+        }
+        if self.aai.is_some() {
+            status |= 0x40;
+        }
+        status
+    }
+
+    fn address(bytes: &[u8]) -> usize {
+        ((bytes[0] as usize) << 16) | ((bytes[1] as usize) << 8) | (bytes[2] as usize)
+    }
+
+    fn program(&mut self, address: usize, word: [u8; 2]) {
+        let len = self.data.len();
+        self.data[address % len] &= word[0];
+        self.data[(address + 1) % len] &= word[1];
+        self.aai = Some(address + 2);
+        self.busy = self.program_polls;
+    }
+
+    /// Clock a byte in while chip select is asserted
+    pub fn spi_write(&mut self, value: u8) {
+        self.transaction.push(value);
+        if self.transaction.len() == 5 && [0x0B, SFDP_OPCODE].contains(&self.transaction[0]) {
+            self.read_address = Self::address(&self.transaction[1..4]);
+        }
+        if self.transaction == [0x9F] {
+            self.read_address = 0;
+        }
+    }
+
+    /// Clock a byte out while chip select is asserted
+    pub fn spi_read(&mut self) -> u8 {
+        match self.transaction.first() {
+            Some(0x05) => {
+                let status = self.status();
+                self.busy = self.busy.saturating_sub(1);
+                status
+            },
+            Some(0x0B) if self.transaction.len() >= 5 && self.busy == 0 => {
+                let value = self.data[self.read_address % self.data.len()];
+                self.read_address += 1;
+                value
+            },
+            Some(&SFDP_OPCODE) if self.transaction.len() >= 5 => match &self.sfdp {
+                Some(sfdp) => {
+                    let value = sfdp.get(self.read_address).copied().unwrap_or(0xFF);
+                    self.read_address += 1;
+                    value
+                },
+                None => 0xFF,
+            },
+            Some(0x9F) => match self.jedec_id {
+                Some(id) => {
+                    let value = id.get(self.read_address).copied().unwrap_or(0xFF);
+                    self.read_address += 1;
+                    value
+                },
+                None => 0xFF,
+            },
+            _ => 0xFF,
+        }
+    }
+
+    /// Erase the sector of size bytes that holds address
+    fn erase(&mut self, address: usize, size: usize) {
+        let start = address & !(size - 1);
+        let end = (start + size).min(self.data.len());
+        if start < end {
+            self.data[start..end].iter_mut().for_each(|x| *x = 0xFF);
+        }
+        self.wel = false;
+        self.busy = self.erase_polls * size / 1024;
+    }
+
+    /// Deassert chip select, which runs the command clocked in
+    pub fn spi_end(&mut self) {
+        let transaction = core::mem::take(&mut self.transaction);
+        if transaction.is_empty() || [0x05, 0x0B, 0x9F, SFDP_OPCODE].contains(&transaction[0]) {
+            return;
+        }
+        if self.busy > 0 {
+            self.rejected += 1;
+            return;
+        }
+
+        match transaction.as_slice() {
+            [0x06] => self.wel = true,
+            [0x04] => {
+                self.wel = false;
+                self.aai = None;
+            },
+            [0x60] | [0xD7, _, _, _] | [0x20, _, _, _] | [0xAD, ..] if ! self.wel => self.rejected += 1,
+            [0x60] => {
+                self.data.iter_mut().for_each(|x| *x = 0xFF);
+                self.wel = false;
+                self.busy = self.erase_polls * self.data.len() / 1024;
+            },
+            [0xD7, address @ ..] => self.erase(Self::address(address), 1024),
+            [0x20, address @ ..] => self.erase(Self::address(address), 4096),
+            [0xAD, a, b, c, word0, word1] if self.aai.is_none() => {
+                self.program(Self::address(&[*a, *b, *c]), [*word0, *word1]);
+            },
+            [0xAD, word0, word1] => match self.aai {
+                Some(address) => self.program(address, [*word0, *word1]),
+                None => self.rejected += 1,
+            },
+            _ => self.rejected += 1,
+        }
+    }
+
+    /// Whether INDAR points at the follow mode registers, for the internal or
+    /// external flash
+    fn follow(&self) -> bool {
+        self.indar & 0x7FFF_0000 == 0x7FFF_0000
+    }
+}
+
+impl Smfi for SpiFlashModel {
+    fn flash_indar1(&mut self, data: u8) -> Result<()> {
+        self.indar = (self.indar & !0xFF00) | ((data as u32) << 8);
+        Ok(())
+    }
+
+    fn flash_address(&mut self, address: u32) -> Result<()> {
+        self.indar = address;
+        Ok(())
+    }
+
+    fn flash_read(&mut self, data: &mut [u8]) -> Result<usize> {
+        for x in data.iter_mut() {
+            *x = if ! self.follow() {
+                self.data[self.indar as usize % self.data.len()]
+            } else if (self.indar >> 8) as u8 == FOLLOW_DATA {
+                self.spi_read()
+            } else {
+                0xFF
+            };
+        }
+        Ok(data.len())
+    }
+
+    fn flash_write(&mut self, data: &[u8]) -> Result<usize> {
+        if self.follow() {
+            for &x in data.iter() {
+                match (self.indar >> 8) as u8 {
+                    FOLLOW_CS_HIGH => self.spi_end(),
+                    FOLLOW_DATA => self.spi_write(x),
+                    _ => (),
+                }
+            }
+        }
+        Ok(data.len())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Pending {
+    None,
+    SpiCommand,
+    SpiWrite,
+    GetParam,
+    SetParam,
+    SetParamValue,
+}
+
+/// Port I/O double for tests of the primary EC in flash mode, which drives a
+/// SpiFlashModel through the follow mode mailbox commands
+///
+/// Follow mode commands sent before entering follow mode are counted as
+/// rejected by the flash.
+#[derive(Clone, Debug)]
+pub struct MailboxModel {
+    pub flash: SpiFlashModel,
+    /// Super I/O chip ID
+    pub id: u16,
+    /// Answer on the keyboard controller ports 0x60/0x64 instead of
+    /// 0x62/0x66, like the EC of older boards
+    pub kbc: bool,
+    /// EC memory behind I2EC
+    pub memory: Vec<u8>,
+    /// Answer to the project command, such as N130WU
+    pub project: String,
+    /// Answer to the version command, which the EC firmware sends without
+    /// the major version, such as 07.02 for 1.07.02
+    pub version: String,
+    /// Run the open-source System76 EC firmware, which answers the project
+    /// and version through the SMFI command region instead of the mailbox
+    pub open: bool,
+    super_io_index: u8,
+    d2_index: u8,
+    i2ec_address: u16,
+    smfi_cmd: [u8; SMFI_CMD_SIZE],
+    follow: bool,
+    pending: Pending,
+    output: VecDeque<u8>,
+}
+
+impl MailboxModel {
+    pub fn new(flash: SpiFlashModel) -> Self {
+        Self {
+            flash,
+            id: 0x8587,
+            kbc: false,
+            memory: vec![0; 0x10000],
+            project: String::new(),
+            version: String::new(),
+            open: false,
+            super_io_index: 0,
+            d2_index: 0,
+            i2ec_address: 0,
+            smfi_cmd: [0; SMFI_CMD_SIZE],
+            follow: false,
+            pending: Pending::None,
+            output: VecDeque::new(),
+        }
+    }
+
+    fn data_port(&self) -> u16 {
+        if self.kbc { 0x60 } else { 0x62 }
+    }
+
+    fn command(&mut self, value: u8) {
+        match core::mem::replace(&mut self.pending, Pending::None) {
+            Pending::SpiCommand => {
+                self.flash.spi_end();
+                self.flash.spi_write(value);
+                return;
+            },
+            Pending::SpiWrite => {
+                self.flash.spi_write(value);
+                return;
+            },
+            _ => (),
+        }
+
+        match value {
+            0x01 => self.follow = true,
+            0x02..=0x04 if ! self.follow => self.flash.rejected += 1,
+            0x02 => self.pending = Pending::SpiCommand,
+            0x03 => self.pending = Pending::SpiWrite,
+            0x04 => {
+                let value = self.flash.spi_read();
+                self.output.push_back(value);
+            },
+            0x05 => {
+                self.flash.spi_end();
+                self.follow = false;
+            },
+            0x80 => self.pending = Pending::GetParam,
+            0x81 => self.pending = Pending::SetParam,
+            0x92 | 0x93 if self.open => (),
+            0x92 => self.output.extend(self.project.bytes().chain(Some(b'$'))),
+            0x93 => self.output.extend(self.version.bytes().chain(Some(b'$'))),
+            0xDC => self.output.push_back(51),
+            _ => (),
+        }
+    }
+
+    /// Run a command of the open-source firmware, answering in the data of the
+    /// command region and clearing the command when done
+    fn smfi_command(&mut self, cmd: u8) {
+        let answer: Vec<u8> = match cmd {
+            1 => vec![0x76, 0xEC, 1],
+            2 => self.project.bytes().chain(Some(0)).collect(),
+            3 => self.version.bytes().chain(Some(0)).collect(),
+            _ => {
+                self.smfi_cmd[1] = 1;
+                return;
+            },
+        };
+        self.smfi_cmd[1] = 0;
+        self.smfi_cmd[2..2 + answer.len()].copy_from_slice(&answer);
+    }
+
+    fn data(&mut self, value: u8) {
+        match core::mem::replace(&mut self.pending, Pending::None) {
+            // Only the flash size is answered, as 0x80 for 128 KiB
+            Pending::GetParam => {
+                let size = if value == EcParam::FlashSizeFlag.offset() && self.flash.data.len() >= 128 * 1024 { 0x80 } else { 0 };
+                self.output.push_back(size);
+            },
+            Pending::SetParam => self.pending = Pending::SetParamValue,
+            _ => (),
+        }
+    }
+}
+
+impl PortIo for MailboxModel {
+    fn interface(&self) -> HostInterface {
+        HostInterface::Mock
+    }
+
+    unsafe fn inb(&mut self, port: u16) -> u8 {
+        match port {
+            0x2F => match self.super_io_index {
+                0x20 => (self.id >> 8) as u8,
+                0x21 => self.id as u8,
+                0x22 => 3,
+                0x2F => match self.d2_index {
+                    0x10 => self.i2ec_address as u8,
+                    0x11 => (self.i2ec_address >> 8) as u8,
+                    0x12 => self.memory[self.i2ec_address as usize],
+                    _ => 0xFF,
+                },
+                _ => 0xFF,
+            },
+            port if self.open && (SMFI_CMD_BASE..SMFI_CMD_BASE + SMFI_CMD_SIZE as u16).contains(&port) => {
+                self.smfi_cmd[(port - SMFI_CMD_BASE) as usize]
+            },
+            port if port == self.data_port() => self.output.pop_front().unwrap_or(0xFF),
+            // Input is taken immediately, so only output can be pending
+            port if port == self.data_port() + 4 => ! self.output.is_empty() as u8,
+            _ => 0xFF,
+        }
+    }
+
+    unsafe fn outb(&mut self, port: u16, value: u8) {
+        match port {
+            0x2E => self.super_io_index = value,
+            0x2F => match (self.super_io_index, self.d2_index) {
+                (0x2E, _) => self.d2_index = value,
+                (0x2F, 0x10) => self.i2ec_address = (self.i2ec_address & 0xFF00) | value as u16,
+                (0x2F, 0x11) => self.i2ec_address = (self.i2ec_address & 0x00FF) | ((value as u16) << 8),
+                (0x2F, 0x12) => self.memory[self.i2ec_address as usize] = value,
+                _ => (),
+            },
+            port if self.open && port == SMFI_CMD_BASE => self.smfi_command(value),
+            port if self.open && (SMFI_CMD_BASE..SMFI_CMD_BASE + SMFI_CMD_SIZE as u16).contains(&port) => {
+                self.smfi_cmd[(port - SMFI_CMD_BASE) as usize] = value;
+            },
+            port if port == self.data_port() => self.data(value),
+            port if port == self.data_port() + 4 => self.command(value),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+    use crate::{Address, DBGCTRL_HALT, DBGCTRL_STEP, Debugger, Ec, EcFlash, EcSmfi, FlashAlgorithm, FlashChip, Flasher, FlasherState, Handshake, ProtectRegion, Sfdp, SpiBus, SpiRom};
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    fn follow(flash: &mut SpiFlashModel, bytes: &[u8]) {
+        flash.spi_end();
+        for &x in bytes {
+            flash.spi_write(x);
+        }
+        flash.spi_end();
+    }
+
+    fn wait(flash: &mut SpiFlashModel) {
+        flash.spi_write(0x05);
+        while flash.spi_read() & 1 != 0 {}
+        flash.spi_end();
+    }
+
+    #[test]
+    fn erase_needs_write_enable() {
+        let mut flash = SpiFlashModel::new(vec![0; 4096]);
+        follow(&mut flash, &[0xD7, 0, 0x04, 0]);
+        assert_eq!(flash.data[0x400], 0);
+        assert_eq!(flash.rejected, 1);
+
+        follow(&mut flash, &[0x06]);
+        follow(&mut flash, &[0xD7, 0, 0x04, 0]);
+        assert!(flash.data[0x400..0x800].iter().all(|&x| x == 0xFF));
+        assert_eq!(flash.data[0x3FF], 0);
+        assert_eq!(flash.data[0x800], 0);
+        assert_eq!(flash.status() & 2, 0);
+    }
+
+    #[test]
+    fn program_only_clears_bits() {
+        let mut flash = SpiFlashModel::new(vec![0xF0; 4096]);
+        follow(&mut flash, &[0x06]);
+        follow(&mut flash, &[0xAD, 0, 0, 0, 0x3C, 0xFF]);
+        assert_eq!(&flash.data[..2], &[0x30, 0xF0]);
+    }
+
+    #[test]
+    fn aai_increments_address() {
+        let mut flash = SpiFlashModel::new(vec![0xFF; 4096]);
+        flash.program_polls = 0;
+        follow(&mut flash, &[0x06]);
+        follow(&mut flash, &[0xAD, 0, 0x01, 0x00, 1, 2]);
+        follow(&mut flash, &[0xAD, 3, 4]);
+        follow(&mut flash, &[0x04]);
+        assert_eq!(&flash.data[0x100..0x104], &[1, 2, 3, 4]);
+
+        // Leaving auto address increment mode needs a new address
+        follow(&mut flash, &[0xAD, 5, 6]);
+        assert_eq!(flash.data[0x104], 0xFF);
+        assert_eq!(flash.rejected, 1);
+    }
+
+    #[test]
+    fn busy_ignores_commands() {
+        let mut flash = SpiFlashModel::new(vec![0; 4096]);
+        follow(&mut flash, &[0x06]);
+        follow(&mut flash, &[0xD7, 0, 0, 0]);
+        follow(&mut flash, &[0x06]);
+        assert_eq!(flash.rejected, 1);
+        assert_eq!(flash.status() & 3, 1);
+
+        wait(&mut flash);
+        follow(&mut flash, &[0x06]);
+        assert_eq!(flash.status() & 3, 2);
+    }
+
+    #[test]
+    fn smfi_follow_mode() {
+        let mut flash = SpiFlashModel::new(pattern(4096));
+        flash.flash_address(0x7FFF_FE00).unwrap();
+        flash.flash_write(&[0]).unwrap();
+        flash.flash_indar1(FOLLOW_DATA).unwrap();
+        flash.flash_write(&[0x0B, 0, 0x02, 0x00, 0]).unwrap();
+        let mut data = [0; 16];
+        flash.flash_read(&mut data).unwrap();
+        assert_eq!(&data[..], &pattern(4096)[0x200..0x210]);
+    }
+
+    fn flasher(data: Vec<u8>) -> Flasher<MailboxModel> {
+        let ec = EcFlash::with_io(MailboxModel::new(SpiFlashModel::new(data)), true).unwrap();
+        let mut flasher = Flasher::new(ec);
+        assert_eq!(unsafe { flasher.start() }, Ok(Handshake::Accepted));
+        flasher
+    }
+
+    #[test]
+    fn flasher_erase_write_read() {
+        let size = 128 * 1024;
+        let mut flasher = flasher(vec![0; size]);
+        assert_eq!(flasher.size, size);
+        flasher.allow_bootblock = true;
+
+        unsafe {
+            flasher.erase(|_| ()).unwrap();
+            assert!(flasher.read(|_| ()).unwrap().iter().all(|&x| x == 0xFF));
+
+            let data = pattern(size);
+            flasher.write(&data, |_| ()).unwrap();
+            assert!(flasher.read(|_| ()).unwrap() == data);
+        }
+
+        assert_eq!(flasher.report.bytes_erased, size);
+        assert_eq!(flasher.report.bytes_written, size);
+    }
+
+    #[test]
+    fn flasher_spi_transfer() {
+        let mut flash = SpiFlashModel::new(vec![0; 128 * 1024]);
+        flash.jedec_id = Some([0xEF, 0x40, 0x14]);
+        let ec = EcFlash::with_io(MailboxModel::new(flash), true).unwrap();
+        let mut flasher = Flasher::new(ec);
+
+        unsafe {
+            assert_eq!(flasher.start(), Ok(Handshake::Accepted));
+            assert_eq!(flasher.spi_transfer(&[0x9F], 3), Ok(vec![0xEF, 0x40, 0x14]));
+            assert_eq!(flasher.spi_transfer(&[0x0B, 0, 0, 0, 0], 2), Ok(vec![0, 0]));
+            assert_eq!(flasher.spi_transfer(&[], 1), Err(()));
+        }
+    }
+
+    #[test]
+    fn spi_rom_detects_sfdp() {
+        let mut flash = SpiFlashModel::new(vec![0; 128 * 1024]);
+        flash.jedec_id = Some([0xEF, 0x40, 0x16]);
+        let mut bus = SpiBus::new(&mut flash, FlashChip::External).unwrap();
+        let mut rom = SpiRom::new(&mut bus);
+        assert_eq!(rom.detect().unwrap().sector_size, 4096);
+
+        // The smallest erase type of the basic table is used
+        drop(rom);
+        drop(bus);
+        let mut space = crate::sfdp::tests::w25q32();
+        space[0x80 + 7 * 4..0x80 + 9 * 4].copy_from_slice(&[0x0F, 0x52, 0x10, 0xD8, 0, 0, 0, 0]);
+        flash.sfdp = Some(space);
+        let mut bus = SpiBus::new(&mut flash, FlashChip::External).unwrap();
+        let mut rom = SpiRom::new(&mut bus);
+        let chip = rom.detect().unwrap();
+        assert_eq!((chip.erase_opcode, chip.sector_size), (0x52, 32768));
+        assert_eq!(rom.sfdp().unwrap().basic().unwrap().capacity, 4 * 1024 * 1024);
+    }
+
+    #[test]
+    fn flasher_reads_sfdp() {
+        let mut flash = SpiFlashModel::new(vec![0; 128 * 1024]);
+        flash.sfdp = Some(crate::sfdp::tests::w25q32());
+        let ec = EcFlash::with_io(MailboxModel::new(flash), true).unwrap();
+        let mut flasher = Flasher::new(ec);
+
+        unsafe {
+            assert_eq!(flasher.start(), Ok(Handshake::Accepted));
+            assert_eq!(flasher.read_sfdp(0, 4), Ok(b"SFDP".to_vec()));
+            let sfdp = Sfdp::read(|address, data| {
+                data.copy_from_slice(&flasher.read_sfdp(address as u32, data.len()).unwrap());
+                Ok(())
+            }).unwrap();
+            assert_eq!(sfdp.tables.len(), 2);
+        }
+    }
+
+    #[test]
+    fn flasher_tracks_state() {
+        let mut flasher = flasher(vec![0; 128 * 1024]);
+        assert_eq!(flasher.state(), FlasherState::Started);
+
+        unsafe {
+            flasher.erase(|_| ()).unwrap();
+            assert_eq!(flasher.state(), FlasherState::Started);
+            flasher.stop().unwrap();
+        }
+        assert_eq!(flasher.state(), FlasherState::Idle);
+    }
+
+    #[test]
+    fn flasher_read_cached() {
+        let size = 128 * 1024;
+        let mut data = vec![0xFF; size];
+        data[0x10000..0x10400].copy_from_slice(&pattern(1024));
+        let mut flasher = flasher(data);
+
+        unsafe {
+            let original = flasher.read(|_| ()).unwrap();
+            flasher.erase_changed(&original, |_| ()).unwrap();
+
+            // Only the one block that was erased is read again
+            flasher.report.bytes_read = 0;
+            assert!(flasher.read_cached(|_| ()).unwrap().iter().all(|&x| x == 0xFF));
+            assert_eq!(flasher.report.bytes_read, 1024);
+
+            flasher.range = 0x10000..0x10400;
+            flasher.write(&pattern(size), |_| ()).unwrap();
+            flasher.report.bytes_read = 0;
+            assert!(flasher.read_cached(|_| ()).unwrap() == flasher.read(|_| ()).unwrap());
+            assert_eq!(flasher.report.bytes_read, 1024 + size);
+        }
+    }
+
+    #[test]
+    fn flasher_keeps_boot_block() {
+        let size = 128 * 1024;
+        let mut flasher = flasher(vec![0; size]);
+
+        unsafe {
+            flasher.erase(|_| ()).unwrap();
+            flasher.write(&pattern(size), |_| ()).unwrap();
+            let data = flasher.read(|_| ()).unwrap();
+            assert!(data[crate::BOOT_BLOCK].iter().all(|&x| x == 0));
+            assert!(data[crate::BOOT_BLOCK.end..] == pattern(size)[crate::BOOT_BLOCK.end..]);
+        }
+    }
+
+    #[test]
+    fn flasher_programs_boot_block_last() {
+        let size = 128 * 1024;
+        let mut flasher = flasher(vec![0; size]);
+        assert_eq!(flasher.program_order(), vec![0..size]);
+
+        flasher.allow_bootblock = true;
+        assert_eq!(flasher.program_order(), vec![crate::BOOT_BLOCK.end..size, crate::BOOT_BLOCK]);
+
+        flasher.range = 0x10000..0x20000;
+        assert_eq!(flasher.program_order(), vec![0x10000..0x20000]);
+    }
+
+    #[test]
+    fn flasher_program() {
+        let size = 128 * 1024;
+        let mut flasher = flasher(vec![0; size]);
+        flasher.allow_bootblock = true;
+
+        unsafe {
+            let original = flasher.read(|_| ()).unwrap();
+            flasher.program(&original, &pattern(size), &|_, _, _| ()).unwrap();
+            assert!(flasher.read(|_| ()).unwrap() == pattern(size));
+            assert_eq!(flasher.range, 0..size);
+            assert_eq!(flasher.report.retries, 0);
+        }
+    }
+
+    #[test]
+    fn flasher_probe_selects_follow_mode() {
+        let ec = EcFlash::with_io(MailboxModel::new(SpiFlashModel::new(vec![0; 128 * 1024])), true).unwrap();
+        let mut flasher = Flasher::new(ec);
+
+        let probe = unsafe { flasher.probe() }.unwrap();
+        assert_eq!(probe.handshake, Handshake::Accepted);
+        assert!(probe.fcommand);
+        assert!(! probe.scratch_rom);
+        assert_eq!(probe.select(), Some(FlashAlgorithm::FollowMode));
+        assert_eq!(flasher.state(), FlasherState::Idle);
+    }
+
+    #[test]
+    fn flasher_kbc_ports() {
+        let size = 128 * 1024;
+        let mut model = MailboxModel::new(SpiFlashModel::new(pattern(size)));
+        model.kbc = true;
+        let mut ec = EcFlash::with_io(model, true).unwrap();
+        ec.use_kbc_ports().unwrap();
+        let mut flasher = Flasher::new(ec);
+        assert_eq!(flasher.size, size);
+
+        unsafe {
+            assert_eq!(flasher.start(), Ok(Handshake::Accepted));
+            assert!(flasher.read(|_| ()).unwrap() == pattern(size));
+        }
+    }
+
+    #[test]
+    fn ec_memory_i2ec() {
+        let mut model = MailboxModel::new(SpiFlashModel::new(pattern(128 * 1024)));
+        model.memory[0x0123] = 0x5A;
+        let mut ec = EcFlash::with_io(model, true).unwrap();
+        unsafe {
+            assert_eq!(ec.memory_read(0x0123), Ok(0x5A));
+            ec.memory_write(0x0124, 0xA5).unwrap();
+            assert_eq!(ec.memory_read(0x0124), Ok(0xA5));
+            assert_eq!(ec.memory_read(0x0123), Ok(0x5A));
+        }
+
+        let model = MailboxModel::new(SpiFlashModel::new(pattern(128 * 1024)));
+        let mut ec = EcFlash::with_io(model, false).unwrap();
+        assert_eq!(unsafe { ec.memory_write(0x0124, 0xA5) }, Err(()));
+    }
+
+    #[test]
+    fn protect_region_i2ec() {
+        let model = MailboxModel::new(SpiFlashModel::new(pattern(128 * 1024)));
+        let mut ec = EcFlash::with_io(model, true).unwrap();
+        let config = ProtectRegion::covering(0x1E400..0x1E800, true).unwrap();
+        unsafe {
+            assert!(! ec.protect_region(0).unwrap().locked);
+            ec.set_protect_region(1, &config).unwrap();
+            assert_eq!(ec.protect_region(1), Ok(config));
+            assert!(! ec.protect_region(0).unwrap().locked);
+            assert_eq!(ec.protect_region(2), Err(()));
+        }
+
+        let model = MailboxModel::new(SpiFlashModel::new(pattern(128 * 1024)));
+        let mut ec = EcFlash::with_io(model, false).unwrap();
+        assert_eq!(unsafe { ec.protect_region(0) }, Err(()));
+    }
+
+    #[test]
+    fn open_firmware_smfi() {
+        let mut model = MailboxModel::new(SpiFlashModel::new(pattern(128 * 1024)));
+        model.project = "system76/lemp9".to_string();
+        model.version = "2024-03-01_5e8f1ab".to_string();
+
+        // The proprietary firmware leaves the command region unanswered
+        let model = match EcSmfi::with_io(model) {
+            Ok(_) => panic!("proprietary firmware probed as open"),
+            Err(model) => model,
+        };
+        assert_eq!(EcFlash::with_io(model.clone(), true).unwrap().project(), "system76/lemp9");
+
+        let mut model = model;
+        model.open = true;
+        let mut ec = EcSmfi::with_io(model.clone()).ok().unwrap();
+        assert_eq!(ec.protocol(), 1);
+        assert_eq!(ec.project(), "system76/lemp9");
+        assert_eq!(ec.version(), "2024-03-01_5e8f1ab");
+        assert_eq!(ec.chip_id(), Some(0x8587));
+        assert_eq!(ec.firmware(), Some("System76 EC"));
+
+        // Whose mailbox does not answer the string commands
+        assert_eq!(EcFlash::with_io(model, true).unwrap().project(), "");
+    }
+
+    /// Debugger registers of an 8051 core that runs one 3 byte instruction
+    /// per register access while it is not halted
+    struct CoreModel {
+        address: u8,
+        control: u8,
+        halted: bool,
+        pc: u16,
+    }
+
+    impl Debugger for CoreModel {
+        fn address(&mut self, address: u8) -> crate::Result<()> {
+            if ! self.halted {
+                self.pc += 3;
+            }
+            self.address = address;
+            Ok(())
+        }
+
+        fn read(&mut self, data: &mut [u8]) -> crate::Result<usize> {
+            data[0] = match self.address {
+                x if x == Address::DBGSTS as u8 => self.halted as u8,
+                x if x == Address::DBGPC0 as u8 => self.pc as u8,
+                x if x == Address::DBGPC1 as u8 => (self.pc >> 8) as u8,
+                _ => 0xFF,
+            };
+            Ok(1)
+        }
+
+        fn write(&mut self, data: &[u8]) -> crate::Result<usize> {
+            if self.address == Address::DBGCTRL as u8 {
+                if self.halted && data[0] & DBGCTRL_STEP != 0 {
+                    self.pc += 3;
+                }
+                self.control = data[0];
+                self.halted = data[0] & DBGCTRL_HALT != 0;
+            }
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn debugger_halt_step_resume() {
+        let mut core = CoreModel { address: 0, control: 0, halted: false, pc: 0x1000 };
+        assert!(core.read_pc().is_err());
+        assert!(core.step().is_err());
+
+        let pc = core.halt().unwrap();
+        assert!(core.halted().unwrap());
+        assert_eq!(core.read_pc(), Ok(pc));
+        assert_eq!(core.step(), Ok(pc + 3));
+        assert_eq!(core.step(), Ok(pc + 6));
+        assert_eq!(core.control, DBGCTRL_HALT | DBGCTRL_STEP);
+
+        core.resume().unwrap();
+        assert!(! core.halted().unwrap());
+        let sample = core.sample_pc().unwrap();
+        assert!(sample > pc + 6);
+        assert!(! core.halted().unwrap());
+        assert!(core.halt().unwrap() > sample);
+    }
+}
@@ -0,0 +1,53 @@
+/// Offsets in the ACPI parameter space of the EC, read with
+/// [`EcFlash::get_param`](crate::EcFlash::get_param) and written with
+/// [`EcFlash::set_param`](crate::EcFlash::set_param)
+///
+/// Only offsets that are used by this crate and behave the same on every
+/// supported EC are named. Others, such as battery and AC adapter state, vary
+/// between boards and are reached with `Raw`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EcParam {
+    /// Flash size flag, 0x80 on ECs with 128 KiB of flash
+    FlashSizeFlag,
+    /// Function of fcommand, which runs when written and is cleared with 0
+    FcmdCommand,
+    /// Argument of the fcommand function
+    FcmdArgument,
+    /// One of the 4 bytes of fcommand data, which are replaced with the result
+    FcmdData(u8),
+    /// Any other offset
+    Raw(u8),
+}
+
+impl EcParam {
+    /// Parameter with a name, such as flash-size-flag or fcmd-data-0
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "flash-size-flag" => Some(EcParam::FlashSizeFlag),
+            "fcmd-command" => Some(EcParam::FcmdCommand),
+            "fcmd-argument" => Some(EcParam::FcmdArgument),
+            "fcmd-data-0" => Some(EcParam::FcmdData(0)),
+            "fcmd-data-1" => Some(EcParam::FcmdData(1)),
+            "fcmd-data-2" => Some(EcParam::FcmdData(2)),
+            "fcmd-data-3" => Some(EcParam::FcmdData(3)),
+            _ => None,
+        }
+    }
+
+    /// Offset of the parameter
+    pub fn offset(self) -> u8 {
+        match self {
+            EcParam::FlashSizeFlag => 0xE5,
+            EcParam::FcmdCommand => 0xF8,
+            EcParam::FcmdArgument => 0xF9,
+            EcParam::FcmdData(index) => 0xFA + (index & 3),
+            EcParam::Raw(offset) => offset,
+        }
+    }
+}
+
+impl From<EcParam> for u8 {
+    fn from(param: EcParam) -> u8 {
+        param.offset()
+    }
+}
@@ -0,0 +1,80 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use super::SymbolMap;
+
+/// Histogram of the program counters sampled from the 8051 core of the EC
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Profile {
+    counts: BTreeMap<u16, usize>,
+    total: usize,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count one sample at pc
+    pub fn add(&mut self, pc: u16) {
+        *self.counts.entry(pc).or_insert(0) += 1;
+        self.total += 1;
+    }
+
+    /// Number of samples
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Sampled addresses with their number of samples, most first
+    pub fn addresses(&self) -> Vec<(u16, usize)> {
+        let mut addresses: Vec<(u16, usize)> = self.counts.iter().map(|(&pc, &count)| (pc, count)).collect();
+        addresses.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        addresses
+    }
+
+    /// Functions of the linker map with their number of samples, most first
+    ///
+    /// Samples below every code symbol are counted under their own address.
+    pub fn functions(&self, map: &SymbolMap) -> Vec<(String, usize)> {
+        let mut functions: BTreeMap<String, usize> = BTreeMap::new();
+        for (&pc, &count) in &self.counts {
+            let name = match map.code_symbol(pc) {
+                Some(symbol) => symbol.c_name().to_string(),
+                None => format!("0x{:04X}", pc),
+            };
+            *functions.entry(name).or_insert(0) += count;
+        }
+
+        let mut functions: Vec<(String, usize)> = functions.into_iter().collect();
+        functions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        functions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram() {
+        let map = SymbolMap::parse("\
+CSEG                    00000100    00000100 =         256. bytes (REL,CON,CODE)
+     00000100  _main            main
+     00000180  _fan_update      fan
+").unwrap();
+
+        let mut profile = Profile::new();
+        for pc in [0x0190, 0x0103, 0x0190, 0x0050, 0x0184] {
+            profile.add(pc);
+        }
+        assert_eq!(profile.total(), 5);
+        assert_eq!(profile.addresses(), vec![(0x0190, 2), (0x0050, 1), (0x0103, 1), (0x0184, 1)]);
+        assert_eq!(profile.functions(&map), vec![
+            ("fan_update".to_string(), 3),
+            ("0x0050".to_string(), 1),
+            ("main".to_string(), 1),
+        ]);
+    }
+}
@@ -0,0 +1,111 @@
+use alloc::string::ToString;
+use core::ops::Range;
+
+use super::regs::smfi;
+use super::{Error, Result};
+
+/// Number of protect regions of the internal flash of ITE ECs
+pub const PROTECT_REGIONS: u8 = 2;
+/// Granularity of protect regions
+pub const PROTECT_BLOCK: usize = 0x1000;
+
+/// SMFI registers of each protect region: low and high byte of the base in
+/// 4 KiB, and size
+pub const PROTECT_REGISTERS: [[u8; 3]; PROTECT_REGIONS as usize] = [
+    [smfi::P0BA0R, smfi::P0BA1R, smfi::P0ZR],
+    [smfi::P1BA0R, smfi::P1BA1R, smfi::P1ZR],
+];
+
+/// Size field of PnZR, as 4 KiB shifted left by it
+const ZR_SIZE_MASK: u8 = 0x0F;
+/// Bit of PnZR that makes the flash controller drop host erase and program
+/// commands inside the region
+const ZR_WRITE_PROTECT: u8 = 0x80;
+
+/// Range of the internal flash that the SMFI protect registers cover
+///
+/// A region is a power of two of 4 KiB, aligned to its size. Locks hold until
+/// the EC is reset, and the EC firmware may set its own at boot.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProtectRegion {
+    pub range: Range<usize>,
+    /// Whether erase and program of the range are dropped
+    pub locked: bool,
+}
+
+impl ProtectRegion {
+    /// Smallest region that covers range
+    pub fn covering(range: Range<usize>, locked: bool) -> Result<Self> {
+        if range.is_empty() {
+            return Err(Error::InvalidInput("empty range".to_string()));
+        }
+
+        (0..=ZR_SIZE_MASK as u32)
+            .map(|shift| PROTECT_BLOCK << shift)
+            .map(|size| (range.start / size * size, size))
+            .find(|&(base, size)| base + size >= range.end)
+            .map(|(base, size)| ProtectRegion { range: base..base + size, locked })
+            .ok_or_else(|| Error::InvalidInput(format!(
+                "0x{:X}-0x{:X} is larger than a protect region",
+                range.start, range.end - 1
+            )))
+    }
+
+    /// Region held by the base low, base high, and size registers
+    pub fn from_registers(registers: [u8; 3]) -> Self {
+        let [base_low, base_high, zr] = registers;
+        let base = u16::from_le_bytes([base_low, base_high]) as usize * PROTECT_BLOCK;
+        let size = PROTECT_BLOCK << (zr & ZR_SIZE_MASK);
+        ProtectRegion {
+            range: base..base + size,
+            locked: zr & ZR_WRITE_PROTECT != 0,
+        }
+    }
+
+    /// Values of the base low, base high, and size registers
+    pub fn registers(&self) -> [u8; 3] {
+        let base = ((self.range.start / PROTECT_BLOCK) as u16).to_le_bytes();
+        let shift = (self.range.len() / PROTECT_BLOCK).trailing_zeros() as u8 & ZR_SIZE_MASK;
+        [base[0], base[1], shift | if self.locked { ZR_WRITE_PROTECT } else { 0 }]
+    }
+
+    /// Whether the region is locked and overlaps range
+    pub fn blocks(&self, range: &Range<usize>) -> bool {
+        self.locked && range.start < self.range.end && self.range.start < range.end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn covering_is_aligned() {
+        let boot = ProtectRegion::covering(0..0x1000, true).unwrap();
+        assert_eq!(boot.range, 0..0x1000);
+
+        let config = ProtectRegion::covering(0x1E400..0x1E800, true).unwrap();
+        assert_eq!(config.range, 0x1E000..0x1F000);
+
+        // Crossing a boundary doubles the size until one is aligned
+        let region = ProtectRegion::covering(0x1F00..0x2100, false).unwrap();
+        assert_eq!(region.range, 0..0x4000);
+        assert!(ProtectRegion::covering(0..0, true).is_err());
+    }
+
+    #[test]
+    fn registers_round_trip() {
+        for region in [
+            ProtectRegion::covering(0..0x1000, true).unwrap(),
+            ProtectRegion::covering(0x1E400..0x1E800, false).unwrap(),
+            ProtectRegion::covering(0x10000..0x20000, true).unwrap(),
+        ] {
+            assert_eq!(ProtectRegion::from_registers(region.registers()), region);
+        }
+
+        let region = ProtectRegion::from_registers([0x1E, 0x00, 0x80]);
+        assert_eq!(region.range, 0x1E000..0x1F000);
+        assert!(region.blocks(&(0x1E7FF..0x1E800)));
+        assert!(! region.blocks(&(0x1F000..0x20000)));
+    }
+}
@@ -0,0 +1,111 @@
+//! Versions of the framing that programmer firmware speaks over its serial
+//! port, shared by `ParallelArduino` and `AsyncParallelArduino`.
+//!
+//! Each frame is a command byte followed by its parameters:
+//!
+//! - `'E' 0 value` echoes value
+//! - `'B' 0` returns the buffer size - 1, as a length
+//! - `'A' address` sets the debugger address
+//! - `'R' len` reads len bytes
+//! - `'W' len data` writes len bytes and acks with len
+//! - `'P' len data` programs len bytes with AAI and acks with len
+//! - `'r' address len` sets the address and reads len bytes
+//! - `'w' address len data` sets the address, writes len bytes, and acks with
+//!   len
+//!
+//! Lengths are sent as length - 1. Version 1 is the original sketch for the
+//! Arduino Mega 2560, with one byte lengths and no `'r'` or `'w'`. Version 2
+//! adds those, saving a frame per transfer. Version 3 is spoken by firmware
+//! for RP2040 and STM32 dongles, which have native USB and much larger
+//! buffers: lengths are two bytes, little endian, and transfers always carry
+//! their address.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// USB vendor IDs of RP2040 and STM32 boards, whose programmer firmware
+/// speaks version 3: Raspberry Pi and STMicroelectronics
+pub const PICO_USB_VIDS: &[u16] = &[0x2E8A, 0x0483];
+
+/// A version of the programmer protocol
+pub trait Protocol: Send {
+    /// Version number, as listed in the module documentation
+    fn version(&self) -> u8;
+
+    /// Bytes that a length takes in a frame
+    fn length_bytes(&self) -> usize {
+        1
+    }
+
+    /// Send the address with each transfer in `'r'` and `'w'` frames, instead
+    /// of a separate `'A'` frame
+    fn combined(&self) -> bool;
+
+    /// Largest transfer that one frame can describe
+    fn max_len(&self) -> usize {
+        1 << (8 * self.length_bytes())
+    }
+
+    /// Encode len, which must be between 1 and max_len, as len - 1
+    fn length(&self, len: usize) -> Vec<u8> {
+        (len - 1).to_le_bytes()[..self.length_bytes()].to_vec()
+    }
+
+    /// Decode a length, such as the answer to `'B'`
+    fn decode_length(&self, bytes: &[u8]) -> usize {
+        bytes.iter().rev().fold(0, |len, &byte| (len << 8) | byte as usize) + 1
+    }
+
+    /// Frame of command, with the address if it is `'r'` or `'w'`, followed
+    /// by the length len
+    fn header(&self, command: u8, address: Option<u8>, len: usize) -> Vec<u8> {
+        let mut header = vec![command];
+        header.extend(address);
+        header.extend(self.length(len));
+        header
+    }
+}
+
+/// Sketch for the Arduino Mega 2560, version 1, or 2 if it has combined frames
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Mega2560 {
+    pub combined: bool,
+}
+
+impl Protocol for Mega2560 {
+    fn version(&self) -> u8 {
+        if self.combined { 2 } else { 1 }
+    }
+
+    fn combined(&self) -> bool {
+        self.combined
+    }
+}
+
+/// Firmware for RP2040 and STM32 dongles, version 3
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Pico;
+
+impl Protocol for Pico {
+    fn version(&self) -> u8 {
+        3
+    }
+
+    fn length_bytes(&self) -> usize {
+        2
+    }
+
+    fn combined(&self) -> bool {
+        true
+    }
+}
+
+/// The protocol with a version number
+pub fn protocol(version: u8) -> Option<Box<dyn Protocol>> {
+    match version {
+        1 => Some(Box::new(Mega2560 { combined: false })),
+        2 => Some(Box::new(Mega2560 { combined: true })),
+        3 => Some(Box::new(Pico)),
+        _ => None,
+    }
+}
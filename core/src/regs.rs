@@ -0,0 +1,128 @@
+#![allow(clippy::missing_safety_doc)]
+
+//! Registers of ITE ECs that are reached from the host, by name
+//!
+//! The Super I/O index and data ports lead to the D2 space, whose I2EC
+//! registers reach EC memory, where the GCTRL, SMFI, and watchdog registers
+//! are. The EC answers there even when its firmware does not run.
+
+use super::PortIo;
+
+/// Index port of the Super I/O
+pub const SUPER_IO_INDEX: u16 = 0x2E;
+/// Data port of the Super I/O
+pub const SUPER_IO_DATA: u16 = 0x2F;
+
+/// Super I/O register with the high byte of the chip ID
+pub const SIO_CHIPID1: u8 = 0x20;
+/// Super I/O register with the low byte of the chip ID
+pub const SIO_CHIPID2: u8 = 0x21;
+/// Super I/O register with the chip version
+pub const SIO_CHIPVER: u8 = 0x22;
+/// Super I/O register that selects a register of the D2 space
+pub const SIO_D2_INDEX: u8 = 0x2E;
+/// Super I/O register with the data of the selected D2 register
+pub const SIO_D2_DATA: u8 = 0x2F;
+
+/// D2 register with the low byte of the I2EC address
+pub const I2EC_ADDR_LOW: u8 = 0x10;
+/// D2 register with the high byte of the I2EC address
+pub const I2EC_ADDR_HIGH: u8 = 0x11;
+/// D2 register with the EC memory byte at the I2EC address
+pub const I2EC_DATA: u8 = 0x12;
+
+/// Base address of the SMFI registers in EC memory
+pub const SMFI_BASE: u16 = 0x1000;
+/// Number of SMFI registers
+pub const SMFI_SIZE: usize = 0x100;
+
+/// SMFI registers, as offsets from SMFI_BASE
+pub mod smfi {
+    /// FBIU configuration
+    pub const FBCFG: u8 = 0x00;
+    /// Flash programming configuration
+    pub const FPCFG: u8 = 0x01;
+    /// Shared memory EC control and status
+    pub const SMECCS: u8 = 0x20;
+    /// Shared memory host semaphore
+    pub const SMHSR: u8 = 0x22;
+    /// Low byte of the base address of protect region 0, in 4 KiB
+    pub const P0BA0R: u8 = 0x28;
+    /// High byte of the base address of protect region 0
+    pub const P0BA1R: u8 = 0x29;
+    /// Size of protect region 0
+    pub const P0ZR: u8 = 0x2A;
+    /// Low byte of the base address of protect region 1, in 4 KiB
+    pub const P1BA0R: u8 = 0x2B;
+    /// High byte of the base address of protect region 1
+    pub const P1BA1R: u8 = 0x2C;
+    /// Size of protect region 1
+    pub const P1ZR: u8 = 0x2D;
+    /// EC-indirect flash address, bits 0-7
+    pub const ECINDAR0: u8 = 0x3B;
+    /// EC-indirect flash address, bits 8-15
+    pub const ECINDAR1: u8 = 0x3C;
+    /// EC-indirect flash address, bits 16-23
+    pub const ECINDAR2: u8 = 0x3D;
+    /// EC-indirect flash address, bits 24-31
+    pub const ECINDAR3: u8 = 0x3E;
+    /// EC-indirect flash data
+    pub const ECINDDR: u8 = 0x3F;
+    /// Flash control 3
+    pub const FLHCTRL3R: u8 = 0x63;
+}
+
+/// External timer and watchdog configuration register
+pub const ETWCFG: u16 = 0x1F01;
+/// ETWCFG bit that lets a write of EWDKEYR reset the EC
+pub const EWDKEYEN: u8 = 1 << 5;
+/// External watchdog key register, writing anything but 0x5C resets the EC
+/// when EWDKEYEN is set
+pub const EWDKEYR: u16 = 0x1F07;
+
+/// Base address of the GCTRL registers in EC memory
+pub const GCTRL_BASE: u16 = 0x2000;
+/// GCTRL register with the high byte of the chip ID
+pub const ECHIPID1: u16 = 0x2000;
+/// GCTRL register with the low byte of the chip ID
+pub const ECHIPID2: u16 = 0x2001;
+/// GCTRL register with the chip version
+pub const ECHIPVER: u16 = 0x2002;
+
+/// Read a Super I/O register
+pub unsafe fn super_io_read<P: PortIo>(io: &mut P, reg: u8) -> u8 {
+    io.outb(SUPER_IO_INDEX, reg);
+    io.inb(SUPER_IO_DATA)
+}
+
+/// Write a Super I/O register
+pub unsafe fn super_io_write<P: PortIo>(io: &mut P, reg: u8, value: u8) {
+    io.outb(SUPER_IO_INDEX, reg);
+    io.outb(SUPER_IO_DATA, value);
+}
+
+/// Read a register of the D2 space
+pub unsafe fn d2_read<P: PortIo>(io: &mut P, reg: u8) -> u8 {
+    super_io_write(io, SIO_D2_INDEX, reg);
+    super_io_read(io, SIO_D2_DATA)
+}
+
+/// Write a register of the D2 space
+pub unsafe fn d2_write<P: PortIo>(io: &mut P, reg: u8, value: u8) {
+    super_io_write(io, SIO_D2_INDEX, reg);
+    super_io_write(io, SIO_D2_DATA, value);
+}
+
+/// Read from EC memory using the I2EC interface of the Super I/O
+pub unsafe fn i2ec_read<P: PortIo>(io: &mut P, addr: u16) -> u8 {
+    d2_write(io, I2EC_ADDR_HIGH, (addr >> 8) as u8);
+    d2_write(io, I2EC_ADDR_LOW, addr as u8);
+    d2_read(io, I2EC_DATA)
+}
+
+/// Write to EC memory using the I2EC interface of the Super I/O
+pub unsafe fn i2ec_write<P: PortIo>(io: &mut P, addr: u16, value: u8) {
+    d2_write(io, I2EC_ADDR_HIGH, (addr >> 8) as u8);
+    d2_write(io, I2EC_ADDR_LOW, addr as u8);
+    d2_write(io, I2EC_DATA, value);
+}
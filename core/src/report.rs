@@ -0,0 +1,48 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+
+/// What a flash did, to be printed at the end so that logs and support tickets
+/// show more than success or failure
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FlashReport {
+    pub bytes_read: usize,
+    pub bytes_erased: usize,
+    pub bytes_written: usize,
+    /// Erase blocks skipped because they were already blank or protected
+    pub blocks_skipped: usize,
+    /// Blocks that were erased or written again after failing to verify
+    pub retries: usize,
+    /// Bytes that failed to verify and were correct after a retry
+    pub mismatches_fixed: usize,
+    /// Time spent in each phase, in the order the phases first ran
+    pub phases: Vec<(String, Duration)>,
+}
+
+impl FlashReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add time spent in a phase, to the existing entry if it ran before
+    pub fn add_phase(&mut self, name: &str, elapsed: Duration) {
+        match self.phases.iter_mut().find(|(phase, _)| phase == name) {
+            Some((_, total)) => *total += elapsed,
+            None => self.phases.push((name.to_string(), elapsed)),
+        }
+    }
+}
+
+impl fmt::Display for FlashReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Read: {} bytes", self.bytes_read)?;
+        writeln!(f, "Erased: {} bytes, {} blocks skipped", self.bytes_erased, self.blocks_skipped)?;
+        writeln!(f, "Written: {} bytes", self.bytes_written)?;
+        write!(f, "Retries: {}, {} mismatched bytes fixed", self.retries, self.mismatches_fixed)?;
+        for (phase, elapsed) in self.phases.iter() {
+            write!(f, "\nTime {}: {}.{:03} s", phase, elapsed.as_secs(), elapsed.subsec_millis())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,289 @@
+//! Serial Flash Discoverable Parameters of JESD216, which SPI flash parts
+//! answer to opcode 0x5A, so that their capacity, erase opcodes, and fast
+//! reads need not be looked up by JEDEC ID
+//!
+//! The SFDP space starts with a header and one parameter header per table,
+//! each pointing at a table of DWORDs elsewhere in the space:
+//!
+//! ```text
+//! 0x00  "SFDP"
+//! 0x04  minor, major revision, number of parameter headers - 1, 0xFF
+//! 0x08  parameter headers, 8 bytes each: ID LSB, minor, major, length in
+//!       DWORDs, 24 bit little endian pointer, ID MSB
+//! ```
+
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+use super::{Error, Result};
+
+/// Opcode that reads the SFDP space, with a 24 bit address and 8 dummy clocks
+pub const SFDP_OPCODE: u8 = 0x5A;
+/// ID of the JEDEC basic flash parameter table
+pub const BASIC_TABLE_ID: u16 = 0xFF00;
+
+/// Signature at the start of the SFDP space
+const SFDP_SIGNATURE: &[u8; 4] = b"SFDP";
+/// Bytes of the header and of each parameter header
+const HEADER_SIZE: usize = 8;
+/// End of the SFDP space that is read, which real parts keep within a few
+/// hundred bytes, so a corrupted pointer does not read megabytes
+const SFDP_MAX: usize = 0x1000;
+
+/// One parameter table of the SFDP space
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SfdpTable {
+    /// ID of the table, BASIC_TABLE_ID or one of a manufacturer
+    pub id: u16,
+    pub major: u8,
+    pub minor: u8,
+    /// Address of the table in the SFDP space
+    pub pointer: usize,
+    /// DWORDs of the table, little endian
+    pub data: Vec<u8>,
+}
+
+impl SfdpTable {
+    /// DWORD index of the table, counting from 1 like JESD216, or 0 past its
+    /// end, where later revisions add fields
+    fn dword(&self, index: usize) -> u32 {
+        let start = (index - 1) * 4;
+        self.data.get(start..start + 4)
+            .map_or(0, |bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+/// Header and parameter tables of the SFDP space of a flash part
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Sfdp {
+    pub major: u8,
+    pub minor: u8,
+    pub tables: Vec<SfdpTable>,
+}
+
+/// Erase command that the basic table lists
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct EraseType {
+    /// Bytes erased
+    pub size: usize,
+    pub opcode: u8,
+}
+
+/// Fast read command that the basic table lists
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FastRead {
+    /// Lines used for the opcode, the address, and the data, such as 1-1-4
+    pub mode: &'static str,
+    pub opcode: u8,
+    /// Wait states after the address
+    pub dummy_clocks: u8,
+    /// Mode bit clocks after the address, before the wait states
+    pub mode_clocks: u8,
+}
+
+/// What the JEDEC basic flash parameter table tells about a part
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BasicFlash {
+    /// Size of the flash in bytes
+    pub capacity: usize,
+    /// Address bytes the part takes: 3, 3 or 4, or 4
+    pub address_bytes: &'static str,
+    /// Erase commands from the smallest to the largest
+    pub erase_types: Vec<EraseType>,
+    pub fast_reads: Vec<FastRead>,
+}
+
+impl Sfdp {
+    /// Read the SFDP space with read, which fills a buffer from an address
+    /// of it, up to the end of the last table
+    pub fn read_space<F: FnMut(usize, &mut [u8]) -> Result<()>>(mut read: F) -> Result<Vec<u8>> {
+        let mut space = vec![0; HEADER_SIZE];
+        read(0, &mut space)?;
+        if ! space.starts_with(SFDP_SIGNATURE) {
+            return Err(Error::InvalidData("no SFDP signature, the flash does not answer 0x5A".to_string()));
+        }
+
+        let headers_end = HEADER_SIZE * (space[6] as usize + 2);
+        space.resize(headers_end, 0);
+        read(HEADER_SIZE, &mut space[HEADER_SIZE..])?;
+
+        let end = space[HEADER_SIZE..].chunks_exact(HEADER_SIZE)
+            .map(|header| Self::pointer(header) + header[3] as usize * 4)
+            .fold(headers_end, usize::max);
+        if end > SFDP_MAX {
+            return Err(Error::InvalidData(format!("SFDP table ends at 0x{:X}, past 0x{:X}", end, SFDP_MAX)));
+        }
+        space.resize(end, 0);
+        read(headers_end, &mut space[headers_end..])?;
+        Ok(space)
+    }
+
+    /// Read and parse the SFDP space with read, see [`Sfdp::read_space`]
+    pub fn read<F: FnMut(usize, &mut [u8]) -> Result<()>>(read: F) -> Result<Self> {
+        Self::parse(&Self::read_space(read)?)
+    }
+
+    fn pointer(header: &[u8]) -> usize {
+        u32::from_le_bytes([header[4], header[5], header[6], 0]) as usize
+    }
+
+    /// Parse the SFDP space, from its start to the end of the last table
+    pub fn parse(space: &[u8]) -> Result<Self> {
+        if space.len() < HEADER_SIZE || ! space.starts_with(SFDP_SIGNATURE) {
+            return Err(Error::InvalidData("no SFDP signature, the flash does not answer 0x5A".to_string()));
+        }
+
+        let count = space[6] as usize + 1;
+        let headers = space.get(HEADER_SIZE..HEADER_SIZE * (count + 1))
+            .ok_or_else(|| Error::InvalidData(format!("SFDP space ends before its {} parameter headers", count)))?;
+        let tables = headers.chunks_exact(HEADER_SIZE)
+            .map(|header| {
+                let id = u16::from_le_bytes([header[0], header[7]]);
+                let pointer = Self::pointer(header);
+                let data = space.get(pointer..pointer + header[3] as usize * 4)
+                    .ok_or_else(|| Error::InvalidData(format!("SFDP table 0x{:04X} at 0x{:X} is past the end", id, pointer)))?;
+                Ok(SfdpTable { id, major: header[2], minor: header[1], pointer, data: data.to_vec() })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Sfdp { major: space[5], minor: space[4], tables })
+    }
+
+    /// Decode the JEDEC basic flash parameter table
+    pub fn basic(&self) -> Result<BasicFlash> {
+        let table = self.tables.iter()
+            .find(|table| table.id == BASIC_TABLE_ID && table.major == 1)
+            .ok_or_else(|| Error::InvalidData("no JEDEC basic flash parameter table".to_string()))?;
+        if table.data.len() < 9 * 4 {
+            return Err(Error::InvalidData(format!("JEDEC basic flash parameter table has {} bytes", table.data.len())));
+        }
+
+        let density = table.dword(2);
+        let bits = if density & 0x8000_0000 == 0 {
+            density as u64 + 1
+        } else {
+            match 1u64.checked_shl(density & 0x7FFF_FFFF) {
+                Some(bits) if bits <= 1 << 35 => bits,
+                _ => return Err(Error::InvalidData(format!("flash density 0x{:08X} is not possible", density))),
+            }
+        };
+
+        let dword1 = table.dword(1);
+        let address_bytes = match (dword1 >> 17) & 3 {
+            0 => "3",
+            1 => "3 or 4",
+            2 => "4",
+            _ => return Err(Error::InvalidData("flash address bytes are reserved".to_string())),
+        };
+
+        // Erase types 1 to 4 in DWORDs 8 and 9, as a power of two of the
+        // size and an opcode, or the 4 KiB erase of DWORD 1 on parts that
+        // leave them out
+        let mut erase_types: Vec<EraseType> = [table.dword(8), table.dword(9)].iter()
+            .flat_map(|&dword| [dword as u16, (dword >> 16) as u16])
+            .filter(|&field| field as u8 != 0 && (field as u8) < 32)
+            .map(|field| EraseType { size: 1 << (field as u8), opcode: (field >> 8) as u8 })
+            .collect();
+        if erase_types.is_empty() && dword1 & 3 == 1 {
+            erase_types.push(EraseType { size: 4096, opcode: (dword1 >> 8) as u8 });
+        }
+        erase_types.sort_by_key(|erase| erase.size);
+
+        // Supported bit and DWORD and half of the parameters of each mode
+        let fast_reads = [
+            ("1-1-2", dword1 & (1 << 16), 4, 0),
+            ("1-2-2", dword1 & (1 << 20), 4, 16),
+            ("1-1-4", dword1 & (1 << 22), 3, 16),
+            ("1-4-4", dword1 & (1 << 21), 3, 0),
+            ("2-2-2", table.dword(5) & (1 << 0), 6, 16),
+            ("4-4-4", table.dword(5) & (1 << 4), 7, 16),
+        ].iter()
+            .filter(|&&(_, supported, _, _)| supported != 0)
+            .map(|&(mode, _, dword, shift)| {
+                let params = (table.dword(dword) >> shift) as u16;
+                FastRead {
+                    mode,
+                    opcode: (params >> 8) as u8,
+                    dummy_clocks: params as u8 & 0x1F,
+                    mode_clocks: (params as u8) >> 5,
+                }
+            })
+            .collect();
+
+        Ok(BasicFlash {
+            capacity: (bits / 8) as usize,
+            address_bytes,
+            erase_types,
+            fast_reads,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// SFDP space of a 4 MiB part like the W25Q32, with a basic table and a
+    /// manufacturer table
+    pub(crate) fn w25q32() -> Vec<u8> {
+        let mut space = vec![0xFF; 0x80 + 9 * 4];
+        space[..16].copy_from_slice(&[
+            b'S', b'F', b'D', b'P', 0x05, 0x01, 0x01, 0xFF,
+            0x00, 0x05, 0x01, 0x09, 0x80, 0x00, 0x00, 0xFF,
+        ]);
+        space[16..24].copy_from_slice(&[0x84, 0x00, 0x01, 0x02, 0x60, 0x00, 0x00, 0xEF]);
+        let basic: [u32; 9] = [
+            0xFF7120E5, 0x01FFFFFF, 0x6B08EB44, 0xBB423B08, 0xFFFFFFEE,
+            0x0000FFFF, 0xEB40FFFF, 0x520F200C, 0x0000D810,
+        ];
+        for (i, dword) in basic.iter().enumerate() {
+            space[0x80 + i * 4..0x84 + i * 4].copy_from_slice(&dword.to_le_bytes());
+        }
+        space[0x60..0x68].copy_from_slice(&[0x00, 0x36, 0x00, 0x27, 0xF4, 0x4F, 0xFF, 0xFF]);
+        space
+    }
+
+    #[test]
+    fn basic_table() {
+        let sfdp = Sfdp::parse(&w25q32()).unwrap();
+        assert_eq!((sfdp.major, sfdp.minor), (1, 5));
+        assert_eq!(sfdp.tables.len(), 2);
+        assert_eq!(sfdp.tables[1].id, 0xEF84);
+        assert_eq!(sfdp.tables[1].data.len(), 8);
+
+        let basic = sfdp.basic().unwrap();
+        assert_eq!(basic.capacity, 4 * 1024 * 1024);
+        assert_eq!(basic.address_bytes, "3");
+        assert_eq!(basic.erase_types, vec![
+            EraseType { size: 4096, opcode: 0x20 },
+            EraseType { size: 32768, opcode: 0x52 },
+            EraseType { size: 65536, opcode: 0xD8 },
+        ]);
+        let modes: Vec<_> = basic.fast_reads.iter().map(|read| (read.mode, read.opcode, read.dummy_clocks)).collect();
+        assert_eq!(modes, vec![("1-1-2", 0x3B, 8), ("1-2-2", 0xBB, 2), ("1-1-4", 0x6B, 8), ("1-4-4", 0xEB, 4)]);
+        assert_eq!(basic.fast_reads[3].mode_clocks, 2);
+    }
+
+    #[test]
+    fn read_space_in_pieces() {
+        let space = w25q32();
+        let mut reads = 0;
+        let read = Sfdp::read_space(|address, data: &mut [u8]| {
+            reads += 1;
+            data.copy_from_slice(&space[address..address + data.len()]);
+            Ok(())
+        }).unwrap();
+        assert_eq!(read, space);
+        assert_eq!(reads, 3);
+
+        // Parts without SFDP read as erased
+        let erased = Sfdp::read(|_, data: &mut [u8]| {
+            data.iter_mut().for_each(|x| *x = 0xFF);
+            Ok(())
+        });
+        assert!(matches!(erased, Err(Error::InvalidData(_))));
+
+        let mut truncated = w25q32();
+        truncated.truncate(0x90);
+        assert!(Sfdp::parse(&truncated).is_err());
+    }
+}
@@ -0,0 +1,60 @@
+//! Ed25519 signatures of firmware images, so that only images signed by a
+//! trusted key are flashed.
+
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use ed25519_dalek::{Signature, VerifyingKey};
+
+use super::{Error, Result};
+
+/// Size of a detached image signature
+pub const SIGNATURE_SIZE: usize = 64;
+
+/// Public keys embedded at build time from ECFLASH_TRUSTED_KEYS, a comma
+/// separated list of hex encoded ed25519 public keys
+pub fn trusted_keys() -> Vec<[u8; 32]> {
+    let keys = option_env!("ECFLASH_TRUSTED_KEYS").unwrap_or("");
+    keys.split(',')
+        .map(|key| key.trim())
+        .filter(|key| ! key.is_empty())
+        .filter_map(|key| {
+            if key.len() != 64 {
+                return None;
+            }
+            let mut bytes = [0; 32];
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(key.get(i * 2..i * 2 + 2)?, 16).ok()?;
+            }
+            Some(bytes)
+        })
+        .collect()
+}
+
+/// Check that the signature of data was made by one of the keys
+pub fn verify_signature(data: &[u8], signature: &[u8], keys: &[[u8; 32]]) -> Result<()> {
+    let signature: [u8; SIGNATURE_SIZE] = signature.try_into()
+        .map_err(|_| Error::InvalidData(format!("signature is {} bytes instead of {}", signature.len(), SIGNATURE_SIZE)))?;
+    let signature = Signature::from_bytes(&signature);
+
+    if keys.is_empty() {
+        return Err(Error::InvalidInput("no trusted keys".to_string()));
+    }
+
+    for key in keys {
+        if let Ok(key) = VerifyingKey::from_bytes(key) {
+            if key.verify_strict(data, &signature).is_ok() {
+                return Ok(());
+            }
+        }
+    }
+
+    Err(Error::InvalidData("signature does not match a trusted key".to_string()))
+}
+
+/// Check the signature of an image against the trusted keys, which every path
+/// that erases the flash calls first
+pub fn verify_image(data: &[u8], signature: Option<&[u8]>) -> Result<()> {
+    let signature = signature.ok_or_else(|| Error::InvalidInput("image is not signed".to_string()))?;
+    verify_signature(data, signature, &trusted_keys())
+}
@@ -0,0 +1,458 @@
+//! SPI flash behind the EC, driven through follow mode of any [`Smfi`]
+//! backend, as the in-system programmer does
+//!
+//! Follow mode passes bytes straight to the flash with chip select held, so
+//! the flash commands here are those of the part, not of the EC.
+
+use alloc::boxed::Box;
+use core::time::Duration;
+
+use super::{BasicFlash, Error, Result, SFDP_OPCODE, Sfdp, Smfi, Timeouts, Timer};
+#[cfg(any(test, feature = "model"))]
+use super::SpiFlashModel;
+
+/// the internal flash of the EC
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FlashChip {
+    /// The flash inside the EC
+    Internal,
+    /// The SPI flash on the FSPI pins of the EC
+    External,
+}
+
+impl FlashChip {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "internal" => Some(FlashChip::Internal),
+            "external" => Some(FlashChip::External),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FlashChip::Internal => "internal",
+            FlashChip::External => "external",
+        }
+    }
+
+    /// Address of the follow mode registers of the flash, whose second byte
+    /// selects chip select high or data
+    pub fn follow_address(self) -> u32 {
+        match self {
+            FlashChip::Internal => 0x7FFF_FE00,
+            FlashChip::External => 0xFFFF_FE00,
+        }
+    }
+}
+
+/// Follow mode access to the flash, which is deselected when dropped
+pub struct SpiBus<'a, T: Smfi> {
+    /// Backend, for SmfiAccel implementations that drive it directly
+    pub port: &'a mut T,
+    flash: FlashChip,
+    data: bool,
+}
+
+impl<'a, T: Smfi> SpiBus<'a, T> {
+    pub fn new(port: &'a mut T, flash: FlashChip) -> Result<Self> {
+        port.flash_address(flash.follow_address())?;
+
+        let mut spi = Self { port, flash, data: false };
+        spi.reset()?;
+        Ok(spi)
+    }
+
+    /// Disable SPI chip - should be done before and after each transaction
+    pub fn reset(&mut self) -> Result<()> {
+        if self.data {
+            self.port.flash_indar1(0xFE)?;
+            self.data = false;
+        }
+        self.port.flash_write(&[0])?;
+        Ok(())
+    }
+
+    /// Read from SPI chip directly using follow mode
+    pub fn read(&mut self, data: &mut [u8]) -> Result<usize> {
+        if !self.data {
+            self.port.flash_indar1(0xFD)?;
+            self.data = true;
+        }
+        self.port.flash_read(data)
+    }
+
+    /// Write to SPI chip directly using follow mode
+    pub fn write(&mut self, data: &[u8]) -> Result<usize> {
+        if !self.data {
+            self.port.flash_indar1(0xFD)?;
+            self.data = true;
+        }
+        self.port.flash_write(data)
+    }
+}
+
+impl<'a, T: SmfiAccel> SpiBus<'a, T> {
+    /// Reopen the transport after it failed, and select the flash again,
+    /// returning false if the transport cannot be reopened
+    pub fn reconnect(&mut self) -> Result<bool> {
+        if ! self.port.reconnect()? {
+            return Ok(false);
+        }
+        self.port.flash_address(self.flash.follow_address())?;
+        self.data = false;
+        self.reset()?;
+        Ok(true)
+    }
+}
+
+impl<'a, T: Smfi> Drop for SpiBus<'a, T> {
+    fn drop(&mut self) {
+        let _ = self.reset();
+    }
+}
+
+/// SPI flash part behind the EC, which decides how sectors are erased
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SpiChip {
+    /// JEDEC manufacturer and device ID, all 0xFF if the flash did not answer
+    pub jedec_id: [u8; 3],
+    /// Opcode that erases one sector
+    pub erase_opcode: u8,
+    /// Bytes erased by erase_opcode
+    pub sector_size: usize,
+}
+
+impl SpiChip {
+    pub fn from_jedec_id(jedec_id: [u8; 3]) -> Self {
+        match jedec_id[0] {
+            // The internal flash of ITE ECs does not answer, and like SST
+            // parts erases 1 KiB sectors
+            0x00 | 0xFF | 0xBF => Self { jedec_id, erase_opcode: 0xD7, sector_size: 1024 },
+            // Other parts use the standard 4 KiB sector erase
+            _ => Self { jedec_id, erase_opcode: 0x20, sector_size: 4096 },
+        }
+    }
+
+    /// Erase with the smallest erase type of the SFDP basic table of the part,
+    /// or as from_jedec_id if it lists none
+    pub fn from_sfdp(jedec_id: [u8; 3], basic: &BasicFlash) -> Self {
+        match basic.erase_types.first() {
+            Some(erase) => Self { jedec_id, erase_opcode: erase.opcode, sector_size: erase.size },
+            None => Self::from_jedec_id(jedec_id),
+        }
+    }
+}
+
+/// Commands of the SPI flash on a bus, which is write disabled when dropped
+pub struct SpiRom<'a, 't, T: Smfi> {
+    pub bus: &'a mut SpiBus<'t, T>,
+    /// Limits for status polling, only the busy timeouts are used since the
+    /// transport has its own
+    pub timeouts: Timeouts,
+    /// Part that erase_sector erases, the internal flash until detect
+    pub chip: SpiChip,
+    timer: Box<dyn Timer + Send>,
+}
+
+impl<'a, 't, T: Smfi> SpiRom<'a, 't, T> {
+    pub fn new(bus: &'a mut SpiBus<'t, T>) -> Self {
+        Self {
+            bus,
+            timeouts: Timeouts::default(),
+            chip: SpiChip::from_jedec_id([0xFF; 3]),
+            #[cfg(feature = "std")]
+            timer: Box::new(super::StdTimer::new()),
+            #[cfg(not(feature = "std"))]
+            timer: Box::new(super::CounterTimer::new()),
+        }
+    }
+
+    /// Set the timer used for the busy timeouts
+    pub fn set_timer<U: Timer + Send + 'static>(&mut self, timer: U) {
+        self.timer = Box::new(timer);
+    }
+
+    /// Read the JEDEC ID to find which part is connected, then configure
+    /// erase from its SFDP tables if it answers them
+    ///
+    /// The internal flash of ITE ECs answers neither, and SFDP that is missing
+    /// or corrupted falls back to the JEDEC ID.
+    pub fn detect(&mut self) -> Result<SpiChip> {
+        let mut jedec_id = [0; 3];
+
+        self.bus.reset()?;
+        self.bus.write(&[0x9F])?;
+        self.bus.read(&mut jedec_id)?;
+
+        self.chip = SpiChip::from_jedec_id(jedec_id);
+        if ! matches!(jedec_id[0], 0x00 | 0xFF) {
+            match self.sfdp().and_then(|sfdp| sfdp.basic()) {
+                Ok(basic) => self.chip = SpiChip::from_sfdp(jedec_id, &basic),
+                Err(Error::InvalidData(_)) => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(self.chip)
+    }
+
+    /// Read the SFDP space from address
+    pub fn read_sfdp(&mut self, address: u32, data: &mut [u8]) -> Result<usize> {
+        if (address & 0xFF00_0000) > 0 {
+            return Err(Error::InvalidInput(
+                format!("address {:X} exceeds 24 bits", address)
+            ));
+        }
+
+        self.bus.reset()?;
+        self.bus.write(&[
+            SFDP_OPCODE,
+            (address >> 16) as u8,
+            (address >> 8) as u8,
+            address as u8,
+            0,
+        ])?;
+        self.bus.read(data)
+    }
+
+    /// Read and parse the SFDP tables of the part
+    pub fn sfdp(&mut self) -> Result<Sfdp> {
+        Sfdp::read(|address, data| self.read_sfdp(address as u32, data).map(|_| ()))
+    }
+
+    /// Poll status until done returns true, or fail after timeout
+    pub fn wait_status<F: Fn(u8) -> bool>(&mut self, timeout: Duration, done: F) -> Result<()> {
+        let timeout_us = timeout.as_micros() as u64;
+        let start = self.timer.now_us();
+        loop {
+            let status = self.status()?;
+            if done(status) {
+                return Ok(());
+            }
+            if self.timer.now_us().wrapping_sub(start) >= timeout_us {
+                return Err(Error::Transport(
+                    format!("timed out after {:?} with SPI status {:02X}", timeout, status)
+                ));
+            }
+        }
+    }
+
+    pub fn status(&mut self) -> Result<u8> {
+        let mut status = [0];
+
+        self.bus.reset()?;
+        self.bus.write(&[0x05])?;
+        self.bus.read(&mut status)?;
+
+        Ok(status[0])
+    }
+
+    pub fn write_disable(&mut self) -> Result<()> {
+        self.bus.reset()?;
+        self.bus.write(&[0x04])?;
+
+        // Poll status for busy and write enable flags
+        self.wait_status(self.timeouts.write_busy, |status| status & 3 == 0)?;
+
+        Ok(())
+    }
+
+    pub fn write_enable(&mut self) -> Result<()> {
+        self.bus.reset()?;
+        self.bus.write(&[0x06])?;
+
+        // Poll status for busy and write enable flags
+        self.wait_status(self.timeouts.write_busy, |status| status & 3 == 2)?;
+
+        Ok(())
+    }
+
+    pub fn erase_chip(&mut self) -> Result<()> {
+        self.write_enable()?;
+
+        self.bus.reset()?;
+        self.bus.write(&[0x60])?;
+
+        // Poll status for busy flag, a chip erase takes many block erases
+        self.wait_status(self.timeouts.erase_busy * 64, |status| status & 1 == 0)?;
+
+        self.write_disable()?;
+
+        Ok(())
+    }
+
+    /// Erase the sector that holds address, returning the sector size
+    pub fn erase_sector(&mut self, address: u32) -> Result<usize> {
+        if (address & 0xFF00_0000) > 0 {
+            return Err(Error::InvalidInput(
+                format!("address {:X} exceeds 24 bits", address)
+            ));
+        }
+
+        self.write_enable()?;
+
+        self.bus.reset()?;
+        self.bus.write(&[
+            self.chip.erase_opcode,
+            (address >> 16) as u8,
+            (address >> 8) as u8,
+            address as u8,
+        ])?;
+
+        // Poll status for busy flag
+        self.wait_status(self.timeouts.erase_busy, |status| status & 1 == 0)?;
+
+        self.write_disable()?;
+
+        Ok(self.chip.sector_size)
+    }
+
+    /// Send a fast read command, after which the bus reads from address on
+    pub fn read_command(&mut self, address: u32) -> Result<()> {
+        if (address & 0xFF00_0000) > 0 {
+            return Err(Error::InvalidInput(
+                format!("address {:X} exceeds 24 bits", address)
+            ));
+        }
+
+        self.bus.reset()?;
+        self.bus.write(&[
+            0x0B,
+            (address >> 16) as u8,
+            (address >> 8) as u8,
+            address as u8,
+            0,
+        ])?;
+        Ok(())
+    }
+
+    pub fn read_at(&mut self, address: u32, data: &mut [u8]) -> Result<usize> {
+        self.read_command(address)?;
+        self.bus.read(data)
+    }
+
+    pub fn write_at(&mut self, address: u32, data: &[u8]) -> Result<usize> {
+        if (address & 0xFF00_0000) > 0 {
+            return Err(Error::InvalidInput(
+                format!("address {:X} exceeds 24 bits", address)
+            ));
+        }
+
+        //TODO: Support programming with any length
+        if !data.len().is_multiple_of(2) {
+            return Err(Error::InvalidInput(
+                format!("length {} is not a multiple of 2", data.len())
+            ));
+        }
+
+        self.write_enable()?;
+
+        for (i, word) in data.chunks_exact(2).enumerate() {
+            self.bus.reset()?;
+            if i == 0 {
+                self.bus.write(&[
+                    0xAD,
+                    (address >> 16) as u8,
+                    (address >> 8) as u8,
+                    address as u8,
+                    word[0],
+                    word[1]
+                ])?;
+            } else {
+                self.bus.write(&[
+                    0xAD,
+                    word[0],
+                    word[1]
+                ])?;
+            }
+
+            // Poll status for busy flag
+            self.wait_status(self.timeouts.write_busy, |status| status & 1 == 0)?;
+        }
+
+        self.write_disable()?;
+
+        Ok(data.len())
+    }
+}
+
+impl<'a, 't, T: Smfi> Drop for SpiRom<'a, 't, T> {
+    fn drop(&mut self) {
+        let _ = self.write_disable();
+    }
+}
+
+/// Backends that may program the SPI ROM faster than word by word
+pub trait SmfiAccel: Smfi + Sized {
+    /// Whether program_aai_bulk is faster than the default
+    fn accelerated(&self) -> bool {
+        false
+    }
+
+    /// Reopen the transport after it failed, returning false if it cannot be
+    /// reopened
+    fn reconnect(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Program data at address with auto address increment word program
+    fn program_aai_bulk(rom: &mut SpiRom<'_, '_, Self>, address: u32, data: &[u8]) -> Result<usize> {
+        rom.write_at(address, data)
+    }
+
+    /// Read the ROM from address and compare it with expected, returning the
+    /// first address that differs and the byte read there
+    fn verify_bulk(rom: &mut SpiRom<'_, '_, Self>, address: u32, expected: &[u8]) -> Result<Option<(usize, u8)>> {
+        let mut data = vec![0; expected.len()];
+        rom.read_at(address, &mut data)?;
+        Ok(data.iter().zip(expected).position(|(a, b)| a != b).map(|i| (address as usize + i, data[i])))
+    }
+}
+
+#[cfg(any(test, feature = "model"))]
+impl SmfiAccel for SpiFlashModel {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    #[test]
+    fn spi_rom_erase_write_read() {
+        let mut flash = SpiFlashModel::new(vec![0; 4096]);
+        {
+            let mut bus = SpiBus::new(&mut flash, FlashChip::Internal).unwrap();
+            let mut rom = SpiRom::new(&mut bus);
+            rom.erase_sector(0x400).unwrap();
+            rom.write_at(0x400, &pattern(512)).unwrap();
+
+            let mut data = vec![0; 1024];
+            rom.read_at(0x400, &mut data).unwrap();
+            assert_eq!(&data[..512], &pattern(512)[..]);
+            assert!(data[512..].iter().all(|&x| x == 0xFF));
+        }
+        assert_eq!(flash.data[0x3FF], 0);
+        assert_eq!(flash.data[0x800], 0);
+        assert_eq!(flash.rejected, 0);
+    }
+
+    #[test]
+    fn spi_rom_erases_detected_sector_size() {
+        let mut flash = SpiFlashModel::new(vec![0; 16 * 1024]);
+        flash.jedec_id = Some([0xEF, 0x40, 0x14]);
+        {
+            let mut bus = SpiBus::new(&mut flash, FlashChip::Internal).unwrap();
+            let mut rom = SpiRom::new(&mut bus);
+            assert_eq!(rom.detect().unwrap().sector_size, 4096);
+            assert_eq!(rom.erase_sector(0x1000).unwrap(), 4096);
+        }
+        assert_eq!(flash.data[0xFFF], 0);
+        assert!(flash.data[0x1000..0x2000].iter().all(|&x| x == 0xFF));
+        assert_eq!(flash.data[0x2000], 0);
+        assert_eq!(flash.rejected, 0);
+    }
+}
@@ -0,0 +1,241 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+use super::{Error, Result};
+
+/// Variable of the EC firmware, from the linker map of its build
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Symbol {
+    /// Name as the linker writes it, such as _power_state for power_state
+    pub name: String,
+    /// Address in the memory space of its area
+    pub address: u16,
+    /// Bytes up to the next symbol or the end of its area, if known
+    pub size: Option<usize>,
+    /// Area the symbol is linked into, such as XSEG
+    pub area: String,
+    /// Whether the area is in external data memory, which is the EC memory
+    /// that ECMS and I2EC reach
+    pub xdata: bool,
+    /// Whether the area is code, so that the symbol is a function or a label
+    pub code: bool,
+}
+
+impl Symbol {
+    /// Name as written in C, without the underscore that the linker adds
+    pub fn c_name(&self) -> &str {
+        self.name.strip_prefix('_').unwrap_or(&self.name)
+    }
+}
+
+/// Symbols of the EC firmware, parsed from the .map file that the SDCC linker
+/// writes next to the image
+///
+/// Each area starts with a line of its name, address, size, and attributes,
+/// followed by its global symbols, one address and name per line:
+///
+/// ```text
+/// Area                    Addr        Size        Decimal Bytes (Attributes)
+/// --------------------    ----        ----        ------- ----- ------------
+/// XSEG                    00000000    00000010 =          16. bytes (REL,CON,XDATA)
+///
+///       Value  Global           Global Defined In Module
+///       -----  --------------------------------
+///      00000000  _power_state     power
+///      00000001  _battery_temp    battery
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SymbolMap {
+    symbols: Vec<Symbol>,
+}
+
+/// Areas of external data memory, for maps that omit the XDATA attribute
+const XDATA_AREAS: &[&str] = &["XSEG", "XISEG", "XABS", "PSEG"];
+/// Areas of code, for maps that omit the CODE attribute
+const CODE_AREAS: &[&str] = &["CSEG", "HOME", "GSINIT", "GSFINAL", "CABS"];
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s.trim_start_matches("0x"), 16).ok()
+}
+
+fn is_identifier(s: &str) -> bool {
+    s.chars().next().is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+        && s.chars().all(|c| c == '_' || c == '$' || c.is_ascii_alphanumeric())
+}
+
+impl SymbolMap {
+    /// Parse the text of a linker map
+    pub fn parse(text: &str) -> Result<Self> {
+        // Name, address, size, and whether it is xdata, of the current area
+        let mut area: Option<(String, u32, u32, bool, bool)> = None;
+        let mut symbols = Vec::new();
+        // Symbols of the current area, with their address as linked
+        let mut pending: Vec<(String, u32)> = Vec::new();
+
+        let mut finish = |area: &Option<(String, u32, u32, bool, bool)>, pending: &mut Vec<(String, u32)>| {
+            let (area, start, size, xdata, code) = match area {
+                Some(area) => area,
+                None => return,
+            };
+            let end = start.saturating_add(*size);
+            let mut addresses: Vec<u32> = pending.iter().map(|(_, address)| *address).collect();
+            addresses.sort_unstable();
+            addresses.dedup();
+            for (name, address) in pending.drain(..) {
+                let address16 = match u16::try_from(address) {
+                    Ok(address16) => address16,
+                    Err(_) => continue,
+                };
+                let next = addresses.iter().copied().find(|&next| next > address).unwrap_or(end);
+                symbols.push(Symbol {
+                    name,
+                    address: address16,
+                    size: if next > address { Some((next - address) as usize) } else { None },
+                    area: area.clone(),
+                    xdata: *xdata,
+                    code: *code,
+                });
+            }
+        };
+
+        for line in text.lines() {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+
+            // Area header, whose name may contain spaces, as in ". .ABS."
+            if let Some(equals) = tokens.iter().position(|&token| token == "=") {
+                if equals >= 3 && line.contains("bytes") {
+                    if let (Some(start), Some(size)) = (parse_hex(tokens[equals - 2]), parse_hex(tokens[equals - 1])) {
+                        finish(&area, &mut pending);
+                        let name = tokens[..equals - 2].join(" ");
+                        let attributes = line.split_once('(')
+                            .and_then(|(_, rest)| rest.split_once(')'))
+                            .map_or("", |(attributes, _)| attributes);
+                        let has = |attribute: &str| attributes.split(',').any(|x| x.trim() == attribute);
+                        let xdata = has("XDATA") || XDATA_AREAS.contains(&name.as_str());
+                        let code = has("CODE") || CODE_AREAS.contains(&name.as_str());
+                        area = Some((name, start, size, xdata, code));
+                    }
+                    continue;
+                }
+            }
+
+            if area.is_none() || tokens.len() < 2 {
+                continue;
+            }
+            // Newer linkers prefix values with their memory space, as in X:
+            let value = match tokens[0].split_once(':') {
+                Some((space, value)) if space.len() == 1 => value,
+                _ => tokens[0],
+            };
+            if let (Some(address), true) = (parse_hex(value), is_identifier(tokens[1])) {
+                if value.len() >= 4 {
+                    pending.push((tokens[1].to_string(), address));
+                }
+            }
+        }
+        finish(&area, &mut pending);
+
+        if symbols.is_empty() {
+            return Err(Error::InvalidData("no symbols found in the linker map".to_string()));
+        }
+        Ok(SymbolMap { symbols })
+    }
+
+    /// Every symbol, in the order of the map
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    /// Symbol by its C name, such as power_state, or by its name in the map
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter()
+            .find(|symbol| symbol.name == name)
+            .or_else(|| self.symbols.iter().find(|symbol| symbol.name.strip_prefix('_') == Some(name)))
+    }
+
+    /// Function or label of code that address is in, which is the closest
+    /// code symbol at or below it
+    pub fn code_symbol(&self, address: u16) -> Option<&Symbol> {
+        self.symbols.iter()
+            .filter(|symbol| symbol.code && symbol.address <= address)
+            .max_by_key(|symbol| symbol.address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: &str = "\
+Area                    Addr        Size        Decimal Bytes (Attributes)
+--------------------    ----        ----        ------- ----- ------------
+.  .ABS.                00000000    00000000 =           0. bytes (ABS,CON)
+
+      Value  Global           Global Defined In Module
+      -----  --------------------------------
+     00000010  l_XSEG
+     0000000F  s_XSEG
+
+Area                    Addr        Size        Decimal Bytes (Attributes)
+--------------------    ----        ----        ------- ----- ------------
+DSEG                    00000008    00000004 =           4. bytes (REL,CON)
+
+      Value  Global           Global Defined In Module
+      -----  --------------------------------
+     00000008  _counter         main
+
+Area                    Addr        Size        Decimal Bytes (Attributes)
+--------------------    ----        ----        ------- ----- ------------
+XSEG                    00000100    00000010 =          16. bytes (REL,CON,XDATA)
+
+      Value  Global           Global Defined In Module
+      -----  --------------------------------
+   X:00000100  _power_state     power
+   X:00000101  _battery_voltage battery
+   X:00000103  _fan_table       fan
+";
+
+    #[test]
+    fn parse_areas_and_sizes() {
+        let map = SymbolMap::parse(MAP).unwrap();
+        assert_eq!(map.symbols().len(), 6);
+
+        let power = map.get("power_state").unwrap();
+        assert_eq!((power.address, power.size, power.xdata), (0x100, Some(1), true));
+        assert_eq!(map.get("_battery_voltage").unwrap().size, Some(2));
+        // The last symbol of an area extends to its end
+        assert_eq!(map.get("fan_table").unwrap().size, Some(0x0D));
+
+        let counter = map.get("counter").unwrap();
+        assert_eq!((counter.area.as_str(), counter.xdata), ("DSEG", false));
+        assert_eq!(map.get("l_XSEG").unwrap().area, ". .ABS.");
+        assert_eq!(map.get("missing"), None);
+    }
+
+    #[test]
+    fn code_symbols() {
+        let map = SymbolMap::parse(&format!("{}{}", MAP, "\
+Area                    Addr        Size        Decimal Bytes (Attributes)
+--------------------    ----        ----        ------- ----- ------------
+CSEG                    00000200    00000100 =         256. bytes (REL,CON,CODE)
+
+      Value  Global           Global Defined In Module
+      -----  --------------------------------
+   C:00000200  _main            main
+   C:00000240  _power_event     power
+")).unwrap();
+
+        assert_eq!(map.code_symbol(0x1FF), None);
+        assert_eq!(map.code_symbol(0x200).unwrap().c_name(), "main");
+        assert_eq!(map.code_symbol(0x23F).unwrap().c_name(), "main");
+        assert_eq!(map.code_symbol(0x2A0).unwrap().c_name(), "power_event");
+        assert!(! map.get("power_state").unwrap().code);
+    }
+
+    #[test]
+    fn parse_empty_map() {
+        assert!(SymbolMap::parse("").is_err());
+        assert!(SymbolMap::parse("Area Addr Size\n").is_err());
+    }
+}
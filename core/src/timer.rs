@@ -0,0 +1,95 @@
+use core::time::Duration;
+
+/// Monotonic time source for timeouts
+pub trait Timer {
+    /// Microseconds since an arbitrary starting point
+    fn now_us(&mut self) -> u64;
+
+    /// Wait for at least the given number of microseconds
+    fn delay_us(&mut self, us: u64) {
+        let start = self.now_us();
+        while self.now_us().wrapping_sub(start) < us {}
+    }
+}
+
+/// Sleeping between polls of a wait that takes longer than a fast spin window,
+/// so that waiting on a busy flash does not keep a CPU core at full load
+///
+/// The sleep starts at min_sleep and doubles on each poll up to max_sleep.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Backoff {
+    /// How long to poll without sleeping
+    pub spin: Duration,
+    /// First sleep after the spin window
+    pub min_sleep: Duration,
+    /// Longest sleep between polls
+    pub max_sleep: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            spin: Duration::from_millis(1),
+            min_sleep: Duration::from_micros(10),
+            max_sleep: Duration::from_millis(1),
+        }
+    }
+}
+
+/// Timer that counts calls instead of measuring time, for when no clock is
+/// available
+///
+/// Each poll of the EC does one port read, which takes about a microsecond on
+/// the LPC bus, so one call is counted as one microsecond. Real timeouts will
+/// still vary with the bus and chipset.
+#[derive(Clone, Debug, Default)]
+pub struct CounterTimer {
+    count: u64,
+}
+
+impl CounterTimer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Timer for CounterTimer {
+    fn now_us(&mut self) -> u64 {
+        self.count += 1;
+        self.count
+    }
+}
+
+/// Timer using the monotonic clock of the operating system
+#[cfg(feature = "std")]
+#[derive(Clone, Debug)]
+pub struct StdTimer {
+    start: std::time::Instant,
+}
+
+#[cfg(feature = "std")]
+impl StdTimer {
+    pub fn new() -> Self {
+        Self {
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for StdTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Timer for StdTimer {
+    fn now_us(&mut self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+
+    fn delay_us(&mut self, us: u64) {
+        std::thread::sleep(std::time::Duration::from_micros(us));
+    }
+}
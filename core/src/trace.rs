@@ -0,0 +1,62 @@
+use core::fmt;
+
+/// A transaction with the EC, as recorded by a Trace
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TraceEvent {
+    /// Command byte taken by the EC
+    Command(u8),
+    /// Data byte taken by the EC
+    Write(u8),
+    /// Data byte returned by the EC
+    Read(u8),
+    /// The EC did not become ready in time
+    Timeout,
+    /// SPI opcode sent to the flash in follow mode
+    SpiOpcode(u8),
+    /// Flash address of the SPI transfer that follows
+    SpiAddress(u32),
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceEvent::Command(value) => write!(f, "cmd 0x{:02X}", value),
+            TraceEvent::Write(value) => write!(f, "write 0x{:02X}", value),
+            TraceEvent::Read(value) => write!(f, "read 0x{:02X}", value),
+            TraceEvent::Timeout => write!(f, "timeout"),
+            TraceEvent::SpiOpcode(value) => write!(f, "spi opcode 0x{:02X}", value),
+            TraceEvent::SpiAddress(address) => write!(f, "spi address 0x{:06X}", address),
+        }
+    }
+}
+
+/// Receiver of every transaction with the EC, for postmortems of failed
+/// flashes
+pub trait Trace {
+    /// Record an event at a time in microseconds from the EC timer
+    fn event(&mut self, time_us: u64, event: TraceEvent);
+}
+
+/// Trace that writes one line per event, with the time in seconds
+///
+/// Lines are written as they happen, unbuffered, so that the last one is what
+/// the EC last acknowledged even if the process exits right after.
+#[cfg(feature = "std")]
+pub struct TraceWriter<W: std::io::Write> {
+    writer: W,
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write> TraceWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write> Trace for TraceWriter<W> {
+    fn event(&mut self, time_us: u64, event: TraceEvent) {
+        let line = format!("{}.{:06} {}\n", time_us / 1_000_000, time_us % 1_000_000, event);
+        let _ = self.writer.write_all(line.as_bytes());
+    }
+}
@@ -0,0 +1,295 @@
+//! Emulation of the parallel port Arduino programmer and the EC behind it, for
+//! developing the ISP code path without hardware
+//!
+//! The programmer is reached through a pseudo terminal, whose path is printed
+//! on start:
+//!
+//! ```text
+//! cargo run --example fake-programmer -- firmware.rom
+//! cargo run --example isp -- --programmer /dev/pts/N new.rom
+//! ```
+//!
+//! With `--protocol 3`, it answers as the firmware of an RP2040 dongle, with
+//! two byte lengths and a larger buffer. The host must then be told the
+//! version, as a pseudo terminal has no USB vendor ID to detect it from.
+//!
+//! The flash starts with the contents of IMAGE, or erased, and is saved back
+//! to IMAGE each time the host closes the port.
+//!
+//! The 8051 core behind the debugger runs a 3 byte instruction per register
+//! access, so halt, step, and sampling the program counter have something to
+//! show.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use ecflash::{Address, DBGCTRL_HALT, DBGCTRL_STEP, DBGSTS_HALTED, Protocol, Smfi, SpiFlashModel};
+
+/// Bytes the emulated sketch buffers per command
+const BUFFER_SIZE: usize = 128;
+/// Bytes the emulated RP2040 firmware buffers per command, which needs the two
+/// byte lengths of version 3
+const PICO_BUFFER_SIZE: usize = 1024;
+
+/// Bytes of code that the emulated core loops through
+const CODE_SIZE: u16 = 0x8000;
+
+/// ITE debugger registers, with EC-indirect access to the flash behind them
+struct Ec {
+    regs: [u8; 256],
+    flash: SpiFlashModel,
+    halted: bool,
+    pc: u16,
+}
+
+impl Ec {
+    fn indar(&self) -> u32 {
+        u32::from_le_bytes([
+            self.regs[Address::INDAR0 as usize],
+            self.regs[Address::INDAR1 as usize],
+            self.regs[Address::INDAR2 as usize],
+            self.regs[Address::INDAR3 as usize],
+        ])
+    }
+
+    /// Run one instruction of the core
+    fn run(&mut self) {
+        self.pc = (self.pc + 3) % CODE_SIZE;
+    }
+
+    fn read(&mut self, address: u8) -> u8 {
+        if ! self.halted {
+            self.run();
+        }
+        let mut value = [self.regs[address as usize]];
+        if address == Address::INDDR as u8 {
+            let _ = self.flash.flash_read(&mut value);
+        } else if address == Address::DBGSTS as u8 {
+            value[0] = if self.halted { DBGSTS_HALTED } else { 0 };
+        } else if address == Address::DBGPC0 as u8 {
+            value[0] = self.pc as u8;
+        } else if address == Address::DBGPC1 as u8 {
+            value[0] = (self.pc >> 8) as u8;
+        }
+        value[0]
+    }
+
+    fn write(&mut self, address: u8, value: u8) {
+        if address == Address::DBGCTRL as u8 {
+            if self.halted && value & DBGCTRL_STEP != 0 {
+                self.run();
+            }
+            self.halted = value & DBGCTRL_HALT != 0;
+        } else if address == Address::INDDR as u8 {
+            let _ = self.flash.flash_write(&[value]);
+            return;
+        }
+
+        self.regs[address as usize] = value;
+        if (Address::INDAR0 as u8..=Address::INDAR3 as u8).contains(&address) {
+            let _ = self.flash.flash_address(self.indar());
+        }
+    }
+}
+
+/// Read a length in the encoding of protocol
+fn read_length(port: &mut File, protocol: &dyn Protocol) -> io::Result<usize> {
+    let mut b = vec![0; protocol.length_bytes()];
+    port.read_exact(&mut b)?;
+    Ok(protocol.decode_length(&b))
+}
+
+/// Serve one session of the programmer protocol until the host closes the port
+fn session(port: &mut File, ec: &mut Ec, protocol: &dyn Protocol, buffer_size: usize) -> io::Result<()> {
+    let mut address = 0;
+    let mut buf = vec![0; buffer_size];
+    loop {
+        let mut command = [0];
+        port.read_exact(&mut command)?;
+        let command = command[0];
+
+        match command {
+            b'E' => {
+                let mut frame = [0; 2];
+                port.read_exact(&mut frame)?;
+                port.write_all(&frame[1..])?;
+            },
+            b'B' => {
+                let mut param = [0];
+                port.read_exact(&mut param)?;
+                port.write_all(&protocol.length(buffer_size))?;
+            },
+            b'A' => {
+                let mut param = [0];
+                port.read_exact(&mut param)?;
+                address = param[0];
+            },
+            b'R' | b'r' => {
+                if command == b'r' {
+                    let mut param = [0];
+                    port.read_exact(&mut param)?;
+                    address = param[0];
+                }
+                let len = read_length(port, protocol)?;
+                for x in buf[..len].iter_mut() {
+                    *x = ec.read(address);
+                }
+                port.write_all(&buf[..len])?;
+            },
+            b'W' | b'w' => {
+                if command == b'w' {
+                    let mut param = [0];
+                    port.read_exact(&mut param)?;
+                    address = param[0];
+                }
+                let len = read_length(port, protocol)?;
+                port.read_exact(&mut buf[..len])?;
+                for &x in buf[..len].iter() {
+                    ec.write(address, x);
+                }
+                port.write_all(&protocol.length(len))?;
+            },
+            b'P' => {
+                // The sketch sends the start of the ROM as the address of the
+                // first word, then continues the auto address increment
+                let len = read_length(port, protocol)?;
+                port.read_exact(&mut buf[..len])?;
+                let flash = &mut ec.flash;
+                for word in buf[..len].chunks(2) {
+                    let word = [word[0], *word.get(1).unwrap_or(&0xFF)];
+                    let transaction: &[u8] = if flash.status() & 0x40 == 0 {
+                        &[0xAD, 0, 0, 0, word[0], word[1]]
+                    } else {
+                        &[0xAD, word[0], word[1]]
+                    };
+                    // Deassert chip select first, as after a status read
+                    flash.spi_end();
+                    for &x in transaction {
+                        flash.spi_write(x);
+                    }
+                    flash.spi_end();
+
+                    // Poll status until the word is programmed
+                    flash.spi_write(0x05);
+                    while flash.spi_read() & 1 != 0 {}
+                    flash.spi_end();
+                }
+                port.write_all(&protocol.length(len))?;
+            },
+            _ => eprintln!("Unknown command {:02X}", command),
+        }
+    }
+}
+
+/// Open a pseudo terminal in raw mode, returning the master and the path of
+/// the slave
+fn open_pty() -> io::Result<(File, String)> {
+    unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master = File::from_raw_fd(fd);
+
+        if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut path = [0 as libc::c_char; 64];
+        if libc::ptsname_r(fd, path.as_mut_ptr(), path.len()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let path = std::ffi::CStr::from_ptr(path.as_ptr()).to_string_lossy().into_owned();
+
+        Ok((master, path))
+    }
+}
+
+fn main() {
+    let mut id = 0x8587;
+    let mut size = 128 * 1024;
+    let mut version = 1;
+    let mut image = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--id" {
+            let value = args.next().expect("--id requires a chip ID");
+            id = u16::from_str_radix(value.trim_start_matches("0x"), 16).expect("invalid chip ID");
+        } else if arg == "--size" {
+            let value = args.next().expect("--size requires a size in KiB");
+            size = value.parse::<usize>().expect("invalid size") * 1024;
+        } else if arg == "--protocol" {
+            let value = args.next().expect("--protocol requires a version number");
+            version = value.parse().expect("invalid protocol version");
+        } else {
+            image = Some(arg);
+        }
+    }
+
+    let protocol = ecflash::protocol(version).expect("unknown protocol version");
+    let buffer_size = if version >= 3 { PICO_BUFFER_SIZE } else { BUFFER_SIZE };
+
+    let mut data = match &image {
+        Some(image) => fs::read(image).expect("failed to read image"),
+        None => Vec::new(),
+    };
+    data.resize(size.max(data.len()), 0xFF);
+    let mut saved = data.clone();
+
+    let mut ec = Ec {
+        regs: [0; 256],
+        flash: SpiFlashModel::new(data),
+        halted: false,
+        pc: 0,
+    };
+    ec.regs[Address::CHIPID0 as usize] = (id >> 8) as u8;
+    ec.regs[Address::CHIPID1 as usize] = id as u8;
+    ec.regs[Address::CHIPVER as usize] = 3;
+
+    let (mut port, path) = match open_pty() {
+        Ok(pty) => pty,
+        Err(err) => {
+            eprintln!("Failed to open pseudo terminal: {}", err);
+            process::exit(1);
+        }
+    };
+    println!("Programmer on {}", path);
+
+    loop {
+        // Reading fails until the host opens the port, and after it closes it
+        if let Err(err) = session(&mut port, &mut ec, &*protocol, buffer_size) {
+            if err.raw_os_error() != Some(libc::EIO) {
+                eprintln!("Session failed: {}", err);
+            }
+        }
+
+        // Deassert chip select, as a reset of the sketch would
+        ec.flash.spi_end();
+
+        if ec.flash.data != saved {
+            if let Some(image) = &image {
+                match fs::write(image, &ec.flash.data) {
+                    Ok(()) => eprintln!("Saved flash to {}", image),
+                    Err(err) => eprintln!("Failed to save flash to {}: {}", image, err),
+                }
+            }
+            saved = ec.flash.data.clone();
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+}
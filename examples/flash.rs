@@ -2,14 +2,10 @@
 
 extern crate ecflash;
 
-use ecflash::{EcFlash, Flasher};
+use ecflash::{EcFlash, Flasher, Handshake};
 use std::{env, fs, io, process, thread, time};
 
 fn main() {
-    extern {
-        fn iopl(level: isize) -> isize;
-    }
-
     let path = env::args().nth(1).expect("no path argument");
 
     let mut data = fs::read(path).expect("Failed to open rom");
@@ -19,13 +15,11 @@ fn main() {
     thread::sleep(time::Duration::new(1, 0));
 
     eprintln!("Sync");
-    process::Command::new("sync")
-        .status()
-        .expect("failed to run sync");
+    unsafe { libc::sync() };
 
     // Get I/O Permission
     unsafe {
-        if iopl(3) < 0 {
+        if libc::iopl(3) < 0 {
             eprintln!("Failed to get I/O permission: {}", io::Error::last_os_error());
             process::exit(1);
         }
@@ -38,7 +32,8 @@ fn main() {
             data.push(0xFF);
         }
 
-        if flasher.start() == Ok(51) {
+        let handshake = flasher.start();
+        if handshake == Ok(Handshake::Accepted) {
             let mut success = false;
 
             if let Ok(_original) = flasher.read(|x| eprint!("\rRead: {} KB", x / 1024)) {
@@ -52,7 +47,7 @@ fn main() {
 
                         //TODO: retry erase on fail
                         for i in 0..erased.len() {
-                            if erased[i] != 0xFF {
+                            if erased[i] != 0xFF && ! flasher.is_protected(i..i + 1) {
                                 println!(
                                     "0x{:X}: 0x{:02X} != 0xFF",
                                     i,
@@ -69,7 +64,7 @@ fn main() {
 
                                 success = true;
                                 for i in 0..written.len() {
-                                    if written[i] != data[i] {
+                                    if written[i] != data[i] && ! flasher.is_protected(i..i + 1) {
                                         println!(
                                             "0x{:X}: 0x{:02X} != 0x{:02X}",
                                             i,
@@ -96,9 +91,7 @@ fn main() {
             }
 
             eprintln!("Sync");
-            process::Command::new("sync")
-                .status()
-                .expect("failed to run sync");
+            libc::sync();
 
             // Will currently power off system
             let _ = flasher.stop();
@@ -106,15 +99,17 @@ fn main() {
             if success {
                 eprintln!("Successfully flashed EC");
 
-                // Shut down
-                process::Command::new("shutdown")
-                    .status()
-                    .expect("failed to run shutdown");
+                // Shut down, if the EC has not cut power already
+                libc::sync();
+                libc::reboot(libc::RB_POWER_OFF);
             } else {
                 eprintln!("Failed to flash EC");
             }
         } else {
-            eprintln!("Failed to start flasher");
+            match handshake {
+                Ok(handshake) => eprintln!("Failed to start flasher: {}", handshake),
+                Err(()) => eprintln!("Failed to start flasher"),
+            }
         }
     }
 }
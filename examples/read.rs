@@ -1,10 +1,15 @@
 extern crate ecflash;
 
-use ecflash::{EcFlash, Flasher};
-use std::{fs, io, process};
+use ecflash::{EcFlash, Flasher, Handshake};
+use std::{env, fs, io, process};
+
+/// Parse a hexadecimal argument, such as 0x10000
+fn parse_hex(arg: &str) -> usize {
+    usize::from_str_radix(arg.trim_start_matches("0x"), 16).expect("Invalid hexadecimal argument")
+}
 
 fn main() {
-    extern {
+    extern "C" {
         fn iopl(level: isize) -> isize;
     }
 
@@ -19,8 +24,14 @@ fn main() {
 
         let mut flasher = Flasher::new(ec);
 
-        if flasher.start() == Ok(51) {
-            if let Ok(data) = flasher.read(|x| { eprint!("\r{} KB", x / 1024) }) {
+        // Optional offset and length, otherwise the whole flash is read
+        let args: Vec<String> = env::args().skip(1).collect();
+        let offset = args.first().map_or(0, |arg| parse_hex(arg));
+        let length = args.get(1).map_or(flasher.size - offset, |arg| parse_hex(arg));
+
+        let handshake = flasher.start();
+        if handshake == Ok(Handshake::Accepted) {
+            if let Ok(data) = flasher.read_range(offset, length, |x| { eprint!("\r{} KB", x / 1024) }) {
                 eprintln!();
                 let _ = fs::write("read.rom", data);
             } else {
@@ -29,7 +40,10 @@ fn main() {
 
             let _ = flasher.stop();
         } else {
-            eprintln!("Failed to start flasher");
+            match handshake {
+                Ok(handshake) => eprintln!("Failed to start flasher: {}", handshake),
+                Err(()) => eprintln!("Failed to start flasher"),
+            }
         }
     }
 }
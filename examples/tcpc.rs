@@ -54,7 +54,7 @@ fn tcpc_test() -> Result<(), ()> {
 }
 
 fn main() {
-    extern {
+    extern "C" {
         fn iopl(level: isize) -> isize;
     }
 
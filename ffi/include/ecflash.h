@@ -0,0 +1,87 @@
+#ifndef ECFLASH_H
+#define ECFLASH_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Return codes, matching the exit codes of the command line tool */
+#define ECFLASH_OK 0
+#define ECFLASH_ERR_FAILURE -1
+#define ECFLASH_ERR_PERMISSION -2
+#define ECFLASH_ERR_NO_EC -3
+#define ECFLASH_ERR_VERIFY -4
+#define ECFLASH_ERR_INCOMPATIBLE -5
+#define ECFLASH_ERR_INVALID -6
+#define ECFLASH_ERR_SIGNATURE -8
+
+/* Opaque handle to an EC */
+typedef struct ecflash ecflash_t;
+
+typedef struct ecflash_info {
+    /* NUL terminated project name */
+    char project[64];
+    /* NUL terminated firmware version */
+    char version[64];
+    /* Flash size in bytes */
+    uint32_t size;
+} ecflash_info_t;
+
+/* Called with the number of bytes processed so far */
+typedef void (*ecflash_progress_t)(size_t bytes, void *user);
+
+/*
+ * Get I/O permission and probe for the primary (primary != 0) or secondary
+ * EC. Returns NULL and sets *error, if not NULL, on failure.
+ */
+ecflash_t *ecflash_probe(int primary, int *error);
+
+/* Release a handle returned by ecflash_probe */
+void ecflash_close(ecflash_t *ec);
+
+/* Read project, version, and flash size */
+int ecflash_info(ecflash_t *ec, ecflash_info_t *info);
+
+/* Flash size in bytes, which is the buffer size needed for read and write */
+size_t ecflash_size(ecflash_t *ec);
+
+/*
+ * Read the entire flash into data, which must be at least ecflash_size bytes.
+ * progress may be NULL.
+ */
+int ecflash_read(ecflash_t *ec, uint8_t *data, size_t len, ecflash_progress_t progress, void *user);
+
+/*
+ * Erase, program, and verify the flash with data, padding with 0xFF up to
+ * ecflash_size bytes. The boot block is left untouched unless allow_bootblock
+ * is non-zero, in which case it is flashed after the rest verifies. Blocks
+ * that do not verify are erased and written again. progress may be NULL.
+ *
+ * Before anything is erased, ECFLASH_ERR_INCOMPATIBLE is returned if the
+ * project of data is not the project of the EC, data is larger than the
+ * flash, or the boot block of data differs from the flash while
+ * allow_bootblock is zero. When built with the signature feature, signature must be the detached
+ * ed25519 signature of data by a trusted key, or ECFLASH_ERR_SIGNATURE is
+ * returned. Otherwise signature may be NULL.
+ *
+ * WARNING: the EC will power off the system when the flash session ends.
+ */
+int ecflash_write(
+    ecflash_t *ec,
+    const uint8_t *data,
+    size_t len,
+    const uint8_t *signature,
+    size_t signature_len,
+    int allow_bootblock,
+    ecflash_progress_t progress,
+    void *user
+);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* ECFLASH_H */
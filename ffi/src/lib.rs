@@ -0,0 +1,221 @@
+#![allow(clippy::missing_safety_doc)]
+
+extern crate ecflash;
+
+use std::os::raw::{c_char, c_int, c_void};
+use std::{ptr, slice};
+
+use ecflash::{Ec, EcFile, EcFlash, Flasher, Handshake, ProgramError};
+
+pub const ECFLASH_OK: c_int = 0;
+pub const ECFLASH_ERR_FAILURE: c_int = -1;
+pub const ECFLASH_ERR_PERMISSION: c_int = -2;
+pub const ECFLASH_ERR_NO_EC: c_int = -3;
+pub const ECFLASH_ERR_VERIFY: c_int = -4;
+pub const ECFLASH_ERR_INCOMPATIBLE: c_int = -5;
+pub const ECFLASH_ERR_INVALID: c_int = -6;
+pub const ECFLASH_ERR_SIGNATURE: c_int = -8;
+
+pub type EcFlashProgress = Option<unsafe extern "C" fn(bytes: usize, user: *mut c_void)>;
+
+pub struct EcFlashHandle {
+    primary: bool,
+    ec: EcFlash,
+}
+
+#[repr(C)]
+pub struct EcFlashInfo {
+    pub project: [c_char; 64],
+    pub version: [c_char; 64],
+    pub size: u32,
+}
+
+fn copy_str(dst: &mut [c_char; 64], src: &str) {
+    let bytes = src.as_bytes();
+    let len = bytes.len().min(dst.len() - 1);
+    for (d, &s) in dst.iter_mut().zip(bytes[..len].iter()) {
+        *d = s as c_char;
+    }
+    dst[len] = 0;
+}
+
+/// Run a flash session, making sure the flasher is stopped afterwards
+unsafe fn session<T, F: FnOnce(&mut Flasher) -> Result<T, c_int>>(primary: bool, f: F) -> Result<T, c_int> {
+    let ec = EcFlash::new(primary).map_err(|_| ECFLASH_ERR_NO_EC)?;
+    let mut flasher = Flasher::new(ec);
+
+    match flasher.start() {
+        Ok(Handshake::Accepted) => (),
+        Ok(Handshake::UnsupportedProtocol(_)) => return Err(ECFLASH_ERR_INCOMPATIBLE),
+        _ => return Err(ECFLASH_ERR_FAILURE),
+    }
+
+    let res = f(&mut flasher);
+
+    let _ = flasher.stop();
+
+    res
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecflash_probe(primary: c_int, error: *mut c_int) -> *mut EcFlashHandle {
+    extern "C" {
+        fn iopl(level: isize) -> isize;
+    }
+
+    let set_error = |code| if ! error.is_null() {
+        *error = code;
+    };
+
+    if iopl(3) < 0 {
+        set_error(ECFLASH_ERR_PERMISSION);
+        return ptr::null_mut();
+    }
+
+    match EcFlash::new(primary != 0) {
+        Ok(ec) => {
+            set_error(ECFLASH_OK);
+            Box::into_raw(Box::new(EcFlashHandle {
+                primary: primary != 0,
+                ec,
+            }))
+        },
+        Err(_) => {
+            set_error(ECFLASH_ERR_NO_EC);
+            ptr::null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecflash_close(ec: *mut EcFlashHandle) {
+    if ! ec.is_null() {
+        drop(Box::from_raw(ec));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecflash_info(ec: *mut EcFlashHandle, info: *mut EcFlashInfo) -> c_int {
+    let (ec, info) = match (ec.as_mut(), info.as_mut()) {
+        (Some(ec), Some(info)) => (ec, info),
+        _ => return ECFLASH_ERR_INVALID,
+    };
+
+    let ec = &mut ec.ec;
+    let (project, version, size) = match (
+        ecflash::read_stable(|| ec.project(), 8),
+        ecflash::read_stable(|| ec.version(), 8),
+        ecflash::read_stable(|| ec.size(), 8),
+    ) {
+        (Ok(project), Ok(version), Ok(size)) => (project, version, size),
+        _ => return ECFLASH_ERR_VERIFY,
+    };
+
+    copy_str(&mut info.project, &project);
+    copy_str(&mut info.version, &version);
+    info.size = size as u32;
+
+    ECFLASH_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecflash_size(ec: *mut EcFlashHandle) -> usize {
+    match ec.as_mut() {
+        Some(ec) => ec.ec.size(),
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecflash_read(ec: *mut EcFlashHandle, data: *mut u8, len: usize, progress: EcFlashProgress, user: *mut c_void) -> c_int {
+    let ec = match ec.as_mut() {
+        Some(ec) => ec,
+        None => return ECFLASH_ERR_INVALID,
+    };
+    if data.is_null() || len < ec.ec.size() {
+        return ECFLASH_ERR_INVALID;
+    }
+    let data = slice::from_raw_parts_mut(data, len);
+
+    let res = session(ec.primary, |flasher| {
+        let rom = flasher.read(|x| if let Some(progress) = progress {
+            progress(x, user);
+        }).map_err(|()| ECFLASH_ERR_FAILURE)?;
+        data[..rom.len()].copy_from_slice(&rom);
+        Ok(())
+    });
+
+    match res {
+        Ok(()) => ECFLASH_OK,
+        Err(err) => err,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecflash_write(
+    ec: *mut EcFlashHandle,
+    data: *const u8,
+    len: usize,
+    signature: *const u8,
+    signature_len: usize,
+    allow_bootblock: c_int,
+    progress: EcFlashProgress,
+    user: *mut c_void,
+) -> c_int {
+    let ec = match ec.as_mut() {
+        Some(ec) => ec,
+        None => return ECFLASH_ERR_INVALID,
+    };
+    if data.is_null() {
+        return ECFLASH_ERR_INVALID;
+    }
+
+    let signature = if signature.is_null() {
+        None
+    } else {
+        Some(slice::from_raw_parts(signature, signature_len))
+    };
+    let mut firmware = slice::from_raw_parts(data, len).to_vec();
+    if ecflash::verify_image(&firmware, signature).is_err() {
+        return ECFLASH_ERR_SIGNATURE;
+    }
+
+    let project = match ecflash::read_stable(|| ec.ec.project(), 8) {
+        Ok(project) => project,
+        Err(_) => return ECFLASH_ERR_VERIFY,
+    };
+    let image_project = EcFile::new(firmware.clone()).project();
+    if image_project.trim().is_empty() || image_project.trim() != project.trim() {
+        return ECFLASH_ERR_INCOMPATIBLE;
+    }
+
+    let size = ec.ec.size();
+    if len > size {
+        return ECFLASH_ERR_INCOMPATIBLE;
+    }
+    firmware.resize(size, 0xFF);
+
+    let res = session(ec.primary, |flasher| {
+        flasher.allow_bootblock = allow_bootblock != 0;
+        let callback = |x| if let Some(progress) = progress {
+            progress(x, user);
+        };
+
+        let original = flasher.read(callback).map_err(|()| ECFLASH_ERR_FAILURE)?;
+        if flasher.bootblock_mismatch(&original, &firmware).is_some() {
+            return Err(ECFLASH_ERR_INCOMPATIBLE);
+        }
+
+        // The boot block is flashed last, and blocks that do not verify are
+        // erased and written again, as the command line does
+        flasher.program(&original, &firmware, &|_, x, _| callback(x)).map_err(|err| match err {
+            ProgramError::Verify { .. } => ECFLASH_ERR_VERIFY,
+            ProgramError::Failed(_) => ECFLASH_ERR_FAILURE,
+        })
+    });
+
+    match res {
+        Ok(()) => ECFLASH_OK,
+        Err(err) => err,
+    }
+}
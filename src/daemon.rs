@@ -0,0 +1,252 @@
+//! DBus system service, which allows unprivileged clients authorized by polkit
+//! to query and flash the EC.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::process::{Command, Stdio};
+
+use ecflash::{Ec, EcFlash, Flasher, Handshake};
+use zbus::blocking::{Connection, MessageIterator};
+use zbus::message::{Header, Type};
+use zbus::zvariant::Value;
+
+use super::{exit, private_dir};
+use super::progress::json_field;
+
+pub const NAME: &str = "com.system76.EcFlash1";
+pub const PATH: &str = "/com/system76/EcFlash1";
+pub const INTERFACE: &str = "com.system76.EcFlash1";
+
+const ERROR_FAILED: &str = "com.system76.EcFlash1.Error.Failed";
+const ERROR_NOT_AUTHORIZED: &str = "com.system76.EcFlash1.Error.NotAuthorized";
+
+const ACTION_INFO: &str = "com.system76.ecflash.info";
+const ACTION_READ: &str = "com.system76.ecflash.read";
+const ACTION_FLASH: &str = "com.system76.ecflash.flash";
+
+/// Polkit flag to allow interactive authentication
+const ALLOW_USER_INTERACTION: u32 = 1;
+
+struct Daemon {
+    connection: Connection,
+}
+
+impl Daemon {
+    fn authorized(&self, header: &Header, action: &str) -> bool {
+        let sender = match header.sender() {
+            Some(sender) => sender.as_str(),
+            None => return false,
+        };
+
+        let mut subject_details = HashMap::new();
+        subject_details.insert("name", Value::from(sender));
+        let subject = ("system-bus-name", subject_details);
+        let details: HashMap<&str, &str> = HashMap::new();
+
+        let reply = self.connection.call_method(
+            Some("org.freedesktop.PolicyKit1"),
+            "/org/freedesktop/PolicyKit1/Authority",
+            Some("org.freedesktop.PolicyKit1.Authority"),
+            "CheckAuthorization",
+            &(subject, action, details, ALLOW_USER_INTERACTION, ""),
+        );
+
+        match reply {
+            Ok(reply) => match reply.body().deserialize::<(bool, bool, HashMap<String, String>)>() {
+                Ok((authorized, _challenge, _details)) => authorized,
+                Err(_) => false,
+            },
+            Err(err) => {
+                eprintln!("Failed to check authorization: {}", err);
+                false
+            }
+        }
+    }
+
+    fn progress(&self, phase: &str, bytes: usize, total: usize) {
+        let _ = self.connection.emit_signal(
+            None::<&str>,
+            PATH,
+            INTERFACE,
+            "Progress",
+            &(phase, bytes as u64, total as u64),
+        );
+    }
+
+    fn info(&self, primary: bool) -> Result<(String, String, u32), String> {
+        let mut ec = EcFlash::new(primary)?;
+        let project = ecflash::read_stable(|| ec.project(), 8)
+            .map_err(|_| "failed to read EC project".to_string())?;
+        let version = ecflash::read_stable(|| ec.version(), 8)
+            .map_err(|_| "failed to read EC version".to_string())?;
+        let size = ecflash::read_stable(|| ec.size(), 8)
+            .map_err(|_| "failed to read EC size".to_string())?;
+        Ok((project, version, size as u32))
+    }
+
+    fn read(&self, primary: bool) -> Result<Vec<u8>, String> {
+        let ec = EcFlash::new(primary)?;
+        let mut flasher = Flasher::new(ec);
+        let size = flasher.size;
+
+        unsafe {
+            match flasher.start() {
+                Ok(Handshake::Accepted) => (),
+                Ok(handshake) => return Err(format!("failed to start flasher: {}", handshake)),
+                Err(()) => return Err("failed to start flasher".to_string()),
+            }
+
+            let res = flasher.read(|x| self.progress("read", x, size))
+                .map_err(|()| "failed to read data".to_string());
+
+            let _ = flasher.stop();
+
+            res
+        }
+    }
+
+    /// Flash through the write command, so that the service has the same
+    /// compatibility, signature, battery, and backup checks, and the same
+    /// ordering of the boot block, as the command line
+    fn flash(&self, primary: bool, firmware: Vec<u8>, signature: Vec<u8>) -> Result<(), String> {
+        let dir = private_dir("ecflash-daemon").map_err(|err| format!("failed to create a directory for the image: {}", err))?;
+        let path = dir.join("firmware.rom");
+
+        let res = (|| {
+            fs::write(&path, &firmware)?;
+            if ! signature.is_empty() {
+                fs::write(dir.join("firmware.rom.sig"), &signature)?;
+            }
+
+            let mut child = Command::new(env::current_exe()?)
+                .arg("--progress-json")
+                .arg("--yes")
+                .arg("write")
+                .arg(if primary { "-1" } else { "-2" })
+                .arg(&path)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .spawn()?;
+
+            let mut result = None;
+            if let Some(stdout) = child.stdout.take() {
+                for line in BufReader::new(stdout).lines() {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(_) => break,
+                    };
+
+                    let field = |name| json_field(&line, name).unwrap_or_default();
+                    let number = |name| field(name).parse::<usize>().unwrap_or(0);
+                    match field("event").as_str() {
+                        "progress" => self.progress(&field("phase"), number("bytes"), number("total")),
+                        "result" => result = Some((
+                            field("exit_code").parse().unwrap_or(exit::FAILURE),
+                            field("message"),
+                        )),
+                        _ => (),
+                    }
+                }
+            }
+
+            let status = child.wait()?;
+            Ok::<_, io::Error>((status, result))
+        })();
+
+        let _ = fs::remove_dir_all(&dir);
+
+        match res {
+            Ok((_, Some((exit::OK, _)))) => Ok(()),
+            Ok((_, Some((_, message)))) => Err(message),
+            Ok((status, None)) => Err(format!("write finished with {} and no result", status)),
+            Err(err) => Err(format!("failed to run write: {}", err)),
+        }
+    }
+
+    fn handle(&self, header: &Header, member: &str, body: zbus::message::Body) -> zbus::Result<()> {
+        let action = match member {
+            "Info" => ACTION_INFO,
+            "Read" => ACTION_READ,
+            "Flash" => ACTION_FLASH,
+            _ => return self.connection.reply_error(
+                header,
+                "org.freedesktop.DBus.Error.UnknownMethod",
+                &(format!("unknown method '{}'", member),),
+            ),
+        };
+
+        if ! self.authorized(header, action) {
+            return self.connection.reply_error(
+                header,
+                ERROR_NOT_AUTHORIZED,
+                &(format!("not authorized for '{}'", action),),
+            );
+        }
+
+        let res = match member {
+            "Info" => body.deserialize::<bool>()
+                .map_err(|err| err.to_string())
+                .and_then(|primary| self.info(primary))
+                .map(|info| self.connection.reply(header, &info)),
+            "Read" => body.deserialize::<bool>()
+                .map_err(|err| err.to_string())
+                .and_then(|primary| self.read(primary))
+                .map(|data| self.connection.reply(header, &data)),
+            _ => body.deserialize::<(bool, Vec<u8>, Vec<u8>)>()
+                .map_err(|err| err.to_string())
+                .and_then(|(primary, firmware, signature)| self.flash(primary, firmware, signature))
+                .map(|()| self.connection.reply(header, &())),
+        };
+
+        match res {
+            Ok(reply) => reply,
+            Err(err) => self.connection.reply_error(header, ERROR_FAILED, &(err,)),
+        }
+    }
+}
+
+pub fn run() -> zbus::Result<()> {
+    let connection = Connection::system()?;
+    connection.request_name(NAME)?;
+
+    let daemon = Daemon {
+        connection: connection.clone(),
+    };
+
+    for msg in MessageIterator::from(&connection) {
+        let msg = msg?;
+        let header = msg.header();
+        if header.message_type() != Type::MethodCall {
+            continue;
+        }
+
+        // Introspectable, Peer, Properties, and other objects are not served,
+        // but callers must still get a reply instead of waiting for a timeout
+        let unknown = if header.path().map(|path| path.as_str()) != Some(PATH) {
+            Some(("org.freedesktop.DBus.Error.UnknownObject", "unknown object"))
+        } else if header.interface().map(|iface| iface.as_str()) != Some(INTERFACE) {
+            Some(("org.freedesktop.DBus.Error.UnknownInterface", "unknown interface"))
+        } else {
+            None
+        };
+        if let Some((name, message)) = unknown {
+            if let Err(err) = connection.reply_error(&header, name, &(message,)) {
+                eprintln!("Failed to reply to unknown call: {}", err);
+            }
+            continue;
+        }
+
+        let member = match header.member() {
+            Some(member) => member.to_string(),
+            None => continue,
+        };
+
+        if let Err(err) = daemon.handle(&header, &member, msg.body()) {
+            eprintln!("Failed to handle {}: {}", member, err);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,99 @@
+//! Minimal hardware debugger for the 8051 core of the EC, through the debug
+//! port that the Arduino programmer drives for ISP.
+//!
+//! Halting the core stops the EC firmware from servicing the host, the
+//! keyboard, and the fans until it is resumed, so the machine should be cooled
+//! and powered by the board under test only while it is halted.
+//!
+//! The profiler halts the core only for as long as it takes to read the
+//! program counter, over and over, and counts where it was.
+
+use std::io::{stdout, Write};
+use std::process;
+use std::time::{Duration, Instant};
+
+use ecflash::{Debugger, Profile};
+
+use super::{exit, parse_int, programmer, ram, Args, USAGE};
+
+/// How long profile samples without --duration
+const DEFAULT_DURATION: Duration = Duration::from_secs(10);
+/// Rows of the histogram that profile prints
+const PROFILE_ROWS: usize = 25;
+
+pub fn dbgr(args: &Args) -> ! {
+    let progress = args.progress();
+    let params: Vec<&str> = args.ec_args.iter().map(|arg| arg.as_str()).collect();
+    let (command, steps) = match params.as_slice() {
+        [command @ ("halt" | "resume" | "pc")] => (*command, 1),
+        ["step"] => ("step", 1),
+        ["step", steps] => match parse_int(steps) {
+            Some(steps) if steps > 0 => ("step", steps),
+            _ => progress.result(exit::USAGE, &format!("Invalid number of steps '{}'\n{}", steps, USAGE)),
+        },
+        _ => progress.result(exit::USAGE, &format!("Invalid dbgr command\n{}", USAGE)),
+    };
+
+    let mut port = programmer::open(args, &progress);
+    let pcs = match command {
+        "halt" => port.halt().map(|pc| vec![pc]),
+        "resume" => port.resume().map(|()| Vec::new()),
+        "pc" => port.read_pc().map(|pc| vec![pc]),
+        _ => (0..steps).map(|_step| port.step()).collect(),
+    };
+    match pcs {
+        Ok(pcs) => {
+            let mut stdout = stdout();
+            for pc in pcs {
+                let _ = writeln!(stdout, "PC 0x{:04X}", pc);
+            }
+            match command {
+                "halt" => progress.result(exit::OK, "Halted the core, resume it with dbgr resume"),
+                "resume" => progress.result(exit::OK, "Resumed the core"),
+                _ => process::exit(exit::OK),
+            }
+        },
+        Err(err) => progress.result(exit::FAILURE, &format!("Failed to {} the core: {}", command, err)),
+    }
+}
+
+/// Sample the program counter for --duration, then print where the core spent
+/// its time, by function with --symbols
+pub fn profile(args: &Args) -> ! {
+    let progress = args.progress();
+    if ! args.ec_args.is_empty() {
+        progress.result(exit::USAGE, &format!("Invalid profile arguments\n{}", USAGE));
+    }
+
+    let map = args.symbols.as_ref().map(|_| ram::load(args, &progress));
+
+    let duration = args.duration.unwrap_or(DEFAULT_DURATION);
+    let mut port = programmer::open(args, &progress);
+    progress.info(&format!("Sampling the program counter for {:.1} s", duration.as_secs_f64()));
+    let mut profile = Profile::new();
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        match port.sample_pc() {
+            Ok(pc) => profile.add(pc),
+            Err(err) => progress.result(exit::FAILURE, &format!("Failed to sample the program counter: {}", err)),
+        }
+    }
+    if profile.total() == 0 {
+        progress.result(exit::FAILURE, "No samples were taken");
+    }
+
+    let rows: Vec<(String, usize)> = match &map {
+        Some(map) => profile.functions(map),
+        None => profile.addresses().into_iter().map(|(pc, count)| (format!("0x{:04X}", pc), count)).collect(),
+    };
+    let mut stdout = stdout();
+    let _ = writeln!(stdout, "{:>8} {:>6}  {}", "Samples", "%", if map.is_some() { "Function" } else { "Address" });
+    for (name, count) in rows.iter().take(PROFILE_ROWS) {
+        let percent = *count as f64 * 100.0 / profile.total() as f64;
+        let _ = writeln!(stdout, "{:>8} {:>5.1}%  {}", count, percent, name);
+    }
+    if rows.len() > PROFILE_ROWS {
+        progress.info(&format!("{} more with fewer samples", rows.len() - PROFILE_ROWS));
+    }
+    process::exit(exit::OK);
+}
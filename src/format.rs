@@ -0,0 +1,119 @@
+//! Output formats for flash dumps, for tools and external programmers that
+//! do not take raw binaries.
+
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    Binary,
+    Ihex,
+    Srec,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bin" | "binary" => Some(Format::Binary),
+            "ihex" | "hex" => Some(Format::Ihex),
+            "srec" => Some(Format::Srec),
+            _ => None,
+        }
+    }
+
+    /// Encode data that was read starting at address
+    pub fn encode(self, address: usize, data: &[u8]) -> Vec<u8> {
+        match self {
+            Format::Binary => data.to_vec(),
+            Format::Ihex => ihex(address, data).into_bytes(),
+            Format::Srec => srec(address, data).into_bytes(),
+        }
+    }
+}
+
+/// Bytes of data per record
+const RECORD_SIZE: usize = 16;
+
+fn ihex_record(out: &mut String, address: u16, kind: u8, data: &[u8]) {
+    let mut checksum = (data.len() as u8)
+        .wrapping_add((address >> 8) as u8)
+        .wrapping_add(address as u8)
+        .wrapping_add(kind);
+    let _ = write!(out, ":{:02X}{:04X}{:02X}", data.len(), address, kind);
+    for &byte in data {
+        checksum = checksum.wrapping_add(byte);
+        let _ = write!(out, "{:02X}", byte);
+    }
+    let _ = writeln!(out, "{:02X}", checksum.wrapping_neg());
+}
+
+/// Intel HEX, with extended linear address records for data above 64 KiB
+pub fn ihex(address: usize, data: &[u8]) -> String {
+    let mut out = String::new();
+    let mut upper = None;
+    let mut i = 0;
+    while i < data.len() {
+        let address = address + i;
+        if upper != Some(address >> 16) {
+            upper = Some(address >> 16);
+            ihex_record(&mut out, 0, 0x04, &((address >> 16) as u16).to_be_bytes());
+        }
+
+        // Records may not cross into the next 64 KiB segment
+        let len = RECORD_SIZE
+            .min(data.len() - i)
+            .min(0x10000 - (address & 0xFFFF));
+        ihex_record(&mut out, address as u16, 0x00, &data[i..i + len]);
+        i += len;
+    }
+    ihex_record(&mut out, 0, 0x01, &[]);
+    out
+}
+
+/// Addresses, hexadecimal bytes, and printable ASCII, 16 bytes per line
+pub fn hexdump(address: usize, data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, chunk) in data.chunks(RECORD_SIZE).enumerate() {
+        let _ = write!(out, "{:08X} ", address + i * RECORD_SIZE);
+        for j in 0..RECORD_SIZE {
+            if j == RECORD_SIZE / 2 {
+                out.push(' ');
+            }
+            match chunk.get(j) {
+                Some(byte) => { let _ = write!(out, " {:02X}", byte); },
+                None => out.push_str("   "),
+            }
+        }
+        out.push_str("  |");
+        for &byte in chunk {
+            out.push(if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' });
+        }
+        out.push_str("|\n");
+    }
+    out
+}
+
+fn srec_record(out: &mut String, kind: u8, address: &[u8], data: &[u8]) {
+    let count = (address.len() + data.len() + 1) as u8;
+    let mut checksum = count;
+    let _ = write!(out, "S{}{:02X}", kind, count);
+    for &byte in address.iter().chain(data.iter()) {
+        checksum = checksum.wrapping_add(byte);
+        let _ = write!(out, "{:02X}", byte);
+    }
+    let _ = writeln!(out, "{:02X}", !checksum);
+}
+
+/// Motorola S-record, with 24-bit addresses if they fit and 32-bit otherwise
+pub fn srec(address: usize, data: &[u8]) -> String {
+    let wide = address + data.len() > 0x100_0000;
+    let (kind, end_kind, width) = if wide { (3, 7, 4) } else { (2, 8, 3) };
+
+    let mut out = String::new();
+    srec_record(&mut out, 0, &[0, 0], b"ecflash");
+    for (i, chunk) in data.chunks(RECORD_SIZE).enumerate() {
+        let address = ((address + i * RECORD_SIZE) as u32).to_be_bytes();
+        srec_record(&mut out, kind, &address[4 - width..], chunk);
+    }
+    srec_record(&mut out, end_kind, &[0; 4][..width], &[]);
+    out
+}
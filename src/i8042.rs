@@ -0,0 +1,51 @@
+//! Coordination with the kernel i8042 driver, which takes every byte that
+//! comes out of the keyboard controller data port on its interrupt.
+//!
+//! With the kbc backend, the EC answers on those ports, so the driver is
+//! unbound from the i8042 device for as long as the EC is open, and bound
+//! again when the process exits, however it exits. The internal keyboard and
+//! touchpad do not work in between.
+
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::progress::Progress;
+
+/// Driver of the keyboard controller, and the device it binds to
+const DRIVER_DIR: &str = "/sys/bus/platform/drivers/i8042";
+const DEVICE: &str = "i8042";
+
+/// Set while the driver is unbound by this process
+static UNBOUND: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    fn atexit(callback: extern "C" fn()) -> i32;
+}
+
+extern "C" fn rebind_at_exit() {
+    if UNBOUND.swap(false, Ordering::SeqCst) {
+        let _ = fs::write(Path::new(DRIVER_DIR).join("bind"), DEVICE);
+    }
+}
+
+/// Unbind the i8042 driver until the process exits, warning if it is bound
+/// but cannot be unbound
+pub fn unbind(progress: &Progress) {
+    let driver = Path::new(DRIVER_DIR);
+    if ! driver.join(DEVICE).exists() || UNBOUND.load(Ordering::SeqCst) {
+        return;
+    }
+
+    match fs::write(driver.join("unbind"), DEVICE) {
+        Ok(()) => {
+            progress.info("Unbound the kernel i8042 driver, the internal keyboard and touchpad do not work until ecflash exits");
+            UNBOUND.store(true, Ordering::SeqCst);
+            unsafe { atexit(rebind_at_exit) };
+        },
+        Err(err) => progress.warning(&format!(
+            "Failed to unbind the kernel i8042 driver, which will take answers of the EC: {}",
+            err
+        )),
+    }
+}
@@ -0,0 +1,85 @@
+//! Exclusive grab of the input devices behind the keyboard controller while
+//! flashing, since keystrokes and touchpad movement race with the mailbox
+//! transactions of a follow mode session. With the devices grabbed, nothing
+//! on the desktop reacts to them, instead of asking the user not to touch
+//! anything and waiting for the keyboard to be quiet.
+//!
+//! Each evdev device whose physical path is on the i8042 ports is opened and
+//! grabbed with EVIOCGRAB until the Grab is dropped, or the process exits,
+//! which closes the devices and releases the grab either way.
+
+use std::fs;
+use std::os::unix::io::AsRawFd;
+
+use super::progress::Progress;
+
+/// Input devices, with their physical path in device/phys
+const INPUT_DIR: &str = "/sys/class/input";
+/// Physical path prefix of the devices behind the keyboard controller
+const I8042_PHYS: &str = "isa0060/";
+/// _IOW('E', 0x90, int)
+const EVIOCGRAB: u64 = 0x4004_4590;
+
+extern "C" {
+    fn ioctl(fd: i32, request: u64, ...) -> i32;
+}
+
+/// Devices grabbed by grab
+pub struct Grab {
+    devices: Vec<fs::File>,
+}
+
+impl Drop for Grab {
+    fn drop(&mut self) {
+        for device in &self.devices {
+            unsafe { ioctl(device.as_raw_fd(), EVIOCGRAB, 0) };
+        }
+    }
+}
+
+/// Names of the evdev devices behind the keyboard controller, such as event0
+fn i8042_devices() -> Vec<String> {
+    let entries = match fs::read_dir(INPUT_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with("event"))
+        .filter(|name| {
+            fs::read_to_string(format!("{}/{}/device/phys", INPUT_DIR, name))
+                .is_ok_and(|phys| phys.starts_with(I8042_PHYS))
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+/// Grab the keyboard and touchpad behind the keyboard controller, returning
+/// None with a warning if none could be grabbed
+pub fn grab(progress: &Progress) -> Option<Grab> {
+    let mut devices = Vec::new();
+    for name in i8042_devices() {
+        let path = format!("/dev/input/{}", name);
+        let device = match fs::File::open(&path) {
+            Ok(device) => device,
+            Err(err) => {
+                progress.warning(&format!("Failed to open '{}' to grab it: {}", path, err));
+                continue;
+            },
+        };
+        if unsafe { ioctl(device.as_raw_fd(), EVIOCGRAB, 1) } < 0 {
+            progress.warning(&format!("Failed to grab '{}': {}", path, std::io::Error::last_os_error()));
+            continue;
+        }
+        progress.info(&format!("Grabbed '{}' until the flash is done", path));
+        devices.push(device);
+    }
+
+    if devices.is_empty() {
+        progress.warning("No keyboard or touchpad behind the keyboard controller could be grabbed");
+        return None;
+    }
+    Some(Grab { devices })
+}
@@ -0,0 +1,153 @@
+//! Journal of the reads and flashes done on this machine, appended to
+//! JOURNAL_PATH as one JSON object per line, so that fleet admins and support
+//! can reconstruct what was done to it.
+//!
+//! An operation begins once its file is known, is filled in as the EC is
+//! opened and read, and is appended by Progress::result with the result it
+//! exits with, so that every way out of the operation is recorded.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use ecflash::{FlashReport, HostInterface};
+
+use super::progress::{json_str, Progress};
+
+pub const JOURNAL_PATH: &str = "/var/lib/ecflash/journal.jsonl";
+
+/// What is known about the operation in progress
+pub struct Entry {
+    /// Command, such as read or write
+    pub operation: String,
+    /// File that was read from the EC or flashed to it
+    pub file: String,
+    pub primary: bool,
+    /// Backend the EC was reached through, once in flash mode
+    pub backend: Option<String>,
+    pub chip_id: Option<u16>,
+    /// Version in the flash before the operation
+    pub old_version: Option<String>,
+    /// Version of the image flashed
+    pub new_version: Option<String>,
+    pub retries: usize,
+    start: Instant,
+}
+
+/// Operation between begin and the result it exits with
+static PENDING: Mutex<Option<Entry>> = Mutex::new(None);
+
+/// Start recording operation on file
+pub fn begin(operation: &str, file: &str, primary: bool) {
+    *PENDING.lock().unwrap_or_else(|err| err.into_inner()) = Some(Entry {
+        operation: operation.to_string(),
+        file: file.to_string(),
+        primary,
+        backend: None,
+        chip_id: None,
+        old_version: None,
+        new_version: None,
+        retries: 0,
+        start: Instant::now(),
+    });
+}
+
+/// Fill in the operation in progress, if there is one
+pub fn update<F: FnOnce(&mut Entry)>(f: F) {
+    if let Some(entry) = PENDING.lock().unwrap_or_else(|err| err.into_inner()).as_mut() {
+        f(entry);
+    }
+}
+
+/// Command of the operation in progress
+pub fn operation() -> Option<String> {
+    PENDING.lock().unwrap_or_else(|err| err.into_inner()).as_ref().map(|entry| entry.operation.clone())
+}
+
+/// Record the backend that reached the EC, by the name --backend takes
+pub fn interface(interface: HostInterface) {
+    update(|entry| entry.backend = Some(match interface {
+        HostInterface::Ports => "ports".to_string(),
+        HostInterface::DevPort => "devport".to_string(),
+        HostInterface::Mmio(base) => format!("mmio:0x{:X}", base),
+        HostInterface::Kernel => "kernel".to_string(),
+        HostInterface::Mock => "mock".to_string(),
+    }));
+}
+
+/// Record the retries of a flash
+pub fn report(report: &FlashReport) {
+    update(|entry| entry.retries = report.retries);
+}
+
+/// UTC time as RFC 3339
+pub fn date(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+    let (days, rem) = ((seconds / 86400) as i64, seconds % 86400);
+
+    // Civil date from days since the epoch, after Howard Hinnant
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, rem / 3600, rem / 60 % 60, rem % 60
+    )
+}
+
+/// User that ran ecflash, looking through sudo
+fn user() -> String {
+    env::var("SUDO_USER")
+        .or_else(|_| env::var("USER"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+fn json_opt(value: &Option<String>) -> String {
+    value.as_deref().map_or("null".to_string(), json_str)
+}
+
+/// Append the operation in progress with its result, warning if the journal
+/// cannot be written
+pub fn finish(progress: &Progress, code: i32, message: &str) {
+    let entry = match PENDING.lock().unwrap_or_else(|err| err.into_inner()).take() {
+        Some(entry) => entry,
+        None => return,
+    };
+
+    let line = format!(
+        "{{\"date\":{},\"user\":{},\"operation\":{},\"file\":{},\"ec\":{},\"backend\":{},\"chip_id\":{},\
+        \"old_version\":{},\"new_version\":{},\"success\":{},\"exit_code\":{},\"message\":{},\"retries\":{},\"seconds\":{:.3}}}",
+        json_str(&date(SystemTime::now())),
+        json_str(&user()),
+        json_str(&entry.operation),
+        json_str(&entry.file),
+        if entry.primary { 1 } else { 2 },
+        json_opt(&entry.backend),
+        json_opt(&entry.chip_id.map(|id| format!("IT{:04X}", id))),
+        json_opt(&entry.old_version),
+        json_opt(&entry.new_version),
+        code == 0,
+        code,
+        json_str(message),
+        entry.retries,
+        entry.start.elapsed().as_secs_f64(),
+    );
+
+    let res = Path::new(JOURNAL_PATH).parent().map_or(Ok(()), fs::create_dir_all).and_then(|()| {
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(JOURNAL_PATH)?;
+        writeln!(file, "{}", line)
+    });
+    if let Err(err) = res {
+        progress.warning(&format!("Failed to append to the journal '{}': {}", JOURNAL_PATH, err));
+    }
+}
@@ -0,0 +1,91 @@
+//! Coordination with the kernel ACPI EC driver, which uses the ports of the
+//! primary EC for its own transactions, such as the query that follows each EC
+//! event.
+//!
+//! Userspace cannot take the ACPI global lock, and unbinding the driver does
+//! not stop transactions on the boot EC, so events are paused instead by
+//! disabling the GPE of the EC through sysfs while in flash mode, and enabled
+//! again afterwards. AML that reads the EC, such as battery status, can still
+//! run, so flash mode should be kept short.
+
+use std::fs;
+use std::sync::Mutex;
+
+use super::parse_int;
+use super::progress::Progress;
+
+/// Devices bound to the kernel EC driver are linked here
+const DRIVER_DIR: &str = "/sys/bus/acpi/drivers/ec";
+/// GPE of the first EC, only visible with debugfs and the ec_sys module
+const DEBUGFS_GPE: &str = "/sys/kernel/debug/ec/ec0/gpe";
+
+/// GPE file that was disabled by pause, and must be enabled again
+static PAUSED: Mutex<Option<String>> = Mutex::new(None);
+
+/// Name of the ACPI device bound to the kernel EC driver, such as PNP0C09:00
+pub fn driver_device() -> Option<String> {
+    fs::read_dir(DRIVER_DIR).ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .find(|name| name.starts_with("PNP0C09"))
+}
+
+/// Pause events of the kernel EC driver, warning if it is bound but cannot be
+/// paused
+pub fn pause(progress: &Progress) {
+    pause_gpe(progress, false)
+}
+
+/// Pause events like pause, but take over a GPE that is already disabled, as
+/// left by a run that was interrupted in flash mode
+pub fn take_over(progress: &Progress) {
+    pause_gpe(progress, true)
+}
+
+fn pause_gpe(progress: &Progress, adopt: bool) {
+    let device = match driver_device() {
+        Some(device) => device,
+        None => return,
+    };
+
+    let gpe = match fs::read_to_string(DEBUGFS_GPE).ok().and_then(|gpe| parse_int(gpe.trim())) {
+        Some(gpe) => gpe,
+        None => {
+            progress.warning(&format!(
+                "The kernel EC driver is bound to {}, but its GPE is unknown, so its events cannot be paused. Load ec_sys with debugfs mounted to pause them",
+                device
+            ));
+            return;
+        }
+    };
+
+    let path = format!("/sys/firmware/acpi/interrupts/gpe{:02X}", gpe);
+    // Leave a GPE that was disabled by someone else alone
+    if fs::read_to_string(&path).is_ok_and(|status| status.contains("disabled")) {
+        if adopt {
+            *PAUSED.lock().unwrap_or_else(|err| err.into_inner()) = Some(path);
+        }
+        return;
+    }
+
+    match fs::write(&path, "disable") {
+        Ok(()) => {
+            progress.info(&format!("Paused events of the kernel EC driver on {} by disabling GPE 0x{:02X}", device, gpe));
+            *PAUSED.lock().unwrap_or_else(|err| err.into_inner()) = Some(path);
+        },
+        Err(err) => progress.warning(&format!("Failed to pause events of the kernel EC driver on {}: {}", device, err)),
+    }
+}
+
+/// Enable the events paused by pause again
+pub fn resume(progress: &Progress) {
+    let path = match PAUSED.lock().unwrap_or_else(|err| err.into_inner()).take() {
+        Some(path) => path,
+        None => return,
+    };
+
+    match fs::write(&path, "enable") {
+        Ok(()) => progress.info("Resumed events of the kernel EC driver"),
+        Err(err) => progress.warning(&format!("Failed to resume events of the kernel EC driver, write enable to {}: {}", path, err)),
+    }
+}
@@ -1,118 +1,2896 @@
 extern crate ecflash;
 
-use std::{env, process};
+use std::{env, process, thread, time};
 use std::fmt::Display;
-use std::fs::File;
-use std::io::{stdout, stderr, BufWriter, Error, Read, Write};
+use std::fs;
+use std::io::{stdin, stdout, stderr, BufRead, BufWriter, Error, Write};
 
-use ecflash::{Ec, EcFile, EcFlash};
+use ecflash::{
+    ACPI_EC_IO, AcpiEc, BLOCK_PROTECT_MASK, BOOT_BLOCK, BOOT_BLOCK_PROTECT, Bundle, Config, DevMemPortIo, DevPort, Dmi, Ec, EcFile, EcFlash, EcParam, EcSmfi, FlashAlgorithm, FlashChip, Flasher, FlasherState,
+    FwupdDevice, Handshake, HostInterface, IspOptions, Layout, PortIo, ProgramError, ProtectRegion, RawPortIo, Region, TraceWriter, CONFIG_PATH, FLASH_OPTION_BASE, FLASH_OPTION_SIZE, PROTECT_REGIONS, isp_internal, sha256,
+};
+use ecflash::regs::smfi;
 
-fn validate<T: PartialEq + Display, F: FnMut() -> T>(mut f: F, attempts: usize) -> Result<T, ()> {
-    for _attempt_i in 0..attempts {
-        let a = f();
-        let b = f();
-        if a == b {
-            return Ok(a);
+use self::format::Format;
+use self::progress::Progress;
+
+#[cfg(feature = "daemon")]
+mod daemon;
+mod dbgr;
+mod format;
+mod i8042;
+mod input;
+mod journal;
+mod kernel;
+mod marker;
+mod migrate;
+mod programmer;
+mod progress;
+mod ram;
+mod remote;
+mod session;
+mod sfdp;
+mod support;
+mod tcpc;
+mod unbrick;
+
+/// Exit codes, which are part of the command line contract so that wrappers
+/// can branch on the result without parsing stderr
+mod exit {
+    /// Success
+    pub const OK: i32 = 0;
+    /// Unspecified failure
+    pub const FAILURE: i32 = 1;
+    /// Failed to get I/O permission
+    pub const PERMISSION: i32 = 2;
+    /// No supported EC was found
+    pub const NO_EC: i32 = 3;
+    /// Data read back from the EC did not match what was expected
+    pub const VERIFY: i32 = 4;
+    /// Image is not compatible with the EC
+    pub const INCOMPATIBLE: i32 = 5;
+    /// Invalid command line arguments
+    pub const USAGE: i32 = 6;
+    /// Failed to open or read a file
+    pub const IO: i32 = 7;
+    /// Image is not signed by a trusted key
+    pub const SIGNATURE: i32 = 8;
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+    Debug,
+}
+
+const USAGE: &str = "\
+Usage: system76_ecflash [OPTIONS] [info] [-1] [-2] [FILE...]
+       system76_ecflash [OPTIONS] info --unknown-chip [-1] [-2]
+       system76_ecflash [OPTIONS] read [-1|-2] [--offset OFFSET] [--length LENGTH] [--format FORMAT] FILE
+       system76_ecflash [OPTIONS] hexdump [--live [-1|-2] | FILE] [--offset OFFSET] [--length LENGTH]
+       system76_ecflash [OPTIONS] bench [-1|-2] [--offset OFFSET] [--length LENGTH]
+       system76_ecflash [OPTIONS] stress [-1|-2] [--cycles N] [--scratch] [--offset OFFSET] [--length LENGTH]
+       system76_ecflash [OPTIONS] write [-1|-2] [--algorithm NAME] [--region REGION] FILE
+       system76_ecflash [OPTIONS] apply [-1|-2] [--algorithm NAME] [--region REGION] BUNDLE
+       system76_ecflash [OPTIONS] restore [-1|-2] [--algorithm NAME] [--region REGION] [BACKUP | ID]
+       system76_ecflash [OPTIONS] repair [-1|-2] [--region REGION] [FILE]
+       system76_ecflash [OPTIONS] reset
+       system76_ecflash [OPTIONS] option [dump | set OFFSET VALUE]
+       system76_ecflash [OPTIONS] param [-1|-2] get PARAM [--watch] | set PARAM VALUE
+       system76_ecflash [OPTIONS] fcommand [-1|-2] CMD DAT [DATA]
+       system76_ecflash [OPTIONS] --symbols MAP ram get NAME [--length LENGTH] [--watch] | set NAME VALUE
+       system76_ecflash [OPTIONS] tcpc [-1|-2] [dump [--port N] | list]
+       system76_ecflash [OPTIONS] protection [-1|-2]
+       system76_ecflash [OPTIONS] map [-1|-2] [--region REGION] [--allow-bootblock]
+       system76_ecflash [OPTIONS] unlock [-1|-2]
+       system76_ecflash [OPTIONS] protect --region REGION --lock | --unlock
+       system76_ecflash [OPTIONS] recover [-1|-2] [--scratch]
+       system76_ecflash [OPTIONS] raw [-1|-2] --cmd VALUE [--write VALUE]... [--read N]
+       system76_ecflash [OPTIONS] spi [-1|-2] --tx HEX [--rx N]
+       system76_ecflash [OPTIONS] sfdp [-1|-2 | --programmer PORT]
+       system76_ecflash [OPTIONS] unbrick [--external [--programmer PORT]] [BACKUP]
+       system76_ecflash [OPTIONS] programmers
+       system76_ecflash [OPTIONS] programmer [--programmer PORT] flash-sketch SKETCH.hex
+       system76_ecflash [OPTIONS] dbgr [--programmer PORT] halt | resume | step [N] | pc
+       system76_ecflash [OPTIONS] profile [--programmer PORT] [--duration DURATION] [--symbols MAP]
+       system76_ecflash [OPTIONS] report [-1|-2] [--trace TRACE] [FILE]
+       system76_ecflash [OPTIONS] interrupted [clear]
+       system76_ecflash [OPTIONS] --backup-dir DIR --allow-bootblock migrate IMAGE | verify | rollback
+       system76_ecflash daemon
+       system76_ecflash --key KEYFILE serve [ADDRESS]
+       system76_ecflash [OPTIONS] --key KEYFILE remote HOST[:PORT] write|apply [-1|-2] [--region REGION] FILE
+
+Commands:
+  info    Print project, version, and size of ECs and EC files (default)
+  read    Read the EC flash into FILE
+  hexdump Print the EC flash or FILE as hexadecimal and ASCII
+  bench   Measure command latency and read, write, and erase speed without
+          changing the flash
+  stress  Read the flash repeatedly, or also erase, write, and verify a
+          scratch pattern with --scratch, then report errors per phase
+  write   Erase and program the EC flash with FILE, then verify it
+  apply   Check an update bundle against the running EC, then write it
+  restore Check that a backup matches the project and flash size of the EC,
+          then write it. ID finds ecN-ID.rom in --backup-dir, and without
+          an argument the backups there are listed
+  repair  Compare the flash against FILE, or the image of the interrupted
+          flash, and erase and program only the sectors that differ
+  reset   Reset the primary EC using its watchdog
+  option  Dump or change the SMFI flash configuration registers
+  param   Read or write an ACPI parameter of the EC, by offset such as 0xE5,
+          or by name: flash-size-flag, fcmd-command, fcmd-argument, or
+          fcmd-data-0 to fcmd-data-3
+  fcommand
+          Run the OEM function CMD with argument DAT and the 4 bytes of
+          DATA, such as 2C000000, then print the 4 bytes it returns
+  ram     Read or write a variable of the EC firmware by its name in the
+          linker map of the build, through I2EC, or through ECMS of the
+          debugger with --programmer. Variables of 1, 2, or 4 bytes are
+          integers, and others hexadecimal bytes of their size
+  tcpc    Print the TCPCI registers of the USB-C port controller, decoding
+          the alert, role control, CC, power, and fault status fields, or
+          list the ports and the IDs of their controllers
+  protection
+          Print the flash status register, its block protect bits, and on
+          the primary EC the protect region registers of the internal flash
+  map     Print the sectors of the flash split into the boot and main
+          regions, and whether write would erase and program each
+  unlock  Clear the block protect bits set by --lock-bootblock
+  protect Lock or unlock REGION (boot or main) of the
+          internal flash of the primary EC with its protect region
+          registers, until the EC is reset
+  recover Leave follow mode and flash mode of an EC left there by a crashed
+          session, or with --scratch leave the scratch ROM of the isp
+          example, which powers off the system
+  raw     Send a command byte through the mailbox, then each --write data
+          byte, then read N bytes, printing every transaction on stderr
+  spi     Send the bytes of --tx to the SPI flash in follow mode, the first
+          as the opcode, then print the --rx bytes it answers with
+  sfdp    Read the SFDP tables of the SPI flash with 0x5A, then print them
+          and decode capacity, erase sizes, and fast reads, with the erase
+          that isp configures from them. With --programmer the external
+          flash is read through the Arduino programmer
+  unbrick Find which recovery path still reaches the primary EC, or with
+          --external on a second machine, walk through restoring BACKUP,
+          backup.rom by default, through the Arduino programmer
+  programmers
+          List the attached Arduino programmers with their buffer size and
+          protocol version
+  programmer
+          Upload the programmer sketch in SKETCH.hex to the Arduino Mega
+          2560 through its bootloader
+  dbgr    Halt the 8051 core of the EC through its debug port, run N
+          instructions of the halted core, read its program counter, or let
+          it run again, through the Arduino programmer
+  profile Sample the program counter of the EC through its debug port for
+          10 seconds, then print the addresses, or with --symbols the
+          functions, that the samples fell in most
+  report  Save the model, EC, protection state, recent journal entries, and
+          the last session of --trace in a tar archive for a support ticket,
+          after showing what it holds
+  interrupted
+          Print the write, apply, or restore that did not finish, if any,
+          exiting with 1, or with clear forget it once the flash is fixed
+  migrate Replace the proprietary firmware of the primary EC with IMAGE of
+          the System76 EC firmware for its board, saving a backup first,
+          then after the next boot verify which firmware answers, or roll
+          back to the backup
+  daemon  Run the DBus system service
+  serve   Accept write and apply requests from remote on ADDRESS, which is
+          0.0.0.0:7676 by default
+  remote  Run write or apply on the EC of a machine running serve
+
+Options:
+  -1               Use the primary EC at 0x62/0x66 (default for read and write)
+  -2               Use the secondary EC at 0x68/0x6C, on boards with two ECs
+  --fwupd          Print fwupd instance IDs, GUIDs, and version
+  --progress-json  Print one JSON object per progress event on stdout
+  --yes            Do not ask before erasing, such as after the summary that
+                   write, apply, and restore print
+  --allow-bootblock
+                   Also erase and program the EC boot block with write, which
+                   refuses an image with a different boot block without it
+  --region REGION  Only erase and program REGION (boot or main)
+  --lock-bootblock After write and apply verify, set the block protect bits of
+                   the flash that cover the boot block
+  --lock           With protect, make the EC drop erase and program of REGION
+  --unlock         With protect, let REGION be erased and programmed again
+  --algorithm NAME Flash with follow (follow mode of the EC firmware) or
+                   scratch (the scratch ROM, which powers off the system)
+                   instead of the safest one the EC answers to
+  --grab-input     Grab the keyboard and touchpad while write, apply, and
+                   restore flash, instead of waiting for all keys to be
+                   released
+  --resume         Restart a write, apply, or restore that was interrupted
+                   with Ctrl-C, with the EC still in flash mode
+  --offset OFFSET  Start reading at OFFSET, such as 0x10000
+  --length LENGTH  Only read LENGTH bytes, such as 64K, or with ram, the
+                   size of a variable that the linker map does not tell
+  --symbols MAP    Linker map of the EC firmware build, whose symbols ram
+                   looks names up in, and profile counts samples by
+  --duration DURATION
+                   How long profile samples, such as 500ms, 10s, or 2m
+  --live           Use the EC flash instead of a file with hexdump
+  --cycles N       Number of stress cycles, 10 by default
+  --port N         USB-C port of tcpc dump, 0 by default
+  --external       With unbrick, skip the internal paths of this machine and
+                   restore BACKUP through the Arduino programmer attached to it
+  --watch          With param get or ram get, read the value every second and
+                   print it each time it changes, until interrupted
+  --scratch        Overwrite the --offset and --length range with stress
+                   patterns, restoring it afterwards
+  --format FORMAT  Save read data as bin (default), ihex, or srec
+  --cmd VALUE      Command byte that raw sends
+  --write VALUE    Data byte that raw sends after the command, repeatable
+  --read N         Number of data bytes that raw reads, 0 by default
+  --tx HEX         Bytes that spi sends, such as 9F or 03000000
+  --rx N           Number of bytes that spi reads, 0 by default
+  --key KEYFILE    Shared secret that authenticates remote requests
+  --unknown-chip   With info, report the raw ID and strings of an EC even if
+                   its Super I/O ID is not known, never entering flash mode
+  --mmio ADDRESS   If the EC ports do not answer, use the memory-mapped host
+                   interface at physical ADDRESS through /dev/mem
+  --backend NAME   Reach the EC through ports (default), devport, mmio,
+                   kernel, which only works with info on the primary EC and
+                   needs ec_sys in debugfs instead of I/O privileges, or kbc,
+                   the keyboard controller ports 0x60/0x64 of legacy boards
+  --backup-dir DIR Save the flash to DIR before write and apply erase it
+  --config FILE    Read defaults from FILE instead of /etc/ecflash.toml
+  --programmer PORT
+                   Serial port or tcp:HOST:PORT of the Arduino programmer that
+                   unbrick, programmer, dbgr, and profile use, instead of the
+                   first one found, or that ram and sfdp reach the EC through
+  --trace FILE     Record every EC command, data byte, SPI opcode, and
+                   address with a timestamp in FILE, or with report, include
+                   the last session recorded in FILE
+  -q               Only print errors
+  -v               Print diagnostic messages
+  -vv              Print debugging messages
+  -h, --help       Print this help
+
+Exit codes:
+  0  Success
+  1  Unspecified failure
+  2  Failed to get I/O permission
+  3  No supported EC was found
+  4  Data read back from the EC did not match what was expected
+  5  Image is not compatible with the EC
+  6  Invalid command line arguments
+  7  Failed to open or read a file
+  8  Image is not signed by a trusted key";
+
+extern "C" {
+    fn iopl(level: isize) -> isize;
+}
+
+/// Read a value with read_stable, printing the last mismatch with -v
+fn validate<T: PartialEq + Display, F: FnMut() -> T>(f: F, attempts: usize, verbosity: Verbosity) -> Result<T, ()> {
+    ecflash::read_stable(f, attempts).map_err(|last| {
+        if let (Some((a, b)), true) = (last, verbosity >= Verbosity::Verbose) {
+            let _ = writeln!(stderr(), "Attempt {}: {} != {}", attempts - 1, a, b);
+        }
+    })
+}
+
+fn read_dmi() -> Dmi {
+    let read = |name| fs::read_to_string(format!("/sys/class/dmi/id/{}", name))
+        .map(|s| s.trim().to_string())
+        .unwrap_or_default();
+
+    Dmi {
+        vendor: read("sys_vendor"),
+        model: read("product_version"),
+    }
+}
+
+/// PCI ID of the ISA bridge at 00:1f.0, which is the LPC or eSPI controller on
+/// Intel platforms
+fn read_bridge() -> Option<String> {
+    let read = |name| fs::read_to_string(format!("/sys/bus/pci/devices/0000:00:1f.0/{}", name))
+        .ok()
+        .map(|s| s.trim().trim_start_matches("0x").to_string());
+
+    Some(format!("{}:{}", read("vendor")?, read("device")?))
+}
+
+#[cfg(feature = "daemon")]
+fn daemon() -> ! {
+    unsafe {
+        if iopl(3) < 0 {
+            let _ = writeln!(stderr(), "Failed to get I/O permission: {}", Error::last_os_error());
+            process::exit(exit::PERMISSION);
+        }
+    }
+
+    match daemon::run() {
+        Ok(()) => process::exit(exit::OK),
+        Err(err) => {
+            let _ = writeln!(stderr(), "Failed to run daemon: {}", err);
+            process::exit(exit::FAILURE);
+        }
+    }
+}
+
+#[cfg(not(feature = "daemon"))]
+fn daemon() -> ! {
+    let _ = writeln!(stderr(), "Built without daemon support, enable the 'daemon' feature");
+    process::exit(exit::USAGE);
+}
+
+struct Args {
+    verbosity: Verbosity,
+    fwupd: bool,
+    progress_json: bool,
+    yes: bool,
+    allow_bootblock: bool,
+    lock_bootblock: bool,
+    lock: bool,
+    unlock: bool,
+    region: Option<String>,
+    resume: bool,
+    grab_input: bool,
+    algorithm: Option<FlashAlgorithm>,
+    offset: Option<usize>,
+    length: Option<usize>,
+    format: Format,
+    live: bool,
+    mmio: Option<u64>,
+    key: Option<String>,
+    programmer: Option<String>,
+    /// Linker map of the EC firmware, for ram and profile
+    symbols: Option<String>,
+    /// How long profile samples
+    duration: Option<time::Duration>,
+    cycles: usize,
+    scratch: bool,
+    watch: bool,
+    external: bool,
+    port: Option<usize>,
+    /// Command, data bytes, and number of bytes to read for raw
+    raw_cmd: Option<u8>,
+    raw_write: Vec<u8>,
+    raw_read: usize,
+    /// Bytes to send and number of bytes to read for spi
+    spi_tx: Vec<u8>,
+    spi_rx: usize,
+    unknown_chip: bool,
+    backend: Option<String>,
+    backup_dir: Option<String>,
+    config_path: Option<String>,
+    trace: Option<String>,
+    /// Defaults from the configuration file, already merged into the options
+    config: Config,
+    ec_args: Vec<String>,
+}
+
+impl Args {
+    fn progress(&self) -> Progress {
+        Progress {
+            json: self.progress_json,
+            verbosity: self.verbosity,
+        }
+    }
+
+    /// Whether the primary EC was selected, for commands using a single EC
+    fn primary(&self) -> bool {
+        ! self.ec_args.iter().any(|arg| arg == "-2")
+    }
+
+    /// The file argument, for commands using a single EC
+    fn file(&self) -> Option<&str> {
+        self.ec_args.iter()
+            .map(|arg| arg.as_str())
+            .find(|&arg| arg != "-1" && arg != "-2")
+    }
+}
+
+/// Flush every filesystem, so files written so far survive the EC cutting
+/// power while flashing
+fn sync() {
+    unsafe { libc::sync() }
+}
+
+/// Parse bytes written as pairs of hexadecimal digits, ignoring spaces
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = s.bytes().filter(|&x| x != b' ').collect();
+    if ! digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+/// Parse a number, which is hexadecimal if prefixed with 0x
+fn parse_int(s: &str) -> Option<u32> {
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// Parse a duration in seconds, which may have an ms, s, or m suffix
+fn parse_duration(s: &str) -> Option<time::Duration> {
+    let (s, unit) = if let Some(s) = s.strip_suffix("ms") {
+        (s, 0.001)
+    } else if let Some(s) = s.strip_suffix('s') {
+        (s, 1.0)
+    } else if let Some(s) = s.strip_suffix('m') {
+        (s, 60.0)
+    } else {
+        (s, 1.0)
+    };
+    let seconds = s.parse::<f64>().ok()? * unit;
+    if seconds.is_finite() && seconds > 0.0 {
+        Some(time::Duration::from_secs_f64(seconds))
+    } else {
+        None
+    }
+}
+
+/// Parse a size, which may have a K or M suffix for KiB or MiB
+fn parse_size(s: &str) -> Option<usize> {
+    let (s, unit) = if let Some(s) = s.strip_suffix(['K', 'k']) {
+        (s, 1024)
+    } else if let Some(s) = s.strip_suffix(['M', 'm']) {
+        (s, 1024 * 1024)
+    } else {
+        (s, 1)
+    };
+    (parse_int(s)? as usize).checked_mul(unit)
+}
+
+/// Ask the user to type "yes" to continue, unless --yes was passed
+fn confirm(args: &Args, prompt: &str) -> bool {
+    if args.yes {
+        let _ = writeln!(stderr(), "{}", prompt);
+        return true;
+    }
+
+    let _ = write!(stderr(), "{}\nType 'yes' to continue: ", prompt);
+    let _ = stderr().flush();
+
+    let mut line = String::new();
+    match stdin().lock().read_line(&mut line) {
+        Ok(_) => line.trim() == "yes",
+        Err(_) => false,
+    }
+}
+
+/// Port I/O used for the EC, which depends on what the probe found
+type Io = Box<dyn PortIo + Send>;
+
+/// Get I/O permission and open the selected EC
+///
+/// With the ports backend, if the legacy ports do not answer and --mmio was
+/// passed, the memory-mapped host interface is tried instead.
+fn open_ec(args: &Args, primary: bool, progress: &Progress) -> EcFlash<Io> {
+    let mut ec = open_ec_io(args, primary, progress);
+
+    if let Some(trace) = &args.trace {
+        let res = fs::OpenOptions::new().append(true).open(trace).and_then(|mut file| {
+            writeln!(file, "# EC flash {}", if primary { 1 } else { 2 })?;
+            Ok(file)
+        });
+        match res {
+            Ok(file) => ec.set_trace(TraceWriter::new(file)),
+            Err(err) => progress.result(exit::IO, &format!("Failed to open '{}': {}", trace, err)),
+        }
+    }
+
+    ec
+}
+
+/// Open the EC through the selected backend
+fn open_ec_io(args: &Args, primary: bool, progress: &Progress) -> EcFlash<Io> {
+    let number = if primary { 1 } else { 2 };
+    let probe = |io: Io| if args.unknown_chip {
+        Ok(EcFlash::with_io_unchecked(io, primary))
+    } else {
+        EcFlash::with_io_known(io, primary, &args.config.known_ids)
+    };
+    let open = |io: Io| match probe(io) {
+        Ok(ec) => ec,
+        Err(err) => progress.result(exit::NO_EC, &format!("Failed to open EC flash {}: {}", number, err)),
+    };
+    let open_mmio = |base: u64| match DevMemPortIo::open(base) {
+        Ok(io) => open(Box::new(io)),
+        Err(err) => progress.result(exit::PERMISSION, &format!("Failed to open /dev/mem: {}", err)),
+    };
+
+    match args.backend.as_deref() {
+        None | Some("ports") => (),
+        Some("devport") => match DevPort::open() {
+            Ok(io) => return open(Box::new(io)),
+            Err(err) => progress.result(exit::PERMISSION, &format!("Failed to open /dev/port: {}", err)),
+        },
+        Some("mmio") => match args.mmio {
+            Some(base) => return open_mmio(base),
+            None => progress.result(exit::USAGE, "The mmio backend needs an address, pass --mmio"),
+        },
+        Some("kernel") => progress.result(exit::USAGE, "The kernel backend is only allowed with info"),
+        Some("kbc") => {
+            if ! primary {
+                progress.result(exit::USAGE, "The kbc backend only reaches the primary EC");
+            }
+            if unsafe { iopl(3) } < 0 {
+                progress.result(exit::PERMISSION, &format!("Failed to get I/O permission: {}", Error::last_os_error()));
+            }
+            progress.warning(
+                "The kbc backend talks to the EC through the keyboard controller ports 0x60/0x64, which only legacy boards need. \
+                A key press or touchpad movement corrupts its transactions, which can brick the EC while flashing"
+            );
+            i8042::unbind(progress);
+            let mut ec = open(Box::new(RawPortIo));
+            if ec.use_kbc_ports().is_err() {
+                progress.result(exit::NO_EC, "Failed to move EC flash 1 to the keyboard controller ports");
+            }
+            return ec;
+        },
+        Some(other) => progress.result(exit::USAGE, &format!("Unknown backend '{}'\n{}", other, USAGE)),
+    }
+
+    let port_err = if unsafe { iopl(3) } < 0 {
+        let err = format!("Failed to get I/O permission: {}", Error::last_os_error());
+        if args.mmio.is_none() {
+            progress.result(exit::PERMISSION, &err);
+        }
+        err
+    } else {
+        match probe(Box::new(RawPortIo)) {
+            Ok(ec) => return ec,
+            Err(err) => err,
+        }
+    };
+
+    let base = match args.mmio {
+        Some(base) => base,
+        None => progress.result(exit::NO_EC, &format!("Failed to open EC flash {}: {}", number, port_err)),
+    };
+
+    progress.info(&format!("{}, trying memory-mapped host interface at 0x{:X}", port_err, base));
+    open_mmio(base)
+}
+
+/// Probe for the open-source System76 EC firmware on the primary EC, which
+/// answers through its SMFI command region on the I/O ports instead of the
+/// mailbox string commands
+fn open_smfi(ec: &mut EcFlash<Io>) -> Option<EcSmfi<Io>> {
+    let io: Io = match ec.interface() {
+        Some((HostInterface::Ports, _, _)) => Box::new(RawPortIo),
+        Some((HostInterface::DevPort, _, _)) => Box::new(DevPort::open().ok()?),
+        _ => return None,
+    };
+    EcSmfi::with_io(io).ok()
+}
+
+/// Get I/O permission and open a flasher for the selected EC
+fn open_flasher(args: &Args, progress: &Progress) -> Flasher<Io> {
+    new_flasher(args, open_ec(args, args.primary(), progress), progress)
+}
+
+/// Wrap ec in a Flasher, suppressing the watchdog configured for its project
+fn new_flasher(args: &Args, mut ec: EcFlash<Io>, progress: &Progress) -> Flasher<Io> {
+    let watchdog = if args.config.watchdog.is_empty() {
+        None
+    } else {
+        match validate(|| ec.project(), 8, args.verbosity) {
+            Ok(project) => args.config.watchdog(&project),
+            Err(()) => progress.result(exit::VERIFY, "Failed to read EC project to look up its watchdog"),
+        }
+    };
+
+    let mut flasher = Flasher::new(ec);
+    if let Some(watchdog) = watchdog {
+        progress.info(&format!(
+            "Suppressing the EC watchdog in flash mode by setting parameter 0x{:02X} to 0x{:02X}",
+            watchdog.param.offset(),
+            watchdog.suppress
+        ));
+        flasher.watchdog = Some(watchdog);
+    }
+    flasher
+}
+
+/// Enter flash mode, exiting with an informative error if the EC refuses
+///
+/// On the primary EC, events of the kernel EC driver are paused until
+/// stop_flasher.
+unsafe fn start_flasher(flasher: &mut Flasher<Io>, primary: bool, progress: &Progress) {
+    if primary {
+        kernel::pause(progress);
+    }
+
+    let (code, message) = match flasher.start() {
+        Ok(Handshake::Accepted) => {
+            if let Some((interface, _, cmd_port)) = flasher.interface() {
+                session::begin(primary, interface, cmd_port, progress);
+                journal::interface(interface);
+            }
+            journal::update(|entry| entry.chip_id = flasher.chip_id());
+            return;
+        },
+        Ok(handshake @ Handshake::UnsupportedProtocol(_)) => {
+            (exit::INCOMPATIBLE, format!("Failed to start flasher: {}", handshake))
+        },
+        Ok(handshake @ Handshake::UnsupportedChip(_)) => {
+            (exit::NO_EC, format!("Failed to start flasher: {}", handshake))
+        },
+        Ok(handshake) => (exit::FAILURE, format!("Failed to start flasher: {}", handshake)),
+        Err(()) => (exit::FAILURE, "Failed to start flasher: EC did not take command".to_string()),
+    };
+    kernel::resume(progress);
+    progress.result(code, &message)
+}
+
+/// Take over an EC that an interrupted run left in flash mode
+unsafe fn resume_flasher(flasher: &mut Flasher<Io>, primary: bool, progress: &Progress) {
+    if primary {
+        kernel::take_over(progress);
+    }
+
+    if flasher.resume().is_err() {
+        kernel::resume(progress);
+        progress.result(exit::NO_EC, "Failed to resume flasher: EC is not supported");
+    }
+    if let Some((interface, _, cmd_port)) = flasher.interface() {
+        session::begin(primary, interface, cmd_port, progress);
+        journal::interface(interface);
+    }
+    journal::update(|entry| entry.chip_id = flasher.chip_id());
+}
+
+/// Leave flash mode, and resume the kernel EC driver
+unsafe fn stop_flasher(flasher: &mut Flasher<Io>, progress: &Progress) {
+    let _ = flasher.stop();
+    session::end();
+    kernel::resume(progress);
+}
+
+/// Read the range selected by --offset and --length from the EC flash,
+/// returning the data and flash size
+fn read_live(args: &Args, progress: &Progress) -> (Vec<u8>, usize) {
+    let mut flasher = open_flasher(args, progress);
+    let size = flasher.size;
+    let offset = args.offset.unwrap_or(0);
+    let length = args.length.unwrap_or_else(|| size.saturating_sub(offset));
+    if offset.checked_add(length).is_none_or(|end| end > size) {
+        progress.result(exit::USAGE, &format!("Range exceeds flash size {}", size));
+    }
+
+    unsafe {
+        start_flasher(&mut flasher, args.primary(), progress);
+
+        let res = flasher.read_range(offset, length, |x| progress.update("read", x, length));
+
+        stop_flasher(&mut flasher, progress);
+
+        match res {
+            Ok(data) => (data, size),
+            Err(()) => progress.result(exit::FAILURE, "Failed to read data"),
+        }
+    }
+}
+
+fn read(args: &Args) -> ! {
+    let progress = args.progress();
+    let file = match args.file() {
+        Some(file) => file,
+        None => progress.result(exit::USAGE, &format!("No file provided\n{}", USAGE)),
+    };
+
+    journal::begin("read", file, args.primary());
+    let (data, size) = read_live(args, &progress);
+    if data.len() == size {
+        journal::update(|entry| entry.old_version = Some(EcFile::new(data.clone()).version()));
+    }
+    let encoded = args.format.encode(args.offset.unwrap_or(0), &data);
+    if let Err(err) = fs::write(file, &encoded) {
+        progress.result(exit::IO, &format!("Failed to write '{}': {}", file, err));
+    }
+    match save_digest(file, &encoded) {
+        Ok(()) => progress.result(exit::OK, &format!("Saved EC flash to '{}'", file)),
+        Err(err) => progress.result(exit::IO, &format!("Failed to write '{}': {}", digest_path(file), err)),
+    }
+}
+
+fn hexdump(args: &Args) -> ! {
+    let progress = args.progress();
+    let offset = args.offset.unwrap_or(0);
+
+    let (data, size) = if args.live {
+        read_live(args, &progress)
+    } else {
+        let file = match args.file() {
+            Some(file) => file,
+            None => progress.result(exit::USAGE, &format!("No file provided, pass --live to use the EC\n{}", USAGE)),
+        };
+        let data = match fs::read(file) {
+            Ok(data) => data,
+            Err(err) => progress.result(exit::IO, &format!("Failed to read '{}': {}", file, err)),
+        };
+        let size = data.len();
+        let length = args.length.unwrap_or_else(|| size.saturating_sub(offset));
+        match offset.checked_add(length) {
+            Some(end) if end <= size => (data[offset..end].to_vec(), size),
+            _ => progress.result(exit::USAGE, &format!("Range exceeds file size {}", size)),
+        }
+    };
+
+    progress.info(&format!("Showing {} of {} bytes", data.len(), size));
+    let _ = stdout().write_all(format::hexdump(offset, &data).as_bytes());
+    process::exit(exit::OK);
+}
+
+fn bench(args: &Args) -> ! {
+    let progress = args.progress();
+    let mut ec = open_ec(args, args.primary(), &progress);
+    let interface = ec.interface();
+
+    // Mailbox round trips, using the harmless flash size parameter
+    let commands = 100;
+    let start = time::Instant::now();
+    for _ in 0..commands {
+        if unsafe { ec.get_param(EcParam::FlashSizeFlag) }.is_err() {
+            progress.result(exit::FAILURE, "Failed to read EC parameter");
+        }
+    }
+    let latency = start.elapsed() / commands;
+
+    let mut flasher = new_flasher(args, ec, &progress);
+    let size = flasher.size;
+    let offset = args.offset.unwrap_or(BOOT_BLOCK.end);
+    let length = args.length.unwrap_or(16 * 1024);
+    if offset.checked_add(length).is_none_or(|end| end > size) {
+        progress.result(exit::USAGE, &format!("Range exceeds flash size {}", size));
+    }
+
+    let rate = |bytes: usize, elapsed: time::Duration| {
+        bytes as f64 / 1024.0 / elapsed.as_secs_f64().max(f64::EPSILON)
+    };
+
+    unsafe {
+        start_flasher(&mut flasher, args.primary(), &progress);
+
+        let res = (|| {
+            let start = time::Instant::now();
+            let data = flasher.read_range(offset, length, |x| progress.update("read", x, length))
+                .map_err(|()| "Failed to read data")?;
+            let read = start.elapsed();
+
+            // Programming 0xFF leaves the flash unchanged, since it can only clear bits
+            flasher.range = offset..offset + length;
+            let start = time::Instant::now();
+            flasher.write(&vec![0xFF; size], |_| ())
+                .map_err(|()| "Failed to write data")?;
+            let write = start.elapsed();
+
+            // Only erase a block that is already blank
+            let blank = (offset.div_ceil(1024) * 1024..offset + length)
+                .step_by(1024)
+                .filter(|&address| address + 1024 <= offset + length)
+                .find(|&address| {
+                    data[address - offset..address - offset + 1024].iter().all(|&x| x == 0xFF)
+                        && ! flasher.is_protected(address..address + 1024)
+                });
+            let erase = match blank {
+                Some(address) => {
+                    flasher.range = address..address + 1024;
+                    let start = time::Instant::now();
+                    flasher.erase(|_| ())
+                        .map_err(|()| "Failed to erase data")?;
+                    Some((address, start.elapsed()))
+                },
+                None => None,
+            };
+
+            Ok((read, write, erase))
+        })();
+
+        stop_flasher(&mut flasher, &progress);
+
+        let (read, write, erase) = match res {
+            Ok(times) => times,
+            Err(err) => progress.result(exit::FAILURE, err),
+        };
+
+        let mut stdout = stdout();
+        if let Some((interface, data_port, cmd_port)) = interface {
+            let _ = writeln!(stdout, "Interface: {}, data 0x{:02X}, command 0x{:02X}", interface, data_port, cmd_port);
+        }
+        let _ = writeln!(stdout, "Command latency: {} us", latency.as_micros());
+        let _ = writeln!(stdout, "Read: {:.1} KB/s ({} KB at 0x{:05X})", rate(length, read), length / 1024, offset);
+        let _ = writeln!(stdout, "Write: {:.1} KB/s ({} KB of 0xFF)", rate(length, write), length / 1024);
+        match erase {
+            Some((address, elapsed)) => {
+                let _ = writeln!(stdout, "Erase: {} ms per block (blank block at 0x{:05X})", elapsed.as_millis(), address);
+            },
+            None => {
+                let _ = writeln!(stdout, "Erase: not measured, no blank block in range");
+            }
+        }
+        process::exit(exit::OK);
+    }
+}
+
+/// Runs, failures, and mismatched bytes of one stress phase
+#[derive(Default)]
+struct PhaseStats {
+    runs: usize,
+    failures: usize,
+    bad_bytes: usize,
+}
+
+impl PhaseStats {
+    /// Count a run, which failed if it returned an error or any byte differed
+    fn record(&mut self, res: Result<usize, ()>) {
+        self.runs += 1;
+        match res {
+            Ok(0) => (),
+            Ok(bad_bytes) => {
+                self.failures += 1;
+                self.bad_bytes += bad_bytes;
+            },
+            Err(()) => self.failures += 1,
+        }
+    }
+}
+
+/// Scratch pattern for a stress cycle, which flips every bit between cycles
+fn stress_pattern(address: usize, cycle: usize) -> u8 {
+    let byte = (address as u8) ^ ((address >> 8) as u8);
+    if cycle.is_multiple_of(2) { byte } else { ! byte }
+}
+
+fn stress(args: &Args) -> ! {
+    let progress = args.progress();
+    let mut flasher = open_flasher(args, &progress);
+    let size = flasher.size;
+
+    let (offset, length) = if args.scratch {
+        let (offset, length) = match (args.offset, args.length) {
+            (Some(offset), Some(length)) => (offset, length),
+            _ => progress.result(exit::USAGE, "--scratch needs --offset and --length"),
+        };
+        if offset % 1024 != 0 || length % 1024 != 0 || length == 0 {
+            progress.result(exit::USAGE, "--scratch needs a range aligned to 1 KB blocks");
+        }
+        (offset, length)
+    } else {
+        let offset = args.offset.unwrap_or(0);
+        (offset, args.length.unwrap_or_else(|| size.saturating_sub(offset)))
+    };
+    if offset.checked_add(length).is_none_or(|end| end > size) {
+        progress.result(exit::USAGE, &format!("Range exceeds flash size {}", size));
+    }
+    let range = offset..offset + length;
+
+    if args.scratch {
+        flasher.range = range.clone();
+        if flasher.is_protected(range.clone()) {
+            progress.result(exit::USAGE, "Range is protected, pass --allow-bootblock to stress it");
+        }
+        let prompt = format!(
+            "Erasing and writing 0x{:05X}-0x{:05X} {} times, which bricks the EC if interrupted",
+            range.start, range.end - 1, args.cycles
+        );
+        if ! confirm(args, &prompt) {
+            progress.result(exit::FAILURE, "Cancelled");
+        }
+    }
+
+    let mut read = PhaseStats::default();
+    let mut erase = PhaseStats::default();
+    let mut write = PhaseStats::default();
+    let mut verify = PhaseStats::default();
+
+    // Count bytes that differ from the expected data, or fail if the read did
+    let mismatched = |res: Result<Vec<u8>, ()>, expected: &dyn Fn(usize) -> u8| {
+        res.map(|data| {
+            data.iter().enumerate().filter(|&(i, &byte)| byte != expected(offset + i)).count()
+        })
+    };
+
+    unsafe {
+        start_flasher(&mut flasher, args.primary(), &progress);
+
+        let original = match flasher.read_range(offset, length, |_| ()) {
+            Ok(original) => original,
+            Err(()) => {
+                stop_flasher(&mut flasher, &progress);
+                progress.result(exit::FAILURE, "Failed to read original data");
+            }
+        };
+
+        for cycle in 0..args.cycles {
+            progress.update("stress", cycle, args.cycles);
+
+            let res = flasher.read_range(offset, length, |_| ());
+            read.record(mismatched(res, &|i| original[i - offset]));
+
+            if ! args.scratch {
+                continue;
+            }
+
+            erase.record(flasher.erase(|_| ()).and_then(|()| {
+                mismatched(flasher.read_range(offset, length, |_| ()), &|_| 0xFF)
+            }));
+
+            let mut data = vec![0xFF; size];
+            for i in range.clone() {
+                data[i] = stress_pattern(i, cycle);
+            }
+            write.record(flasher.write(&data, |_| ()).map(|()| 0));
+
+            let res = flasher.read_range(offset, length, |_| ());
+            verify.record(mismatched(res, &|i| stress_pattern(i, cycle)));
+        }
+        progress.update("stress", args.cycles, args.cycles);
+
+        let restored = ! args.scratch || (|| {
+            flasher.erase(|_| ())?;
+            let mut data = vec![0xFF; size];
+            data[range.clone()].copy_from_slice(&original);
+            flasher.write(&data, |_| ())?;
+            flasher.read_range(offset, length, |_| ())
+        })().is_ok_and(|data| data == original);
+
+        stop_flasher(&mut flasher, &progress);
+
+        let mut stdout = stdout();
+        let _ = writeln!(stdout, "Cycles: {} over 0x{:05X}-0x{:05X}", args.cycles, range.start, range.end - 1);
+        let _ = writeln!(stdout, "Phase   Runs  Failed  Bad bytes  Error rate");
+        let mut failures = 0;
+        for (name, stats) in [("read", &read), ("erase", &erase), ("write", &write), ("verify", &verify)] {
+            if stats.runs == 0 {
+                continue;
+            }
+            let _ = writeln!(
+                stdout,
+                "{:<6} {:>5} {:>7} {:>10} {:>10.2}%",
+                name, stats.runs, stats.failures, stats.bad_bytes,
+                stats.failures as f64 * 100.0 / stats.runs as f64
+            );
+            failures += stats.failures;
+        }
+
+        if ! restored {
+            progress.result(exit::VERIFY, "Failed to restore original data, flash it again before resetting the EC");
+        }
+        if failures > 0 {
+            progress.result(exit::VERIFY, &format!("{} of {} phases failed", failures, read.runs + erase.runs + write.runs + verify.runs));
+        }
+        progress.result(exit::OK, "All stress cycles passed")
+    }
+}
+
+fn write(args: &Args) -> ! {
+    let progress = args.progress();
+    let file = match args.file() {
+        Some(file) => file,
+        None => progress.result(exit::USAGE, &format!("No file provided\n{}", USAGE)),
+    };
+
+    let data = match fs::read(file) {
+        Ok(data) => data,
+        Err(err) => progress.result(exit::IO, &format!("Failed to read '{}': {}", file, err)),
+    };
+    check_digest(&progress, file, &data);
+
+    // Detached signature, only checked when built with the signature feature
+    let signature = fs::read(format!("{}.sig", file)).ok();
+
+    journal::begin("write", file, args.primary());
+    let flasher = open_flasher(args, &progress);
+    flash(args, &progress, flasher, data, signature)
+}
+
+/// Find a backup by path, or by the ID in its name in the backup directory
+fn find_backup(args: &Args, name: &str) -> Option<String> {
+    if fs::metadata(name).is_ok_and(|metadata| metadata.is_file()) {
+        return Some(name.to_string());
+    }
+
+    let dir = args.backup_dir.as_deref()?.trim_end_matches('/');
+    let number = if args.primary() { 1 } else { 2 };
+    vec![
+        format!("{}/{}", dir, name),
+        format!("{}/{}.rom", dir, name),
+        format!("{}/ec{}-{}.rom", dir, number, name),
+    ].into_iter().find(|path| fs::metadata(path).is_ok_and(|metadata| metadata.is_file()))
+}
+
+/// Print the backups of the selected EC in the backup directory, oldest first
+fn list_backups(args: &Args, progress: &Progress) -> ! {
+    let dir = match &args.backup_dir {
+        Some(dir) => dir,
+        None => progress.result(exit::USAGE, &format!("No backup provided, and no --backup-dir to list\n{}", USAGE)),
+    };
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => progress.result(exit::IO, &format!("Failed to read '{}': {}", dir, err)),
+    };
+
+    let prefix = format!("ec{}-", if args.primary() { 1 } else { 2 });
+    let mut ids: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            Some(name.strip_prefix(&prefix)?.strip_suffix(".rom")?.to_string())
+        })
+        .collect();
+    ids.sort_by_key(|id| id.parse::<u64>().unwrap_or(0));
+
+    let mut stdout = stdout();
+    for id in ids {
+        let path = format!("{}/{}{}.rom", dir.trim_end_matches('/'), prefix, id);
+        match fs::read(&path) {
+            Ok(data) => {
+                let size = data.len();
+                let mut file = EcFile::new(data);
+                let unknown = |s: String| if s.is_empty() { "unknown".to_string() } else { s };
+                let _ = writeln!(stdout, "{}: {} {}, {} KB", id, unknown(file.project()), unknown(file.version()), size / 1024);
+            },
+            Err(err) => { let _ = writeln!(stdout, "{}: {}", id, err); },
+        }
+    }
+    process::exit(exit::OK);
+}
+
+fn restore(args: &Args) -> ! {
+    let progress = args.progress();
+    let name = match args.file() {
+        Some(name) => name,
+        None => list_backups(args, &progress),
+    };
+    let path = match find_backup(args, name) {
+        Some(path) => path,
+        None => progress.result(exit::IO, &format!("No backup '{}' as a file or in the backup directory", name)),
+    };
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(err) => progress.result(exit::IO, &format!("Failed to read '{}': {}", path, err)),
+    };
+    check_digest(&progress, &path, &data);
+
+    journal::begin("restore", &path, args.primary());
+    let mut ec = open_ec(args, args.primary(), &progress);
+    let project = match validate(|| ec.project(), 8, args.verbosity) {
+        Ok(project) => project,
+        Err(()) => progress.result(exit::VERIFY, "Failed to read EC project"),
+    };
+    let backup_project = EcFile::new(data.clone()).project();
+    if backup_project.is_empty() {
+        progress.result(exit::INCOMPATIBLE, &format!("Backup '{}' does not hold an EC project", path));
+    }
+    if backup_project.trim() != project.trim() {
+        progress.result(exit::INCOMPATIBLE, &format!(
+            "Backup '{}' is for project {}, but the EC is {}",
+            path, backup_project.trim(), project.trim()
+        ));
+    }
+    let size = ec.size();
+    if data.len() != size {
+        progress.result(exit::INCOMPATIBLE, &format!(
+            "Backup '{}' is {} bytes, but the flash is {} bytes",
+            path, data.len(), size
+        ));
+    }
+    progress.info(&format!("Restoring backup '{}' for {}", path, project.trim()));
+
+    // Detached signature, only checked when built with the signature feature
+    let signature = fs::read(format!("{}.sig", path)).ok();
+
+    flash(args, &progress, new_flasher(args, ec, &progress), data, signature)
+}
+
+fn apply(args: &Args) -> ! {
+    let progress = args.progress();
+    let file = match args.file() {
+        Some(file) => file,
+        None => progress.result(exit::USAGE, &format!("No bundle provided\n{}", USAGE)),
+    };
+
+    let bundle = match fs::read(file) {
+        Ok(data) => match Bundle::parse(&data) {
+            Ok(bundle) => bundle,
+            Err(err) => progress.result(exit::INCOMPATIBLE, &format!("Invalid bundle '{}': {}", file, err)),
+        },
+        Err(err) => progress.result(exit::IO, &format!("Failed to read '{}': {}", file, err)),
+    };
+
+    journal::begin("apply", file, args.primary());
+    let mut ec = open_ec(args, args.primary(), &progress);
+    let project = match validate(|| ec.project(), 8, args.verbosity) {
+        Ok(project) => project,
+        Err(()) => progress.result(exit::VERIFY, "Failed to read EC project"),
+    };
+    let version = match validate(|| ec.version(), 8, args.verbosity) {
+        Ok(version) => version,
+        Err(()) => progress.result(exit::VERIFY, "Failed to read EC version"),
+    };
+
+    if let Err(err) = bundle.manifest.check(&project, &version) {
+        progress.result(exit::INCOMPATIBLE, &format!("Bundle does not apply: {}", err));
+    }
+    progress.info(&format!("Applying bundle for {} over version {}", bundle.manifest.project, version.trim()));
+
+    flash(args, &progress, new_flasher(args, ec, &progress), bundle.firmware, bundle.signature)
+}
+
+/// Refuse to flash on battery power below threshold percent
+fn check_battery(threshold: u8, progress: &Progress) {
+    let supplies = match fs::read_dir("/sys/class/power_supply") {
+        Ok(supplies) => supplies,
+        Err(_) => return,
+    };
+
+    let mut on_ac = false;
+    let mut lowest = None;
+    for supply in supplies.flatten() {
+        let read = |name| fs::read_to_string(supply.path().join(name))
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default();
+        match read("type").as_str() {
+            "Mains" => on_ac |= read("online") == "1",
+            "Battery" => if let Ok(capacity) = read("capacity").parse::<u8>() {
+                lowest = Some(lowest.map_or(capacity, |lowest: u8| lowest.min(capacity)));
+            },
+            _ => (),
+        }
+    }
+
+    if let (false, Some(capacity)) = (on_ac, lowest) {
+        if capacity < threshold {
+            progress.result(exit::FAILURE, &format!(
+                "Battery is at {}%, below the configured {}%, connect AC power to flash",
+                capacity, threshold
+            ));
+        }
+    }
+}
+
+/// Save the flash contents in dir, returning the path of the new file
+fn save_backup(dir: &str, primary: bool, data: &[u8]) -> std::io::Result<String> {
+    fs::create_dir_all(dir)?;
+    let seconds = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let path = format!("{}/ec{}-{}.rom", dir.trim_end_matches('/'), if primary { 1 } else { 2 }, seconds);
+
+    // The backup must be on disk before anything is erased
+    let mut file = fs::File::create(&path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    save_digest(&path, data)?;
+    fs::File::open(dir)?.sync_all()?;
+    session::saved_backup(&path);
+    Ok(path)
+}
+
+/// Create a directory for an image handed to a child process, readable only by
+/// this user, and failing if one of that name is already there so that nobody
+/// can swap the image underneath
+fn private_dir(name: &str) -> std::io::Result<std::path::PathBuf> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    let nanos = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|duration| duration.subsec_nanos())
+        .unwrap_or(0);
+    let dir = env::temp_dir().join(format!("{}-{}-{}", name, process::id(), nanos));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+/// Sidecar file that holds the digest of path
+fn digest_path(path: &str) -> String {
+    format!("{}.sha256", path)
+}
+
+/// Save the sha256 of data, the contents of path, to its sidecar file in the
+/// format of sha256sum, so that a truncated copy is caught before it is
+/// flashed
+fn save_digest(path: &str, data: &[u8]) -> std::io::Result<()> {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let hex: String = sha256(data).iter().map(|byte| format!("{:02x}", byte)).collect();
+    let mut file = fs::File::create(digest_path(path))?;
+    writeln!(file, "{}  {}", hex, name)?;
+    file.sync_all()
+}
+
+/// Check data, read from path, against the digest in its sidecar file,
+/// returning whether there was one to check
+fn verify_digest(path: &str, data: &[u8]) -> Result<bool, String> {
+    let sidecar = digest_path(path);
+    let text = match fs::read_to_string(&sidecar) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(format!("failed to read '{}': {}", sidecar, err)),
+    };
+
+    let hex: String = sha256(data).iter().map(|byte| format!("{:02x}", byte)).collect();
+    match text.split_whitespace().next() {
+        Some(expected) if expected.eq_ignore_ascii_case(&hex) => Ok(true),
+        Some(expected) if expected.len() == 64 => Err(format!(
+            "'{}' does not match the sha256 in '{}', it may be a truncated or corrupted copy of {} bytes",
+            path, sidecar, data.len()
+        )),
+        _ => Err(format!("'{}' does not hold a sha256", sidecar)),
+    }
+}
+
+/// Check the digest of a file that is about to be flashed, if it has one
+fn check_digest(progress: &Progress, path: &str, data: &[u8]) {
+    match verify_digest(path, data) {
+        Ok(true) => progress.info(&format!("Verified '{}' against '{}'", path, digest_path(path))),
+        Ok(false) => (),
+        Err(err) => progress.result(exit::IO, &err),
+    }
+}
+
+/// What a flash interrupted with Ctrl-C needs to be restarted, with the EC
+/// left in flash mode
+struct ResumeState {
+    /// FILE argument of the interrupted command
+    image: String,
+    /// Phase that was interrupted, erase or write
+    phase: String,
+    /// Backup of the flash before it was erased
+    backup: Option<String>,
+}
+
+/// Where the resume state of the EC is saved, which does not need to survive a
+/// reboot since that leaves flash mode
+fn resume_path(primary: bool) -> String {
+    env::temp_dir()
+        .join(format!("system76_ecflash-ec{}.resume", if primary { 1 } else { 2 }))
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn save_resume(path: &str, state: &ResumeState) -> std::io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    writeln!(file, "image={}", state.image)?;
+    writeln!(file, "phase={}", state.phase)?;
+    if let Some(backup) = &state.backup {
+        writeln!(file, "backup={}", backup)?;
+    }
+    file.sync_all()
+}
+
+fn load_resume(path: &str) -> std::io::Result<ResumeState> {
+    let text = fs::read_to_string(path)?;
+    let mut state = ResumeState {
+        image: String::new(),
+        phase: String::new(),
+        backup: None,
+    };
+    for line in text.lines() {
+        match line.split_once('=') {
+            Some(("image", value)) => state.image = value.to_string(),
+            Some(("phase", value)) => state.phase = value.to_string(),
+            Some(("backup", value)) => state.backup = Some(value.to_string()),
+            _ => (),
+        }
+    }
+    Ok(state)
+}
+
+/// Check that the backup at path reads back as data, and that BACKUP_CHECKS
+/// sectors of the flash read the same again
+unsafe fn verify_backup(flasher: &mut Flasher<Io>, path: &str, data: &[u8]) -> Result<(), String> {
+    let saved = fs::read(path).map_err(|err| format!("failed to read it back: {}", err))?;
+    if saved != data {
+        return Err("it does not read back as written".to_string());
+    }
+
+    let unstable = ecflash::verify_backup(data, |offset, again| {
+        let read = flasher.read_range(offset, again.len(), |_| ())
+            .map_err(|()| format!("failed to read 0x{:05X} again", offset))?;
+        again.copy_from_slice(&read);
+        Ok::<_, String>(())
+    })?;
+    match unstable {
+        Some(offset) => Err(format!("0x{:05X}-0x{:05X} reads differently the second time", offset, offset + 1023)),
+        None => Ok(()),
+    }
+}
+
+fn verify_image(progress: &Progress, data: &[u8], signature: Option<&[u8]>) {
+    match ecflash::verify_image(data, signature) {
+        Ok(()) => if cfg!(feature = "signature") {
+            progress.info("Image signature verified");
+        },
+        Err(err) => progress.result(exit::SIGNATURE, &format!("Failed to verify image signature: {}", err)),
+    }
+}
+
+/// Limit erase and write of flasher to --region, refusing a protected region,
+/// and keep the protected ranges of the configuration out of them
+fn limit_flasher(args: &Args, progress: &Progress, flasher: &mut Flasher<Io>) -> Option<Region> {
+    let size = flasher.size;
+    flasher.allow_bootblock = args.allow_bootblock;
+    let region = args.region.as_ref().map(|name| match Layout::new(size).region(name) {
+        Some(region) => region.clone(),
+        None => progress.result(exit::USAGE, &format!("Unknown region '{}'", name)),
+    });
+    if let Some(region) = &region {
+        flasher.range = region.range.clone();
+        if flasher.is_protected(region.range.clone()) {
+            progress.result(exit::USAGE, &format!("Region '{}' is protected, pass --allow-bootblock to flash it", region.name));
+        }
+    }
+    match args.config.protected_ranges(size) {
+        Ok(ranges) => flasher.protected.extend(ranges),
+        Err(err) => progress.result(exit::USAGE, &format!("Invalid configuration: {}", err)),
+    }
+
+    // The EC drops erase and program inside a locked protect region, which
+    // verify would only find after the rest of the flash is erased
+    if args.primary() {
+        for index in 0..PROTECT_REGIONS {
+            match unsafe { flasher.protect_region(index) } {
+                Ok(protect) if protect.blocks(&flasher.range) => {
+                    let name = Layout::new(size).region_at(protect.range.start).map_or("main", |region| region.name);
+                    progress.result(exit::USAGE, &format!(
+                        "0x{:05X}-0x{:05X} is locked by protect region {}, run ecflash protect --region {} --unlock first",
+                        protect.range.start, protect.range.end - 1, index, name
+                    ));
+                },
+                _ => (),
+            }
+        }
+    }
+    region
+}
+
+/// Ask the EC which flash algorithms it answers to, and pick --algorithm or
+/// the safest of them
+unsafe fn select_algorithm(args: &Args, progress: &Progress, flasher: &mut Flasher<Io>) -> FlashAlgorithm {
+    if args.primary() {
+        kernel::pause(progress);
+    }
+    let probe = flasher.probe();
+    kernel::resume(progress);
+    let probe = match probe {
+        Ok(probe) => probe,
+        Err(()) => progress.result(exit::FAILURE, "Failed to probe flash algorithms: EC did not take command"),
+    };
+
+    progress.info(&format!("Follow mode: {}", probe.handshake));
+    progress.info(&format!(
+        "Fcommand: {}",
+        if probe.fcommand { "answers, but has no known function to program the flash" } else { "does not answer" }
+    ));
+    progress.info(&format!("Scratch ROM: {}", if probe.scratch_rom { "I2EC answers" } else { "I2EC does not answer" }));
+
+    let algorithm = match args.algorithm {
+        Some(algorithm) if probe.supports(algorithm) => algorithm,
+        Some(algorithm) => progress.result(exit::INCOMPATIBLE, &format!("The EC does not answer to {}", algorithm)),
+        None => match probe.select() {
+            Some(algorithm) => algorithm,
+            None => {
+                let code = match probe.handshake {
+                    Handshake::UnsupportedChip(_) => exit::NO_EC,
+                    Handshake::UnsupportedProtocol(_) => exit::INCOMPATIBLE,
+                    _ => exit::FAILURE,
+                };
+                progress.result(code, &format!("Failed to start flasher: {}", probe.handshake))
+            },
+        },
+    };
+    progress.info(&format!("Flashing with {}", algorithm));
+    algorithm
+}
+
+/// Erase and program the whole flash of the primary EC with data through the
+/// scratch ROM, which powers off the system when done
+fn flash_scratch_rom(args: &Args, progress: &Progress, mut flasher: Flasher<Io>, image: &str, data: Vec<u8>) -> ! {
+    if ! flasher.allow_bootblock || args.region.is_some() || flasher.protected.len() > 1 {
+        progress.result(
+            exit::USAGE,
+            "The scratch ROM erases and programs the whole flash, so it needs --allow-bootblock, and no --region or protected ranges"
+        );
+    }
+    if ! matches!(flasher.interface(), Some((HostInterface::Ports, _, _))) {
+        progress.result(exit::USAGE, "The scratch ROM is only reached through the ports backend");
+    }
+    drop(flasher);
+
+    let prompt = format!(
+        "Image:   {}\nThe scratch ROM stops the EC firmware, and the system powers off when it is done, even if it fails",
+        image
+    );
+    if ! confirm(args, &prompt) {
+        progress.result(exit::FAILURE, "Cancelled");
+    }
+
+    // The backup is saved, and the marker written, once the scratch ROM has
+    // read the flash and only if it differs
+    let operation = journal::operation().unwrap_or_else(|| "write".to_string());
+    let mut backup = |rom: &[u8]| {
+        let path = match &args.backup_dir {
+            Some(dir) => {
+                let path = save_backup(dir, true, rom).map_err(|err| format!("failed to save backup in '{}': {}", dir, err))?;
+                progress.info(&format!("Saved backup to '{}'", path));
+                Some(path)
+            },
+            None => None,
+        };
+        if let Err(err) = marker::set(&operation, image, true, path.as_deref()) {
+            progress.warning(&format!("Failed to write '{}', an interrupted flash will not be noticed: {}", marker::MARKER_PATH, err));
+        }
+        Ok(())
+    };
+    let mut update = |phase: &str, done: usize, total: usize| progress.update(phase, done, total);
+    let mut power_off = || {
+        progress.info("Sync");
+        sync();
+    };
+    let mut opts = IspOptions::new(FlashChip::Internal);
+    opts.known_ids = &args.config.known_ids;
+    opts.backup = Some(&mut backup);
+    opts.progress = Some(&mut update);
+    opts.power_off = Some(&mut power_off);
+
+    progress.info("Sync");
+    sync();
+
+    // The scratch ROM cannot be left without powering off, so it is not
+    // interrupted
+    session::catch_signals();
+    kernel::pause(progress);
+    progress.info("Waiting for all keys to be released");
+    let res = unsafe { isp_internal(RawPortIo, &data, opts) };
+    kernel::resume(progress);
+
+    match res {
+        Ok(isp) => {
+            journal::update(|entry| entry.chip_id = Some(isp.chip_id));
+            progress.report(&isp.report);
+            if let Err(err) = marker::clear() {
+                progress.warning(&format!("Failed to remove '{}': {}", marker::MARKER_PATH, err));
+            }
+            if isp.unchanged {
+                progress.result(exit::OK, "The flash already holds the image")
+            }
+            progress.result(exit::OK, "Successfully flashed EC through the scratch ROM")
+        },
+        Err(err) => progress.result(exit::FAILURE, &format!("Failed to flash EC through the scratch ROM: {}", err)),
+    }
+}
+
+/// Erase and program the flash with data, then verify it
+fn flash(args: &Args, progress: &Progress, mut flasher: Flasher<Io>, mut data: Vec<u8>, signature: Option<Vec<u8>>) -> ! {
+    verify_image(progress, &data, signature.as_deref());
+
+    let size = flasher.size;
+
+    if data.len() > size {
+        progress.result(exit::INCOMPATIBLE, &format!("File size {} exceeds flash size {}", data.len(), size));
+    }
+    data.resize(size, 0xFF);
+
+    if let Some(region) = limit_flasher(args, progress, &mut flasher) {
+        progress.info(&format!("Only flashing region '{}'", region.name));
+    }
+    if ! flasher.allow_bootblock {
+        progress.info("Leaving boot block untouched, pass --allow-bootblock to flash it");
+    }
+
+    if let Some(threshold) = args.config.battery_threshold {
+        check_battery(threshold, progress);
+    }
+
+    let image = args.file().unwrap_or("").to_string();
+    if args.resume {
+        // The interrupted run left the EC in flash mode, so it used follow mode
+        if args.algorithm == Some(FlashAlgorithm::ScratchRom) {
+            progress.result(exit::USAGE, "Only follow mode can be resumed");
+        }
+    } else if unsafe { select_algorithm(args, progress, &mut flasher) } == FlashAlgorithm::ScratchRom {
+        flash_scratch_rom(args, progress, flasher, &image, data);
+    }
+
+    let resume_path = resume_path(args.primary());
+    let resume = if args.resume {
+        match load_resume(&resume_path) {
+            Ok(state) if state.image == image => {
+                progress.info(&format!("Restarting the flash interrupted during {}", state.phase));
+                Some(state)
+            },
+            Ok(state) => progress.result(
+                exit::USAGE,
+                &format!("The interrupted flash was of '{}', not '{}'", state.image, image)
+            ),
+            Err(err) => progress.result(
+                exit::USAGE,
+                &format!("Nothing to resume, failed to read '{}': {}", resume_path, err)
+            ),
+        }
+    } else {
+        None
+    };
+
+    // Read the original data to skip blank blocks when erasing, and to show
+    // what would change, in its own session since the keyboard of the primary
+    // EC does not work in flash mode. A resumed run stays in flash mode.
+    let original = unsafe {
+        match &resume {
+            Some(_) => resume_flasher(&mut flasher, args.primary(), progress),
+            None => start_flasher(&mut flasher, args.primary(), progress),
+        }
+        let res = (|| {
+            let original = flasher.read(|x| progress.update("read", x, size))
+                .map_err(|()| (exit::FAILURE, "Failed to read original data".to_string()))?;
+
+            if let Some(state) = &resume {
+                // The flash is partly erased, so the backup is the one from
+                // before the interrupted run
+                Ok((original, state.backup.clone()))
+            } else if let Some(dir) = &args.backup_dir {
+                let path = save_backup(dir, args.primary(), &original)
+                    .map_err(|err| (exit::IO, format!("Failed to save backup in '{}': {}", dir, err)))?;
+                progress.info(&format!("Saved backup to '{}'", path));
+                verify_backup(&mut flasher, &path, &original)
+                    .map_err(|err| (exit::VERIFY, format!("Backup '{}' is not trustworthy, {}", path, err)))?;
+                progress.info(&format!("Verified backup against {} sectors read again", ecflash::BACKUP_CHECKS));
+                Ok((original, Some(path)))
+            } else {
+                Ok((original, None))
+            }
+        })();
+        if resume.is_none() || res.is_err() {
+            stop_flasher(&mut flasher, progress);
+        }
+        match res {
+            Ok(original) => original,
+            Err((code, message)) => progress.result(code, &format!("Failed to flash EC: {}", message)),
+        }
+    };
+    let (original, backup) = original;
+    if let Some(i) = flasher.bootblock_mismatch(&original, &data) {
+        progress.result(exit::INCOMPATIBLE, &format!(
+            "The boot block of the image differs from the flash at 0x{:X}, pass --allow-bootblock to flash it",
+            i
+        ));
+    }
+
+    let changed = flasher.range.clone().step_by(1024)
+        .filter(|&block| {
+            let end = (block + 1024).min(flasher.range.end);
+            (block..end).any(|i| original[i] != data[i] && ! flasher.is_protected(i..i + 1))
+        })
+        .count();
+    let (mut current, mut target) = (EcFile::new(original.clone()), EcFile::new(data.clone()));
+    journal::update(|entry| {
+        entry.old_version = Some(current.version());
+        entry.new_version = Some(target.version());
+    });
+    let unknown = |s: String| if s.is_empty() { "unknown".to_string() } else { s };
+    let prompt = format!(
+        "Current: {} {}\nTarget:  {} {}\nChip ID: {}\nSectors: {} of 1 KB to erase and write\nBackup:  {}",
+        unknown(current.project()), unknown(current.version()),
+        unknown(target.project()), unknown(target.version()),
+        flasher.chip_id().map_or("unknown".to_string(), |id| format!("IT{:04X}", id)),
+        changed,
+        backup.as_deref().unwrap_or("none, pass --backup-dir to save one"),
+    );
+    // The keyboard of the primary EC does not work while resuming, and the
+    // summary was confirmed by the interrupted run
+    if resume.is_none() && ! confirm(args, &prompt) {
+        progress.result(exit::FAILURE, "Cancelled");
+    }
+
+    // Keys pressed while the devices are grabbed go nowhere, so there is no
+    // need to wait for them to be released
+    let grab = if args.grab_input { input::grab(progress) } else { None };
+    if resume.is_none() && grab.is_none() {
+        // Wait for any key releases
+        progress.info("Waiting for all keys to be released");
+        if unsafe { flasher.wait_keys_released(time::Duration::from_secs(1), time::Duration::from_secs(30)) }.is_err() {
+            progress.result(exit::FAILURE, "Keys were still pressed after 30 seconds, release them and try again");
+        }
+    }
+
+    progress.info("Sync");
+    sync();
+
+    unsafe {
+        let operation = journal::operation().unwrap_or_else(|| "write".to_string());
+        if let Err(err) = marker::set(&operation, &image, args.primary(), backup.as_deref()) {
+            progress.warning(&format!("Failed to write '{}', an interrupted flash will not be noticed: {}", marker::MARKER_PATH, err));
+        }
+
+        if resume.is_none() {
+            start_flasher(&mut flasher, args.primary(), progress);
+        }
+
+        // Ctrl-C stops erase and write before the next block instead
+        session::catch_signals();
+        flasher.interrupt = Some(&session::INTERRUPTED);
+
+        // The boot block is erased and written last, so that the old one is
+        // still there to boot from if power is lost before the rest verifies
+        let range = flasher.range.clone();
+        let res = (|| {
+            let blank = original.chunks(1024).filter(|block| block.iter().all(|&x| x == 0xFF)).count();
+            progress.info(&format!("Skipping erase of {} blank blocks", blank));
+            flasher.program(&original, &data, &|phase, x, total| progress.update(phase, x, total))
+                .map_err(|err| match err {
+                    ProgramError::Verify { .. } => (exit::VERIFY, err.to_string()),
+                    ProgramError::Failed(_) => (exit::FAILURE, err.to_string()),
+                })?;
+
+            if args.lock_bootblock {
+                progress.info("Setting block protect bits over the boot block");
+                flasher.lock_bootblock()
+                    .map_err(|()| (exit::FAILURE, "Flashed, but failed to set block protect bits".to_string()))?;
+            }
+
+            Ok(())
+        })();
+        flasher.range = range;
+
+        session::release_signals();
+        progress.info("Sync");
+        sync();
+
+        if let Some(address) = flasher.interrupted() {
+            // Stopping would reset the EC into a partly written flash, so it
+            // is left in flash mode for --resume
+            let state = ResumeState {
+                image,
+                phase: if flasher.state() == FlasherState::Erasing { "erase" } else { "write" }.to_string(),
+                backup,
+            };
+            let saved = save_resume(&resume_path, &state);
+            session::end();
+            progress.report(&flasher.report);
+            let message = match saved {
+                Ok(()) => format!(
+                    "Interrupted before {} of 0x{:05X}, with the EC still in flash mode. Do not power off, and run the same command with --resume to restart it",
+                    state.phase, address
+                ),
+                Err(err) => format!(
+                    "Interrupted before {} of 0x{:05X}, with the EC still in flash mode, and failed to save '{}': {}. Do not power off",
+                    state.phase, address, resume_path, err
+                ),
+            };
+            progress.result(exit::FAILURE, &message);
+        }
+        if resume.is_some() {
+            let _ = fs::remove_file(&resume_path);
+        }
+
+        // Will currently power off system
+        stop_flasher(&mut flasher, progress);
+        drop(grab);
+
+        progress.report(&flasher.report);
+        match res {
+            Ok(()) => {
+                if let Err(err) = marker::clear() {
+                    progress.warning(&format!("Failed to remove '{}': {}", marker::MARKER_PATH, err));
+                }
+                progress.result(exit::OK, "Successfully flashed EC")
+            },
+            Err((code, message)) => progress.result(code, &format!("Failed to flash EC: {}", message)),
+        }
+    }
+}
+
+/// Read the image that repair compares against, unpacking the firmware of a
+/// bundle, along with its signature
+fn read_target(progress: &Progress, path: &str) -> (Vec<u8>, Option<Vec<u8>>) {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) => progress.result(exit::IO, &format!("Failed to read '{}': {}", path, err)),
+    };
+    match Bundle::parse(&data) {
+        Ok(bundle) => (bundle.firmware, bundle.signature),
+        Err(_) => {
+            check_digest(progress, path, &data);
+            (data, fs::read(format!("{}.sig", path)).ok())
+        },
+    }
+}
+
+/// Compare the flash against FILE, or the image of the interrupted flash, and
+/// erase and program only the 1 KB blocks that differ, boot block last
+fn repair(args: &Args) -> ! {
+    let progress = args.progress();
+    let interrupted = marker::load();
+    let file = match (args.file(), &interrupted) {
+        (Some(file), _) => file.to_string(),
+        (None, Some(marker)) => {
+            if marker.primary != args.primary() {
+                progress.result(exit::USAGE, &format!(
+                    "The interrupted flash was of EC {}, pass -{} or an image",
+                    if marker.primary { 1 } else { 2 }, if marker.primary { 1 } else { 2 }
+                ));
+            }
+            marker.image.clone()
+        },
+        (None, None) => progress.result(exit::USAGE, &format!("No image provided, and no interrupted flash to repair
+{}", USAGE)),
+    };
+    let (mut data, signature) = read_target(&progress, &file);
+    verify_image(&progress, &data, signature.as_deref());
+
+    journal::begin("repair", &file, args.primary());
+    let mut flasher = open_flasher(args, &progress);
+    let size = flasher.size;
+    if data.len() > size {
+        progress.result(exit::INCOMPATIBLE, &format!("File size {} exceeds flash size {}", data.len(), size));
+    }
+    data.resize(size, 0xFF);
+
+    if let Some(region) = limit_flasher(args, &progress, &mut flasher) {
+        progress.info(&format!("Only repairing region '{}'", region.name));
+    }
+
+    unsafe {
+        start_flasher(&mut flasher, args.primary(), &progress);
+        let current = match flasher.read(|x| progress.update("read", x, size)) {
+            Ok(current) => current,
+            Err(()) => {
+                stop_flasher(&mut flasher, &progress);
+                progress.result(exit::FAILURE, "Failed to read current data");
+            },
+        };
+        journal::update(|entry| {
+            entry.old_version = Some(EcFile::new(current.clone()).version());
+            entry.new_version = Some(EcFile::new(data.clone()).version());
+        });
+        if let Some(i) = flasher.bootblock_mismatch(&current, &data) {
+            stop_flasher(&mut flasher, &progress);
+            progress.result(exit::INCOMPATIBLE, &format!(
+                "The boot block of the image differs from the flash at 0x{:X}, pass --allow-bootblock to repair it",
+                i
+            ));
+        }
+
+        let damaged: Vec<usize> = flasher.range.clone().step_by(1024)
+            .filter(|&block| {
+                let end = (block + 1024).min(flasher.range.end);
+                (block..end).any(|i| current[i] != data[i] && ! flasher.is_protected(i..i + 1))
+            })
+            .collect();
+        if damaged.is_empty() {
+            stop_flasher(&mut flasher, &progress);
+            if interrupted.is_some() && args.file().is_none() {
+                if let Err(err) = marker::clear() {
+                    progress.warning(&format!("Failed to remove '{}': {}", marker::MARKER_PATH, err));
+                }
+            }
+            progress.result(exit::OK, "The flash already matches the image");
+        }
+        // Print each run of damaged blocks once
+        for (i, &block) in damaged.iter().enumerate() {
+            if i > 0 && damaged[i - 1] + 1024 == block {
+                continue;
+            }
+            let run = damaged[i..].iter().enumerate().take_while(|&(j, &b)| b == block + j * 1024).count();
+            progress.info(&format!("Damaged: 0x{:05X}-0x{:05X}", block, block + run * 1024 - 1));
+        }
+
+        let prompt = format!("Image:   {}\nSectors: {} of 1 KB to erase and write", file, damaged.len());
+        if ! confirm(args, &prompt) {
+            stop_flasher(&mut flasher, &progress);
+            progress.result(exit::FAILURE, "Cancelled");
+        }
+
+        progress.info("Sync");
+        sync();
+
+        let range = flasher.range.clone();
+        let res = (|| {
+            for part in flasher.program_order() {
+                for &block in damaged.iter().filter(|&&block| part.contains(&block)) {
+                    flasher.range = block..(block + 1024).min(part.end);
+                    flasher.erase(|_| ())
+                        .map_err(|()| (exit::FAILURE, format!("Failed to erase 0x{:05X}", block)))?;
+                    flasher.write(&data, |_| ())
+                        .map_err(|()| (exit::FAILURE, format!("Failed to write 0x{:05X}", block)))?;
+                    progress.update("repair", block + 1024, size);
+                }
+            }
+            flasher.range = range.clone();
+
+            // Only the blocks that were repaired are read again
+            let mut written = flasher.read_cached(|x| progress.update("verify", x, size))
+                .map_err(|()| (exit::FAILURE, "Failed to read repaired data".to_string()))?;
+            flasher.retry_blocks(&mut written, &data, true)
+                .map_err(|()| (exit::FAILURE, "Failed to write blocks again".to_string()))?;
+            match flasher.mismatch(&written, &data) {
+                Some(i) => Err((exit::VERIFY, format!("0x{:X}: 0x{:02X} != 0x{:02X}", i, written[i], data[i]))),
+                None => Ok(()),
+            }
+        })();
+        flasher.range = range;
+
+        progress.info("Sync");
+        sync();
+
+        // Will currently power off system
+        stop_flasher(&mut flasher, &progress);
+
+        progress.report(&flasher.report);
+        match res {
+            Ok(()) => {
+                if interrupted.is_some() {
+                    if let Err(err) = marker::clear() {
+                        progress.warning(&format!("Failed to remove '{}': {}", marker::MARKER_PATH, err));
+                    }
+                }
+                progress.result(exit::OK, &format!("Repaired {} sectors", damaged.len()))
+            },
+            Err((code, message)) => progress.result(code, &format!("Failed to repair EC: {}", message)),
+        }
+    }
+}
+
+fn reset(args: &Args) -> ! {
+    let progress = args.progress();
+    if ! args.primary() {
+        progress.result(exit::USAGE, "Only the primary EC can be reset");
+    }
+
+    let mut ec = open_ec(args, true, &progress);
+
+    progress.warning("Resetting EC, the system may power off");
+    progress.info("Sync");
+    sync();
+
+    match unsafe { ec.reset() } {
+        Ok(()) => progress.result(exit::OK, "Reset EC"),
+        Err(()) => progress.result(exit::FAILURE, "Failed to reset EC, it is not supported or still answers"),
+    }
+}
+
+fn option(args: &Args) -> ! {
+    let progress = args.progress();
+    if ! args.primary() {
+        progress.result(exit::USAGE, "Flash options are only available on the primary EC");
+    }
+
+    let params: Vec<&str> = args.ec_args.iter()
+        .map(|arg| arg.as_str())
+        .filter(|&arg| arg != "-1")
+        .collect();
+
+    match params.as_slice() {
+        [] | ["dump"] => {
+            let mut ec = open_ec(args, true, &progress);
+            let mut stdout = stdout();
+            for row in (0..FLASH_OPTION_SIZE).step_by(16) {
+                let _ = write!(stdout, "{:04X}:", FLASH_OPTION_BASE as usize + row);
+                for offset in row..row + 16 {
+                    match unsafe { ec.flash_option(offset as u8) } {
+                        Ok(value) => { let _ = write!(stdout, " {:02X}", value); },
+                        Err(()) => progress.result(exit::FAILURE, "Failed to read flash options"),
+                    }
+                }
+                let _ = writeln!(stdout);
+            }
+            process::exit(exit::OK);
+        },
+        ["set", offset, value] => {
+            let (offset, value) = match (parse_int(offset), parse_int(value)) {
+                (Some(offset), Some(value)) if (offset as usize) < FLASH_OPTION_SIZE && value <= 0xFF => {
+                    (offset as u8, value as u8)
+                },
+                _ => progress.result(exit::USAGE, &format!("Invalid offset or value\n{}", USAGE)),
+            };
+
+            let mut ec = open_ec(args, true, &progress);
+            let old = match unsafe { ec.flash_option(offset) } {
+                Ok(old) => old,
+                Err(()) => progress.result(exit::FAILURE, "Failed to read flash option"),
+            };
+
+            let prompt = format!(
+                "Changing flash option 0x{:04X} from 0x{:02X} to 0x{:02X}, which can lock the host out of the EC flash",
+                FLASH_OPTION_BASE + offset as u16, old, value
+            );
+            if ! confirm(args, &prompt) {
+                progress.result(exit::FAILURE, "Cancelled");
+            }
+
+            if unsafe { ec.set_flash_option(offset, value) }.is_err() {
+                progress.result(exit::FAILURE, "Failed to write flash option");
+            }
+            match unsafe { ec.flash_option(offset) } {
+                Ok(new) if new == value => progress.result(exit::OK, "Set flash option"),
+                Ok(new) => progress.result(exit::VERIFY, &format!("Flash option reads back as 0x{:02X}", new)),
+                Err(()) => progress.result(exit::FAILURE, "Failed to read flash option"),
+            }
+        },
+        _ => progress.result(exit::USAGE, &format!("Invalid option command\n{}", USAGE)),
+    }
+}
+
+/// Parse an ACPI parameter by offset or name
+fn parse_param(s: &str) -> Option<EcParam> {
+    match parse_int(s) {
+        Some(offset) if offset <= 0xFF => Some(EcParam::Raw(offset as u8)),
+        Some(_) => None,
+        None => EcParam::from_name(s),
+    }
+}
+
+fn param(args: &Args) -> ! {
+    let progress = args.progress();
+    let params: Vec<&str> = args.ec_args.iter()
+        .map(|arg| arg.as_str())
+        .filter(|&arg| arg != "-1" && arg != "-2")
+        .collect();
+
+    match params.as_slice() {
+        ["get", param] => {
+            let param = match parse_param(param) {
+                Some(param) => param,
+                None => progress.result(exit::USAGE, &format!("Invalid parameter '{}'\n{}", param, USAGE)),
+            };
+
+            let mut ec = open_ec(args, args.primary(), &progress);
+            let start = time::Instant::now();
+            let mut last = None;
+            loop {
+                let value = match unsafe { ec.get_param(param) } {
+                    Ok(value) => value,
+                    Err(()) => progress.result(exit::FAILURE, "Failed to read parameter"),
+                };
+                if ! args.watch {
+                    let _ = writeln!(stdout(), "0x{:02X}", value);
+                    process::exit(exit::OK);
+                }
+
+                if last != Some(value) {
+                    let _ = writeln!(stdout(), "{:.1} 0x{:02X}", start.elapsed().as_secs_f64(), value);
+                    last = Some(value);
+                }
+                thread::sleep(time::Duration::from_secs(1));
+            }
+        },
+        ["set", param, value] => {
+            let (param, value) = match (parse_param(param), parse_int(value)) {
+                (Some(param), Some(value)) if value <= 0xFF => (param, value as u8),
+                _ => progress.result(exit::USAGE, &format!("Invalid parameter or value\n{}", USAGE)),
+            };
+
+            let mut ec = open_ec(args, args.primary(), &progress);
+            if unsafe { ec.set_param(param, value) }.is_err() {
+                progress.result(exit::FAILURE, "Failed to write parameter");
+            }
+            progress.result(exit::OK, &format!("Set parameter 0x{:02X} to 0x{:02X}", param.offset(), value))
+        },
+        _ => progress.result(exit::USAGE, &format!("Invalid param command\n{}", USAGE)),
+    }
+}
+
+fn fcommand(args: &Args) -> ! {
+    let progress = args.progress();
+    let params: Vec<&str> = args.ec_args.iter()
+        .map(|arg| arg.as_str())
+        .filter(|&arg| arg != "-1" && arg != "-2")
+        .collect();
+
+    let (cmd, dat, data) = match params.as_slice() {
+        [cmd, dat] => (*cmd, *dat, "00000000"),
+        [cmd, dat, data] => (*cmd, *dat, *data),
+        _ => progress.result(exit::USAGE, &format!("Invalid fcommand arguments\n{}", USAGE)),
+    };
+    let (cmd, dat, mut buf) = match (parse_int(cmd), parse_int(dat), parse_hex(data)) {
+        (Some(cmd), Some(dat), Some(data)) if cmd <= 0xFF && dat <= 0xFF && data.len() == 4 => {
+            (cmd as u8, dat as u8, [data[0], data[1], data[2], data[3]])
+        },
+        _ => progress.result(exit::USAGE, &format!("Invalid command, argument, or 4 bytes of data\n{}", USAGE)),
+    };
+
+    let mut ec = open_ec(args, args.primary(), &progress);
+    if unsafe { ec.fcommand(cmd, dat, &mut buf) }.is_err() {
+        progress.result(exit::FAILURE, "Failed to run fcommand");
+    }
+    let _ = writeln!(stdout(), "{:02X} {:02X} {:02X} {:02X}", buf[0], buf[1], buf[2], buf[3]);
+    process::exit(exit::OK);
+}
+
+/// SMFI registers that define the regions of the internal flash that the EC
+/// protects from erase and program, as offsets from FLASH_OPTION_BASE
+const PROTECT_REGISTERS: &[(&str, u8)] = &[
+    ("P0BA0R", smfi::P0BA0R),
+    ("P0BA1R", smfi::P0BA1R),
+    ("P0ZR", smfi::P0ZR),
+    ("P1BA0R", smfi::P1BA0R),
+    ("P1BA1R", smfi::P1BA1R),
+    ("P1ZR", smfi::P1ZR),
+];
+
+fn protection(args: &Args) -> ! {
+    let progress = args.progress();
+    let primary = args.primary();
+    let mut ec = open_ec(args, primary, &progress);
+    let mut stdout = stdout();
+
+    // Flash options are read through the Super I/O, outside of flash mode
+    let mut registers = Vec::new();
+    if primary {
+        for &(name, offset) in PROTECT_REGISTERS {
+            match unsafe { ec.flash_option(offset) } {
+                Ok(value) => registers.push((name, offset, value)),
+                Err(()) => progress.result(exit::FAILURE, "Failed to read flash protect registers"),
+            }
+        }
+    }
+
+    let mut flasher = new_flasher(args, ec, &progress);
+    let status = unsafe {
+        start_flasher(&mut flasher, primary, &progress);
+        let status = flasher.status();
+        stop_flasher(&mut flasher, &progress);
+        status
+    };
+    let status = match status {
+        Ok(status) => status,
+        Err(()) => progress.result(exit::FAILURE, "Failed to read flash status register"),
+    };
+
+    let flag = |bit: u8| if status & bit != 0 { "yes" } else { "no" };
+    let _ = writeln!(stdout, "Status register: 0x{:02X}", status);
+    let _ = writeln!(stdout, "  Busy: {}", flag(1 << 0));
+    let _ = writeln!(stdout, "  Write enabled: {}", flag(1 << 1));
+    let block_protect = (status >> 2) & 0xF;
+    if block_protect == 0 {
+        let _ = writeln!(stdout, "  Block protect: none");
+    } else {
+        let _ = writeln!(stdout, "  Block protect: BP=0x{:X}, erase and program of protected blocks are ignored", block_protect);
+    }
+    let _ = writeln!(stdout, "  Block protect locked: {}", flag(1 << 7));
+
+    if ! registers.is_empty() {
+        let _ = writeln!(stdout, "Internal flash protect regions:");
+        for (name, offset, value) in registers.iter() {
+            let _ = writeln!(stdout, "  {:<7} 0x{:04X}: 0x{:02X}", name, FLASH_OPTION_BASE + *offset as u16, value);
+        }
+        for (index, values) in registers.chunks(3).enumerate() {
+            let region = ProtectRegion::from_registers([values[0].2, values[1].2, values[2].2]);
+            let _ = writeln!(
+                stdout,
+                "  Region {}: 0x{:05X}-0x{:05X} {}",
+                index, region.range.start, region.range.end - 1, if region.locked { "locked" } else { "unlocked" }
+            );
+        }
+    }
+
+    process::exit(exit::OK);
+}
+
+fn map(args: &Args) -> ! {
+    let progress = args.progress();
+    let mut flasher = open_flasher(args, &progress);
+    let region = limit_flasher(args, &progress, &mut flasher);
+    let status = unsafe {
+        start_flasher(&mut flasher, args.primary(), &progress);
+        let status = flasher.status();
+        stop_flasher(&mut flasher, &progress);
+        status
+    };
+    let status = match status {
+        Ok(status) => status,
+        Err(()) => progress.result(exit::FAILURE, "Failed to read flash status register"),
+    };
+
+    let size = flasher.size;
+    let layout = Layout::new(size);
+    let block_protect = status & BLOCK_PROTECT_MASK;
+    let mut stdout = stdout();
+    let _ = writeln!(stdout, "Flash: {} KiB, {} sectors of 64 KiB, erased in 1 KiB blocks", size / 1024, size / 65536);
+    let scope = region.map_or("whole flash".to_string(), |region| format!("region '{}'", region.name));
+    let _ = writeln!(stdout, "Erase and write: {}", scope);
+    if block_protect != 0 && block_protect != BOOT_BLOCK_PROTECT {
+        progress.warning(&format!(
+            "Block protect bits are BP=0x{:X}, which the chip maps to blocks that erase and program ignore",
+            block_protect >> 2
+        ));
+    }
+
+    // Split each sector where a region, the range of --region, or a protected
+    // range starts or ends
+    let mut bounds: Vec<usize> = (0..=size).step_by(65536).collect();
+    bounds.extend(layout.regions.iter().flat_map(|region| vec![region.range.start, region.range.end]));
+    bounds.extend(vec![flasher.range.start, flasher.range.end]);
+    bounds.extend(flasher.protected.iter().flat_map(|range| vec![range.start, range.end]));
+    bounds.retain(|&bound| bound <= size);
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    for window in bounds.windows(2) {
+        let (start, end) = (window[0], window[1]);
+        if start % 65536 == 0 {
+            let _ = writeln!(stdout, "Sector {} 0x{:05X}-0x{:05X}", start / 65536, start, (start + 65536).min(size) - 1);
+        }
+
+        let name = layout.region_at(start).map_or("", |region| region.name);
+        let touched = if start < flasher.range.start || end > flasher.range.end {
+            "untouched, outside of --region"
+        } else if flasher.is_protected(start..end) {
+            if BOOT_BLOCK.contains(&start) {
+                "untouched, pass --allow-bootblock to flash it"
+            } else {
+                "untouched, protected in the configuration"
+            }
+        } else if block_protect == BOOT_BLOCK_PROTECT && BOOT_BLOCK.contains(&start) {
+            "ignored by the chip, block protect bits are set"
         } else {
-            let _ = writeln!(stderr(), "Attempt {}: {} != {}", _attempt_i, a, b);
+            "erased and written"
+        };
+        let _ = writeln!(
+            stdout,
+            "  0x{:05X}-0x{:05X} {:<5} {:>3} KiB  {}",
+            start, end - 1, name, (end - start) / 1024, touched
+        );
+    }
+
+    process::exit(exit::OK);
+}
+
+fn unlock(args: &Args) -> ! {
+    let progress = args.progress();
+    let mut flasher = open_flasher(args, &progress);
+    unsafe {
+        start_flasher(&mut flasher, args.primary(), &progress);
+        let res = flasher.unlock_bootblock();
+        stop_flasher(&mut flasher, &progress);
+        match res {
+            Ok(()) => progress.result(exit::OK, "Cleared block protect bits"),
+            Err(()) => progress.result(exit::FAILURE, "Failed to clear block protect bits, the status register may be locked until reset"),
         }
     }
-    Err(())
 }
 
-fn main() {
-    extern {
-        fn iopl(level: isize) -> isize;
+/// Lock or unlock --region of the internal flash of the primary EC with the
+/// protect region registers, which hold until the EC is reset
+fn protect(args: &Args) -> ! {
+    let progress = args.progress();
+    if ! args.primary() {
+        progress.result(exit::USAGE, "Protect regions are only on the internal flash of the primary EC");
     }
+    let name = match (&args.region, args.lock, args.unlock) {
+        (Some(name), true, false) | (Some(name), false, true) => name.as_str(),
+        _ => progress.result(exit::USAGE, &format!("protect needs --region and one of --lock or --unlock\n{}", USAGE)),
+    };
 
-    // Get I/O Permission
+    let mut ec = open_ec(args, true, &progress);
+    let layout = Layout::new(ec.size());
+    let region = match layout.region(name) {
+        Some(region) => region.clone(),
+        None => progress.result(exit::USAGE, &format!("Unknown region '{}'", name)),
+    };
+    let target = match ProtectRegion::covering(region.range.clone(), args.lock) {
+        Ok(target) => target,
+        Err(err) => progress.result(exit::USAGE, &format!("Failed to protect region '{}': {}", name, err)),
+    };
+    if target.range != region.range {
+        progress.info(&format!(
+            "Region '{}' is 0x{:05X}-0x{:05X}, protect regions are aligned powers of two of 4 KiB, so 0x{:05X}-0x{:05X} is covered",
+            name, region.range.start, region.range.end - 1, target.range.start, target.range.end - 1
+        ));
+    }
+
+    let mut current = Vec::new();
+    for index in 0..PROTECT_REGIONS {
+        match unsafe { ec.protect_region(index) } {
+            Ok(protect) => current.push(protect),
+            Err(()) => progress.result(exit::FAILURE, "Failed to read flash protect registers"),
+        }
+    }
+
+    let changes: Vec<(u8, ProtectRegion)> = if args.lock {
+        if current.contains(&target) {
+            progress.result(exit::OK, &format!("Region '{}' is already locked", name));
+        }
+        // Reuse the slot that already holds the range, else the first free one
+        let index = current.iter().position(|protect| ! protect.locked && protect.range == target.range)
+            .or_else(|| current.iter().position(|protect| ! protect.locked));
+        match index {
+            Some(index) => vec![(index as u8, target.clone())],
+            None => progress.result(exit::FAILURE, "Both protect regions are locked, unlock one first"),
+        }
+    } else {
+        current.iter().enumerate()
+            .filter(|(_, protect)| protect.blocks(&region.range))
+            .map(|(index, protect)| (index as u8, ProtectRegion { range: protect.range.clone(), locked: false }))
+            .collect()
+    };
+    if changes.is_empty() {
+        progress.result(exit::OK, &format!("Region '{}' is not locked", name));
+    }
+
+    for (index, protect) in changes {
+        if unsafe { ec.set_protect_region(index, &protect) }.is_err() {
+            progress.result(exit::FAILURE, &format!("Failed to program protect region {}, the EC may not support it", index));
+        }
+        progress.info(&format!(
+            "Protect region {}: 0x{:05X}-0x{:05X} {}",
+            index, protect.range.start, protect.range.end - 1, if protect.locked { "locked" } else { "unlocked" }
+        ));
+    }
+    let action = if args.lock { "Locked" } else { "Unlocked" };
+    progress.result(exit::OK, &format!("{} region '{}' until the EC is reset", action, name))
+}
+
+/// Command and status port of the third PMC, which the scratch ROM of the isp
+/// example serves
+const PMC3_CMD: u16 = 0x6E;
+
+/// Leave the scratch ROM by sending its exit command to the third PMC
+unsafe fn recover_scratch(progress: &Progress) -> ! {
+    if iopl(3) < 0 {
+        progress.result(exit::PERMISSION, &format!("Failed to get I/O permission: {}", Error::last_os_error()));
+    }
+
+    let mut io = RawPortIo;
+    let start = time::Instant::now();
+    while io.inb(PMC3_CMD) & 2 != 0 {
+        if start.elapsed() >= time::Duration::from_secs(1) {
+            progress.result(exit::NO_EC, "The scratch ROM is not running, its PMC does not take commands");
+        }
+    }
+
+    progress.info("Sync");
+    sync();
+    io.outb(PMC3_CMD, 0xEC);
+    progress.result(exit::OK, "Sent the scratch ROM exit command, the system will power off")
+}
+
+fn recover(args: &Args) -> ! {
+    let progress = args.progress();
+    if args.scratch {
+        unsafe { recover_scratch(&progress) }
+    }
+
+    // Only an EC in flash mode stops answering the mailbox, and the stop
+    // sequence must not be sent to a running EC
+    let mut ec = open_ec(args, args.primary(), &progress);
+    if ! ec.project().trim().is_empty() {
+        progress.result(exit::OK, "The EC answers, it is not in flash mode");
+    }
+
+    let mut flasher = Flasher::new(ec);
     unsafe {
-        if iopl(3) < 0 {
-            let _ = writeln!(stderr(), "Failed to get I/O permission: {}", Error::last_os_error());
-            process::exit(1);
+        let res = flasher.recover();
+        // The crashed session left the events of the kernel EC driver paused,
+        // and its resume state can no longer be used
+        kernel::take_over(&progress);
+        kernel::resume(&progress);
+        let _ = fs::remove_file(resume_path(args.primary()));
+        match res {
+            Ok(()) => progress.result(exit::OK, "Sent the exit follow mode and stop sequences, the EC should reset"),
+            Err(()) => progress.result(exit::FAILURE, "The EC did not take the stop sequence"),
+        }
+    }
+}
+
+/// Drive the mailbox directly, for trying EC commands that this tool does not
+/// know
+fn raw(args: &Args) -> ! {
+    let progress = args.progress();
+    let cmd = match args.raw_cmd {
+        Some(cmd) => cmd,
+        None => progress.result(exit::USAGE, &format!("raw needs --cmd\n{}", USAGE)),
+    };
+
+    let mut ec = open_ec(args, args.primary(), &progress);
+    // Traced to the --trace file instead if one was given
+    if args.trace.is_none() {
+        ec.set_trace(TraceWriter::new(stderr()));
+    }
+
+    let res = unsafe {
+        let _ = ec.flush();
+        (|| {
+            ec.cmd(cmd)?;
+            for &value in &args.raw_write {
+                ec.write(value)?;
+            }
+            (0..args.raw_read).map(|_| ec.read()).collect::<Result<Vec<u8>, ()>>()
+        })()
+    };
+
+    match res {
+        Ok(data) => {
+            if ! data.is_empty() {
+                let hex: Vec<String> = data.iter().map(|x| format!("{:02X}", x)).collect();
+                let _ = writeln!(stdout(), "{}", hex.join(" "));
+            }
+            process::exit(exit::OK);
+        },
+        Err(()) => progress.result(exit::FAILURE, "The EC did not take the transaction in time"),
+    }
+}
+
+/// Send an SPI transaction in follow mode, for identifying unknown flash parts
+/// and trying opcodes
+fn spi(args: &Args) -> ! {
+    let progress = args.progress();
+    if args.spi_tx.is_empty() {
+        progress.result(exit::USAGE, &format!("spi needs --tx\n{}", USAGE));
+    }
+
+    let mut flasher = open_flasher(args, &progress);
+    let res = unsafe {
+        start_flasher(&mut flasher, args.primary(), &progress);
+        let res = flasher.spi_transfer(&args.spi_tx, args.spi_rx);
+        stop_flasher(&mut flasher, &progress);
+        res
+    };
+
+    match res {
+        Ok(data) => {
+            let hex: Vec<String> = data.iter().map(|x| format!("{:02X}", x)).collect();
+            let _ = writeln!(stdout(), "{}", hex.join(" "));
+            process::exit(exit::OK);
+        },
+        Err(()) => progress.result(exit::FAILURE, "The EC did not take the SPI transaction in follow mode"),
+    }
+}
+
+/// Read the shared secret for serve and remote from the --key file
+fn read_key(args: &Args, progress: &Progress) -> Vec<u8> {
+    let path = match &args.key {
+        Some(path) => path,
+        None => progress.result(exit::USAGE, &format!("No key file provided\n{}", USAGE)),
+    };
+
+    match fs::read(path) {
+        Ok(key) => {
+            let key = key.trim_ascii().to_vec();
+            if key.is_empty() {
+                progress.result(exit::USAGE, &format!("Key file '{}' is empty", path));
+            }
+            key
+        },
+        Err(err) => progress.result(exit::IO, &format!("Failed to read '{}': {}", path, err)),
+    }
+}
+
+fn serve(args: &Args) -> ! {
+    let progress = args.progress();
+    let key = read_key(args, &progress);
+    let address = match args.ec_args.as_slice() {
+        [] => format!("0.0.0.0:{}", remote::DEFAULT_PORT),
+        [address] => address.clone(),
+        _ => progress.result(exit::USAGE, &format!("Too many arguments\n{}", USAGE)),
+    };
+
+    match remote::serve(&address, &key) {
+        Ok(()) => progress.result(exit::OK, "Stopped serving"),
+        Err(err) => progress.result(exit::FAILURE, &format!("Failed to serve on '{}': {}", address, err)),
+    }
+}
+
+fn remote(args: &Args) -> ! {
+    let progress = args.progress();
+    let key = read_key(args, &progress);
+
+    let (host, command, ecs, file) = match args.ec_args.split_first() {
+        Some((host, rest)) => {
+            let ecs: Vec<&String> = rest.iter().filter(|arg| *arg == "-1" || *arg == "-2").collect();
+            let others: Vec<&String> = rest.iter().filter(|arg| *arg != "-1" && *arg != "-2").collect();
+            match others.as_slice() {
+                [command, file] if *command == "write" || *command == "apply" => (host, *command, ecs, *file),
+                _ => progress.result(exit::USAGE, &format!("Expected write or apply and a file\n{}", USAGE)),
+            }
+        },
+        None => progress.result(exit::USAGE, &format!("No host provided\n{}", USAGE)),
+    };
+
+    let data = match fs::read(file) {
+        Ok(data) => data,
+        Err(err) => progress.result(exit::IO, &format!("Failed to read '{}': {}", file, err)),
+    };
+    let signature = if command == "write" {
+        fs::read(format!("{}.sig", file)).unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+
+    let mut forwarded = vec![command.clone()];
+    forwarded.extend(ecs.into_iter().cloned());
+    if args.allow_bootblock {
+        forwarded.push("--allow-bootblock".to_string());
+    }
+    if args.lock_bootblock {
+        forwarded.push("--lock-bootblock".to_string());
+    }
+    if let Some(region) = &args.region {
+        forwarded.push("--region".to_string());
+        forwarded.push(region.clone());
+    }
+
+    remote::request(host, &key, &forwarded, &data, &signature, &progress)
+}
+
+/// Report what the EC answers without trusting its Super I/O ID, using only
+/// the mailbox string commands
+fn info_unknown(args: &Args) -> ! {
+    let progress = args.progress();
+    let mut numbers: Vec<bool> = args.ec_args.iter()
+        .filter_map(|arg| match arg.as_str() {
+            "-1" => Some(true),
+            "-2" => Some(false),
+            _ => None,
+        })
+        .collect();
+    if numbers.len() != args.ec_args.len() {
+        progress.result(exit::USAGE, &format!("--unknown-chip only works with -1 and -2\n{}", USAGE));
+    }
+    if numbers.is_empty() {
+        numbers.push(true);
+    }
+
+    let mut stdout = stdout();
+    for primary in numbers {
+        let mut ec = open_ec(args, primary, &progress);
+        let _ = writeln!(stdout, "EC {}{}", if primary { 1 } else { 2 }, if ec.supported() { "" } else { " (unsupported)" });
+        if let Some(id) = ec.chip_id() {
+            let _ = writeln!(stdout, "  Chip ID: 0x{:04X}", id);
+        }
+        if let Some(chip_version) = ec.chip_version() {
+            let _ = writeln!(stdout, "  Chip Version: {}", chip_version);
+        }
+        if let Some((interface, data_port, cmd_port)) = ec.interface() {
+            let _ = writeln!(stdout, "  Interface: {}, data 0x{:02X}, command 0x{:02X}", interface, data_port, cmd_port);
+        }
+
+        let _ = unsafe { ec.flush() };
+        let project = unsafe { ec.get_str(0x92) };
+        let version = unsafe { ec.get_str(0x93) };
+        let responds = project.is_ok() || version.is_ok();
+        let _ = writeln!(stdout, "  Mailbox: {}", if responds { "responds" } else { "does not respond" });
+        for (index, string) in [(0x92, project), (0x93, version)] {
+            match string {
+                Ok(string) => { let _ = writeln!(stdout, "  String 0x{:02X}: {:?}", index, string); },
+                Err(()) => { let _ = writeln!(stdout, "  String 0x{:02X}: no answer", index); },
+            }
         }
     }
 
-    let mut ecs: Vec<(String, Box<dyn Ec>)> = Vec::new();
+    process::exit(exit::OK);
+}
+
+/// An EC or EC file to print, with its file name and regions marked blank or not
+type InfoEntry = (String, Box<dyn Ec>, Vec<(Region, bool)>);
+
+fn info(args: &Args) -> ! {
+    if args.unknown_chip {
+        info_unknown(args);
+    }
+
+    let verbosity = args.verbosity;
+
+    let progress = args.progress();
+
+    let mut ecs: Vec<InfoEntry> = Vec::new();
 
-    for arg in env::args().skip(1) {
+    for arg in args.ec_args.iter().cloned() {
         match arg.as_str() {
-            "-1" => match EcFlash::new(true) {
-                Ok(ec_flash) => {
-                    ecs.push((String::new(), Box::new(ec_flash)));
-                },
-                Err(err) => {
-                    let _ = writeln!(stderr(), "Failed to open EC flash 1: {}", err);
-                    process::exit(1);
+            "-1" | "-2" if args.backend.as_deref() == Some("kernel") => {
+                if arg == "-2" {
+                    progress.result(exit::USAGE, "The kernel backend only reaches the primary EC");
+                }
+                match AcpiEc::open() {
+                    Ok(ec) => ecs.push((String::new(), Box::new(ec), Vec::new())),
+                    Err(err) => progress.result(
+                        exit::PERMISSION,
+                        &format!(
+                            "Failed to open '{}', load ec_sys without write_support, with debugfs mounted: {}",
+                            ACPI_EC_IO, err
+                        )
+                    ),
                 }
             },
-            "-2" => match EcFlash::new(false) {
-                Ok(ec_flash) => {
-                    ecs.push((String::new(), Box::new(ec_flash)));
-                },
-                Err(err) => {
-                    let _ = writeln!(stderr(), "Failed to open EC flash 2: {}", err);
-                    process::exit(1);
-                }
-            },
-            _ => match File::open(&arg) {
-                Ok(mut ec_file) => {
-                    let mut data = Vec::new();
-                    match ec_file.read_to_end(&mut data) {
-                        Ok(_) => ecs.push((arg, Box::new(EcFile::new(data)))),
-                        Err(err) => {
-                            let _ = writeln!(stderr(), "Failed to read EC file '{}': {}", arg, err);
-                            process::exit(1);
-                        }
-                    }
+            "-1" | "-2" => {
+                let mut ec_flash = open_ec(args, arg == "-1", &progress);
+                let ec_smfi = if arg == "-1" { open_smfi(&mut ec_flash) } else { None };
+                match ec_smfi {
+                    Some(ec_smfi) => {
+                        progress.info(&format!("Found the System76 EC firmware, SMFI protocol {}", ec_smfi.protocol()));
+                        ecs.push((String::new(), Box::new(ec_smfi), Vec::new()));
+                    },
+                    None => ecs.push((String::new(), Box::new(ec_flash), Vec::new())),
+                }
+            },
+            _ => match EcFile::open(&arg) {
+                Ok(ec_file) => {
+                    let regions = ec_file.layout().regions.into_iter().map(|region| {
+                        let blank = ec_file.is_blank(&region);
+                        (region, blank)
+                    }).collect();
+                    ecs.push((arg, Box::new(ec_file), regions));
                 },
                 Err(err) => {
-                    let _ = writeln!(stderr(), "Failed to open EC file '{}': {}", arg, err);
-                    process::exit(1);
+                    let _ = writeln!(stderr(), "Failed to read EC file '{}': {}", arg, err);
+                    process::exit(exit::IO);
                 }
             }
         }
     }
 
     let mut stdout = BufWriter::new(stdout());
+    let print = verbosity >= Verbosity::Normal;
+    let dmi = if args.fwupd { read_dmi() } else { Dmi::default() };
 
-    for (name, mut ec) in ecs {
-        if name.is_empty() {
-            let _ = writeln!(stdout, "EC Flash");
-        } else {
-            let _ = writeln!(stdout, "EC File {}:", name);
+    for (name, mut ec, regions) in ecs {
+        if verbosity >= Verbosity::Debug {
+            let _ = writeln!(stderr(), "Querying {}", if name.is_empty() { "EC flash" } else { &name });
         }
 
-        match validate(|| ec.project(), 8) {
-            Ok(project) => {
-                let _ = writeln!(stdout, "  Project: {}", project);
-            },
+        if print {
+            if name.is_empty() {
+                let _ = writeln!(stdout, "EC Flash");
+            } else {
+                let _ = writeln!(stdout, "EC File {}:", name);
+            }
+        }
+
+        let project = match validate(|| ec.project(), 8, verbosity) {
+            Ok(project) => project,
             Err(()) => {
                 let _ = writeln!(stderr(), "Failed to read EC project");
-                process::exit(1);
+                process::exit(exit::VERIFY);
             }
-        }
+        };
 
-        match validate(|| ec.version(), 8) {
-            Ok(version) => {
-                let _ = writeln!(stdout, "  Version: {}", version);
-            },
+        let version = match validate(|| ec.version(), 8, verbosity) {
+            Ok(version) => version,
             Err(()) => {
                 let _ = writeln!(stderr(), "Failed to read EC version");
-                process::exit(1);
+                process::exit(exit::VERIFY);
+            }
+        };
+
+        // The kernel driver only exposes the ACPI space, not the mailbox
+        // commands that return the project and version
+        let kernel = matches!(ec.interface(), Some((HostInterface::Kernel, _, _)));
+
+        if args.fwupd {
+            if kernel {
+                let _ = writeln!(stderr(), "The kernel backend cannot read the project and version that fwupd needs");
+                process::exit(exit::INCOMPATIBLE);
+            }
+            let device = FwupdDevice::new(&project, &version, &dmi);
+            if print {
+                let _ = writeln!(stdout, "  Version: {}", device.version);
+                let _ = writeln!(stdout, "  VersionFormat: plain");
+                for (instance_id, guid) in device.instance_ids.iter().zip(device.guids()) {
+                    let _ = writeln!(stdout, "  InstanceId: {}", instance_id);
+                    let _ = writeln!(stdout, "  Guid: {} <- {}", guid, instance_id);
+                }
+            }
+            continue;
+        }
+
+        if print {
+            let unknown = |s: &str| match (s.is_empty(), kernel) {
+                (true, true) => "not readable through the kernel driver".to_string(),
+                (true, false) => "unknown".to_string(),
+                (false, _) => s.to_string(),
+            };
+            let _ = writeln!(stdout, "  Project: {}", unknown(&project));
+            let _ = writeln!(stdout, "  Version: {}", unknown(&version));
+            if let Some(firmware) = ec.firmware() {
+                let _ = writeln!(stdout, "  Firmware: {}", firmware);
+            }
+            if let Some(id) = ec.chip_id() {
+                let _ = writeln!(stdout, "  Chip ID: IT{:04X}", id);
+            }
+            if let Some(chip_version) = ec.chip_version() {
+                let _ = writeln!(stdout, "  Chip Version: {}", chip_version);
+            }
+            if let Some((interface, data_port, cmd_port)) = ec.interface() {
+                let _ = writeln!(
+                    stdout,
+                    "  Interface: {}, data 0x{:02X}, command 0x{:02X}",
+                    interface, data_port, cmd_port
+                );
+                if let Some(bridge) = read_bridge() {
+                    let _ = writeln!(stdout, "  Bridge: {}", bridge);
+                }
+            }
+            for (region, blank) in regions.iter() {
+                let _ = writeln!(
+                    stdout,
+                    "  Region {}: 0x{:05X}-0x{:05X}{}",
+                    region.name,
+                    region.range.start,
+                    region.range.end - 1,
+                    if *blank { " (blank)" } else { "" }
+                );
             }
         }
 
-        match validate(|| ec.size(), 8) {
-            Ok(size) => {
+        match validate(|| ec.size(), 8, verbosity) {
+            Ok(size) => if print {
                 let _ = writeln!(stdout, "  Size: {} KB", size/1024);
             },
             Err(()) => {
                 let _ = writeln!(stderr(), "Failed to read EC size");
-                process::exit(1);
+                process::exit(exit::VERIFY);
             }
         }
     }
 
     let _ = stdout.flush();
+
+    process::exit(exit::OK);
+}
+
+fn main() {
+    session::install_hook();
+
+    let mut command = None;
+    let mut args = Args {
+        verbosity: Verbosity::Normal,
+        fwupd: false,
+        progress_json: false,
+        yes: false,
+        allow_bootblock: false,
+        lock_bootblock: false,
+        lock: false,
+        unlock: false,
+        region: None,
+        resume: false,
+        grab_input: false,
+        algorithm: None,
+        offset: None,
+        length: None,
+        format: Format::Binary,
+        live: false,
+        mmio: None,
+        key: None,
+        programmer: None,
+        symbols: None,
+        duration: None,
+        cycles: 10,
+        scratch: false,
+        watch: false,
+        external: false,
+        port: None,
+        raw_cmd: None,
+        raw_write: Vec::new(),
+        raw_read: 0,
+        spi_tx: Vec::new(),
+        spi_rx: 0,
+        unknown_chip: false,
+        backend: None,
+        backup_dir: None,
+        config_path: None,
+        trace: None,
+        config: Config::default(),
+        ec_args: Vec::new(),
+    };
+
+    let mut env_args = env::args().skip(1);
+    while let Some(arg) = env_args.next() {
+        match arg.as_str() {
+            "info" | "read" | "hexdump" | "bench" | "stress" | "write" | "apply" | "restore" | "repair" | "reset" | "option" | "param" | "fcommand" | "ram" | "tcpc" | "protection" | "map" | "unlock" | "protect" | "recover" | "raw" | "spi" | "sfdp" | "unbrick" | "programmers" | "programmer" | "dbgr" | "profile" | "report" | "interrupted" | "migrate" | "daemon" | "serve" | "remote" if command.is_none() && args.ec_args.is_empty() => {
+                command = Some(arg);
+            },
+            "--fwupd" => args.fwupd = true,
+            "--progress-json" => args.progress_json = true,
+            "--yes" => args.yes = true,
+            "--allow-bootblock" => args.allow_bootblock = true,
+            "--lock-bootblock" => args.lock_bootblock = true,
+            "--lock" => args.lock = true,
+            "--unlock" => args.unlock = true,
+            "--resume" => args.resume = true,
+            "--grab-input" => args.grab_input = true,
+            "--live" => args.live = true,
+            "--scratch" => args.scratch = true,
+            "--watch" => args.watch = true,
+            "--external" => args.external = true,
+            "--unknown-chip" => args.unknown_chip = true,
+            "--cycles" => match env_args.next().as_deref().and_then(parse_int) {
+                Some(cycles) if cycles > 0 => args.cycles = cycles as usize,
+                _ => {
+                    let _ = writeln!(stderr(), "Invalid or missing value for '--cycles'\n{}", USAGE);
+                    process::exit(exit::USAGE);
+                }
+            },
+            "--cmd" | "--write" => match env_args.next().as_deref().and_then(parse_int) {
+                Some(value) if value <= 0xFF && arg == "--cmd" => args.raw_cmd = Some(value as u8),
+                Some(value) if value <= 0xFF => args.raw_write.push(value as u8),
+                _ => {
+                    let _ = writeln!(stderr(), "Invalid or missing byte for '{}'\n{}", arg, USAGE);
+                    process::exit(exit::USAGE);
+                }
+            },
+            "--port" => match env_args.next().as_deref().and_then(parse_int) {
+                Some(port) => args.port = Some(port as usize),
+                None => {
+                    let _ = writeln!(stderr(), "Invalid or missing value for '--port'\n{}", USAGE);
+                    process::exit(exit::USAGE);
+                }
+            },
+            "--read" | "--rx" => match env_args.next().as_deref().and_then(parse_int) {
+                Some(count) if arg == "--read" => args.raw_read = count as usize,
+                Some(count) => args.spi_rx = count as usize,
+                None => {
+                    let _ = writeln!(stderr(), "Invalid or missing value for '{}'\n{}", arg, USAGE);
+                    process::exit(exit::USAGE);
+                }
+            },
+            "--tx" => match env_args.next().as_deref().and_then(parse_hex) {
+                Some(bytes) if ! bytes.is_empty() => args.spi_tx = bytes,
+                _ => {
+                    let _ = writeln!(stderr(), "Invalid or missing bytes for '--tx'\n{}", USAGE);
+                    process::exit(exit::USAGE);
+                }
+            },
+            "--region" => match env_args.next() {
+                Some(region) => args.region = Some(region),
+                None => {
+                    let _ = writeln!(stderr(), "No region provided\n{}", USAGE);
+                    process::exit(exit::USAGE);
+                }
+            },
+            "--offset" | "--length" => match env_args.next().as_deref().and_then(parse_size) {
+                Some(value) if arg == "--offset" => args.offset = Some(value),
+                Some(value) => args.length = Some(value),
+                None => {
+                    let _ = writeln!(stderr(), "Invalid or missing value for '{}'\n{}", arg, USAGE);
+                    process::exit(exit::USAGE);
+                }
+            },
+            "--duration" => match env_args.next().as_deref().and_then(parse_duration) {
+                Some(duration) => args.duration = Some(duration),
+                None => {
+                    let _ = writeln!(stderr(), "Invalid or missing value for '--duration'\n{}", USAGE);
+                    process::exit(exit::USAGE);
+                }
+            },
+            "--mmio" => match env_args.next().as_deref().and_then(parse_int) {
+                Some(base) => args.mmio = Some(base as u64),
+                None => {
+                    let _ = writeln!(stderr(), "Invalid or missing address for '--mmio'\n{}", USAGE);
+                    process::exit(exit::USAGE);
+                }
+            },
+            "--backend" | "--backup-dir" | "--config" | "--trace" | "--programmer" | "--symbols" => match env_args.next() {
+                Some(value) if arg == "--backend" => args.backend = Some(value),
+                Some(value) if arg == "--programmer" => args.programmer = Some(value),
+                Some(value) if arg == "--symbols" => args.symbols = Some(value),
+                Some(value) if arg == "--backup-dir" => args.backup_dir = Some(value),
+                Some(value) if arg == "--trace" => args.trace = Some(value),
+                Some(value) => args.config_path = Some(value),
+                None => {
+                    let _ = writeln!(stderr(), "No value provided for '{}'\n{}", arg, USAGE);
+                    process::exit(exit::USAGE);
+                }
+            },
+            "--key" => match env_args.next() {
+                Some(key) => args.key = Some(key),
+                None => {
+                    let _ = writeln!(stderr(), "No key file provided\n{}", USAGE);
+                    process::exit(exit::USAGE);
+                }
+            },
+            "--algorithm" => match env_args.next().as_deref() {
+                Some(name) if FlashAlgorithm::parse(name).is_some() => args.algorithm = FlashAlgorithm::parse(name),
+                Some("fcommand") => {
+                    let _ = writeln!(stderr(), "The EC firmware has no known fcommand for programming the flash\n{}", USAGE);
+                    process::exit(exit::USAGE);
+                },
+                _ => {
+                    let _ = writeln!(stderr(), "Invalid or missing algorithm\n{}", USAGE);
+                    process::exit(exit::USAGE);
+                }
+            },
+            "--format" => match env_args.next().as_deref().and_then(Format::from_name) {
+                Some(format) => args.format = format,
+                None => {
+                    let _ = writeln!(stderr(), "Invalid or missing format\n{}", USAGE);
+                    process::exit(exit::USAGE);
+                }
+            },
+            "-q" => args.verbosity = Verbosity::Quiet,
+            "-v" => args.verbosity = Verbosity::Verbose,
+            "-vv" => args.verbosity = Verbosity::Debug,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(exit::OK);
+            },
+            _ if arg.starts_with('-') && arg != "-1" && arg != "-2" => {
+                let _ = writeln!(stderr(), "Unknown option '{}'\n{}", arg, USAGE);
+                process::exit(exit::USAGE);
+            },
+            _ => args.ec_args.push(arg),
+        }
+    }
+
+    let config_path = args.config_path.as_deref().unwrap_or(CONFIG_PATH);
+    args.config = match Config::load(config_path) {
+        Ok(config) => config,
+        Err(err) => {
+            let _ = writeln!(stderr(), "Failed to load '{}': {}", config_path, err);
+            process::exit(exit::USAGE);
+        }
+    };
+    args.mmio = args.mmio.or(args.config.mmio);
+    args.backend = args.backend.take().or_else(|| args.config.backend.clone());
+    args.backup_dir = args.backup_dir.take().or_else(|| args.config.backup_dir.clone());
+
+    // Each EC that is opened appends to the trace, which report reads instead
+    if let Some(trace) = args.trace.as_ref().filter(|_| command.as_deref() != Some("report")) {
+        if let Err(err) = fs::File::create(trace) {
+            let _ = writeln!(stderr(), "Failed to create '{}': {}", trace, err);
+            process::exit(exit::IO);
+        }
+    }
+
+    if args.unknown_chip && command.as_deref().is_some_and(|command| command != "info") {
+        let _ = writeln!(stderr(), "--unknown-chip is only allowed with info\n{}", USAGE);
+        process::exit(exit::USAGE);
+    }
+
+    let kernel = args.backend.as_deref() == Some("kernel");
+    if kernel && (command.as_deref().is_some_and(|command| command != "info") || args.unknown_chip || args.fwupd) {
+        let _ = writeln!(
+            stderr(),
+            "The kernel backend cannot enter flash mode or read the project and version, so it is only allowed with info\n{}",
+            USAGE
+        );
+        process::exit(exit::USAGE);
+    }
+
+    // A flash cut short by a crash or power loss is only noticed at the next
+    // cold boot otherwise
+    if ! matches!(command.as_deref(), Some("interrupted") | Some("repair") | Some("daemon")) {
+        marker::warn(&args.progress());
+    }
+
+    match command.as_deref() {
+        Some("read") => read(&args),
+        Some("hexdump") => hexdump(&args),
+        Some("bench") => bench(&args),
+        Some("stress") => stress(&args),
+        Some("write") => write(&args),
+        Some("apply") => apply(&args),
+        Some("restore") => restore(&args),
+        Some("repair") => repair(&args),
+        Some("reset") => reset(&args),
+        Some("option") => option(&args),
+        Some("param") => param(&args),
+        Some("fcommand") => fcommand(&args),
+        Some("ram") => ram::ram(&args),
+        Some("tcpc") => tcpc::tcpc(&args),
+        Some("protection") => protection(&args),
+        Some("map") => map(&args),
+        Some("unlock") => unlock(&args),
+        Some("protect") => protect(&args),
+        Some("recover") => recover(&args),
+        Some("raw") => raw(&args),
+        Some("spi") => spi(&args),
+        Some("sfdp") => sfdp::sfdp(&args),
+        Some("unbrick") => unbrick::unbrick(&args),
+        Some("programmers") => programmer::programmers(&args),
+        Some("programmer") => programmer::programmer(&args),
+        Some("dbgr") => dbgr::dbgr(&args),
+        Some("profile") => dbgr::profile(&args),
+        Some("report") => support::report(&args),
+        Some("interrupted") => marker::interrupted(&args),
+        Some("migrate") => migrate::migrate(&args),
+        Some("daemon") => daemon(),
+        Some("serve") => serve(&args),
+        Some("remote") => remote(&args),
+        _ => info(&args),
+    }
 }
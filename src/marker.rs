@@ -0,0 +1,119 @@
+//! Marker of a destructive operation in progress, written to MARKER_PATH
+//! before the flash is erased and removed once it verifies, so that a flash
+//! cut short by a crash or power loss is noticed on the next run instead of
+//! at the next cold boot.
+//!
+//! `ecflash interrupted` prints a stale marker and exits 1, for the systemd
+//! unit that checks at boot, and `ecflash interrupted clear` removes it once
+//! the flash has been checked. `ecflash repair` removes it once the flash
+//! matches the image again.
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time::SystemTime;
+
+use super::journal::date;
+use super::progress::Progress;
+use super::{exit, Args, USAGE};
+
+pub const MARKER_PATH: &str = "/var/lib/ecflash/interrupted";
+
+/// What the interrupted operation was doing
+pub struct Marker {
+    /// Command, such as write or restore
+    pub operation: String,
+    /// Image that was being flashed
+    pub image: String,
+    pub primary: bool,
+    /// Backup of the flash before it was erased
+    pub backup: Option<String>,
+    /// When the operation began, as RFC 3339
+    pub date: String,
+}
+
+/// Record that operation is about to erase the flash, synced so that it
+/// survives a power loss right after
+pub fn set(operation: &str, image: &str, primary: bool, backup: Option<&str>) -> std::io::Result<()> {
+    if let Some(dir) = Path::new(MARKER_PATH).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = fs::File::create(MARKER_PATH)?;
+    writeln!(file, "operation={}", operation)?;
+    writeln!(file, "image={}", image)?;
+    writeln!(file, "ec={}", if primary { 1 } else { 2 })?;
+    if let Some(backup) = backup {
+        writeln!(file, "backup={}", backup)?;
+    }
+    writeln!(file, "date={}", date(SystemTime::now()))?;
+    file.sync_all()
+}
+
+/// Remove the marker after the flash verified
+pub fn clear() -> std::io::Result<()> {
+    match fs::remove_file(MARKER_PATH) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// The stale marker, if an operation did not finish
+pub fn load() -> Option<Marker> {
+    let text = fs::read_to_string(MARKER_PATH).ok()?;
+    let mut marker = Marker {
+        operation: String::new(),
+        image: String::new(),
+        primary: true,
+        backup: None,
+        date: String::new(),
+    };
+    for line in text.lines() {
+        match line.split_once('=') {
+            Some(("operation", value)) => marker.operation = value.to_string(),
+            Some(("image", value)) => marker.image = value.to_string(),
+            Some(("ec", value)) => marker.primary = value != "2",
+            Some(("backup", value)) => marker.backup = Some(value.to_string()),
+            Some(("date", value)) => marker.date = value.to_string(),
+            _ => (),
+        }
+    }
+    Some(marker)
+}
+
+/// What happened, and how to check and repair the flash
+fn describe(marker: &Marker) -> String {
+    let ec = if marker.primary { "" } else { " -2" };
+    let restore = match &marker.backup {
+        Some(backup) => format!("or restore the original with 'ecflash restore{} {}'", ec, backup),
+        None => "there is no backup of the original".to_string(),
+    };
+    format!(
+        "The {} of '{}' on EC {} started {} did not finish, so the flash may be partly erased. \
+        Reprogram only the damaged sectors with 'ecflash repair{}', flash it again with 'ecflash {}{} {}', {}, \
+        then run 'ecflash interrupted clear'",
+        marker.operation, marker.image, if marker.primary { 1 } else { 2 }, marker.date,
+        ec, marker.operation, ec, marker.image, restore
+    )
+}
+
+/// Warn about a stale marker, before a command that does not deal with it
+pub fn warn(progress: &Progress) {
+    if let Some(marker) = load() {
+        progress.warning(&describe(&marker));
+    }
+}
+
+pub fn interrupted(args: &Args) -> ! {
+    let progress = args.progress();
+    match args.ec_args.iter().map(|arg| arg.as_str()).collect::<Vec<_>>().as_slice() {
+        [] => match load() {
+            Some(marker) => progress.result(exit::FAILURE, &describe(&marker)),
+            None => progress.result(exit::OK, "No interrupted flash"),
+        },
+        ["clear"] => match clear() {
+            Ok(()) => progress.result(exit::OK, "Cleared the interrupted flash marker"),
+            Err(err) => progress.result(exit::IO, &format!("Failed to remove '{}': {}", MARKER_PATH, err)),
+        },
+        _ => progress.result(exit::USAGE, &format!("Invalid interrupted command\n{}", USAGE)),
+    }
+}
@@ -0,0 +1,323 @@
+//! Migration of the primary EC from the proprietary firmware to the
+//! open-source System76 EC firmware, and back.
+//!
+//! `ecflash migrate IMAGE` checks that IMAGE is of the open firmware for the
+//! board of the running project and fills the whole flash, then flashes it
+//! with a backup, recording the migration in MIGRATION_PATH first. Flashing
+//! powers off the system, so `ecflash migrate verify` checks on the next boot
+//! which firmware answers, and `ecflash migrate rollback` flashes the backup
+//! of the proprietary firmware again.
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ecflash::{Ec, EcFile};
+
+use super::journal::{self, date};
+use super::progress::Progress;
+use super::{check_digest, exit, flash, new_flasher, open_ec, open_smfi, validate, Args, USAGE};
+
+pub const MIGRATION_PATH: &str = "/var/lib/ecflash/migration";
+
+/// Migration that has not been verified yet
+struct Migration {
+    /// Project and version of the proprietary firmware
+    project: String,
+    version: String,
+    /// Board and version of the open firmware in the image
+    board: String,
+    target: String,
+    image: String,
+    /// Directory of the backup that flash saved
+    backup_dir: String,
+    /// When the first attempt began, in seconds, which the ID of the backup
+    /// of the proprietary firmware is not older than
+    since: u64,
+    /// When the first attempt began, as RFC 3339
+    date: String,
+}
+
+fn save(migration: &Migration) -> std::io::Result<()> {
+    if let Some(dir) = Path::new(MIGRATION_PATH).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = fs::File::create(MIGRATION_PATH)?;
+    writeln!(file, "project={}", migration.project)?;
+    writeln!(file, "version={}", migration.version)?;
+    writeln!(file, "board={}", migration.board)?;
+    writeln!(file, "target={}", migration.target)?;
+    writeln!(file, "image={}", migration.image)?;
+    writeln!(file, "backup_dir={}", migration.backup_dir)?;
+    writeln!(file, "since={}", migration.since)?;
+    writeln!(file, "date={}", migration.date)?;
+    file.sync_all()
+}
+
+fn load() -> Option<Migration> {
+    let text = fs::read_to_string(MIGRATION_PATH).ok()?;
+    let mut migration = Migration {
+        project: String::new(),
+        version: String::new(),
+        board: String::new(),
+        target: String::new(),
+        image: String::new(),
+        backup_dir: String::new(),
+        since: 0,
+        date: String::new(),
+    };
+    for line in text.lines() {
+        match line.split_once('=') {
+            Some(("project", value)) => migration.project = value.to_string(),
+            Some(("version", value)) => migration.version = value.to_string(),
+            Some(("board", value)) => migration.board = value.to_string(),
+            Some(("target", value)) => migration.target = value.to_string(),
+            Some(("image", value)) => migration.image = value.to_string(),
+            Some(("backup_dir", value)) => migration.backup_dir = value.to_string(),
+            Some(("since", value)) => migration.since = value.parse().unwrap_or(0),
+            Some(("date", value)) => migration.date = value.to_string(),
+            _ => (),
+        }
+    }
+    Some(migration)
+}
+
+fn clear() -> std::io::Result<()> {
+    match fs::remove_file(MIGRATION_PATH) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// The backup of the proprietary firmware, which is the oldest backup of the
+/// primary EC saved since the migration began
+fn find_backup(migration: &Migration) -> Option<String> {
+    let mut ids: Vec<u64> = fs::read_dir(&migration.backup_dir).ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            name.strip_prefix("ec1-")?.strip_suffix(".rom")?.parse().ok()
+        })
+        .filter(|&id| id >= migration.since)
+        .collect();
+    ids.sort_unstable();
+    ids.first().map(|id| format!("{}/ec1-{}.rom", migration.backup_dir.trim_end_matches('/'), id))
+}
+
+/// Refuse options that would leave part of the old firmware in the flash
+fn check_whole_flash(args: &Args, progress: &Progress) {
+    if ! args.allow_bootblock || args.region.is_some() || ! args.config.protected.is_empty() {
+        progress.result(
+            exit::USAGE,
+            "Migration replaces the whole flash, so it needs --allow-bootblock, and no --region or protected regions"
+        );
+    }
+}
+
+/// Check IMAGE against the running EC, then flash it
+fn start(args: &Args, progress: &Progress, path: &str) -> ! {
+    check_whole_flash(args, progress);
+    let backup_dir = match &args.backup_dir {
+        Some(dir) => dir.clone(),
+        None => progress.result(exit::USAGE, "Migration saves a backup of the proprietary firmware to roll back to, pass --backup-dir"),
+    };
+
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) => progress.result(exit::IO, &format!("Failed to read '{}': {}", path, err)),
+    };
+    check_digest(progress, path, &data);
+    let mut image = EcFile::new(data);
+    if ! image.is_open() {
+        progress.result(exit::INCOMPATIBLE, &format!("'{}' is not an image of the System76 EC firmware", path));
+    }
+    let (board, target) = (image.project(), image.version());
+
+    let mut ec = open_ec(args, true, progress);
+    if let Some(mut ec_smfi) = open_smfi(&mut ec) {
+        progress.result(exit::INCOMPATIBLE, &format!(
+            "The EC already runs the System76 EC firmware, {} {}",
+            ec_smfi.project(), ec_smfi.version()
+        ));
+    }
+    let project = match validate(|| ec.project(), 8, args.verbosity) {
+        Ok(project) => project.trim().to_string(),
+        Err(()) => progress.result(exit::VERIFY, "Failed to read EC project"),
+    };
+    let version = match validate(|| ec.version(), 8, args.verbosity) {
+        Ok(version) => version.trim().to_string(),
+        Err(()) => progress.result(exit::VERIFY, "Failed to read EC version"),
+    };
+    if project.is_empty() {
+        progress.result(exit::INCOMPATIBLE, "The EC does not report its project, so its board is not known");
+    }
+    if ! args.config.migrates_to(&project, &board) {
+        progress.result(exit::INCOMPATIBLE, &format!(
+            "No migration from {} to {} is known, add \"{}:{}\" to migrate in the configuration file if it is the same board",
+            project, board, project, board
+        ));
+    }
+
+    // The open firmware is built for the whole flash, and an image that is
+    // shorter, or has no boot block, would leave the EC unbootable
+    let size = ec.size();
+    if image.data().len() != size {
+        progress.result(exit::INCOMPATIBLE, &format!(
+            "'{}' is {} bytes, but the flash is {} bytes",
+            path, image.data().len(), size
+        ));
+    }
+    for region in image.layout().regions.iter() {
+        if image.is_blank(region) {
+            progress.result(exit::INCOMPATIBLE, &format!("Region '{}' of '{}' is blank", region.name, path));
+        }
+    }
+
+    // A previous attempt that did not finish keeps its start, so that the
+    // backup it saved of the proprietary firmware is still the one found
+    let now = SystemTime::now();
+    let migration = match load() {
+        Some(previous) if previous.project == project && previous.backup_dir == backup_dir => Migration {
+            board: board.clone(),
+            target: target.clone(),
+            image: path.to_string(),
+            ..previous
+        },
+        _ => Migration {
+            project: project.clone(),
+            version: version.clone(),
+            board: board.clone(),
+            target: target.clone(),
+            image: path.to_string(),
+            backup_dir,
+            since: now.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0),
+            date: date(now),
+        },
+    };
+    if let Err(err) = save(&migration) {
+        progress.result(exit::IO, &format!("Failed to write '{}': {}", MIGRATION_PATH, err));
+    }
+    progress.info(&format!(
+        "Migrating {} {} to {} {}. The system powers off when done, then run 'ecflash migrate verify' once it is back on",
+        project, version, board, target
+    ));
+
+    // Detached signature, only checked when built with the signature feature
+    let signature = fs::read(format!("{}.sig", path)).ok();
+
+    journal::begin("migrate", path, true);
+    flash(args, progress, new_flasher(args, ec, progress), image.data().to_vec(), signature)
+}
+
+/// Check which firmware answers after the first boot
+fn verify(args: &Args, progress: &Progress) -> ! {
+    let migration = match load() {
+        Some(migration) => migration,
+        None => progress.result(exit::OK, "No migration to verify"),
+    };
+    let rollback = match find_backup(&migration) {
+        Some(backup) => format!("roll back to '{}' with 'ecflash migrate rollback --allow-bootblock'", backup),
+        None => format!("no backup of the proprietary firmware is in '{}'", migration.backup_dir),
+    };
+
+    let mut ec = open_ec(args, true, progress);
+    if let Some(mut ec_smfi) = open_smfi(&mut ec) {
+        let (board, version) = (ec_smfi.project(), ec_smfi.version());
+        if board != migration.board || version != migration.target {
+            progress.result(exit::VERIFY, &format!(
+                "The EC runs {} {} instead of {} {} of '{}', {}",
+                board, version, migration.board, migration.target, migration.image, rollback
+            ));
+        }
+        if let Err(err) = clear() {
+            progress.warning(&format!("Failed to remove '{}': {}", MIGRATION_PATH, err));
+        }
+        progress.result(exit::OK, &format!(
+            "Migrated from {} {} to {} {}, {}",
+            migration.project, migration.version, board, version, rollback
+        ));
+    }
+
+    let project = validate(|| ec.project(), 8, args.verbosity).unwrap_or_default();
+    let version = validate(|| ec.version(), 8, args.verbosity).unwrap_or_default();
+    if project.trim() == migration.project && version.trim() == migration.version {
+        // Cancelled, failed before the EC was reset, or rolled back
+        if let Err(err) = clear() {
+            progress.warning(&format!("Failed to remove '{}': {}", MIGRATION_PATH, err));
+        }
+        progress.result(exit::FAILURE, &format!(
+            "The EC runs the proprietary firmware {} {} as before the migration started {}",
+            migration.project, migration.version, migration.date
+        ));
+    }
+    progress.result(exit::VERIFY, &format!(
+        "The EC answers as neither the System76 EC firmware nor {} {}, {}",
+        migration.project, migration.version, rollback
+    ))
+}
+
+/// Flash the backup of the proprietary firmware again
+fn rollback(args: &Args, progress: &Progress) -> ! {
+    check_whole_flash(args, progress);
+    let migration = match load() {
+        Some(migration) => migration,
+        None => progress.result(exit::USAGE, "No migration to roll back"),
+    };
+    let path = match find_backup(&migration) {
+        Some(path) => path,
+        None => progress.result(exit::IO, &format!(
+            "No backup of the primary EC saved since {} in '{}'",
+            migration.date, migration.backup_dir
+        )),
+    };
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(err) => progress.result(exit::IO, &format!("Failed to read '{}': {}", path, err)),
+    };
+    check_digest(progress, &path, &data);
+    let mut backup = EcFile::new(data);
+    if backup.project().trim() != migration.project || backup.is_open() {
+        progress.result(exit::INCOMPATIBLE, &format!(
+            "Backup '{}' does not hold the proprietary firmware of {}",
+            path, migration.project
+        ));
+    }
+
+    let mut ec = open_ec(args, true, progress);
+    let size = ec.size();
+    if backup.data().len() != size {
+        progress.result(exit::INCOMPATIBLE, &format!(
+            "Backup '{}' is {} bytes, but the flash is {} bytes",
+            path, backup.data().len(), size
+        ));
+    }
+    progress.info(&format!(
+        "Rolling back to {} {} from '{}'. The system powers off when done, then run 'ecflash migrate verify' once it is back on",
+        migration.project, migration.version, path
+    ));
+
+    // Detached signature, only checked when built with the signature feature
+    let signature = fs::read(format!("{}.sig", path)).ok();
+
+    journal::begin("migrate", &path, true);
+    flash(args, progress, new_flasher(args, ec, progress), backup.data().to_vec(), signature)
+}
+
+pub fn migrate(args: &Args) -> ! {
+    let progress = args.progress();
+    if ! args.primary() {
+        progress.result(exit::USAGE, "Only the primary EC runs the System76 EC firmware");
+    }
+    let params: Vec<&str> = args.ec_args.iter()
+        .map(|arg| arg.as_str())
+        .filter(|&arg| arg != "-1")
+        .collect();
+
+    match params.as_slice() {
+        ["verify"] => verify(args, &progress),
+        ["rollback"] => rollback(args, &progress),
+        [path] => start(args, &progress, path),
+        _ => progress.result(exit::USAGE, &format!("Invalid migrate command\n{}", USAGE)),
+    }
+}
@@ -0,0 +1,136 @@
+//! Arduino programmer on the debug header of the EC, which unbrick, ram, dbgr,
+//! profile, and sfdp reach the EC through when the host cannot.
+//!
+//! The programmer is the one passed with --programmer or configured, or else
+//! the first serial port that one may be attached to.
+
+use std::fs;
+use std::io::{stdout, Write};
+use std::process;
+
+use ecflash::{check_id, flash_sketch, parse_ihex, Error, ParallelArduino};
+
+use super::progress::Progress;
+use super::{exit, Args, USAGE};
+
+/// Serial ports that an Arduino programmer may be attached to, starting with
+/// the one passed with --programmer or configured
+pub fn find_programmers(args: &Args) -> Vec<String> {
+    let mut ports = Vec::new();
+    if let Some(port) = args.programmer.as_ref().or(args.config.serial_port.as_ref()) {
+        ports.push(port.clone());
+    }
+    if let Ok(entries) = fs::read_dir("/dev") {
+        let mut found: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with("ttyACM") || name.starts_with("ttyUSB"))
+            .map(|name| format!("/dev/{}", name))
+            .filter(|port| ! ports.contains(port))
+            .collect();
+        found.sort();
+        ports.extend(found);
+    }
+    ports
+}
+
+/// Exit code of a programmer error
+fn code(err: &Error) -> i32 {
+    match err {
+        Error::Incompatible(_) => exit::INCOMPATIBLE,
+        _ => exit::FAILURE,
+    }
+}
+
+/// Open the programmer on port and check the chip ID through it, before
+/// anything else is sent to the EC
+pub fn open_port(args: &Args, progress: &Progress, port: &str) -> Result<ParallelArduino, (i32, String)> {
+    let mut programmer = ParallelArduino::open(port, None)
+        .map_err(|err| (code(&err), format!("Failed to open the programmer on {}: {}", port, err)))?;
+    progress.info(&format!("Programmer: {}, {} byte buffer", port, programmer.buffer_size()));
+
+    let (id, version) = check_id(&mut programmer, &args.config.known_ids)
+        .map_err(|err| (code(&err), format!("Failed to check the chip ID through the programmer: {}", err)))?;
+    progress.info(&format!("EC 0x{:04X} version {}", id, version));
+    Ok(programmer)
+}
+
+/// Open the programmer passed with --programmer, or the first one found
+pub fn open(args: &Args, progress: &Progress) -> ParallelArduino {
+    let port = match find_programmers(args).into_iter().next() {
+        Some(port) => port,
+        None => progress.result(exit::FAILURE, "No programmer found on /dev/ttyACM* or /dev/ttyUSB*"),
+    };
+    match open_port(args, progress, &port) {
+        Ok(programmer) => programmer,
+        Err((code, err)) => progress.result(code, &err),
+    }
+}
+
+/// List the serial ports that may have a programmer attached, with the
+/// buffer size and protocol version of those that answer as one
+pub fn programmers(args: &Args) -> ! {
+    let progress = args.progress();
+    let ports = find_programmers(args);
+    if ports.is_empty() {
+        progress.result(exit::FAILURE, "No programmer found on /dev/ttyACM* or /dev/ttyUSB*");
+    }
+
+    let mut stdout = stdout();
+    let _ = writeln!(stdout, "{:<16} {:>6} {:>8}  Frames", "Programmer", "Buffer", "Protocol");
+    for port in ports {
+        let res = ParallelArduino::open(&port, None).and_then(|mut programmer| {
+            Ok((programmer.buffer_size(), programmer.protocol()?))
+        });
+        let _ = match res {
+            Ok((buffer_size, protocol)) => writeln!(
+                stdout,
+                "{:<16} {:>6} {:>8}  {}",
+                port,
+                buffer_size,
+                protocol,
+                if protocol >= 2 { "combined" } else { "separate address" }
+            ),
+            Err(err) => writeln!(stdout, "{:<16} {:>6} {:>8}  not a programmer: {}", port, "-", "-", err),
+        };
+    }
+    process::exit(exit::OK);
+}
+
+/// Upload the programmer sketch in an Intel HEX file through the bootloader
+/// of the Mega 2560, to the programmer passed with --programmer or the first
+/// one found
+pub fn programmer(args: &Args) -> ! {
+    let progress = args.progress();
+    let params: Vec<&str> = args.ec_args.iter().map(|arg| arg.as_str()).collect();
+    let path = match params.as_slice() {
+        ["flash-sketch", path] => *path,
+        _ => progress.result(exit::USAGE, &format!("Invalid programmer command\n{}", USAGE)),
+    };
+
+    let port = match find_programmers(args).into_iter().next() {
+        Some(port) => port,
+        None => progress.result(exit::FAILURE, "No programmer found on /dev/ttyACM* or /dev/ttyUSB*"),
+    };
+    if port.starts_with("tcp:") {
+        progress.result(exit::USAGE, "The bootloader of the programmer is only reachable through its serial port");
+    }
+
+    let sketch = match fs::read_to_string(path) {
+        Ok(text) => match parse_ihex(&text) {
+            Ok(sketch) => sketch,
+            Err(err) => progress.result(exit::INCOMPATIBLE, &format!("Failed to parse '{}': {}", path, err)),
+        },
+        Err(err) => progress.result(exit::IO, &format!("Failed to read '{}': {}", path, err)),
+    };
+
+    let res = flash_sketch(&port, &sketch, &mut |phase, done, total| progress.update(phase, done, total))
+        .and_then(|mut programmer| Ok((programmer.buffer_size(), programmer.protocol()?)));
+    match res {
+        Ok((buffer_size, protocol)) => progress.result(exit::OK, &format!(
+            "Flashed {} to the programmer on {}, which answers with a {} byte buffer and protocol {}",
+            path, port, buffer_size, protocol
+        )),
+        Err(err) => progress.result(exit::FAILURE, &format!("Failed to flash {} to the programmer on {}: {}", path, port, err)),
+    }
+}
@@ -0,0 +1,147 @@
+//! Progress reporting for the binary, either human readable on stderr or as
+//! one JSON object per line on stdout for frontends.
+
+use std::io::{stderr, stdout, Write};
+use std::process;
+
+use ecflash::FlashReport;
+
+use super::{journal, Verbosity};
+
+/// Escape a string for use in JSON
+pub fn json_str(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Get a string or number field from a JSON object printed by Progress
+pub fn json_field(line: &str, name: &str) -> Option<String> {
+    let start = line.find(&format!("{}:", json_str(name)))? + name.len() + 3;
+    let rest = &line[start..];
+
+    let mut chars = match rest.strip_prefix('"') {
+        Some(rest) => rest.chars(),
+        None => {
+            let end = rest.find([',', '}']).unwrap_or(rest.len());
+            return Some(rest[..end].to_string());
+        }
+    };
+
+    let mut value = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                'r' => value.push('\r'),
+                't' => value.push('\t'),
+                'u' => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    value.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                },
+                c => value.push(c),
+            },
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// The result event for an exit code and message
+pub fn result_json(code: i32, message: &str) -> String {
+    format!(
+        "{{\"event\":\"result\",\"success\":{},\"exit_code\":{},\"message\":{}}}",
+        code == 0, code, json_str(message)
+    )
+}
+
+pub struct Progress {
+    pub json: bool,
+    pub verbosity: Verbosity,
+}
+
+impl Progress {
+    fn event(&self, fields: &str) {
+        let mut stdout = stdout();
+        let _ = writeln!(stdout, "{{{}}}", fields);
+        let _ = stdout.flush();
+    }
+
+    /// Report the number of bytes processed in a phase
+    pub fn update(&self, phase: &str, bytes: usize, total: usize) {
+        if self.json {
+            self.event(&format!(
+                "\"event\":\"progress\",\"phase\":{},\"bytes\":{},\"total\":{}",
+                json_str(phase), bytes, total
+            ));
+        } else if self.verbosity >= Verbosity::Normal {
+            let _ = write!(stderr(), "\r{}: {} / {} KB", phase, bytes / 1024, total / 1024);
+            if bytes >= total {
+                let _ = writeln!(stderr());
+            }
+        }
+    }
+
+    /// Report a message that does not stop the operation
+    pub fn warning(&self, message: &str) {
+        if self.json {
+            self.event(&format!("\"event\":\"warning\",\"message\":{}", json_str(message)));
+        } else if self.verbosity >= Verbosity::Normal {
+            let _ = writeln!(stderr(), "Warning: {}", message);
+        }
+    }
+
+    /// Report a diagnostic message, only shown with -v
+    pub fn info(&self, message: &str) {
+        if self.json {
+            self.event(&format!("\"event\":\"info\",\"message\":{}", json_str(message)));
+        } else if self.verbosity >= Verbosity::Verbose {
+            let _ = writeln!(stderr(), "{}", message);
+        }
+    }
+
+    /// Report what a flash did, before its result
+    pub fn report(&self, report: &FlashReport) {
+        journal::report(report);
+        if self.json {
+            let phases: Vec<String> = report.phases.iter()
+                .map(|(phase, elapsed)| format!("{}:{:.3}", json_str(phase), elapsed.as_secs_f64()))
+                .collect();
+            self.event(&format!(
+                "\"event\":\"report\",\"bytes_read\":{},\"bytes_erased\":{},\"bytes_written\":{},\
+                \"blocks_skipped\":{},\"retries\":{},\"mismatches_fixed\":{},\"seconds\":{{{}}}",
+                report.bytes_read, report.bytes_erased, report.bytes_written,
+                report.blocks_skipped, report.retries, report.mismatches_fixed, phases.join(",")
+            ));
+        } else if self.verbosity >= Verbosity::Normal {
+            let _ = writeln!(stderr(), "{}", report);
+        }
+    }
+
+    /// Report the final result, and exit with the given code, after
+    /// appending the operation in progress to the journal
+    pub fn result(&self, code: i32, message: &str) -> ! {
+        journal::finish(self, code, message);
+        if self.json {
+            let mut stdout = stdout();
+            let _ = writeln!(stdout, "{}", result_json(code, message));
+            let _ = stdout.flush();
+        } else if code != 0 || self.verbosity >= Verbosity::Normal {
+            let _ = writeln!(stderr(), "{}", message);
+        }
+        process::exit(code);
+    }
+}
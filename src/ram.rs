@@ -0,0 +1,224 @@
+//! Variables of the running EC firmware, by the names in the linker map of
+//! its build.
+//!
+//! The map is passed with --symbols. Variables are read and written in EC
+//! memory through the I2EC interface of the Super I/O of the primary EC, or
+//! with --programmer through EC memory snoop (ECMS) of the debugger behind the
+//! Arduino programmer. Either way, only variables in external data memory are
+//! reachable.
+
+use std::fs;
+use std::io::{stdout, Write};
+use std::process;
+use std::{thread, time};
+
+use ecflash::{Debugger, EcFlash, ParallelArduino, Symbol, SymbolMap};
+
+use super::progress::Progress;
+use super::{confirm, exit, open_ec, parse_hex, parse_int, programmer, Args, Io, USAGE};
+
+/// Size of EC memory, which 16 bit addresses reach
+const MEMORY_SIZE: usize = 0x10000;
+
+/// Load the linker map passed with --symbols
+pub fn load(args: &Args, progress: &Progress) -> SymbolMap {
+    let path = match &args.symbols {
+        Some(path) => path,
+        None => progress.result(exit::USAGE, &format!("No linker map provided, pass --symbols FILE\n{}", USAGE)),
+    };
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) => progress.result(exit::IO, &format!("Failed to read '{}': {}", path, err)),
+    };
+    match SymbolMap::parse(&text) {
+        Ok(map) => map,
+        Err(err) => progress.result(exit::IO, &format!("Failed to parse '{}': {}", path, err)),
+    }
+}
+
+/// Find a variable of EC memory, and the number of bytes of it to access,
+/// which --length overrides
+fn resolve<'a>(args: &Args, progress: &Progress, map: &'a SymbolMap, name: &str) -> (&'a Symbol, usize) {
+    let symbol = match map.get(name) {
+        Some(symbol) => symbol,
+        None => progress.result(exit::USAGE, &format!("No symbol '{}' in the linker map", name)),
+    };
+    if ! symbol.xdata {
+        progress.result(exit::USAGE, &format!("'{}' is in {}, which is not in EC memory", name, symbol.area));
+    }
+
+    let size = match args.length.or(symbol.size) {
+        Some(size) => size,
+        None => {
+            progress.warning(&format!("The size of '{}' is not known, pass --length to access more than 1 byte", name));
+            1
+        },
+    };
+    if size == 0 || symbol.address as usize + size > MEMORY_SIZE {
+        progress.result(exit::USAGE, &format!("{} bytes at 0x{:04X} exceed EC memory", size, symbol.address));
+    }
+
+    progress.info(&format!("{} is at 0x{:04X} in {}, size {}", symbol.name, symbol.address, symbol.area, size));
+    (symbol, size)
+}
+
+/// Way of reaching EC memory
+enum Access {
+    /// I2EC of the Super I/O of the primary EC
+    I2ec(EcFlash<Io>),
+    /// ECMS of the debugger, through the programmer
+    Ecms(ParallelArduino),
+}
+
+impl Access {
+    fn open(args: &Args, progress: &Progress) -> Access {
+        match &args.programmer {
+            Some(_) => Access::Ecms(programmer::open(args, progress)),
+            None => Access::I2ec(open_ec(args, true, progress)),
+        }
+    }
+
+    fn read(&mut self, address: u16, size: usize) -> Result<Vec<u8>, String> {
+        match self {
+            Access::I2ec(ec) => (0..size)
+                .map(|i| unsafe { ec.memory_read(address + i as u16) })
+                .collect::<Result<Vec<u8>, ()>>()
+                .map_err(|()| "Failed to read EC memory".to_string()),
+            Access::Ecms(port) => {
+                let mut data = vec![0; size];
+                for (i, byte) in data.iter_mut().enumerate() {
+                    port.ecms_read_at(address + i as u16, std::slice::from_mut(byte))
+                        .map_err(|err| format!("Failed to read EC memory through the programmer: {}", err))?;
+                }
+                Ok(data)
+            },
+        }
+    }
+
+    fn write(&mut self, address: u16, data: &[u8]) -> Result<(), String> {
+        match self {
+            Access::I2ec(ec) => data.iter()
+                .enumerate()
+                .try_for_each(|(i, &value)| unsafe { ec.memory_write(address + i as u16, value) })
+                .map_err(|()| "Failed to write EC memory, the EC may not be supported".to_string()),
+            Access::Ecms(port) => data.iter()
+                .enumerate()
+                .try_for_each(|(i, &value)| port.ecms_write_at(address + i as u16, &[value]).map(|_| ()))
+                .map_err(|err| format!("Failed to write EC memory through the programmer: {}", err)),
+        }
+    }
+}
+
+/// Value of a variable, as a little endian integer like SDCC stores them if
+/// it has 1, 2, or 4 bytes, and as its bytes otherwise
+fn format_value(data: &[u8]) -> String {
+    match data.len() {
+        1 | 2 | 4 => {
+            let value = data.iter().rev().fold(0u32, |value, &byte| (value << 8) | byte as u32);
+            format!("0x{:0width$X} ({})", value, value, width = data.len() * 2)
+        },
+        _ => data.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(" "),
+    }
+}
+
+/// Bytes of a new value of a variable, an integer that fits if it has 1, 2,
+/// or 4 bytes, and hexadecimal bytes of its size otherwise
+fn parse_value(s: &str, size: usize) -> Option<Vec<u8>> {
+    match size {
+        1 | 2 | 4 => {
+            let value = parse_int(s)?;
+            if size < 4 && value >> (size * 8) != 0 {
+                return None;
+            }
+            Some(value.to_le_bytes()[..size].to_vec())
+        },
+        _ => parse_hex(s).filter(|data| data.len() == size),
+    }
+}
+
+/// Print a variable, and with --watch each time it changes
+fn get(args: &Args, progress: &Progress, mut access: Access, symbol: &Symbol, size: usize) -> ! {
+    let start = time::Instant::now();
+    let mut last = None;
+    loop {
+        let data = match access.read(symbol.address, size) {
+            Ok(data) => data,
+            Err(err) => progress.result(exit::FAILURE, &err),
+        };
+        if ! args.watch {
+            let _ = writeln!(stdout(), "{}", format_value(&data));
+            process::exit(exit::OK);
+        }
+
+        if last.as_ref() != Some(&data) {
+            let _ = writeln!(stdout(), "{:.1} {}", start.elapsed().as_secs_f64(), format_value(&data));
+            last = Some(data);
+        }
+        thread::sleep(time::Duration::from_secs(1));
+    }
+}
+
+/// Change a variable after showing its old and new value, then read it back
+fn set(args: &Args, progress: &Progress, mut access: Access, symbol: &Symbol, data: &[u8]) -> ! {
+    let old = match access.read(symbol.address, data.len()) {
+        Ok(old) => old,
+        Err(err) => progress.result(exit::FAILURE, &err),
+    };
+
+    let prompt = format!(
+        "Changing {} at 0x{:04X} from {} to {}, which the running EC firmware acts on at once",
+        symbol.name, symbol.address, format_value(&old), format_value(data)
+    );
+    if ! confirm(args, &prompt) {
+        progress.result(exit::FAILURE, "Cancelled");
+    }
+
+    if let Err(err) = access.write(symbol.address, data) {
+        progress.result(exit::FAILURE, &err);
+    }
+    match access.read(symbol.address, data.len()) {
+        Ok(new) if new == data => progress.result(exit::OK, &format!("Set {} to {}", symbol.name, format_value(data))),
+        Ok(new) => progress.result(exit::VERIFY, &format!(
+            "{} reads back as {}, the EC firmware may have changed it again",
+            symbol.name, format_value(&new)
+        )),
+        Err(err) => progress.result(exit::FAILURE, &err),
+    }
+}
+
+pub fn ram(args: &Args) -> ! {
+    let progress = args.progress();
+    let params: Vec<&str> = args.ec_args.iter()
+        .map(|arg| arg.as_str())
+        .filter(|&arg| arg != "-1")
+        .collect();
+
+    let (name, value) = match params.as_slice() {
+        ["get", name] => (*name, None),
+        ["set", name, value] => (*name, Some(*value)),
+        _ => progress.result(exit::USAGE, &format!("Invalid ram command\n{}", USAGE)),
+    };
+    if ! args.primary() {
+        progress.result(exit::USAGE, "EC memory is only reachable on the primary EC");
+    }
+
+    let map = load(args, &progress);
+    let (symbol, size) = resolve(args, &progress, &map, name);
+    match value {
+        Some(value) => {
+            let data = match parse_value(value, size) {
+                Some(data) => data,
+                None if matches!(size, 1 | 2 | 4) => progress.result(
+                    exit::USAGE,
+                    &format!("Invalid value '{}', '{}' takes an integer of {} bits", value, name, size * 8)
+                ),
+                None => progress.result(
+                    exit::USAGE,
+                    &format!("Invalid value '{}', '{}' takes {} bytes as hexadecimal", value, name, size)
+                ),
+            };
+            set(args, &progress, Access::open(args, &progress), symbol, &data)
+        },
+        None => get(args, &progress, Access::open(args, &progress), symbol, size),
+    }
+}
@@ -0,0 +1,280 @@
+//! Remote flashing, where `serve` runs on the machine with the EC and `remote`
+//! sends it firmware from a workstation.
+//!
+//! The server greets each connection with MAGIC and a random nonce. The client
+//! answers with an HMAC-SHA-256 of the nonce and its request, keyed with a
+//! shared secret, followed by the request itself: the arguments separated by
+//! newlines, the file, and the detached signature, each prefixed with its
+//! length as a big endian u32. The server then runs the command with
+//! --progress-json and relays its events, one JSON object per line, ending with
+//! the result.
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ecflash::hmac_sha256;
+
+use super::{exit, private_dir};
+use super::progress::{json_field, result_json, Progress};
+
+/// Greeting sent by the server, which also versions the protocol
+pub const MAGIC: &[u8; 8] = b"ECFLASH1";
+/// Port used when the address does not have one
+pub const DEFAULT_PORT: u16 = 7676;
+
+const NONCE_SIZE: usize = 32;
+const MAC_SIZE: usize = 32;
+const MAX_ARGS: usize = 4096;
+const MAX_FILE: usize = 16 * 1024 * 1024;
+
+/// How long the server waits for the whole request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long the server waits for the client to take progress events
+const WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Connections read at the same time, beyond which new ones are dropped
+const MAX_CONNECTIONS: usize = 16;
+
+/// Reader that fails once the deadline of the request has passed, so that a
+/// client sending a byte at a time cannot hold it open
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "request took too long"));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Read a length prefixed field, appending the raw bytes to signed for the MAC
+fn read_field<R: Read>(reader: &mut R, max: usize, signed: &mut Vec<u8>) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("field of {} bytes is too large", len)));
+    }
+
+    let mut data = vec![0; len];
+    reader.read_exact(&mut data)?;
+    signed.extend_from_slice(&(len as u32).to_be_bytes());
+    signed.extend_from_slice(&data);
+    Ok(data)
+}
+
+fn write_field(request: &mut Vec<u8>, data: &[u8]) {
+    request.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    request.extend_from_slice(data);
+}
+
+/// Compare MACs without exiting early on the first difference
+fn mac_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Check that only commands and options that are safe to take from the network
+/// are passed to the flashing process
+fn check_args(args: &[String]) -> Result<(), String> {
+    match args.first().map(|arg| arg.as_str()) {
+        Some("write") | Some("apply") => (),
+        Some(command) => return Err(format!("command '{}' is not allowed", command)),
+        None => return Err("no command".to_string()),
+    }
+
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "-1" | "-2" | "--allow-bootblock" | "--lock-bootblock" => (),
+            "--region" => match options.next() {
+                Some(region) if ! region.starts_with('-') => (),
+                _ => return Err("no region".to_string()),
+            },
+            _ => return Err(format!("option '{}' is not allowed", option)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Authenticate one request, run it once no other request is running, and
+/// relay its progress
+fn handle(mut stream: TcpStream, key: &[u8], running: &Mutex<()>) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+
+    let mut nonce = [0; NONCE_SIZE];
+    fs::File::open("/dev/urandom")?.read_exact(&mut nonce)?;
+    stream.write_all(MAGIC)?;
+    stream.write_all(&nonce)?;
+
+    let mut reader = Deadline {
+        stream: &stream,
+        deadline: Instant::now() + REQUEST_TIMEOUT,
+    };
+    let mut mac = [0; MAC_SIZE];
+    reader.read_exact(&mut mac)?;
+
+    let mut signed = nonce.to_vec();
+    let args = read_field(&mut reader, MAX_ARGS, &mut signed)?;
+    let file = read_field(&mut reader, MAX_FILE, &mut signed)?;
+    let signature = read_field(&mut reader, MAX_FILE, &mut signed)?;
+
+    if ! mac_eq(&mac, &hmac_sha256(key, &signed)) {
+        eprintln!("{}: authentication failed", peer);
+        return writeln!(stream, "{}", result_json(exit::FAILURE, "Authentication failed"));
+    }
+
+    let args: Vec<String> = String::from_utf8_lossy(&args).lines().map(|arg| arg.to_string()).collect();
+    if let Err(err) = check_args(&args) {
+        eprintln!("{}: rejected request: {}", peer, err);
+        return writeln!(stream, "{}", result_json(exit::USAGE, &format!("Rejected request: {}", err)));
+    }
+
+    // Requests share the EC, so they run one at a time
+    let _running = running.lock().unwrap_or_else(|err| err.into_inner());
+    eprintln!("{}: {}", peer, args.join(" "));
+
+    let dir = private_dir("ecflash-remote")?;
+    let path = dir.join("firmware.rom");
+    let res = (|| {
+        fs::write(&path, &file)?;
+        if ! signature.is_empty() {
+            fs::write(dir.join("firmware.rom.sig"), &signature)?;
+        }
+
+        let mut child = Command::new(env::current_exe()?)
+            .arg("--progress-json")
+            .arg("--yes")
+            .args(&args)
+            .arg(&path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        if let Some(mut stdout) = child.stdout.take() {
+            stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+            if let Err(err) = io::copy(&mut stdout, &mut stream) {
+                // A closed pipe would stop the flash halfway, so the rest of
+                // its output is read and dropped
+                eprintln!("{}: lost connection: {}", peer, err);
+                let _ = io::copy(&mut stdout, &mut io::sink());
+            }
+        }
+
+        // The image must stay until the flash has finished with it
+        let status = child.wait()?;
+        eprintln!("{}: finished with {}", peer, status);
+        Ok(())
+    })();
+
+    let _ = fs::remove_dir_all(&dir);
+    res
+}
+
+/// Accept requests on address, reading each in its own thread, and running
+/// them one at a time, since they share the EC
+pub fn serve(address: &str, key: &[u8]) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    eprintln!("Listening on {}", listener.local_addr()?);
+
+    let key = Arc::new(key.to_vec());
+    let running = Arc::new(Mutex::new(()));
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Failed to accept connection: {}", err);
+                continue;
+            }
+        };
+        if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            eprintln!("Dropping connection, {} are already open", MAX_CONNECTIONS);
+            continue;
+        }
+
+        let (key, running, connections) = (key.clone(), running.clone(), connections.clone());
+        thread::spawn(move || {
+            if let Err(err) = handle(stream, &key, &running) {
+                eprintln!("Failed to handle request: {}", err);
+            }
+            connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+
+    Ok(())
+}
+
+/// Send a request to the server at host, and report its progress as if it ran
+/// locally
+pub fn request(host: &str, key: &[u8], args: &[String], file: &[u8], signature: &[u8], progress: &Progress) -> ! {
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, DEFAULT_PORT)
+    };
+
+    let res = (|| {
+        let mut stream = TcpStream::connect(&address)?;
+
+        let mut greeting = [0; MAGIC.len() + NONCE_SIZE];
+        stream.read_exact(&mut greeting)?;
+        let (magic, nonce) = greeting.split_at(MAGIC.len());
+        if magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not an ecflash server"));
+        }
+
+        let mut request = Vec::new();
+        write_field(&mut request, args.join("\n").as_bytes());
+        write_field(&mut request, file);
+        write_field(&mut request, signature);
+
+        let mut signed = nonce.to_vec();
+        signed.extend_from_slice(&request);
+        stream.write_all(&hmac_sha256(key, &signed))?;
+        stream.write_all(&request)?;
+
+        Ok(BufReader::new(stream))
+    })();
+
+    let reader = match res {
+        Ok(reader) => reader,
+        Err(err) => progress.result(exit::FAILURE, &format!("Failed to connect to '{}': {}", address, err)),
+    };
+
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => progress.result(exit::FAILURE, &format!("Lost connection to '{}': {}", address, err)),
+        };
+
+        let field = |name| json_field(&line, name).unwrap_or_default();
+        let number = |name| field(name).parse::<usize>().unwrap_or(0);
+        match field("event").as_str() {
+            "progress" => progress.update(&field("phase"), number("bytes"), number("total")),
+            "warning" => progress.warning(&field("message")),
+            "info" => progress.info(&field("message")),
+            "result" => {
+                let code = field("exit_code").parse().unwrap_or(exit::FAILURE);
+                progress.result(code, &field("message"))
+            },
+            _ => (),
+        }
+    }
+
+    // Flashing the EC may power off the server before it sends the result
+    progress.result(exit::FAILURE, &format!("Connection to '{}' closed without a result", address))
+}
@@ -0,0 +1,148 @@
+//! The flash session that is active, so that a panic does not leave the EC
+//! waiting in flash mode until its battery is removed.
+//!
+//! The Flasher of the session is out of reach of the panic hook, so the hook
+//! opens the EC again through the same host interface and sends the exit
+//! follow mode and stop commands itself. It then exits instead of unwinding,
+//! so that the Flasher does not send them a second time when dropped.
+//!
+//! SIGINT and SIGTERM only set INTERRUPTED while erasing and writing, which the
+//! Flasher checks between blocks, so that the run can be resumed with the EC
+//! still in flash mode.
+
+use std::panic;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use ecflash::{DevMemPortIo, DevPort, EcFlash, HostInterface, RawPortIo};
+
+use super::progress::Progress;
+use super::{exit, kernel, sync, Io, Verbosity};
+
+/// What is needed to reach the EC of a session again
+struct Session {
+    primary: bool,
+    interface: HostInterface,
+    /// Command port of the mailbox, which is 0x64 with the kbc backend
+    cmd_port: u16,
+    json: bool,
+    verbosity: Verbosity,
+}
+
+/// Session between start_flasher and stop_flasher
+static ACTIVE: Mutex<Option<Session>> = Mutex::new(None);
+/// Most recent backup saved by this process
+static BACKUP: Mutex<Option<String>> = Mutex::new(None);
+
+const SIGINT: i32 = 2;
+const SIGTERM: i32 = 15;
+const SIG_DFL: usize = 0;
+
+extern "C" {
+    fn signal(signum: i32, handler: usize) -> usize;
+}
+
+/// Set by SIGINT and SIGTERM between catch_signals and release_signals
+pub static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn interrupt(_signum: i32) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Set INTERRUPTED on SIGINT and SIGTERM instead of dying
+pub fn catch_signals() {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    let handler = interrupt as extern "C" fn(i32) as usize;
+    unsafe {
+        signal(SIGINT, handler);
+        signal(SIGTERM, handler);
+    }
+}
+
+/// Die on SIGINT and SIGTERM again
+pub fn release_signals() {
+    unsafe {
+        signal(SIGINT, SIG_DFL);
+        signal(SIGTERM, SIG_DFL);
+    }
+}
+
+/// Record that the EC reached through interface entered flash mode
+pub fn begin(primary: bool, interface: HostInterface, cmd_port: u16, progress: &Progress) {
+    *ACTIVE.lock().unwrap_or_else(|err| err.into_inner()) = Some(Session {
+        primary,
+        interface,
+        cmd_port,
+        json: progress.json,
+        verbosity: progress.verbosity,
+    });
+}
+
+/// Record that the EC left flash mode
+pub fn end() {
+    ACTIVE.lock().unwrap_or_else(|err| err.into_inner()).take();
+}
+
+/// Record the path of a backup, which the panic hook prints
+pub fn saved_backup(path: &str) {
+    *BACKUP.lock().unwrap_or_else(|err| err.into_inner()) = Some(path.to_string());
+}
+
+/// Exit follow mode and leave flash mode on a new handle to the EC
+///
+/// The port backend reuses the I/O permission the process already has.
+unsafe fn recover(session: &Session) -> Result<(), String> {
+    let io: Io = match session.interface {
+        HostInterface::Ports => Box::new(RawPortIo),
+        HostInterface::DevPort => Box::new(DevPort::open().map_err(|err| err.to_string())?),
+        HostInterface::Mmio(base) => Box::new(DevMemPortIo::open(base).map_err(|err| err.to_string())?),
+        other => return Err(format!("cannot reach the EC through the {} again", other)),
+    };
+
+    let mut ec = EcFlash::with_io_unchecked(io, session.primary);
+    if session.cmd_port == 0x64 {
+        let _ = ec.use_kbc_ports();
+    }
+    let res = ec.cmd(5).and_then(|()| ec.cmd(0x95)).and_then(|()| ec.cmd(0xFC));
+    res.map_err(|()| "the EC did not take the stop command".to_string())
+}
+
+/// Chain a hook to the default panic hook that recovers the EC of an active
+/// session, syncs, and prints where the most recent backup is
+pub fn install_hook() {
+    let default = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default(info);
+
+        // The lock may be held by the panicking thread, so do not wait on it
+        let session = match ACTIVE.try_lock() {
+            Ok(mut active) => active.take(),
+            Err(_) => None,
+        };
+        let session = match session {
+            Some(session) => session,
+            None => return,
+        };
+
+        let progress = Progress {
+            json: session.json,
+            verbosity: session.verbosity,
+        };
+        let mut message = match unsafe { recover(&session) } {
+            Ok(()) => "Panicked in flash mode, the EC was stopped".to_string(),
+            Err(err) => format!(
+                "Panicked in flash mode, and failed to stop the EC, {}. Do not power off until it is flashed again",
+                err
+            ),
+        };
+        kernel::resume(&progress);
+        sync();
+
+        if let Ok(backup) = BACKUP.try_lock() {
+            if let Some(backup) = backup.as_ref() {
+                message.push_str(&format!(". The most recent backup is '{}'", backup));
+            }
+        }
+        progress.result(exit::FAILURE, &message)
+    }));
+}
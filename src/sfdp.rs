@@ -0,0 +1,112 @@
+//! SFDP tables of the SPI flash behind the EC, read with opcode 0x5A in
+//! follow mode through the mailbox, or with --programmer from the external
+//! flash on the FSPI pins through the Arduino programmer. The internal flash
+//! of ITE ECs has no SFDP tables.
+//!
+//! The tables are printed as read, then the JEDEC basic flash parameter table
+//! decoded, along with the erase that SpiChip configures from it.
+
+use std::io::{stdout, Write};
+use std::process;
+
+use ecflash::{Error, FlashChip, Sfdp, SpiBus, SpiChip, SpiRom, BASIC_TABLE_ID};
+
+use super::progress::Progress;
+use super::{exit, open_flasher, programmer, start_flasher, stop_flasher, Args, USAGE};
+
+/// Read the JEDEC ID and the SFDP space of the flash that the EC follows
+fn read_mailbox(args: &Args, progress: &Progress) -> ([u8; 3], ecflash::Result<Vec<u8>>) {
+    let mut flasher = open_flasher(args, progress);
+    unsafe {
+        start_flasher(&mut flasher, args.primary(), progress);
+        let jedec_id = flasher.spi_transfer(&[0x9F], 3);
+        let space = Sfdp::read_space(|address, data| match flasher.read_sfdp(address as u32, data.len()) {
+            Ok(bytes) => {
+                data.copy_from_slice(&bytes);
+                Ok(())
+            },
+            Err(()) => Err(Error::Transport("the EC did not take the SFDP read in follow mode".to_string())),
+        });
+        stop_flasher(&mut flasher, progress);
+
+        match jedec_id {
+            Ok(jedec_id) => ([jedec_id[0], jedec_id[1], jedec_id[2]], space),
+            Err(()) => progress.result(exit::FAILURE, "The EC did not take the SPI transaction in follow mode"),
+        }
+    }
+}
+
+/// Read the JEDEC ID and the SFDP space of the external flash through the
+/// programmer
+fn read_programmer(args: &Args, progress: &Progress) -> ([u8; 3], ecflash::Result<Vec<u8>>) {
+    let mut port = programmer::open(args, progress);
+    let res = SpiBus::new(&mut port, FlashChip::External).and_then(|mut bus| {
+        let mut spi = SpiRom::new(&mut bus);
+        let chip = spi.detect()?;
+        let space = Sfdp::read_space(|address, data| spi.read_sfdp(address as u32, data).map(|_| ()));
+        Ok((chip.jedec_id, space))
+    });
+    match res {
+        Ok(res) => res,
+        Err(err) => progress.result(exit::FAILURE, &format!("Failed to read the JEDEC ID through the programmer: {}", err)),
+    }
+}
+
+/// Print the SFDP tables of the flash and decode its basic parameters
+pub fn sfdp(args: &Args) -> ! {
+    let progress = args.progress();
+    if args.ec_args.iter().any(|arg| arg != "-1" && arg != "-2") {
+        progress.result(exit::USAGE, &format!("Invalid sfdp arguments\n{}", USAGE));
+    }
+
+    let (jedec_id, space) = match &args.programmer {
+        Some(_) => read_programmer(args, &progress),
+        None => read_mailbox(args, &progress),
+    };
+    let sfdp = match space.and_then(|space| Sfdp::parse(&space)) {
+        Ok(sfdp) => sfdp,
+        Err(Error::InvalidData(err)) => progress.result(exit::INCOMPATIBLE, &format!(
+            "Flash {:02X} {:02X} {:02X} has no SFDP tables: {}", jedec_id[0], jedec_id[1], jedec_id[2], err
+        )),
+        Err(err) => progress.result(exit::FAILURE, &format!("Failed to read SFDP tables: {}", err)),
+    };
+
+    let mut stdout = stdout();
+    let _ = writeln!(stdout, "JEDEC ID: {:02X} {:02X} {:02X}", jedec_id[0], jedec_id[1], jedec_id[2]);
+    let _ = writeln!(stdout, "SFDP revision {}.{}, {} parameter tables", sfdp.major, sfdp.minor, sfdp.tables.len());
+    for table in sfdp.tables.iter() {
+        let name = if table.id == BASIC_TABLE_ID { " JEDEC basic flash parameters" } else { "" };
+        let _ = writeln!(
+            stdout,
+            "Table 0x{:04X}{} revision {}.{} at 0x{:06X}, {} DWORDs",
+            table.id, name, table.major, table.minor, table.pointer, table.data.len() / 4
+        );
+        for (i, line) in table.data.chunks(16).enumerate() {
+            let hex: Vec<String> = line.iter().map(|x| format!("{:02X}", x)).collect();
+            let _ = writeln!(stdout, "  0x{:06X}: {}", table.pointer + i * 16, hex.join(" "));
+        }
+    }
+
+    let basic = match sfdp.basic() {
+        Ok(basic) => basic,
+        Err(err) => progress.result(exit::INCOMPATIBLE, &format!("Failed to decode SFDP tables: {}", err)),
+    };
+    let _ = writeln!(stdout, "Capacity: {} KiB", basic.capacity / 1024);
+    let _ = writeln!(stdout, "Address bytes: {}", basic.address_bytes);
+    let erase: Vec<String> = basic.erase_types.iter()
+        .map(|erase| format!("{} KiB with 0x{:02X}", erase.size / 1024, erase.opcode))
+        .collect();
+    let _ = writeln!(stdout, "Erase: {}", if erase.is_empty() { "none listed".to_string() } else { erase.join(", ") });
+    let _ = writeln!(stdout, "Fast read:");
+    for read in basic.fast_reads.iter() {
+        let _ = writeln!(
+            stdout,
+            "  {} 0x{:02X}, {} mode and {} dummy clocks",
+            read.mode, read.opcode, read.mode_clocks, read.dummy_clocks
+        );
+    }
+
+    let chip = SpiChip::from_sfdp(jedec_id, &basic);
+    let _ = writeln!(stdout, "Chip configuration: erase {} KiB sectors with 0x{:02X}", chip.sector_size / 1024, chip.erase_opcode);
+    process::exit(exit::OK);
+}
@@ -0,0 +1,170 @@
+//! Diagnostic bundle for support tickets, written by `ecflash report` as a tar
+//! archive of text files.
+//!
+//! Only what identifies the model and the state of the EC is gathered, not
+//! serial numbers or UUIDs. Journal entries name the users and files of past
+//! runs, so everything that goes in is shown and confirmed first.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::time;
+
+use ecflash::{Ec, FLASH_OPTION_BASE};
+
+use super::journal::JOURNAL_PATH;
+use super::progress::Progress;
+use super::{
+    confirm, exit, kernel, new_flasher, open_ec_io, read_bridge, start_flasher, stop_flasher, validate, Args,
+    PROTECT_REGISTERS,
+};
+
+/// Journal entries included, most recent last
+const JOURNAL_ENTRIES: usize = 20;
+
+/// DMI fields that name the model and firmware, leaving out serial numbers
+const DMI_FIELDS: &[&str] = &[
+    "sys_vendor", "product_name", "product_version", "board_vendor", "board_name", "bios_version", "bios_date",
+];
+
+/// Model, EC, and protection state, as text
+fn info(args: &Args, progress: &Progress) -> String {
+    let mut info = String::new();
+    let _ = writeln!(info, "ecflash: {}", env!("CARGO_PKG_VERSION"));
+    for name in DMI_FIELDS {
+        let value = fs::read_to_string(format!("/sys/class/dmi/id/{}", name)).unwrap_or_default();
+        let _ = writeln!(info, "{}: {}", name, value.trim());
+    }
+    let _ = writeln!(info, "ISA bridge: {}", read_bridge().unwrap_or_else(|| "unknown".to_string()));
+    let _ = writeln!(info, "Kernel EC driver: {}", kernel::driver_device().unwrap_or_else(|| "not bound".to_string()));
+
+    let primary = args.primary();
+    let mut ec = open_ec_io(args, primary, progress);
+    let _ = writeln!(info, "EC: {}", if primary { 1 } else { 2 });
+    if let Some(id) = ec.chip_id() {
+        let _ = writeln!(info, "Chip ID: IT{:04X}", id);
+    }
+    if let Some(chip_version) = ec.chip_version() {
+        let _ = writeln!(info, "Chip version: {}", chip_version);
+    }
+    if let Some((interface, data_port, cmd_port)) = ec.interface() {
+        let _ = writeln!(info, "Interface: {}, data 0x{:02X}, command 0x{:02X}", interface, data_port, cmd_port);
+    }
+    let answer = |s: Result<String, ()>| s.map_or("no answer".to_string(), |s| s.trim().to_string());
+    let _ = writeln!(info, "Project: {}", answer(validate(|| ec.project(), 8, args.verbosity)));
+    let _ = writeln!(info, "Version: {}", answer(validate(|| ec.version(), 8, args.verbosity)));
+    let _ = writeln!(info, "Flash size: {} KiB", ec.size() / 1024);
+
+    if primary {
+        for &(name, offset) in PROTECT_REGISTERS {
+            match unsafe { ec.flash_option(offset) } {
+                Ok(value) => { let _ = writeln!(info, "{} 0x{:04X}: 0x{:02X}", name, FLASH_OPTION_BASE + offset as u16, value); },
+                Err(()) => { let _ = writeln!(info, "{} 0x{:04X}: no answer", name, FLASH_OPTION_BASE + offset as u16); },
+            }
+        }
+    }
+
+    let mut flasher = new_flasher(args, ec, progress);
+    let status = unsafe {
+        start_flasher(&mut flasher, primary, progress);
+        let status = flasher.status();
+        stop_flasher(&mut flasher, progress);
+        status
+    };
+    match status {
+        Ok(status) => { let _ = writeln!(info, "Status register: 0x{:02X}", status); },
+        Err(()) => { let _ = writeln!(info, "Status register: no answer"); },
+    }
+
+    info
+}
+
+/// The last JOURNAL_ENTRIES lines of the journal
+fn journal() -> String {
+    let text = fs::read_to_string(JOURNAL_PATH).unwrap_or_default();
+    let lines: Vec<&str> = text.lines().collect();
+    let start = lines.len().saturating_sub(JOURNAL_ENTRIES);
+    lines[start..].iter().map(|line| format!("{}\n", line)).collect()
+}
+
+/// The last EC session of the trace at path, from its last header
+fn trace(path: &str) -> std::io::Result<String> {
+    let text = fs::read_to_string(path)?;
+    let start = text.rfind("# EC flash").unwrap_or(0);
+    Ok(text[start..].to_string())
+}
+
+/// Field of a tar header, as a NUL terminated octal number
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(digits.as_bytes());
+}
+
+/// Archive files in the ustar format, which any tar reads
+fn tar(files: &[(&str, String)], mtime: u64) -> Vec<u8> {
+    let mut archive = Vec::new();
+    for (name, data) in files {
+        let mut header = [0; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        octal(&mut header[100..108], 0o644);
+        octal(&mut header[108..116], 0);
+        octal(&mut header[116..124], 0);
+        octal(&mut header[124..136], data.len() as u64);
+        octal(&mut header[136..148], mtime);
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+
+        // The checksum is taken with its own field as spaces
+        header[148..156].copy_from_slice(b"        ");
+        let checksum: u32 = header.iter().map(|&x| x as u32).sum();
+        octal(&mut header[148..155], checksum as u64);
+        header[155] = b' ';
+
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data.as_bytes());
+        archive.resize(archive.len().div_ceil(512) * 512, 0);
+    }
+    // Two zero blocks end the archive
+    archive.resize(archive.len() + 1024, 0);
+    archive
+}
+
+pub fn report(args: &Args) -> ! {
+    let progress = args.progress();
+    let mtime = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let path = args.file().map_or_else(|| format!("ecflash-report-{}.tar", mtime), |file| file.to_string());
+
+    // The EC is opened without a trace, so this is the trace of an earlier run
+    let trace = match &args.trace {
+        Some(trace) => match self::trace(trace) {
+            Ok(text) => Some(text),
+            Err(err) => progress.result(exit::IO, &format!("Failed to read '{}': {}", trace, err)),
+        },
+        None => None,
+    };
+    let journal = journal();
+    let info = info(args, &progress);
+
+    let mut prompt = format!("Report '{}' will hold:\n\ninfo.txt\n{}", path, info);
+    let _ = writeln!(
+        prompt,
+        "\njournal.jsonl\n{} entries, which name the users and files of those runs",
+        journal.lines().count()
+    );
+    let mut files = vec![("info.txt", info), ("journal.jsonl", journal)];
+    if let Some(trace) = trace {
+        let _ = writeln!(prompt, "\ntrace.log\n{} lines of EC transactions from '{}'", trace.lines().count(), args.trace.as_deref().unwrap_or(""));
+        files.push(("trace.log", trace));
+    }
+    if ! confirm(args, &prompt) {
+        progress.result(exit::FAILURE, "Cancelled");
+    }
+
+    match fs::write(&path, tar(&files, mtime)) {
+        Ok(()) => progress.result(exit::OK, &format!("Saved report to '{}', attach it to the support ticket", path)),
+        Err(err) => progress.result(exit::IO, &format!("Failed to write '{}': {}", path, err)),
+    }
+}
@@ -0,0 +1,223 @@
+//! USB Type-C port controllers behind the EC, read through the fcommand
+//! interface as the standard TCPCI register map.
+//!
+//! Each read returns the 16 bit word at a register, so 8 bit registers are the
+//! low byte of their word. Boards with more than one USB-C port have a TCPC
+//! for each at its own I2C address behind the EC, which are listed in
+//! tcpc_addresses of the configuration file.
+
+use std::io::{stdout, Write};
+use std::process;
+
+use ecflash::EcFlash;
+
+use super::{exit, open_ec, Args, Io, USAGE};
+
+/// fcommand that passes a read through to the I2C bus of the TCPC
+const FCMD_TCPC: u8 = 0x76;
+const FCMD_TCPC_READ: u8 = 0x10;
+/// I2C address the EC reaches the TCPC of port 0 at, when tcpc_addresses is
+/// not configured
+const DEFAULT_ADDRESS: u8 = 0x2C;
+
+/// A register of the TCPCI register map
+struct Register {
+    offset: u8,
+    name: &'static str,
+    /// 8 or 16 bits
+    word: bool,
+    /// Fields of the value worth printing
+    decode: Option<fn(u16) -> String>,
+}
+
+const fn register(offset: u8, name: &'static str, word: bool) -> Register {
+    Register { offset, name, word, decode: None }
+}
+
+const fn decoded(offset: u8, name: &'static str, word: bool, decode: fn(u16) -> String) -> Register {
+    Register { offset, name, word, decode: Some(decode) }
+}
+
+const REGISTERS: &[Register] = &[
+    register(0x00, "VENDOR_ID", true),
+    register(0x02, "PRODUCT_ID", true),
+    register(0x04, "DEVICE_ID", true),
+    register(0x06, "USBTYPEC_REV", true),
+    register(0x08, "USBPD_REV_VER", true),
+    register(0x0A, "PD_INTERFACE_REV", true),
+    decoded(0x10, "ALERT", true, alert),
+    register(0x12, "ALERT_MASK", true),
+    register(0x14, "POWER_STATUS_MASK", false),
+    register(0x15, "FAULT_STATUS_MASK", false),
+    register(0x18, "CONFIG_STANDARD_OUTPUT", false),
+    register(0x19, "TCPC_CONTROL", false),
+    decoded(0x1A, "ROLE_CONTROL", false, role_control),
+    register(0x1B, "FAULT_CONTROL", false),
+    register(0x1C, "POWER_CONTROL", false),
+    decoded(0x1D, "CC_STATUS", false, cc_status),
+    decoded(0x1E, "POWER_STATUS", false, power_status),
+    decoded(0x1F, "FAULT_STATUS", false, fault_status),
+    register(0x24, "DEVICE_CAPABILITIES_1", true),
+    register(0x26, "DEVICE_CAPABILITIES_2", true),
+    register(0x28, "STANDARD_INPUT_CAPABILITIES", false),
+    register(0x29, "STANDARD_OUTPUT_CAPABILITIES", false),
+    register(0x2E, "MESSAGE_HEADER_INFO", false),
+    register(0x2F, "RECEIVE_DETECT", false),
+    decoded(0x70, "VBUS_VOLTAGE", true, vbus_voltage),
+    register(0x72, "VBUS_SINK_DISCONNECT_THRESHOLD", true),
+    register(0x74, "VBUS_STOP_DISCHARGE_THRESHOLD", true),
+    register(0x76, "VBUS_VOLTAGE_ALARM_HI_CFG", true),
+    register(0x78, "VBUS_VOLTAGE_ALARM_LO_CFG", true),
+];
+
+/// Names of the bits that are set, or none
+fn bits(value: u16, names: &[&str]) -> String {
+    let set: Vec<&str> = names.iter()
+        .enumerate()
+        .filter(|&(bit, _)| value & (1 << bit) != 0)
+        .map(|(_, &name)| name)
+        .collect();
+    if set.is_empty() {
+        "none".to_string()
+    } else {
+        set.join(", ")
+    }
+}
+
+fn alert(value: u16) -> String {
+    bits(value, &[
+        "CC status", "power status", "received SOP message", "received hard reset",
+        "transmit failed", "transmit discarded", "transmit successful", "VBUS voltage alarm high",
+        "VBUS voltage alarm low", "fault", "receive buffer overflow", "VBUS sink disconnect",
+        "beginning SOP message", "extended status", "alert extended", "vendor defined",
+    ])
+}
+
+fn role_control(value: u16) -> String {
+    let cc = |value: u16| ["Ra", "Rp", "Rd", "open"][value as usize & 3];
+    let rp = ["default", "1.5 A", "3.0 A", "reserved"][(value as usize >> 4) & 3];
+    format!(
+        "CC1: {}, CC2: {}, Rp: {}{}",
+        cc(value), cc(value >> 2), rp,
+        if value & (1 << 6) != 0 { ", dual role toggling" } else { "" }
+    )
+}
+
+fn cc_status(value: u16) -> String {
+    // As a sink, the state is the Rp the source presents
+    let sink = value & (1 << 4) != 0;
+    let cc = |value: u16| if sink {
+        ["SNK.Open", "SNK.Default", "SNK.Power1.5", "SNK.Power3.0"][value as usize & 3]
+    } else {
+        ["SRC.Open", "SRC.Ra", "SRC.Rd", "reserved"][value as usize & 3]
+    };
+    format!(
+        "CC1: {}, CC2: {}, presenting {}{}",
+        cc(value), cc(value >> 2),
+        if sink { "Rd" } else { "Rp" },
+        if value & (1 << 5) != 0 { ", looking for connection" } else { "" }
+    )
+}
+
+fn power_status(value: u16) -> String {
+    bits(value, &[
+        "sinking VBUS", "VCONN present", "VBUS present", "VBUS detection enabled",
+        "sourcing VBUS", "sourcing high voltage", "TCPC initializing", "debug accessory",
+    ])
+}
+
+fn fault_status(value: u16) -> String {
+    bits(value, &[
+        "I2C interface error", "VCONN overcurrent", "VBUS overvoltage", "VBUS overcurrent",
+        "forced discharge failed", "auto discharge failed", "force off VBUS", "registers reset to default",
+    ])
+}
+
+fn vbus_voltage(value: u16) -> String {
+    // 25 mV per step, of a measurement divided by 2 to the scale factor
+    let scale = (value >> 10) & 3;
+    format!("{} mV", ((value as u32 & 0x3FF) * 25) << scale)
+}
+
+/// I2C addresses of the TCPCs, by port
+fn addresses(args: &Args) -> Vec<u8> {
+    if args.config.tcpc_addresses.is_empty() {
+        vec![DEFAULT_ADDRESS]
+    } else {
+        args.config.tcpc_addresses.clone()
+    }
+}
+
+/// Read the 16 bit word at a register of the TCPC at address
+unsafe fn read_word(ec: &mut EcFlash<Io>, address: u8, offset: u8) -> Result<u16, ()> {
+    let mut buf = [address, offset, 0x00, 0x00];
+    ec.fcommand(FCMD_TCPC, FCMD_TCPC_READ, &mut buf)?;
+    Ok(u16::from_le_bytes([buf[2], buf[3]]))
+}
+
+/// Print each port with the vendor and product IDs of its TCPC, if it answers
+fn list(args: &Args) -> ! {
+    let progress = args.progress();
+    let mut ec = open_ec(args, args.primary(), &progress);
+    let mut stdout = stdout();
+
+    for (port, address) in addresses(args).into_iter().enumerate() {
+        let ids = unsafe {
+            read_word(&mut ec, address, 0x00)
+                .and_then(|vendor| Ok((vendor, read_word(&mut ec, address, 0x02)?)))
+        };
+        let _ = match ids {
+            // Nothing answers at the address if the IDs read as all 0 or 1
+            Ok((vendor, _)) if vendor == 0 || vendor == 0xFFFF => writeln!(stdout, "Port {}: 0x{:02X}, no TCPC", port, address),
+            Ok((vendor, product)) => writeln!(stdout, "Port {}: 0x{:02X}, vendor 0x{:04X}, product 0x{:04X}", port, address, vendor, product),
+            Err(()) => writeln!(stdout, "Port {}: 0x{:02X}, fcommand failed", port, address),
+        };
+    }
+
+    process::exit(exit::OK);
+}
+
+fn dump(args: &Args) -> ! {
+    let progress = args.progress();
+    let port = args.port.unwrap_or(0);
+    let address = match addresses(args).get(port) {
+        Some(&address) => address,
+        None => progress.result(
+            exit::USAGE,
+            &format!("No TCPC address for port {}, add it to tcpc_addresses in the configuration file", port)
+        ),
+    };
+
+    let mut ec = open_ec(args, args.primary(), &progress);
+    let mut stdout = stdout();
+
+    for register in REGISTERS {
+        let value = match unsafe { read_word(&mut ec, address, register.offset) } {
+            Ok(value) if register.word => value,
+            Ok(value) => value & 0xFF,
+            Err(()) => progress.result(exit::FAILURE, &format!("Failed to read TCPC register {}", register.name)),
+        };
+
+        let hex = if register.word { format!("0x{:04X}", value) } else { format!("0x{:02X}", value) };
+        let _ = write!(stdout, "0x{:02X} {:<31} {}", register.offset, register.name, hex);
+        if let Some(decode) = register.decode {
+            let _ = write!(stdout, "  {}", decode(value));
+        }
+        let _ = writeln!(stdout);
+    }
+
+    process::exit(exit::OK);
+}
+
+pub fn tcpc(args: &Args) -> ! {
+    let params: Vec<&str> = args.ec_args.iter()
+        .map(|arg| arg.as_str())
+        .filter(|&arg| arg != "-1" && arg != "-2")
+        .collect();
+
+    match params.as_slice() {
+        [] | ["dump"] => dump(args),
+        ["list"] => list(args),
+        _ => args.progress().result(exit::USAGE, &format!("Invalid tcpc command\n{}", USAGE)),
+    }
+}
@@ -0,0 +1,210 @@
+//! Guided recovery of an EC that no longer flashes normally.
+//!
+//! On the bricked machine, each internal path is tried, from the mailbox that
+//! `write` uses, to the I2EC interface of the Super I/O that the scratch ROM
+//! path needs, and the one to use is printed. If none of them answers, the EC
+//! is dead to the host, and its flash can only be programmed with the Arduino
+//! programmer, from a second machine that still boots. There, `--external`
+//! walks the user through connecting it and restoring the backup.
+//!
+//! The scratch ROM is only recommended, not entered, since the EC cannot
+//! leave it without powering off the system.
+
+use std::fs;
+use std::io::{self, stdin, stderr, stdout, BufRead, Write};
+use std::path::Path;
+
+use ecflash::{isp_external, Ec, EcFile, EcFlash, FlashChip, IspOptions, PortIo, RawPortIo, KNOWN_IDS};
+use ecflash::regs::{ECHIPID1, ECHIPID2};
+
+use super::progress::Progress;
+use super::programmer::{find_programmers, open_port};
+use super::{exit, iopl, verify_digest, verify_image, Args};
+
+/// Backup that the isp example saves before erasing, used by default
+const DEFAULT_BACKUP: &str = "backup.rom";
+
+/// Status port of the keyboard controller, which floats high without an EC
+const KBC_STATUS: u16 = 0x64;
+
+/// How far the internal paths got
+enum Diagnosis {
+    /// The mailbox answers, so write can still be used
+    Mailbox,
+    /// The Super I/O answers with a known ID but the mailbox does not
+    I2ec(u16),
+    /// Nothing answers, or only with a bogus ID
+    Dead,
+}
+
+/// Print a step and wait for the user to press enter, returning false if they
+/// typed q to stop
+fn step(text: &str) -> bool {
+    let _ = write!(stderr(), "\n{}\nPress enter to continue, or type 'q' to stop: ", text);
+    let _ = stderr().flush();
+
+    let mut line = String::new();
+    match stdin().lock().read_line(&mut line) {
+        Ok(0) | Err(_) => false,
+        Ok(_) => line.trim() != "q",
+    }
+}
+
+/// Try each internal path in turn, printing what answered
+fn diagnose(args: &Args) -> Diagnosis {
+    let mut stdout = stdout();
+
+    if unsafe { iopl(3) } < 0 {
+        args.progress().result(
+            exit::PERMISSION,
+            &format!("Failed to get I/O permission, run as root to try the internal paths: {}", io::Error::last_os_error())
+        );
+    }
+
+    let mut io = RawPortIo;
+    let kbc = unsafe { io.inb(KBC_STATUS) } != 0xFF;
+    let _ = writeln!(stdout, "Keyboard controller: {}", if kbc { "responds" } else { "does not respond" });
+
+    let mut ec = EcFlash::with_io_unchecked(RawPortIo, true);
+    let id = ec.chip_id().unwrap_or(0);
+    let known = KNOWN_IDS.contains(&id) || args.config.known_ids.contains(&id);
+    let _ = writeln!(stdout, "Super I/O ID: 0x{:04X}{}", id, if known { "" } else { " (bogus or unknown)" });
+    if ! known {
+        return Diagnosis::Dead;
+    }
+
+    let _ = unsafe { ec.flush() };
+    if let Ok(project) = unsafe { ec.get_str(0x92) } {
+        if ! project.is_empty() {
+            let _ = writeln!(stdout, "Mailbox: responds, project {}, version {}", project.trim(), ec.version().trim());
+            return Diagnosis::Mailbox;
+        }
+    }
+    let _ = writeln!(stdout, "Mailbox: does not respond");
+
+    let i2ec_id = unsafe {
+        match (ec.memory_read(ECHIPID1), ec.memory_read(ECHIPID2)) {
+            (Ok(a), Ok(b)) => ((a as u16) << 8) | b as u16,
+            _ => 0,
+        }
+    };
+    if i2ec_id != id {
+        let _ = writeln!(stdout, "I2EC: reads chip ID 0x{:04X} instead of 0x{:04X}", i2ec_id, id);
+        return Diagnosis::Dead;
+    }
+    let _ = writeln!(stdout, "I2EC: responds");
+    Diagnosis::I2ec(id)
+}
+
+/// Check that backup is a complete EC image, printing what it holds, and
+/// return it
+fn check_backup(backup: &str) -> Result<Vec<u8>, String> {
+    let data = fs::read(backup).map_err(|err| format!("failed to read '{}': {}", backup, err))?;
+    verify_digest(backup, &data)?;
+    let size = data.len();
+    if size != 128 * 1024 && size != 256 * 1024 {
+        return Err(format!("'{}' is {} bytes, not a 128 or 256 KiB EC image", backup, size));
+    }
+
+    let mut file = EcFile::new(data.clone());
+    let (project, version) = (file.project(), file.version());
+    if project.is_empty() {
+        return Err(format!("'{}' does not hold an EC project string", backup));
+    }
+    let _ = writeln!(stdout(), "Backup: {}, project {}, version {}, {} KiB", backup, project, version, size / 1024);
+    Ok(data)
+}
+
+/// Walk through connecting the external programmer to the bricked machine,
+/// from the second machine this runs on, then restore the backup through it
+fn external(args: &Args, progress: &Progress, backup: &str) -> Result<String, String> {
+    if ! step("Power off the bricked machine, unplug its AC adapter and battery, and connect the Arduino programmer to its EC debug header.") {
+        return Err("cancelled".to_string());
+    }
+
+    let port = loop {
+        // A port passed with --programmer is used even before it appears
+        if let Some(port) = &args.programmer {
+            let _ = writeln!(stdout(), "Programmer: {}", port);
+            break port.clone();
+        }
+        let ports = find_programmers(args);
+        if let Some(port) = ports.first() {
+            let _ = writeln!(stdout(), "Programmer: {}", ports.join(", "));
+            break port.clone();
+        }
+        if ! step("No programmer found on /dev/ttyACM* or /dev/ttyUSB*. Plug its USB cable into this machine.") {
+            return Err("no programmer found".to_string());
+        }
+    };
+
+    let mut firmware = check_backup(backup)?;
+    // Detached signature, only checked when built with the signature feature
+    let signature = fs::read(format!("{}.sig", backup)).ok();
+    verify_image(progress, &firmware, signature.as_deref());
+
+    // Erased bytes at the end need not be programmed, but words are
+    while firmware.last() == Some(&0xFF) {
+        firmware.pop();
+    }
+    if firmware.len() % 2 != 0 {
+        firmware.push(0xFF);
+    }
+
+    let _ = writeln!(stdout(), "The chip ID is read through the programmer twice, and nothing is erased unless both reads agree on a known ID.");
+    if ! step(&format!("Restoring {} through {}.", backup, port)) {
+        return Err("cancelled".to_string());
+    }
+
+    let mut programmer = open_port(args, progress, &port).map_err(|(_, err)| err)?;
+    let mut update = |phase: &str, done: usize, total: usize| progress.update(phase, done, total);
+    let mut opts = IspOptions::new(FlashChip::Internal);
+    opts.progress = Some(&mut update);
+    let isp = isp_external(&mut programmer, &firmware, opts)
+        .map_err(|err| format!("failed to restore {}: {}", backup, err))?;
+    progress.report(&isp.report);
+    if isp.unchanged {
+        return Ok(format!("The flash already holds {}", backup));
+    }
+    Ok(format!("Restored {} with the programmer on {}", backup, port))
+}
+
+pub fn unbrick(args: &Args) -> ! {
+    let progress = args.progress();
+    let backup = args.file().unwrap_or(DEFAULT_BACKUP);
+
+    if args.external {
+        match external(args, &progress, backup) {
+            Ok(message) => progress.result(exit::OK, &message),
+            Err(err) => progress.result(exit::FAILURE, &format!("Unbrick stopped: {}", err)),
+        }
+    }
+
+    let external = format!(
+        "connect the Arduino programmer to its EC debug header, and run on a second machine: system76_ecflash unbrick --external {}",
+        backup
+    );
+    match diagnose(args) {
+        Diagnosis::Mailbox => progress.result(
+            exit::OK,
+            &format!("The EC still answers, so flash it normally with: system76_ecflash write {}", backup)
+        ),
+        Diagnosis::I2ec(id) => {
+            progress.warning(&format!(
+                "EC 0x{:04X} is reachable through I2EC but its firmware does not answer. The scratch ROM path may still work, and powers off the system when done",
+                id
+            ));
+            if Path::new(backup).exists() {
+                if let Err(err) = check_backup(backup) {
+                    progress.warning(&err);
+                }
+            }
+            let _ = writeln!(stdout(), "Scratch ROM: system76_ecflash write --algorithm scratch --allow-bootblock {}", backup);
+            progress.result(exit::FAILURE, &format!("If the scratch ROM path fails, {}", external))
+        },
+        Diagnosis::Dead => progress.result(
+            exit::NO_EC,
+            &format!("The EC does not answer on any internal path, {}", external)
+        ),
+    }
+}